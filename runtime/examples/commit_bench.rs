@@ -0,0 +1,68 @@
+//! Times committing many small functions that each reference a global and a dynamic label, and counts the heap
+//! allocations made by those commits. Run with `cargo run --release --example commit_bench`.
+
+extern crate dynasmrt;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use dynasmrt::{x64, DynasmApi, DynasmLabelApi};
+
+const FUNCTIONS: usize = 10_000;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn main() {
+    // size the buffer up front, so the commits don't have to move it
+    let mut ops = x64::Assembler::with_initial_size(FUNCTIONS * 16).unwrap();
+    ops.global_label("exit");
+    ops.push(0xC3);
+    // the first commit sets up the scratch space that later commits reuse
+    ops.push(0xE9);
+    ops.push_u32(0);
+    ops.global_reloc("exit", 0, (0, 4));
+    ops.commit().unwrap();
+    let labels: Vec<_> = (0 .. FUNCTIONS).map(|_| ops.new_dynamic_label()).collect();
+
+    let mut allocations = 0;
+    let start = Instant::now();
+    for &label in &labels {
+        // call =>label; jmp ->exit
+        ops.dynamic_label(label);
+        ops.push(0xE8);
+        ops.push_u32(0);
+        ops.dynamic_reloc(label, 0, (0, 4));
+        ops.push(0xE9);
+        ops.push_u32(0);
+        ops.global_reloc("exit", 0, (0, 4));
+
+        let before = ALLOCATIONS.load(Ordering::Relaxed);
+        ops.commit().unwrap();
+        allocations += ALLOCATIONS.load(Ordering::Relaxed) - before;
+    }
+    let elapsed = start.elapsed();
+
+    println!("{} commits in {:?}, {} allocations while committing", FUNCTIONS, elapsed, allocations);
+    // tests/commit_allocations.rs checks this as well
+    assert_eq!(allocations, 0, "committing allocated");
+}
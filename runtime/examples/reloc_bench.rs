@@ -0,0 +1,53 @@
+//! Times committing a function with a large amount of branches, like a big switch lowered to a jump table.
+//! Run with `cargo run --release --example reloc_bench`.
+
+extern crate dynasmrt;
+
+use std::time::Instant;
+
+use dynasmrt::{x64, DynasmApi, DynasmLabelApi};
+
+const CASES: usize = 50_000;
+
+fn jmp(ops: &mut x64::Assembler) {
+    ops.push(0xE9);
+    ops.push_u32(0);
+}
+
+fn main() {
+    let mut ops = x64::Assembler::new().unwrap();
+
+    let start = Instant::now();
+    let cases: Vec<_> = (0 .. CASES).map(|_| ops.new_dynamic_label()).collect();
+
+    // the jump table
+    for &case in &cases {
+        jmp(&mut ops);
+        ops.dynamic_reloc(case, 0, (0, 4));
+    }
+
+    // the cases, each skipping a local label and leaving through a shared global exit
+    for &case in &cases {
+        ops.dynamic_label(case);
+        jmp(&mut ops);
+        ops.forward_reloc("next", 0, (0, 4));
+        ops.local_label("next");
+        jmp(&mut ops);
+        ops.global_reloc("exit", 0, (0, 4));
+    }
+    ops.global_label("exit");
+    ops.push(0xC3);
+    let assembled = start.elapsed();
+
+    let start = Instant::now();
+    ops.commit().unwrap();
+    let committed = start.elapsed();
+
+    let start = Instant::now();
+    for &case in &cases {
+        ops.free_dynamic_label(case).unwrap();
+    }
+    let freed = start.elapsed();
+
+    println!("{} cases: assembled in {:?}, committed in {:?}, labels freed in {:?}", CASES, assembled, committed, freed);
+}
@@ -1,6 +1,8 @@
 use crate::relocations::{Relocation, RelocationSize, RelocationKind, ImpossibleRelocation, fits_signed_bitfield};
 use byteorder::{ByteOrder, LittleEndian};
-use std::convert::TryFrom;
+use core::convert::TryFrom;
+#[cfg(feature = "std")]
+use crate::{AssemblyOffset, DynasmError};
 
 /// Relocation implementation for the aarch64 architecture.
 #[derive(Debug, Clone)]
@@ -162,9 +164,39 @@ impl Relocation for Aarch64Relocation {
 }
 
 
+// brk #0
+#[cfg(feature = "std")]
+const BRK: [u8; 4] = [0x00, 0x00, 0x20, 0xD4];
+
+#[cfg(feature = "std")]
+impl crate::Assembler<Aarch64Relocation> {
+    /// Emit a breakpoint, a `brk #0` instruction.
+    pub fn breakpoint(&mut self) {
+        self.extend(&BRK);
+    }
+
+    /// Install a breakpoint at the committed offset `at`, by overwriting the instruction there with a `brk #0`.
+    /// The overwritten instruction is saved, so `clear_breakpoint` can put it back. While this is happening no code
+    /// can be executed as the relevant pages are remapped as writable. Does nothing if a breakpoint was already
+    /// installed at `at`. Returns an error if the instruction at `at` is not within the committed code.
+    pub fn set_breakpoint(&mut self, at: AssemblyOffset) -> Result<(), DynasmError> {
+        self.set_breakpoint_with(at, &BRK)
+    }
+}
+
+
+#[cfg(feature = "std")]
 pub type Assembler = crate::Assembler<Aarch64Relocation>;
+#[cfg(feature = "std")]
 pub type AssemblyModifier<'a> = crate::Modifier<'a, Aarch64Relocation>;
 pub type UncommittedModifier<'a> = crate::UncommittedModifier<'a>;
+pub type SliceAssembler<'a> = crate::SliceAssembler<'a, Aarch64Relocation>;
+pub type SizingAssembler = crate::SizingAssembler<Aarch64Relocation>;
+pub type VecLabelAssembler = crate::VecLabelAssembler<Aarch64Relocation>;
+#[cfg(feature = "std")]
+pub type RingAssembler = crate::ring::RingAssembler<Aarch64Relocation>;
+#[cfg(feature = "std")]
+pub type AssemblerPool = crate::pool::AssemblerPool<Aarch64Relocation>;
 
 
 /// Helper function for validating that a given value can be encoded as a 32-bit logical immediate
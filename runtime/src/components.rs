@@ -1,18 +1,36 @@
-// some basic types that are used across multiple assemblers.
-// like primitives for assembling buffers, etc
-
+//! This module contains the building blocks that the assemblers in this crate are made of, like primitives for
+//! assembling buffers, and the bookkeeping of labels and relocations.
+//!
+//! These can be reused to build assemblers for other backends. All assemblers in this crate are generic over
+//! the `Relocation` trait, which is where the encoding of relocations is decided. Implementing it for a
+//! new encoding is enough to use `Assembler` or `SliceAssembler` with it. Custom assemblers can instead
+//! combine a `LabelRegistry` and `RelocRegistry` directly. A forward reference is recorded in the
+//! `RelocRegistry` as a `PatchLoc`, taken out again with `take_locals_named` when the local label is
+//! defined, and then resolved with `PatchLoc::patch`. Backward references and references to other labels
+//! are resolved the same way, using the offset from `LabelRegistry`.
+
+use alloc::collections::btree_map::{BTreeMap, Entry};
+use alloc::vec::Vec;
+use alloc::vec;
+use alloc::string::String;
+use core::ops::Range;
+#[cfg(feature = "std")]
 use std::io;
-use std::collections::hash_map::{HashMap, Entry};
-use std::collections::BTreeMap;
-use std::sync::{Arc, RwLock, RwLockWriteGuard};
+#[cfg(feature = "std")]
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+#[cfg(feature = "std")]
 use std::mem;
+#[cfg(feature = "std")]
+use std::cmp;
 
-use crate::{DynamicLabel, AssemblyOffset, DynasmError, LabelKind, DynasmLabelApi};
-use crate::mmap::{ExecutableBuffer, MutableBuffer};
+use crate::{DynamicLabel, AssemblyOffset, DynasmError, DuplicateLabelPolicy, LabelKind, TargetKind, DynasmLabelApi};
+#[cfg(feature = "std")]
+use crate::mmap::{self, ExecutableBuffer, MutableBuffer, AllocError, DataMap};
 use crate::relocations::{Relocation, RelocationKind, RelocationSize, ImpossibleRelocation};
 
 
 /// This struct implements a protection-swapping assembling buffer
+#[cfg(feature = "std")]
 #[derive(Debug)]
 pub struct MemoryManager {
     // buffer where the end result is copied into
@@ -24,23 +42,180 @@ pub struct MemoryManager {
     asmoffset: usize,
 
     // the address that the current execbuffer starts at
-    execbuffer_addr: usize
+    execbuffer_addr: usize,
+
+    // if the execbuffer was mapped at a fixed address. Such buffers cannot grow.
+    fixed: bool,
+
+    // if executable buffers should be zeroed when they are dropped
+    zero_on_drop: bool,
+
+    // if executable buffers are mapped writable as well, so they never have to be remapped
+    writable: bool,
+
+    // the size the executable buffer may never grow beyond
+    max_size: Option<usize>,
+
+    // once data referenced by the code has been mapped, the address that it and the executable buffer are kept close to
+    anchor: Option<usize>,
 }
 
+#[cfg(feature = "std")]
 impl MemoryManager {
     /// Create a new memory manager, with `initial_mmap_size` data allocated
     pub fn new(initial_mmap_size: usize) -> io::Result<Self> {
-        let execbuffer = ExecutableBuffer::new(initial_mmap_size)?;
+        Ok(Self::try_new(initial_mmap_size)?)
+    }
+
+    /// Create a new memory manager that doesn't allocate any memory until data is committed into it.
+    pub fn new_lazy() -> Self {
+        Self::from_buffer(ExecutableBuffer::default())
+    }
+
+    /// Like `new`, but tells why the memory could not be obtained.
+    pub fn try_new(initial_mmap_size: usize) -> Result<Self, AllocError> {
+        let execbuffer = ExecutableBuffer::try_new(initial_mmap_size)?;
+        let execbuffer_addr = execbuffer.as_ptr() as usize;
+
+        Ok(MemoryManager {
+            execbuffer: Arc::new(RwLock::new(execbuffer)),
+            execbuffer_size: initial_mmap_size,
+            asmoffset: 0,
+            execbuffer_addr,
+            fixed: false,
+            zero_on_drop: false,
+            writable: false,
+            max_size: None,
+            anchor: None,
+        })
+    }
+
+    /// Create a new memory manager, with `initial_mmap_size` data allocated, whose memory is mapped readable, writable
+    /// and executable at the same time. This memory is never remapped when data is committed into it or changed.
+    /// This gives up on memory never being writable and executable at the same time, so it should only be used
+    /// during development.
+    pub fn new_writable(initial_mmap_size: usize) -> io::Result<Self> {
+        let execbuffer = MutableBuffer::new_writable(initial_mmap_size)?.make_exec()?;
         let execbuffer_addr = execbuffer.as_ptr() as usize;
 
         Ok(MemoryManager {
             execbuffer: Arc::new(RwLock::new(execbuffer)),
             execbuffer_size: initial_mmap_size,
             asmoffset: 0,
-            execbuffer_addr
+            execbuffer_addr,
+            fixed: false,
+            zero_on_drop: false,
+            writable: true,
+            max_size: None,
+            anchor: None,
         })
     }
 
+    /// Create a new memory manager, managing `size` bytes of memory mapped at exactly `addr`.
+    /// As the memory cannot move, committing more than `size` bytes into this manager is impossible.
+    pub fn new_at(addr: usize, size: usize) -> io::Result<Self> {
+        let execbuffer = ExecutableBuffer::new_at(addr, size)?;
+
+        Ok(MemoryManager {
+            execbuffer: Arc::new(RwLock::new(execbuffer)),
+            execbuffer_size: size,
+            asmoffset: 0,
+            execbuffer_addr: addr,
+            fixed: true,
+            zero_on_drop: false,
+            writable: false,
+            max_size: None,
+            anchor: None,
+        })
+    }
+
+    /// Create a new memory manager that takes over `buffer`, treating its contents as already committed.
+    pub fn from_buffer(buffer: ExecutableBuffer) -> Self {
+        let execbuffer_size = buffer.size();
+        let asmoffset = buffer.len();
+        let execbuffer_addr = buffer.as_ptr() as usize;
+        let fixed = buffer.is_fixed();
+        let zero_on_drop = buffer.zero_on_drop();
+        let writable = buffer.is_writable();
+        // data attached to the buffer was placed close to it
+        let anchor = if buffer.has_data() { Some(execbuffer_addr) } else { None };
+
+        MemoryManager {
+            execbuffer: Arc::new(RwLock::new(buffer)),
+            execbuffer_size,
+            asmoffset,
+            execbuffer_addr,
+            fixed,
+            zero_on_drop,
+            writable,
+            max_size: None,
+            anchor,
+        }
+    }
+
+    /// Returns true if the managed memory is mapped at a fixed address, so the code in it never moves.
+    pub fn is_fixed(&self) -> bool {
+        self.fixed
+    }
+
+    /// Returns the maximum amount of bytes that can be committed to this manager, or `None` if it can grow indefinitely.
+    pub fn capacity(&self) -> Option<usize> {
+        if self.fixed {
+            Some(self.execbuffer_size)
+        } else {
+            self.max_size
+        }
+    }
+
+    /// Never grow the managed memory beyond `max_size` bytes. Commits that would need more memory fail with
+    /// `DynasmError::BufferFull` instead. `None` removes the limit. Memory that was already allocated is kept, even if
+    /// it exceeds the limit.
+    pub fn set_max_size(&mut self, max_size: Option<usize>) {
+        self.max_size = max_size;
+    }
+
+    /// Zero the managed memory before it is unmapped. This applies to the current executable buffer,
+    /// the buffers it is moved into when it grows, and through those to the buffer returned by `finalize`.
+    pub fn set_zero_on_drop(&mut self, enabled: bool) {
+        self.zero_on_drop = enabled;
+        self.write().set_zero_on_drop(enabled);
+    }
+
+    /// Discard all committed data, keeping the managed memory to commit new data into. The committed part of the
+    /// memory is zeroed, and any unwind info and read-only data attached to it are dropped. If the memory is still
+    /// referenced through `reader`, new memory of the same size is allocated instead, so code that might still be in
+    /// use isn't changed. As memory mapped at a fixed address cannot be allocated again, an error is returned then.
+    pub fn reset(&mut self) -> io::Result<()> {
+        if Arc::strong_count(&self.execbuffer) == 1 {
+            let mut lock = self.write();
+            let buffer = mem::take(&mut *lock);
+            let mut buffer = buffer.make_mut().expect("Could not swap buffer protection modes");
+            buffer.clear();
+            *lock = buffer.make_exec().expect("Could not swap buffer protection modes");
+        } else {
+            if self.fixed {
+                return Err(io::Error::new(io::ErrorKind::AddrInUse, "Memory mapped at a fixed address is still in use"));
+            }
+
+            let mut buffer = if self.writable {
+                MutableBuffer::new_writable(self.execbuffer_size)?.make_exec()?
+            } else {
+                ExecutableBuffer::new(self.execbuffer_size)?
+            };
+            buffer.set_zero_on_drop(self.zero_on_drop);
+            self.execbuffer_addr = buffer.as_ptr() as usize;
+            self.execbuffer = Arc::new(RwLock::new(buffer));
+        }
+        self.asmoffset = 0;
+        self.anchor = None;
+        Ok(())
+    }
+
+    /// Returns the size of the currently allocated executable memory
+    pub fn size(&self) -> usize {
+        self.execbuffer_size
+    }
+
     /// Returns the amount of bytes already committed to the manager
     pub fn committed(&self) -> usize {
         self.asmoffset
@@ -51,37 +226,67 @@ impl MemoryManager {
         self.execbuffer_addr
     }
 
+    /// Returns the address that data referenced by the code should be mapped close to, so the code can reach it through
+    /// 32-bit displacements. Once data has been added, the executable buffer also stays close to this address when it
+    /// moves. Returns `None` if no memory has been mapped yet.
+    pub(crate) fn data_anchor(&self) -> Option<usize> {
+        self.anchor.or(if self.execbuffer_addr != 0 { Some(self.execbuffer_addr) } else { None })
+    }
+
+    /// Returns the address that the executable buffer has to stay close to when it moves, if any data was added.
+    pub(crate) fn anchor(&self) -> Option<usize> {
+        self.anchor
+    }
+
+    /// Attach a mapping of data referenced by the code to the executable buffer, keeping it alive as long as the code.
+    pub(crate) fn add_data(&mut self, data: DataMap) {
+        if self.anchor.is_none() {
+            self.anchor = Some(self.data_anchor().unwrap_or(data.as_ptr() as usize));
+        }
+        self.write().add_data(data);
+    }
+
+    // take note of the current buffer after it was changed outside of `commit`, which may have grown and moved it
+    pub(crate) fn buffer_changed(&mut self) {
+        let (size, len, addr) = {
+            let buffer = self.read();
+            (buffer.size(), buffer.len(), buffer.as_ptr() as usize)
+        };
+        self.execbuffer_size = size;
+        self.asmoffset = len;
+        self.execbuffer_addr = addr;
+    }
+
     /// Commits the data from `new` into the managed memory, calling `f` when the buffer is moved to fix anything
-    /// that relies on the address of the buffer
-    pub fn commit<F>(&mut self, new: &mut Vec<u8>, f: F) where F: FnOnce(&mut [u8], usize, usize) {
+    /// that relies on the address of the buffer. Any allocated memory past the committed data is zero-initialized.
+    /// If the managed memory cannot grow large enough to hold the data, for instance because allocating a larger
+    /// buffer failed, `DynasmError::BufferFull` is returned. Nothing is committed then, and the current buffer stays usable.
+    pub fn commit<F>(&mut self, new: &mut Vec<u8>, f: F) -> Result<(), DynasmError> where F: FnOnce(&mut [u8], usize, usize) {
         let old_asmoffset = self.asmoffset;
-        let new_asmoffset = self.asmoffset + new.len();
+        let new_asmoffset = self.asmoffset.checked_add(new.len()).ok_or(DynasmError::BufferFull)?;
 
         if old_asmoffset >= new_asmoffset {
-            return;
+            return Ok(());
         }
 
         // see if we need to request a new buffer
         if new_asmoffset > self.execbuffer_size {
-            while self.execbuffer_size <= new_asmoffset {
-                self.execbuffer_size *= 2;
+            if self.fixed {
+                return Err(DynasmError::BufferFull);
             }
 
-            // create a larger writable buffer
-            let mut new_buffer = MutableBuffer::new(self.execbuffer_size).expect("Could not allocate a larger buffer");
-            new_buffer.set_len(new_asmoffset);
-
-            // copy over the data
-            new_buffer[.. old_asmoffset].copy_from_slice(&self.execbuffer.read().unwrap());
-            new_buffer[old_asmoffset..].copy_from_slice(&new);
-            let new_buffer_addr = new_buffer.as_ptr() as usize;
-
-            // allow modifications to be made
-            f(&mut new_buffer, self.execbuffer_addr, new_buffer_addr);
-
-            // swap the buffers
-            self.execbuffer_addr = new_buffer_addr;
-            *self.execbuffer.write().unwrap() = new_buffer.make_exec().expect("Could not swap buffer protection modes")
+            // memory is mapped in whole pages, so don't allocate less than one
+            let mut new_size = cmp::max(self.execbuffer_size, mmap::page_size());
+            while new_size <= new_asmoffset {
+                new_size = new_size.checked_mul(2).ok_or(DynasmError::BufferFull)?;
+            }
+            if let Some(max_size) = self.max_size {
+                if new_asmoffset > max_size {
+                    return Err(DynasmError::BufferFull);
+                }
+                new_size = cmp::min(new_size, max_size);
+            }
+            self.reallocate(new_size, new, f)?;
 
         } else {
 
@@ -101,6 +306,58 @@ impl MemoryManager {
 
         new.clear();
         self.asmoffset = new_asmoffset;
+        Ok(())
+    }
+
+    /// Eagerly grow the managed memory to at least `size` bytes, calling `f` when the buffer is moved like `commit`.
+    /// This allows the cost of growing to be paid at a convenient moment, instead of during a later commit.
+    /// Nothing happens if the managed memory is already large enough. If it cannot grow,
+    /// `DynasmError::BufferFull` is returned.
+    pub fn grow_to<F>(&mut self, size: usize, f: F) -> Result<(), DynasmError> where F: FnOnce(&mut [u8], usize, usize) {
+        if size <= self.execbuffer_size {
+            return Ok(());
+        }
+        if self.fixed || self.max_size.is_some_and(|max_size| size > max_size) {
+            return Err(DynasmError::BufferFull);
+        }
+        self.reallocate(size, &[], f)
+    }
+
+    // move the committed data, followed by `new`, into a new buffer of `new_size` bytes
+    fn reallocate<F>(&mut self, new_size: usize, new: &[u8], f: F) -> Result<(), DynasmError> where F: FnOnce(&mut [u8], usize, usize) {
+        // mappings larger than isize::MAX bytes cannot be indexed
+        if new_size > isize::MAX as usize {
+            return Err(DynasmError::BufferFull);
+        }
+
+        // create a larger writable buffer. Until it is swapped in, any failure leaves the current buffer intact.
+        let old_asmoffset = self.asmoffset;
+        let mut new_buffer = MutableBuffer::new_near(new_size, self.anchor, self.writable).map_err(|_| DynasmError::BufferFull)?;
+        new_buffer.set_zero_on_drop(self.zero_on_drop);
+        new_buffer.set_len(old_asmoffset + new.len());
+
+        // copy over the data, keeping code that was retired retired
+        {
+            let old_buffer = self.execbuffer.read().unwrap();
+            new_buffer[.. old_asmoffset].copy_from_slice(&old_buffer);
+            new_buffer.set_retired(old_buffer.retired().to_vec());
+        }
+        new_buffer[old_asmoffset..].copy_from_slice(new);
+        let new_buffer_addr = new_buffer.as_ptr() as usize;
+
+        // allow modifications to be made
+        f(&mut new_buffer, self.execbuffer_addr, new_buffer_addr);
+        let mut new_buffer = new_buffer.make_exec().map_err(|_| DynasmError::BufferFull)?;
+
+        // swap the buffers, keeping any data referenced by the code alive
+        {
+            let mut lock = self.write();
+            new_buffer.set_data(lock.take_data());
+            *lock = new_buffer;
+        }
+        self.execbuffer_size = new_size;
+        self.execbuffer_addr = new_buffer_addr;
+        Ok(())
     }
 
     /// Borrow the internal memory buffer mutably
@@ -108,6 +365,11 @@ impl MemoryManager {
         self.execbuffer.write().unwrap()
     }
 
+    /// Borrow the internal memory buffer immutably
+    pub fn read(&self) -> RwLockReadGuard<'_, ExecutableBuffer> {
+        self.execbuffer.read().unwrap()
+    }
+
     /// finalizes the currently committed part of the buffer.
     pub fn finalize(self) -> Result<ExecutableBuffer, Self> {
         match Arc::try_unwrap(self.execbuffer) {
@@ -126,51 +388,121 @@ impl MemoryManager {
 }
 
 
+// The state of a dynamic label id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DynamicLabelEntry {
+    // the id has been handed out, and the label is located at the given offset once defined.
+    // `named` is set if the label was created for a name.
+    Allocated { offset: Option<AssemblyOffset>, named: bool },
+    // the id has been freed, and can be handed out again
+    Free,
+}
+
 /// A registry of labels. Contains all necessessities for keeping track of dynasm labels.
 /// This is useful when implementing your own assembler and can also be used to query
 /// assemblers for the offsets of labels.
 #[derive(Debug, Clone, Default)]
 pub struct LabelRegistry {
     // mapping of global labels to offsets
-    global_labels: HashMap<&'static str, AssemblyOffset>,
+    global_labels: BTreeMap<&'static str, AssemblyOffset>,
     // mapping of local labels to offsets
-    local_labels: HashMap<&'static str, AssemblyOffset>,
-    // mapping of dynamic label ids to offsets
-    dynamic_labels: Vec<Option<AssemblyOffset>>,
+    local_labels: BTreeMap<&'static str, AssemblyOffset>,
+    // mapping of dynamic label ids to their offsets, if they are defined
+    dynamic_labels: Vec<DynamicLabelEntry>,
+    // dynamic label ids that have been freed and can be handed out again
+    free_dynamic_labels: Vec<DynamicLabel>,
+    // dynamic labels created for names only known at runtime
+    named_dynamic_labels: BTreeMap<String, DynamicLabel>,
+    // what to do when a global label is defined again
+    duplicate_policy: DuplicateLabelPolicy,
 }
 
 impl LabelRegistry {
     /// Create a new, empty label registry
     pub fn new() -> LabelRegistry {
         LabelRegistry {
-            global_labels: HashMap::new(),
-            local_labels: HashMap::new(),
+            global_labels: BTreeMap::new(),
+            local_labels: BTreeMap::new(),
             dynamic_labels: Vec::new(),
+            free_dynamic_labels: Vec::new(),
+            named_dynamic_labels: BTreeMap::new(),
+            duplicate_policy: DuplicateLabelPolicy::Error,
         }
     }
 
-    /// Create a new dynamic label id
+    /// Create a new dynamic label id. Ids of freed labels are reused first.
     pub fn new_dynamic_label(&mut self) -> DynamicLabel {
+        let entry = DynamicLabelEntry::Allocated { offset: None, named: false };
+        if let Some(id) = self.free_dynamic_labels.pop() {
+            self.dynamic_labels[id.0] = entry;
+            return id;
+        }
+
         let id = self.dynamic_labels.len();
-        self.dynamic_labels.push(None);
+        self.dynamic_labels.push(entry);
         DynamicLabel(id)
     }
 
+    /// Returns the dynamic label for `name`, creating it if this is the first time `name` is used.
+    /// This allows referring to labels by names that are only known at runtime, as an alternative to
+    /// global labels which require `&'static str` names.
+    pub fn named_dynamic_label(&mut self, name: &str) -> DynamicLabel {
+        if let Some(&id) = self.named_dynamic_labels.get(name) {
+            return id;
+        }
+
+        let id = self.new_dynamic_label();
+        self.dynamic_labels[id.0] = DynamicLabelEntry::Allocated { offset: None, named: true };
+        self.named_dynamic_labels.insert(String::from(name), id);
+        id
+    }
+
+    /// Free the dynamic label `id`, forgetting its definition and allowing `new_dynamic_label` to hand out its id again.
+    /// If `id` was created for a name, the name is forgotten as well.
+    /// `id` should not be used after this. Any relocations targeting it should already have been resolved.
+    /// Returns `DynasmError::UnknownLabel` if `id` was already freed, or wasn't created by this registry.
+    pub fn free_dynamic_label(&mut self, id: DynamicLabel) -> Result<(), DynasmError> {
+        let named = match self.dynamic_labels.get(id.0) {
+            Some(&DynamicLabelEntry::Allocated { named, .. }) => named,
+            _ => return Err(DynasmError::UnknownLabel(LabelKind::Dynamic(id))),
+        };
+        if named {
+            self.named_dynamic_labels.retain(|_, &mut named| named != id);
+        }
+        self.dynamic_labels[id.0] = DynamicLabelEntry::Free;
+        self.free_dynamic_labels.push(id);
+        Ok(())
+    }
+
     /// Define a the dynamic label `id` to be located at `offset`.
     pub fn define_dynamic(&mut self, id: DynamicLabel, offset: AssemblyOffset) -> Result<(), DynasmError> {
-        let entry = &mut self.dynamic_labels[id.0];
-        if entry.is_some() {
-            return Err(DynasmError::DuplicateLabel(LabelKind::Dynamic(id)));
+        match self.dynamic_labels.get_mut(id.0) {
+            Some(DynamicLabelEntry::Allocated { offset: ref mut entry @ None, .. }) => {
+                *entry = Some(offset);
+                Ok(())
+            },
+            Some(DynamicLabelEntry::Allocated { .. }) => Err(DynasmError::DuplicateLabel(LabelKind::Dynamic(id))),
+            Some(DynamicLabelEntry::Free) | None => Err(DynasmError::UnknownLabel(LabelKind::Dynamic(id))),
         }
+    }
 
-        *entry = Some(offset);
-        Ok(())
+    /// Set what happens when a global label is defined that was already defined before.
+    pub fn set_duplicate_label_policy(&mut self, policy: DuplicateLabelPolicy) {
+        self.duplicate_policy = policy;
     }
 
-    /// Define a the global label `name` to be located at `offset`.
+    /// Define a the global label `name` to be located at `offset`. If it was already defined, the duplicate label
+    /// policy decides if this replaces the old definition, returns `DynasmError::DuplicateLabel` or panics.
     pub fn define_global(&mut self, name: &'static str, offset: AssemblyOffset) -> Result<(), DynasmError> {
         match self.global_labels.entry(name) {
-            Entry::Occupied(_) => Err(DynasmError::DuplicateLabel(LabelKind::Global(name))),
+            Entry::Occupied(mut o) => match self.duplicate_policy {
+                DuplicateLabelPolicy::Error => Err(DynasmError::DuplicateLabel(LabelKind::Global(name))),
+                DuplicateLabelPolicy::Replace => {
+                    o.insert(offset);
+                    Ok(())
+                },
+                DuplicateLabelPolicy::Panic => panic!("Duplicate global label '{}'", name),
+            },
             Entry::Vacant(v) => {
                 v.insert(offset);
                 Ok(())
@@ -185,7 +517,46 @@ impl LabelRegistry {
 
     /// Returns the offset at which the dynamic label `id` was defined, if one was defined.
     pub fn resolve_dynamic(&self, id: DynamicLabel) -> Result<AssemblyOffset, DynasmError> {
-        self.dynamic_labels.get(id.0).and_then(|&e| e).ok_or_else(|| DynasmError::UnknownLabel(LabelKind::Dynamic(id)))
+        match self.dynamic_labels.get(id.0) {
+            Some(&DynamicLabelEntry::Allocated { offset: Some(offset), .. }) => Ok(offset),
+            _ => Err(DynasmError::UnknownLabel(LabelKind::Dynamic(id))),
+        }
+    }
+
+    /// Returns true if the dynamic label `id` has been defined. Unknown ids are reported as undefined.
+    pub fn is_dynamic_defined(&self, id: DynamicLabel) -> bool {
+        matches!(self.dynamic_labels.get(id.0), Some(DynamicLabelEntry::Allocated { offset: Some(_), .. }))
+    }
+
+    /// Returns true if the global label `name` has been defined.
+    pub fn is_global_defined(&self, name: &'static str) -> bool {
+        self.global_labels.contains_key(&name)
+    }
+
+    /// Iterate through all defined global labels and their offsets.
+    pub fn globals<'a>(&'a self) -> impl Iterator<Item=(&'static str, AssemblyOffset)> + 'a {
+        self.global_labels.iter().map(|(&name, &offset)| (name, offset))
+    }
+
+    /// Iterate through all defined local labels and the offset of their latest definition.
+    pub fn locals<'a>(&'a self) -> impl Iterator<Item=(&'static str, AssemblyOffset)> + 'a {
+        self.local_labels.iter().map(|(&name, &offset)| (name, offset))
+    }
+
+    /// Iterate through all allocated dynamic labels, and their offsets if they have been defined.
+    /// Freed labels are skipped.
+    pub fn dynamics<'a>(&'a self) -> impl Iterator<Item=(DynamicLabel, Option<AssemblyOffset>)> + 'a {
+        self.dynamic_labels.iter()
+            .enumerate()
+            .filter_map(|(id, entry)| match *entry {
+                DynamicLabelEntry::Allocated { offset, .. } => Some((DynamicLabel(id), offset)),
+                DynamicLabelEntry::Free => None,
+            })
+    }
+
+    /// Iterate through all dynamic labels created for a name by `named_dynamic_label`, sorted by name.
+    pub fn named_dynamics<'a>(&'a self) -> impl Iterator<Item=(&'a str, DynamicLabel)> + 'a {
+        self.named_dynamic_labels.iter().map(|(name, &id)| (name.as_str(), id))
     }
 
     /// Returns the offset at which the global label `name` was defined, if one was defined.
@@ -200,7 +571,8 @@ impl LabelRegistry {
 }
 
 
-/// An abstraction of a relocation of type `R` that was defined to exist at offset `offset`
+/// An abstraction of a relocation of type `R` that was defined to exist at offset `location`.
+/// `offset` is added to the target address when the relocation is resolved.
 #[derive(Clone, Debug)]
 pub struct PatchLoc<R: Relocation> {
     pub location: AssemblyOffset,
@@ -218,6 +590,12 @@ impl<R: Relocation> PatchLoc<R> {
         }
     }
 
+    /// Returns the range of offsets of the field holding this relocation.
+    pub fn field(&self) -> Range<usize> {
+        let start = self.location.0 - self.relocation.field_offset();
+        start .. start + self.relocation.size()
+    }
+
     // Slice out the relevant part of an assembling buffer
     fn slice<'a>(&self, buf_offset: usize, buffer: &'a mut [u8]) -> &'a mut [u8] {
         let field_offset = self.location.0 - buf_offset - self.relocation.field_offset();
@@ -271,7 +649,9 @@ impl<R: Relocation> PatchLoc<R> {
 pub struct RelocRegistry<R: Relocation> {
     global: Vec<(PatchLoc<R>, &'static str)>,
     dynamic: Vec<(PatchLoc<R>, DynamicLabel)>,
-    local: HashMap<&'static str, Vec<PatchLoc<R>>>
+    // the amount of relocations in `dynamic` targeting each dynamic label id
+    dynamic_counts: Vec<usize>,
+    local: BTreeMap<&'static str, Vec<PatchLoc<R>>>
 }
 
 impl<R: Relocation> RelocRegistry<R> {
@@ -280,7 +660,8 @@ impl<R: Relocation> RelocRegistry<R> {
         RelocRegistry {
             global: Vec::new(),
             dynamic: Vec::new(),
-            local: HashMap::new()
+            dynamic_counts: Vec::new(),
+            local: BTreeMap::new()
         }
     }
 
@@ -291,9 +672,18 @@ impl<R: Relocation> RelocRegistry<R> {
 
     /// Add a new patch targetting the dynamic label `id`.
     pub fn add_dynamic(&mut self, id: DynamicLabel, patchloc: PatchLoc<R>) {
+        if self.dynamic_counts.len() <= id.0 {
+            self.dynamic_counts.resize(id.0 + 1, 0);
+        }
+        self.dynamic_counts[id.0] += 1;
         self.dynamic.push((patchloc, id))
     }
 
+    /// Returns true if there are unresolved patches targetting the dynamic label `id`.
+    pub fn has_dynamic(&self, id: DynamicLabel) -> bool {
+        matches!(self.dynamic_counts.get(id.0), Some(&count) if count != 0)
+    }
+
     /// Add a new patch targetting the next local label `name`.
     /// As any relocation targetting a previous local label can be immediately resolved these should not be recorded.
     pub fn add_local(&mut self, name: &'static str, patchloc: PatchLoc<R>) {
@@ -320,6 +710,7 @@ impl<R: Relocation> RelocRegistry<R> {
     /// Return an iterator through all defined relocations targeting dynamic labels and the labels they target.
     /// These relocations are removed from the registry.
     pub fn take_dynamics<'a>(&'a mut self) -> impl Iterator<Item=(PatchLoc<R>, DynamicLabel)> + 'a {
+        self.dynamic_counts.clear();
         self.dynamic.drain(..)
     }
 
@@ -328,6 +719,15 @@ impl<R: Relocation> RelocRegistry<R> {
     pub fn take_locals<'a>(&'a mut self) -> impl Iterator<Item=(PatchLoc<R>, &'static str)> + 'a {
         self.local.iter_mut().flat_map(|(&k, v)| v.drain(..).map(move |p| (p, k)))
     }
+
+    /// Return an iterator through all defined relocations and a description of the label they target.
+    /// These relocations are not removed from the registry.
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item=(&'a PatchLoc<R>, TargetKind)> + 'a {
+        let globals = self.global.iter().map(|(p, name)| (p, TargetKind::Global(name)));
+        let dynamics = self.dynamic.iter().map(|(p, id)| (p, TargetKind::Dynamic(*id)));
+        let locals = self.local.iter().flat_map(|(&k, v)| v.iter().map(move |p| (p, TargetKind::Forward(k))));
+        globals.chain(dynamics).chain(locals)
+    }
 }
 
 
@@ -375,6 +775,21 @@ impl<R: Relocation> ManagedRelocs<R> {
     pub fn iter<'a>(&'a self) -> impl Iterator<Item=&'a PatchLoc<R>> + 'a {
         self.managed.values()
     } 
+
+    /// Adjust all managed relocations in `buffer` for moving it from `old_addr` to `new_addr`. `buffer` should
+    /// contain all code these relocations were recorded for, starting from offset 0. This can be used to move
+    /// code containing absolute references to a new address. If any relocation cannot be adjusted, the
+    /// others are still adjusted before an error is returned.
+    pub fn rebase(&self, buffer: &mut [u8], old_addr: usize, new_addr: usize) -> Result<(), ImpossibleRelocation> {
+        let change = new_addr.wrapping_sub(old_addr) as isize;
+        let mut result = Ok(());
+        for reloc in self.managed.values() {
+            if let Err(e) = reloc.adjust(0, buffer, change) {
+                result = Err(e);
+            }
+        }
+        result
+    }
 }
 
 
@@ -412,7 +827,7 @@ impl LitPool {
     // align the pool to the specified size, record the offset, and bump the offset
     fn bump_offset(&mut self, size: RelocationSize) -> isize {
         // Correct for alignment
-        self.align(size as usize, 0);
+        self.align((size as usize).next_power_of_two(), 0);
         let offset = self.offset;
         self.offset += size as usize;
         offset as isize
@@ -491,6 +906,10 @@ impl LitPool {
             RelocationSize::Word => assembler.push_u16(0),
             RelocationSize::DWord => assembler.push_u32(0),
             RelocationSize::QWord => assembler.push_u64(0),
+            RelocationSize::TByte
+            | RelocationSize::OWord => for _ in 0 .. size.size() {
+                assembler.push(0)
+            },
         }
     }
 
@@ -524,12 +943,92 @@ impl LitPool {
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests {
     use crate::*;
     use std::fmt::Debug;
     use relocations::{Relocation, RelocationSize};
 
+    #[test]
+    fn test_label_defined() {
+        let mut labels = components::LabelRegistry::new();
+        let id = labels.new_dynamic_label();
+        assert!(!labels.is_dynamic_defined(id));
+        labels.define_dynamic(id, AssemblyOffset(0)).unwrap();
+        assert!(labels.is_dynamic_defined(id));
+        let other = labels.new_dynamic_label();
+        assert!(!labels.is_dynamic_defined(other));
+
+        assert!(!labels.is_global_defined("global"));
+        labels.define_global("global", AssemblyOffset(0)).unwrap();
+        assert!(labels.is_global_defined("global"));
+
+        // ids from another registry are out of range here
+        let mut foreign = components::LabelRegistry::new();
+        assert!(!foreign.is_dynamic_defined(id));
+        assert_eq!(foreign.define_dynamic(id, AssemblyOffset(0)), Err(DynasmError::UnknownLabel(LabelKind::Dynamic(id))));
+    }
+
+    #[test]
+    fn test_duplicate_label_policy() {
+        let mut labels = components::LabelRegistry::new();
+        labels.define_global("f", AssemblyOffset(0)).unwrap();
+        assert_eq!(labels.define_global("f", AssemblyOffset(4)), Err(DynasmError::DuplicateLabel(LabelKind::Global("f"))));
+        assert_eq!(labels.resolve_global("f"), Ok(AssemblyOffset(0)));
+
+        labels.set_duplicate_label_policy(DuplicateLabelPolicy::Replace);
+        labels.define_global("f", AssemblyOffset(4)).unwrap();
+        assert_eq!(labels.resolve_global("f"), Ok(AssemblyOffset(4)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Duplicate global label 'f'")]
+    fn test_duplicate_label_panic() {
+        let mut ops = SliceAssembler::<RelocationSize>::new(&mut []);
+        ops.set_duplicate_label_policy(DuplicateLabelPolicy::Panic);
+        ops.global_label("f");
+        ops.global_label("f");
+    }
+
+    #[test]
+    fn test_pending_dynamic_relocs() {
+        let mut labels = components::LabelRegistry::new();
+        let mut relocs = components::RelocRegistry::new();
+        let id = labels.new_dynamic_label();
+        let other = labels.new_dynamic_label();
+        assert!(!relocs.has_dynamic(id));
+        relocs.add_dynamic(other, components::PatchLoc::new(AssemblyOffset(1), 0, RelocationSize::Byte));
+        assert!(!relocs.has_dynamic(id));
+        assert!(relocs.has_dynamic(other));
+        assert_eq!(relocs.take_dynamics().count(), 1);
+        assert!(!relocs.has_dynamic(other));
+
+        // freed labels can't be defined until their id is handed out again
+        labels.free_dynamic_label(id).unwrap();
+        assert_eq!(labels.define_dynamic(id, AssemblyOffset(0)), Err(DynasmError::UnknownLabel(LabelKind::Dynamic(id))));
+        assert_eq!(labels.dynamics().collect::<Vec<_>>(), vec![(other, None)]);
+        assert_eq!(labels.new_dynamic_label(), id);
+        labels.define_dynamic(id, AssemblyOffset(0)).unwrap();
+        assert_eq!(labels.resolve_dynamic(id), Ok(AssemblyOffset(0)));
+    }
+
+    #[test]
+    fn test_named_dynamic_label() {
+        let mut labels = components::LabelRegistry::new();
+        let name = format!("fn_{}", 42);
+        let id = labels.named_dynamic_label(&name);
+        assert_eq!(labels.named_dynamic_label("fn_42"), id);
+        assert_ne!(labels.named_dynamic_label("fn_43"), id);
+        assert_eq!(labels.named_dynamics().collect::<Vec<_>>(), vec![("fn_42", id), ("fn_43", DynamicLabel(1))]);
+
+        // freeing the label forgets the name
+        labels.free_dynamic_label(id).unwrap();
+        assert_eq!(labels.named_dynamics().count(), 1);
+        let reused = labels.named_dynamic_label("fn_44");
+        assert_eq!(reused, id);
+        assert_ne!(labels.named_dynamic_label("fn_42"), id);
+    }
+
     #[test]
     fn test_litpool_size() {
         test_litpool::<RelocationSize>();
@@ -0,0 +1,156 @@
+//! This module implements assembling read-only data next to code, for things like lookup tables,
+//! strings and floating point constants.
+//!
+//! Data is emitted through the `DataBuilder` of an `Assembler`, obtained with `Assembler::data`.
+//! Data labels live in their own namespace, separate from the labels used in code, and are referenced
+//! from code using `Assembler::data_reloc`. When the assembler commits, the data emitted since the
+//! previous commit is placed in a new read-only, non-executable mapping. Committed data never moves,
+//! so references to it stay valid for as long as the code referencing it does. The mapping is placed close to the
+//! code if possible, and the code is kept close to it when it grows, so 32-bit displacements can reach the data.
+//!
+//! Next to read-only data, the builder hands out counters through `Assembler::reserve_counter`. These are 64-bit
+//! slots that code is allowed to write to, like profiling counters. They are placed in a writable, non-executable
+//! mapping of their own when they are committed, and never move either.
+
+use core::iter::Extend;
+
+use alloc::collections::btree_map::BTreeMap;
+use alloc::vec::Vec;
+
+use crate::{AssemblyOffset, DynasmApi, DynasmError, LabelKind};
+use crate::mmap::DataMap;
+
+
+/// A 64-bit counter reserved through `Assembler::reserve_counter`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Counter(usize);
+
+/// A builder for read-only data. Offsets reported by this builder are relative to the start of
+/// the data emitted since the last commit. Each commit starts a new page-aligned mapping, so
+/// alignments up to the page size are preserved.
+#[derive(Debug, Default)]
+pub struct DataBuilder {
+    // data emitted since the last commit
+    pending: Vec<u8>,
+    // labels defined in the pending data, and their offsets in it
+    pending_labels: BTreeMap<&'static str, usize>,
+    // addresses of all committed labels
+    labels: BTreeMap<&'static str, usize>,
+    // the amount of counters reserved since the last commit
+    pending_counters: usize,
+    // addresses of all committed counters
+    counters: Vec<usize>,
+    error: Option<DynasmError>,
+}
+
+impl DataBuilder {
+    /// Create a new, empty data builder.
+    pub fn new() -> DataBuilder {
+        DataBuilder {
+            pending: Vec::new(),
+            pending_labels: BTreeMap::new(),
+            labels: BTreeMap::new(),
+            pending_counters: 0,
+            counters: Vec::new(),
+            error: None,
+        }
+    }
+
+    /// Define the data label `name` at the current offset. Data labels can only be defined once.
+    pub fn label(&mut self, name: &'static str) {
+        if self.labels.contains_key(&name) || self.pending_labels.contains_key(&name) {
+            self.error = Some(DynasmError::DuplicateLabel(LabelKind::Global(name)));
+            return;
+        }
+        self.pending_labels.insert(name, self.pending.len());
+    }
+
+    /// Push all bytes in `bytes`.
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.pending.extend_from_slice(bytes);
+    }
+
+    /// Returns the address of the data label `name`, if it has been committed.
+    pub fn addr(&self, name: &'static str) -> Option<usize> {
+        self.labels.get(&name).cloned()
+    }
+
+    /// Reserve a new counter, which starts at zero once it is committed.
+    pub(crate) fn reserve_counter(&mut self) -> Counter {
+        let counter = Counter(self.counters.len() + self.pending_counters);
+        self.pending_counters += 1;
+        counter
+    }
+
+    /// Returns the address of `counter`, if it has been committed.
+    pub(crate) fn counter_addr(&self, counter: Counter) -> Option<usize> {
+        self.counters.get(counter.0).cloned()
+    }
+
+    /// Place all pending counters in a new writable mapping, close to `near` if possible, and make their addresses
+    /// available. Returns the new mapping, if any counters were pending.
+    pub(crate) fn commit_counters(&mut self, near: Option<usize>) -> Result<Option<DataMap>, DynasmError> {
+        if self.pending_counters == 0 {
+            return Ok(None);
+        }
+
+        let map = DataMap::writable(self.pending_counters * 8, near).map_err(|_| DynasmError::BufferFull)?;
+        let base = map.as_ptr() as usize;
+        self.counters.extend((0 .. self.pending_counters).map(|i| base + i * 8));
+        self.pending_counters = 0;
+        Ok(Some(map))
+    }
+
+    /// Place all pending data in a new read-only mapping, close to `near` if possible, and make the addresses of the
+    /// labels in it available. Returns the new mapping, if any data was pending.
+    pub(crate) fn commit(&mut self, near: Option<usize>) -> Result<Option<DataMap>, DynasmError> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        // any labels stay pending until there is data for them to point into
+        if self.pending.is_empty() {
+            return Ok(None);
+        }
+
+        let map = DataMap::read_only(&self.pending, near).map_err(|_| DynasmError::BufferFull)?;
+
+        let base = map.as_ptr() as usize;
+        for (name, offset) in self.pending_labels.iter() {
+            self.labels.insert(name, base + offset);
+        }
+        self.pending_labels.clear();
+        self.pending.clear();
+        Ok(Some(map))
+    }
+}
+
+impl Extend<u8> for DataBuilder {
+    fn extend<T>(&mut self, iter: T) where T: IntoIterator<Item=u8> {
+        self.pending.extend(iter)
+    }
+}
+
+impl<'a> Extend<&'a u8> for DataBuilder {
+    fn extend<T>(&mut self, iter: T) where T: IntoIterator<Item=&'a u8> {
+        self.pending.extend(iter)
+    }
+}
+
+impl DynasmApi for DataBuilder {
+    fn offset(&self) -> AssemblyOffset {
+        AssemblyOffset(self.pending.len())
+    }
+
+    fn push(&mut self, value: u8) {
+        self.pending.push(value);
+    }
+
+    fn align(&mut self, alignment: usize, with: u8) {
+        let offset = self.offset().0 % alignment;
+        if offset != 0 {
+            for _ in offset .. alignment {
+                self.push(with);
+            }
+        }
+    }
+}
@@ -1,23 +1,67 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+// no_std already injects `core` into the crate root, but std builds need it declared for the `core::` imports
+#[cfg(feature = "std")]
+extern crate core;
+#[cfg(feature = "std")]
 extern crate memmap;
+#[cfg(all(feature = "std", unix))]
+extern crate libc;
 extern crate byteorder;
+extern crate alloc;
 
+#[cfg(feature = "std")]
 pub mod mmap;
 pub mod components;
 pub mod relocations;
 pub mod x64;
 pub mod x86;
 pub mod aarch64;
+#[cfg(feature = "std")]
+pub mod unwind;
+#[cfg(feature = "std")]
+pub mod data;
+#[cfg(feature = "std")]
+pub mod ring;
+#[cfg(feature = "std")]
+pub mod pool;
+#[cfg(feature = "std")]
+pub mod slot;
+#[cfg(all(test, feature = "std"))]
+mod roundtrip;
 
-pub use crate::mmap::ExecutableBuffer;
-use crate::components::{MemoryManager, LabelRegistry, RelocRegistry, ManagedRelocs, PatchLoc};
-use crate::relocations::Relocation;
+#[cfg(feature = "std")]
+pub use crate::mmap::{ExecutableBuffer, AllocError};
+#[cfg(feature = "std")]
+use crate::components::{MemoryManager, ManagedRelocs};
+use crate::components::{LabelRegistry, RelocRegistry, PatchLoc};
+use crate::relocations::{Relocation, ImpossibleRelocation};
+#[cfg(feature = "std")]
+use crate::relocations::{RelocationKind, RelocationSize};
+#[cfg(feature = "std")]
+use crate::unwind::UnwindRegistry;
+#[cfg(feature = "std")]
+use crate::data::{DataBuilder, Counter};
 
-use std::iter::Extend;
+use core::iter::Extend;
+use core::fmt;
+use core::ops;
+use alloc::vec::Vec;
+use alloc::string::String;
+#[cfg(feature = "std")]
 use std::sync::{Arc, RwLock, RwLockReadGuard};
+#[cfg(feature = "std")]
+use std::sync::atomic::AtomicU64;
+#[cfg(feature = "std")]
 use std::io;
+#[cfg(feature = "std")]
 use std::error;
-use std::fmt;
+#[cfg(feature = "std")]
 use std::mem;
+#[cfg(feature = "std")]
+use std::cmp;
+#[cfg(feature = "std")]
+use std::collections::{BTreeMap, BTreeSet};
 
 /// This macro takes a *const pointer from the source operand, and then casts it to the desired return type.
 /// this allows it to be used as an easy shorthand for passing pointers as dynasm immediate arguments.
@@ -35,9 +79,61 @@ macro_rules! MutPointer {
 
 /// A struct representing an offset into the assembling buffer of a `DynasmLabelApi` struct.
 /// The wrapped `usize` is the offset from the start of the assembling buffer in bytes.
+///
+/// For an `Assembler`, offsets are absolute positions in its executable buffer, which includes both committed
+/// and uncommitted code. An offset returned by `offset` keeps addressing the same byte after later commits,
+/// even when the buffer moves to grow, so it can be held on to in order to patch code later. `Modifier::goto`
+/// and the `Modifier` passed to `alter` use the same offsets. As committed code is never removed, these offsets
+/// stay valid for as long as the assembler exists, although `alter`, `patch` and `restore` can change the bytes at them.
+/// Offsets of a `SliceAssembler` are relative to the start of its slice instead.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct AssemblyOffset(pub usize);
 
+impl AssemblyOffset {
+    /// Returns true if this offset is a multiple of `alignment`, which must be a power of two.
+    pub fn is_aligned_to(self, alignment: usize) -> bool {
+        debug_assert!(alignment.is_power_of_two(), "Alignment must be a power of two");
+        self.0 & (alignment - 1) == 0
+    }
+}
+
+impl ops::Add<usize> for AssemblyOffset {
+    type Output = AssemblyOffset;
+
+    fn add(self, rhs: usize) -> AssemblyOffset {
+        AssemblyOffset(self.0 + rhs)
+    }
+}
+
+impl ops::Sub<usize> for AssemblyOffset {
+    type Output = AssemblyOffset;
+
+    fn sub(self, rhs: usize) -> AssemblyOffset {
+        AssemblyOffset(self.0 - rhs)
+    }
+}
+
+/// The distance in bytes between two offsets.
+impl ops::Sub<AssemblyOffset> for AssemblyOffset {
+    type Output = usize;
+
+    fn sub(self, rhs: AssemblyOffset) -> usize {
+        self.0 - rhs.0
+    }
+}
+
+impl From<usize> for AssemblyOffset {
+    fn from(offset: usize) -> AssemblyOffset {
+        AssemblyOffset(offset)
+    }
+}
+
+impl From<AssemblyOffset> for usize {
+    fn from(offset: AssemblyOffset) -> usize {
+        offset.0
+    }
+}
+
 /// A dynamic label
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct DynamicLabel(usize);
@@ -53,6 +149,8 @@ impl DynamicLabel {
 
 /// A read-only shared reference to the executable buffer inside an Assembler. By
 /// locking it the internal `ExecutableBuffer` can be accessed and executed.
+/// Executors are `Send + Sync`, and can be cloned to execute code from multiple threads at once.
+#[cfg(feature = "std")]
 #[derive(Debug, Clone)]
 pub struct Executor {
     execbuffer: Arc<RwLock<ExecutableBuffer>>
@@ -60,6 +158,7 @@ pub struct Executor {
 
 /// A read-only lockable reference to the internal `ExecutableBuffer` of an Assembler.
 /// To gain access to this buffer, it must be locked.
+#[cfg(feature = "std")]
 impl Executor {
     /// Gain read-access to the internal `ExecutableBuffer`. While the returned guard
     /// is alive, it can be used to read and execute from the `ExecutableBuffer`.
@@ -69,9 +168,49 @@ impl Executor {
     pub fn lock(&self) -> RwLockReadGuard<ExecutableBuffer> {
         self.execbuffer.read().unwrap()
     }
+
+    /// Obtain a pointer to the code at `offset`, checking that it lies within the committed code.
+    /// As the lock is released before this function returns, the pointer stays valid only as long as
+    /// the assembler does not move the buffer, which can happen on any commit that grows it.
+    /// When that can happen concurrently, use `lock` instead and keep the guard alive while using the pointer.
+    pub fn ptr_at(&self, offset: AssemblyOffset) -> Result<*const u8, DynasmError> {
+        let buffer = self.lock();
+        if offset.0 >= buffer.len() {
+            return Err(DynasmError::OutOfBounds(offset));
+        }
+        Ok(buffer.ptr(offset))
+    }
+
+    /// Returns true if `offset` lies within the committed code, so it can be executed. Code that hasn't been
+    /// committed yet and the unused memory past the end of the committed code are not considered executable,
+    /// even though the latter is mapped as such.
+    pub fn is_executable(&self, offset: AssemblyOffset) -> bool {
+        let buffer = self.lock();
+        offset.0 < buffer.len() && !buffer.is_retired(offset.0)
+    }
+
+    /// Call `f` with the committed code. The buffer is kept locked while `f` runs, so the code can't be changed
+    /// or moved by a concurrent commit in the meantime. This gives a consistent snapshot of the code, for instance to
+    /// hash or copy it. As the assembler has to wait for `f` to return before it can commit, `f` should be quick.
+    pub fn with_bytes<F, O>(&self, f: F) -> O where F: FnOnce(&[u8]) -> O {
+        f(&self.lock())
+    }
 }
 
 
+/// What happens when a global label is defined that was already defined before.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum DuplicateLabelPolicy {
+    /// The new definition is rejected with `DynasmError::DuplicateLabel`. This is the default.
+    #[default]
+    Error,
+    /// The new definition replaces the old one. Relocations that have already been resolved keep
+    /// referencing the old definition, so this is mostly useful when code is generated again.
+    Replace,
+    /// Defining the label panics.
+    Panic,
+}
+
 /// A description of a label. Used for error reporting.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum LabelKind {
@@ -91,6 +230,17 @@ impl fmt::Display for LabelKind {
 }
 
 
+/// Where a label of an `Assembler` is defined, as returned by `Assembler::label_definition`.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LabelDefinition {
+    /// The label points at committed code
+    Committed(AssemblyOffset),
+    /// The label points at code that hasn't been committed yet, or at the end of the committed code
+    Uncommitted(AssemblyOffset),
+}
+
+
 /// A description of a relocation target. Used for error reporting.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum TargetKind {
@@ -116,10 +266,115 @@ impl fmt::Display for TargetKind {
 }
 
 
+/// A copy of the committed code of an `Assembler`, taken by `Assembler::snapshot`. It derefs to the copied code.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    code: Vec<u8>,
+    // the address of the executable buffer when the snapshot was taken
+    addr: usize,
+}
+
+#[cfg(feature = "std")]
+impl ops::Deref for Snapshot {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.code
+    }
+}
+
+
+/// A description of a recorded relocation. Used for diagnostics and instrumentation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RelocInfo {
+    /// The label this relocation targets
+    pub target: TargetKind,
+    /// The offset at which this relocation was recorded
+    pub location: AssemblyOffset,
+}
+
+
+/// A reference that has been resolved in the code of an `Assembler`, as listed by `Assembler::relocations`.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppliedReloc {
+    /// The bytes of the code holding the reference
+    pub field: ops::Range<AssemblyOffset>,
+    /// How the reference is encoded. `RelocationKind::Relative` references to labels keep working when the code
+    /// moves together with their label, all others have to be fixed up.
+    pub kind: RelocationKind,
+    /// What the reference targets. References to data from `Assembler::data` target `TargetKind::Extern`.
+    pub target: TargetKind,
+    /// The offset of the label the reference points at, or `None` if it targets an absolute address.
+    /// Weak references that were pointed at the placeholder list the placeholder here.
+    pub target_offset: Option<AssemblyOffset>,
+    /// The addend that was added to the target
+    pub addend: isize,
+}
+
+#[cfg(feature = "std")]
+impl AppliedReloc {
+    fn new<R: Relocation>(loc: &PatchLoc<R>, target: TargetKind, target_offset: Option<AssemblyOffset>) -> AppliedReloc {
+        let field = loc.field();
+        AppliedReloc {
+            field: AssemblyOffset(field.start) .. AssemblyOffset(field.end),
+            kind: loc.relocation.kind(),
+            target,
+            target_offset,
+            addend: loc.offset,
+        }
+    }
+}
+
+
+/// A reference to a symbol outside of the generated code, recorded by `Assembler::extern_reloc_by_name` for an
+/// external linker to resolve. The field holding it is left as it was emitted, typically zeroed.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone)]
+pub struct ExternalReloc<R> {
+    /// The bytes of the code holding the reference
+    pub field: ops::Range<AssemblyOffset>,
+    /// The offset that `RelocationKind::Relative` and `RelocationKind::RelToAbs` references are relative to
+    pub base: AssemblyOffset,
+    /// The name of the referenced symbol
+    pub symbol: String,
+    /// The addend that should be added to the address of the symbol
+    pub addend: isize,
+    /// The kind of relocation, which also tells how the value is written into the field
+    pub relocation: R,
+}
+
+/// A global label in the committed code of an `Assembler`, as listed by `Assembler::exported_symbols`.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportedSymbol {
+    /// The name of the label
+    pub name: &'static str,
+    /// The offset the label is defined at
+    pub offset: AssemblyOffset,
+    /// The size of the code belonging to the symbol
+    pub size: usize,
+}
+
+/// A relocation inside a pre-assembled blob of code, to be retargeted when the blob is inserted using `Assembler::splice`.
+#[derive(Debug, Clone)]
+pub struct BlobReloc<R> {
+    /// The offset in the blob at which this relocation would have been recorded while assembling it,
+    /// i.e. the offset right after the instruction or field containing it.
+    pub offset: usize,
+    /// The label this relocation should reference. `TargetKind::Extern` references an absolute address.
+    pub target: TargetKind,
+    /// An addend that is added to the address of the target
+    pub addend: isize,
+    /// The kind of relocation
+    pub relocation: R,
+}
+
+
 /// The various error types generated by dynasm functions.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum DynasmError {
-    /// A check (like `Modifier::check` or `Modifier::check_exact`) that failed
+    /// A check (like `Modifier::check`, `Modifier::check_exact` or `Modifier::check_aligned`) that failed
     CheckFailed,
     /// A duplicate label dynamic/global label was defined
     DuplicateLabel(LabelKind),
@@ -127,6 +382,18 @@ pub enum DynasmError {
     UnknownLabel(LabelKind),
     /// The user tried to declare a relocation too far away from the label it targets
     ImpossibleRelocation(TargetKind),
+    /// More data was emitted than fits in a fixed-size assembling buffer, or than could be allocated
+    BufferFull,
+    /// An access was made outside of the bounds of the assembled code, at the given offset
+    OutOfBounds(AssemblyOffset),
+    /// A label was freed while relocations targeting it were still pending
+    LabelInUse(LabelKind),
+    /// The unwind info of the committed code could not be registered with the platform unwinder
+    UnwindFailed,
+    /// An alignment was requested that isn't a power of two, or is too large
+    InvalidAlignment(usize),
+    /// The verifier set with `Assembler::set_verifier` rejected the committed code, for the given reason
+    VerificationFailed(String),
 }
 
 impl fmt::Display for DynasmError {
@@ -136,10 +403,17 @@ impl fmt::Display for DynasmError {
             DynasmError::DuplicateLabel(l) => write!(f, "Duplicate label defined: '{}'", l),
             DynasmError::UnknownLabel(l) => write!(f, "Unknown label: '{}'", l),
             DynasmError::ImpossibleRelocation(s) => write!(f, "Impossible relocation: '{}'", s),
+            DynasmError::BufferFull => write!(f, "The assembling buffer is full"),
+            DynasmError::OutOfBounds(o) => write!(f, "Out of bounds access at offset {}", o.0),
+            DynasmError::LabelInUse(l) => write!(f, "Label freed while still in use: '{}'", l),
+            DynasmError::UnwindFailed => write!(f, "Unwind info could not be registered"),
+            DynasmError::InvalidAlignment(a) => write!(f, "Invalid alignment: {}", a),
+            DynasmError::VerificationFailed(reason) => write!(f, "Code verification failed: {}", reason),
         }
     }
 }
 
+#[cfg(feature = "std")]
 impl error::Error for DynasmError {
     fn description(&self) -> &str {
         match self {
@@ -147,11 +421,52 @@ impl error::Error for DynasmError {
             DynasmError::DuplicateLabel(_) => "Duplicate label defined",
             DynasmError::UnknownLabel(_) => "Unknown label",
             DynasmError::ImpossibleRelocation(_) => "Impossible relocation",
+            DynasmError::BufferFull => "The assembling buffer is full",
+            DynasmError::OutOfBounds(_) => "Out of bounds access",
+            DynasmError::LabelInUse(_) => "Label freed while still in use",
+            DynasmError::UnwindFailed => "Unwind info could not be registered",
+            DynasmError::InvalidAlignment(_) => "Invalid alignment",
+            DynasmError::VerificationFailed(_) => "Code verification failed",
         }
     }
 }
 
 
+/// The error returned by `Modifier::try_check` and `Modifier::try_check_exact`, and their `UncommittedModifier`
+/// counterparts, when the modification cursor isn't where the layout of the code says it should be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LayoutError {
+    /// The offset the cursor was checked against
+    pub expected: AssemblyOffset,
+    /// The offset the cursor was actually at
+    pub actual: AssemblyOffset,
+}
+
+impl fmt::Display for LayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Modification cursor at offset {} instead of offset {}", self.actual.0, self.expected.0)
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for LayoutError {}
+
+impl From<LayoutError> for DynasmError {
+    fn from(_: LayoutError) -> DynasmError {
+        DynasmError::CheckFailed
+    }
+}
+
+// check that the cursor at `actual` has not moved past `expected`, or is exactly at it
+fn check_layout(actual: usize, expected: AssemblyOffset, exact: bool) -> Result<(), LayoutError> {
+    if actual > expected.0 || (exact && actual != expected.0) {
+        Err(LayoutError { expected, actual: AssemblyOffset(actual) })
+    } else {
+        Ok(())
+    }
+}
+
+
 /// This trait represents the interface that must be implemented to allow
 /// the dynasm preprocessor to assemble into a datastructure.
 pub trait DynasmApi: Extend<u8> + for<'a> Extend<&'a u8> {
@@ -160,6 +475,8 @@ pub trait DynasmApi: Extend<u8> + for<'a> Extend<&'a u8> {
     /// Push a byte into the assembling target
     fn push(&mut self, byte: u8);
     /// Push filler until the assembling target end is aligned to the given alignment.
+    /// The alignment doesn't have to be a power of two, the end is padded to the next multiple of it
+    /// regardless, which can be useful for packing structures. This panics if the alignment is zero.
     fn align(&mut self, alignment: usize, with: u8);
 
     #[inline]
@@ -197,6 +514,132 @@ pub trait DynasmApi: Extend<u8> + for<'a> Extend<&'a u8> {
     fn push_u64(&mut self, value: u64) {
         self.extend(&value.to_le_bytes());
     }
+    /// Push a signed word into the assembling target in little-endian byte order
+    #[inline]
+    fn push_i16_le(&mut self, value: i16) {
+        self.extend(&value.to_le_bytes());
+    }
+    /// Push a signed word into the assembling target in big-endian byte order
+    #[inline]
+    fn push_i16_be(&mut self, value: i16) {
+        self.extend(&value.to_be_bytes());
+    }
+    /// Push a signed doubleword into the assembling target in little-endian byte order
+    #[inline]
+    fn push_i32_le(&mut self, value: i32) {
+        self.extend(&value.to_le_bytes());
+    }
+    /// Push a signed doubleword into the assembling target in big-endian byte order
+    #[inline]
+    fn push_i32_be(&mut self, value: i32) {
+        self.extend(&value.to_be_bytes());
+    }
+    /// Push a signed quadword into the assembling target in little-endian byte order
+    #[inline]
+    fn push_i64_le(&mut self, value: i64) {
+        self.extend(&value.to_le_bytes());
+    }
+    /// Push a signed quadword into the assembling target in big-endian byte order
+    #[inline]
+    fn push_i64_be(&mut self, value: i64) {
+        self.extend(&value.to_be_bytes());
+    }
+    /// Push an unsigned word into the assembling target in little-endian byte order
+    #[inline]
+    fn push_u16_le(&mut self, value: u16) {
+        self.extend(&value.to_le_bytes());
+    }
+    /// Push an unsigned word into the assembling target in big-endian byte order
+    #[inline]
+    fn push_u16_be(&mut self, value: u16) {
+        self.extend(&value.to_be_bytes());
+    }
+    /// Push an unsigned doubleword into the assembling target in little-endian byte order
+    #[inline]
+    fn push_u32_le(&mut self, value: u32) {
+        self.extend(&value.to_le_bytes());
+    }
+    /// Push an unsigned doubleword into the assembling target in big-endian byte order
+    #[inline]
+    fn push_u32_be(&mut self, value: u32) {
+        self.extend(&value.to_be_bytes());
+    }
+    /// Push an unsigned quadword into the assembling target in little-endian byte order
+    #[inline]
+    fn push_u64_le(&mut self, value: u64) {
+        self.extend(&value.to_le_bytes());
+    }
+    /// Push an unsigned quadword into the assembling target in big-endian byte order
+    #[inline]
+    fn push_u64_be(&mut self, value: u64) {
+        self.extend(&value.to_be_bytes());
+    }
+    /// Push bytes into the assembling target, returning the offset at which they start.
+    /// This allows emitting a placeholder and patching it later, for instance with `Assembler::alter`.
+    #[inline]
+    fn put(&mut self, bytes: &[u8]) -> AssemblyOffset {
+        let offset = self.offset();
+        self.extend(bytes);
+        offset
+    }
+    /// Push a byte into the assembling target, returning the offset at which it was written
+    #[inline]
+    fn put_u8(&mut self, value: u8) -> AssemblyOffset {
+        self.put(&[value])
+    }
+    /// Push an unsigned word into the assembling target, returning the offset at which it starts
+    #[inline]
+    fn put_u16(&mut self, value: u16) -> AssemblyOffset {
+        self.put(&value.to_le_bytes())
+    }
+    /// Push an unsigned doubleword into the assembling target, returning the offset at which it starts
+    #[inline]
+    fn put_u32(&mut self, value: u32) -> AssemblyOffset {
+        self.put(&value.to_le_bytes())
+    }
+    /// Push an unsigned quadword into the assembling target, returning the offset at which it starts
+    #[inline]
+    fn put_u64(&mut self, value: u64) -> AssemblyOffset {
+        self.put(&value.to_le_bytes())
+    }
+    /// Push filler until the assembling target end is aligned to the given alignment, followed by `bytes`,
+    /// returning the aligned offset at which they start. This places constants with an alignment requirement
+    /// inline in the code, like the memory operands of `movaps`. This panics if the alignment is zero.
+    #[inline]
+    fn push_aligned(&mut self, bytes: &[u8], alignment: usize, with: u8) -> AssemblyOffset {
+        self.align(alignment, with);
+        self.put(bytes)
+    }
+    /// Push filler until the assembling target end is aligned to the given alignment, after checking that
+    /// the alignment is a power of two. Otherwise nothing is pushed and `DynasmError::InvalidAlignment` is returned.
+    /// This catches invalid alignments that `align` would accept.
+    #[inline]
+    fn try_align(&mut self, alignment: usize, with: u8) -> Result<(), DynasmError> {
+        if !alignment.is_power_of_two() {
+            return Err(DynasmError::InvalidAlignment(alignment));
+        }
+        self.align(alignment, with);
+        Ok(())
+    }
+    /// Push filler until the assembling target end is aligned to the given alignment, which must be a
+    /// power of two. This is only checked by a debug assertion, use `try_align` to check it in release builds too.
+    #[inline]
+    fn align_to_power_of_two(&mut self, alignment: usize, with: u8) {
+        debug_assert!(alignment.is_power_of_two(), "alignment {} is not a power of two", alignment);
+        self.align(alignment, with);
+    }
+    /// Push filler until the assembling target end is located at the given offset.
+    /// This panics if the target end is already past this offset.
+    #[inline]
+    fn pad_to(&mut self, target: AssemblyOffset, with: u8) {
+        let offset = self.offset();
+        if offset > target {
+            self.runtime_error("Tried to pad to an offset that was already passed");
+        }
+        for _ in offset.0 .. target.0 {
+            self.push(with);
+        }
+    }
     /// This function is called in when a runtime error has to be generated. It panics.
     #[inline]
     fn runtime_error(&self, msg: &'static str) -> ! {
@@ -205,6 +648,14 @@ pub trait DynasmApi: Extend<u8> + for<'a> Extend<&'a u8> {
 }
 
 /// This trait extends DynasmApi to not only allow assembling, but also labels and various directives
+///
+/// Labels differ in how far away from their definition they can be referenced. A forward reference to a local label is
+/// resolved by the next definition of that label, and a backward reference by the latest one, so a local label only
+/// reaches the code up to its neighbouring definitions. References to global and dynamic labels are resolved when the
+/// code containing them is committed or finalized. Those labels can therefore be defined anywhere before that, both
+/// before and after the reference, and in earlier commits. The same holds for references to data labels from
+/// `Assembler::data`. If the label of a reference is still undefined at that point, committing fails with
+/// `DynasmError::UnknownLabel`. Weak references can be used for labels that might never be defined.
 pub trait DynasmLabelApi : DynasmApi {
     /// The relocation info type this assembler uses. 
     type Relocation: Relocation;
@@ -216,7 +667,9 @@ pub trait DynasmLabelApi : DynasmApi {
     /// Record the definition of a dynamic label
     fn dynamic_label(&mut self, id: DynamicLabel);
 
-    /// Record a relocation spot for a forward reference to a local label
+    /// Record a relocation spot for a forward reference to a local label.
+    /// For all relocations, `offset` is an addend that is added to the address of the target before
+    /// the relocation is encoded, which allows referencing `label + offset`.
     fn forward_reloc( &mut self, name: &'static str, offset: isize, kind: <Self::Relocation as Relocation>::Encoding) {
         self.forward_relocation(name, offset, Self::Relocation::from_encoding(kind))
     }
@@ -246,7 +699,33 @@ pub trait DynasmLabelApi : DynasmApi {
 }
 
 
+/// The operations shared by the assemblers that support labels, so a code generator can be written once and
+/// run against any of them: `Assembler` to execute the code, `VecLabelAssembler` or `SliceAssembler` to place it
+/// elsewhere, and `SizingAssembler` for a pre-pass that only measures it.
+///
+/// What committing means differs between them. `Assembler` copies the code into executable memory, while the other
+/// assemblers only resolve the references that are pending, as they have nowhere else to put the code. In all of
+/// them references to global and dynamic labels have to be resolvable by the time of the commit, and forward
+/// references to local labels may not cross it.
+pub trait CodeBuffer : DynasmLabelApi {
+    /// Create a new dynamic label id.
+    fn new_dynamic_label(&mut self) -> DynamicLabel;
+
+    /// Access the labels defined so far.
+    fn labels(&self) -> &LabelRegistry;
+
+    /// Resolve all pending references, returning the first error encountered while assembling or resolving them.
+    fn commit(&mut self) -> Result<(), DynasmError>;
+
+    /// Returns a copy of the code emitted in `range`, or `None` if `range` extends past the code emitted so far.
+    /// References that haven't been resolved by a commit read back as whatever was emitted in their place.
+    /// `SizingAssembler` doesn't keep the code, so it always returns `None`.
+    fn read_code(&self, range: ops::Range<AssemblyOffset>) -> Option<Vec<u8>>;
+}
+
+
 /// An assembler that is purely a `Vec<u8>`. It doesn't support labels, but can be used to easily inspect generated code.
+/// `VecLabelAssembler` also assembles into a `Vec<u8>`, with support for labels.
 pub struct VecAssembler(Vec<u8>);
 
 impl Extend<u8> for VecAssembler {
@@ -281,6 +760,13 @@ impl DynasmApi for VecAssembler {
 /// A full assembler implementation. Supports labels, all types of relocations,
 /// incremental compilation and multithreaded execution with simultaneous compiltion.
 /// Its implementation guarantees no memory is executable and writable at the same time.
+///
+/// An `Assembler` is `Send`, but not meant to be shared: assembling requires `&mut self`. Executing
+/// code from other threads happens through `Executor`s, which are `Send + Sync`. These only ever
+/// access the executable buffer through its `RwLock`, and the `Assembler` holds the write side of
+/// this lock while it commits or alters code. Remapping the buffer therefore waits until no
+/// thread holds an `Executor` lock anymore.
+#[cfg(feature = "std")]
 #[derive(Debug)]
 pub struct Assembler<R: Relocation> {
     ops: Vec<u8>,
@@ -288,533 +774,5095 @@ pub struct Assembler<R: Relocation> {
     labels: LabelRegistry,
     relocs: RelocRegistry<R>,
     managed: ManagedRelocs<R>,
+    unwind: UnwindRegistry,
+    hooks: CommitHooks,
+    verifier: CommitVerifier,
+    data: DataBuilder,
+    // references from code to data labels that haven't been committed yet
+    data_relocs: Vec<(PatchLoc<R>, &'static str)>,
+    // references from code to counters that haven't been committed yet
+    counter_relocs: Vec<(PatchLoc<R>, Counter)>,
+    // resolved references from code to data, and the address of the data they reference
+    data_refs: Vec<(PatchLoc<R>, usize)>,
+    // references to labels that might never be defined, and the label they target
+    weak_relocs: Vec<(PatchLoc<R>, TargetKind)>,
+    // the code that weak references to undefined labels are pointed at
+    placeholder: Option<AssemblyOffset>,
+    // committed weak references that were pointed at the placeholder, and the label they should target
+    placeholders: Vec<(PatchLoc<R>, TargetKind)>,
+    // fields of the given size ending at the given offset, holding the difference between two labels
+    diff_relocs: Vec<(AssemblyOffset, RelocationSize, TargetKind, TargetKind)>,
+    // how often dynamic labels are targeted from `weak_relocs`, `placeholders` and `diff_relocs`
+    dynamic_uses: DynamicUses,
+    // the functions emitted through `function`
+    functions: Vec<(&'static str, ops::Range<AssemblyOffset>)>,
+    // all references that have been resolved, by the start of their field, if they are tracked
+    applied: Option<BTreeMap<usize, AppliedReloc>>,
+    // the code replaced by breakpoints in committed code, by the offset of the breakpoint
+    breakpoints: BTreeMap<usize, Vec<u8>>,
+    // the labels of the thunks emitted by architecture specific helpers, by the register they branch to
+    thunks: BTreeMap<u8, DynamicLabel>,
+    // the relocation used by the `*_reloc_default` methods
+    default_relocation: Option<R>,
+    // the instruction boundaries recorded through `mark_insn_boundary`
+    boundaries: BTreeSet<usize>,
+    // references to symbols that are resolved by an external linker
+    externals: Vec<ExternalReloc<R>>,
+    // the global labels that may be referenced, once `declare_globals` has been called
+    declared: Option<BTreeSet<&'static str>>,
+    // if commits are padded to a page boundary
+    page_align_sections: bool,
+    // set when resolving references failed during a commit, which disables the check for pending references on drop
+    commit_failed: bool,
+    // the fields checked for overlaps in debug builds, kept so checking doesn't allocate on every commit
+    overlap_fields: Vec<(ops::Range<usize>, TargetKind)>,
     error: Option<DynasmError>,
 }
 
-impl<R: Relocation> Assembler<R> {
-    /// Create a new, empty assembler, with initial allocation size `page_size`.
-    pub fn new() -> io::Result<Self> {
-        Ok(Self {
-            ops: Vec::new(),
-            memory: MemoryManager::new(R::page_size())?,
-            labels: LabelRegistry::new(),
-            relocs: RelocRegistry::new(),
-            managed: ManagedRelocs::new(),
-            error: None
-        })
+/// The configuration of an `Assembler` and its executable buffer, as used by `Assembler::with_config`.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AssemblerConfig {
+    /// The amount of bytes allocated up front, rounded up to a multiple of the page size like `Assembler::with_initial_size`.
+    pub min_size: usize,
+    /// The size the buffer may never grow beyond, or `None` to let it grow as needed. This should be a multiple of
+    /// the page size, as memory is mapped in whole pages.
+    pub max_size: Option<usize>,
+    /// If every commit pads the code with zeroes to the next page boundary, see `page_align_sections`.
+    pub page_align_sections: bool,
+    /// If the references resolved in the code are recorded, see `track_relocations`.
+    pub track_relocations: bool,
+}
+
+#[cfg(feature = "std")]
+impl AssemblerConfig {
+    /// Set `min_size`.
+    pub fn min_size(mut self, size: usize) -> Self {
+        self.min_size = size;
+        self
     }
 
-    /// Create a new dynamic label ID
-    pub fn new_dynamic_label(&mut self) -> DynamicLabel {
-        self.labels.new_dynamic_label()
+    /// Set `max_size`.
+    pub fn max_size(mut self, size: Option<usize>) -> Self {
+        self.max_size = size;
+        self
     }
 
-    /// Use an `UncommittedModifier` to alter uncommitted code.
-    /// This does not allow the user to change labels/relocations.
-    pub fn alter_uncommitted(&mut self) -> UncommittedModifier {
-        let offset = self.memory.committed();
-        UncommittedModifier::new(&mut self.ops, AssemblyOffset(offset))
+    /// Pad the code of every commit with zeroes up to the next page boundary, so every commit occupies pages of its
+    /// own. The protection of the code of a single commit can then be changed without affecting its neighbours, as
+    /// `Assembler::retire` does. This wastes the rest of the last page of every commit. Committing without any new
+    /// code adds no padding.
+    pub fn page_align_sections(mut self, enabled: bool) -> Self {
+        self.page_align_sections = enabled;
+        self
     }
 
-    /// Use a `Modifier` to alter committed code directly. While this is happening
-    /// no code can be executed as the relevant pages are remapped as writable.
-    /// This API supports defining new labels/relocations, and overwriting previously defined relocations.
-    pub fn alter<F, O>(&mut self, f: F) -> Result<O, DynasmError>
-    where F: FnOnce(&mut Modifier<R>) -> O {
-        self.commit()?;
+    /// Record every reference that is resolved in the code, so they can be listed through `Assembler::relocations`.
+    /// This is off by default, as it costs an allocation for every reference and keeps them around for as long as the
+    /// assembler lives.
+    pub fn track_relocations(mut self, enabled: bool) -> Self {
+        self.track_relocations = enabled;
+        self
+    }
+}
 
-        // swap out a buffer from base
-        let mut lock = self.memory.write();
-        let buffer = mem::replace(&mut *lock, ExecutableBuffer::default());
-        let mut buffer = buffer.make_mut().expect("Could not swap buffer protection modes");
+// The size of the buffer allocated for at least `size` bytes of code. Buffers consist of at least one page.
+#[cfg(feature = "std")]
+fn initial_size(size: usize) -> io::Result<usize> {
+    let page_size = mmap::page_size();
+    let size = cmp::max(size, 1);
+    Ok(size.checked_add(page_size - 1)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Initial size too large"))?
+        / page_size * page_size)
+}
 
-        // construct the modifier
-        let mut modifier = Modifier {
-            asmoffset: 0,
-            previous_asmoffset: 0,
-            buffer: &mut *buffer,
+/// A description of a commit, passed to the hooks registered with `Assembler::on_commit`.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommitInfo {
+    /// The range of code that was committed
+    pub range: ops::Range<AssemblyOffset>,
+    /// The relocations to global and dynamic labels that were resolved during this commit.
+    /// References to local labels are resolved while assembling, and are not included.
+    pub relocations: Vec<RelocInfo>,
+}
 
-            labels: &mut self.labels,
-            relocs: &mut self.relocs,
-            old_managed: &mut self.managed,
-            new_managed: ManagedRelocs::new(),
+/// A commit hook, as registered with `Assembler::on_commit`.
+#[cfg(feature = "std")]
+pub type CommitHook = Box<dyn FnMut(&CommitInfo) + Send>;
 
-            error: None
-        };
+// Wrapper around the registered commit hooks, as closures don't implement Debug
+#[cfg(feature = "std")]
+#[derive(Default)]
+struct CommitHooks(Vec<CommitHook>);
 
-        // execute the user code
-        let output = f(&mut modifier);
+#[cfg(feature = "std")]
+impl fmt::Debug for CommitHooks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CommitHooks({} hooks)", self.0.len())
+    }
+}
 
-        // flush any changes made by the user code to the buffer
-        modifier.encode_relocs()?;
+/// A verifier of committed code, as set with `Assembler::set_verifier`. It returns the reason for rejecting the code.
+#[cfg(feature = "std")]
+pub type Verifier = Box<dyn FnMut(&[u8]) -> Result<(), String> + Send>;
 
-        // repack the buffer
-        let buffer = buffer.make_exec().expect("Could not swap buffer protection modes");
-        mem::replace(&mut *lock, buffer);
+// Wrapper around the verifier, for the same reason as `CommitHooks`
+#[cfg(feature = "std")]
+#[derive(Default)]
+struct CommitVerifier(Option<Verifier>);
 
-        // call it a day
-        Ok(output)
+#[cfg(feature = "std")]
+impl fmt::Debug for CommitVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CommitVerifier({})", if self.0.is_some() { "set" } else { "unset" })
     }
+}
 
-    /// Commit code, flushing the temporary internal assembling buffer to the mapped executable memory.
-    /// This makes assembled code available for execution.
-    pub fn commit(&mut self) -> Result<(), DynasmError> {
-        self.encode_relocs()?;
-
-        let managed = &self.managed;
-        let error = &mut self.error;
-
-        self.memory.commit(&mut self.ops, |buffer, old_addr, new_addr| {
-            let change = new_addr.wrapping_sub(old_addr) as isize;
+// The amount of weak references, placeholders and label differences targeting each dynamic label id, so freeing a
+// label doesn't have to search them
+#[cfg(feature = "std")]
+#[derive(Debug, Default)]
+struct DynamicUses(Vec<usize>);
 
-            for reloc in managed.iter() {
-                if let Err(_) = reloc.adjust(0, buffer, change) {
-                    *error = Some(DynasmError::ImpossibleRelocation(TargetKind::Managed))
-                }
+#[cfg(feature = "std")]
+impl DynamicUses {
+    fn add(&mut self, target: TargetKind) {
+        if let TargetKind::Dynamic(id) = target {
+            if self.0.len() <= id.0 {
+                self.0.resize(id.0 + 1, 0);
             }
-        });
-
-        if let Some(e) = self.error.take() {
-            return Err(e);
+            self.0[id.0] += 1;
         }
-        Ok(())
     }
 
-    /// Finalize this assembler, returning the internal executablebuffer if no Executor instances exist.
-    /// This panics if any uncommitted changes caused errors near the end. To handle these, call `commit()` explicitly beforehand.
-    pub fn finalize(mut self) -> Result<ExecutableBuffer, Self> {
-        self.commit().expect("Errors were encountered when committing before finalization");
-        match self.memory.finalize() {
-            Ok(execbuffer) => Ok(execbuffer),
-            Err(memory) => Err(Self {
-                memory,
-                ..self
-            })
+    fn remove(&mut self, target: TargetKind) {
+        if let TargetKind::Dynamic(id) = target {
+            self.0[id.0] -= 1;
         }
     }
 
-    /// Create an executor which can be used to execute code while still assembling code
-    pub fn reader(&self) -> Executor {
-        Executor {
-            execbuffer: self.memory.reader()
-        }
+    fn contains(&self, id: DynamicLabel) -> bool {
+        matches!(self.0.get(id.0), Some(&count) if count != 0)
     }
+}
 
-    /// Provides access to the assemblers internal labels registry
-    pub fn labels(&self) -> &LabelRegistry {
-        &self.labels
+#[cfg(feature = "std")]
+impl<R: Relocation> Assembler<R> {
+    /// Create a new, empty assembler, with initial allocation size `page_size`.
+    pub fn new() -> io::Result<Self> {
+        Ok(Self::with_memory(MemoryManager::new(R::page_size())?))
     }
 
-    /// Provides mutable access to the assemblers internal labels registry
-    pub fn labels_mut(&mut self) -> &mut LabelRegistry {
-        &mut self.labels
+    /// Create a new, empty assembler that doesn't allocate any executable memory until code is first committed.
+    /// This saves mapping memory for assemblers that might end up never emitting anything, and can't fail.
+    pub fn new_lazy() -> Self {
+        Self::with_memory(MemoryManager::new_lazy())
     }
 
-    // encode uncommited relocations
-    fn encode_relocs(&mut self) -> Result<(), DynasmError> {
-        let buf_offset = self.memory.committed();
-        let buf_addr = self.memory.execbuffer_addr();
-        let buf = &mut self.ops;
+    /// Like `new`, but returns an `AllocError` that tells why the executable memory could not be obtained.
+    /// This distinguishes running out of memory from a system policy that doesn't allow making memory executable.
+    pub fn try_new() -> Result<Self, AllocError> {
+        Ok(Self::with_memory(MemoryManager::try_new(R::page_size())?))
+    }
 
-        // If we accrued any errors while assembling before, emit them now.
-        if let Some(e) = self.error.take() {
-            return Err(e);
-        }
+    /// Create a new, empty assembler that allocates room for at least `size` bytes of code up front,
+    /// rounded up to a multiple of the page size. This avoids having to grow the buffer when the size
+    /// of the generated code is roughly known in advance.
+    pub fn with_initial_size(size: usize) -> io::Result<Self> {
+        Ok(Self::with_memory(MemoryManager::new(initial_size(size)?)?))
+    }
 
-        // Resolve globals
-        for (loc, name) in self.relocs.take_globals() {
-            let target = self.labels.resolve_global(name)?;
-            if let Err(_) = loc.patch(buf_offset, buf_addr, buf, target.0) {
-                return Err(DynasmError::ImpossibleRelocation(TargetKind::Global(name)));
-            }
-            if loc.needs_adjustment() {
-                self.managed.add(loc)
-            }
+    /// Create a new, empty assembler with the buffer sizes from `config`. If the initial size is larger than the
+    /// maximum size, an error is returned. A commit that needs a buffer larger than the maximum size fails with
+    /// `DynasmError::BufferFull` instead of allocating it, which bounds the executable memory a code generator can
+    /// tie up. The code stays uncommitted then, and can be discarded with `reset`.
+    pub fn with_config(config: AssemblerConfig) -> io::Result<Self> {
+        let size = initial_size(config.min_size)?;
+        if config.max_size.is_some_and(|max_size| size > max_size) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Initial size exceeds the maximum size"));
         }
-
-        // Resolve dynamics
-        for (loc, id) in self.relocs.take_dynamics() {
-            let target = self.labels.resolve_dynamic(id)?;
-            if let Err(_) = loc.patch(buf_offset, buf_addr, buf, target.0) {
-                return Err(DynasmError::ImpossibleRelocation(TargetKind::Dynamic(id)));
-            }
-            if loc.needs_adjustment() {
-                self.managed.add(loc)
-            }
+        let mut memory = MemoryManager::new(size)?;
+        memory.set_max_size(config.max_size);
+        let mut assembler = Self::with_memory(memory);
+        assembler.page_align_sections = config.page_align_sections;
+        if config.track_relocations {
+            assembler.applied = Some(BTreeMap::new());
         }
+        Ok(assembler)
+    }
 
-        // Check that there are no unknown local labels
-        for (_, name) in self.relocs.take_locals() {
-            return Err(DynasmError::UnknownLabel(LabelKind::Local(name)));
-        }
+    /// Create a new, empty assembler whose code is placed in the memory range `region`. The start and end
+    /// of the range should be multiples of the page size. If the memory cannot be mapped at exactly this
+    /// location, an error is returned. As the code cannot move out of this region, committing more code
+    /// than fits into it fails with `DynasmError::BufferFull`.
+    ///
+    /// As the code always ends up at the same address, this also makes code that depends on its own address, like
+    /// relative references to absolute addresses, reproducible byte for byte. Tests can use this to compare emitted code
+    /// against a snapshot. The region is never forced onto memory that is already mapped, as that would silently
+    /// replace the existing mapping, so tests should pick a region that the process doesn't use otherwise.
+    /// Read-only data from `data` is mapped separately, close to the region if possible, but doesn't end up at a fixed address.
+    pub fn new_in(region: ops::Range<usize>) -> io::Result<Self> {
+        Ok(Self::with_memory(MemoryManager::new_at(region.start, region.end - region.start)?))
+    }
+
+    /// Create a new, empty assembler in development mode, where the memory holding the code is mapped readable,
+    /// writable and executable at the same time. Committing and patching code then write to the memory directly,
+    /// instead of remapping it as writable and back as executable every time, which makes repeatedly patching code
+    /// a lot cheaper. This also holds for the buffers the code is moved into when it grows.
+    ///
+    /// This is insecure: any bug that allows writing to memory now also allows writing new code for the process to
+    /// execute. It should only be used during development, and never for code that runs on untrusted inputs.
+    /// Some platforms refuse to map memory like this, in which case an error is returned.
+    pub fn new_dev_mode() -> io::Result<Self> {
+        Ok(Self::with_memory(MemoryManager::new_writable(R::page_size())?))
+    }
 
+    /// Discard all code, labels, relocations, hooks and other state of this assembler, keeping the memory it
+    /// allocated for new code. This leaves the assembler like a newly created one, except that the memory doesn't have
+    /// to be allocated again. The committed code is zeroed. If any `Executor` of this assembler still exists, the old
+    /// code is left alone for it and new memory is allocated after all. As memory placed through `new_in` can't be
+    /// allocated again, this fails if such an `Executor` exists for it, in which case nothing is discarded. The
+    /// configuration passed to `with_config` is kept.
+    pub fn reset(&mut self) -> io::Result<()> {
+        self.memory.reset()?;
+        let memory = mem::replace(&mut self.memory, MemoryManager::new_lazy());
+        // the discarded code is allowed to reference labels that were never defined
+        self.relocs = RelocRegistry::new();
+        self.weak_relocs.clear();
+        let page_align_sections = self.page_align_sections;
+        let track_relocations = self.applied.is_some();
+        *self = Self::with_memory(memory);
+        self.page_align_sections = page_align_sections;
+        if track_relocations {
+            self.applied = Some(BTreeMap::new());
+        }
         Ok(())
     }
-}
 
-impl<R: Relocation> Extend<u8> for Assembler<R> {
-    fn extend<T>(&mut self, iter: T) where T: IntoIterator<Item=u8> {
-        self.ops.extend(iter)
+    /// Create an assembler that continues assembling after the code in `buffer`, for instance one returned by
+    /// `finalize`. The code in `buffer` is treated as committed. No labels are known for it, but they can be redeclared
+    /// through `labels_mut`. As the buffer can move when it grows, the code in it should not depend on its own address.
+    pub fn from_buffer(buffer: ExecutableBuffer) -> Self {
+        Self::with_memory(MemoryManager::from_buffer(buffer))
     }
-}
 
-impl<'a, R: Relocation> Extend<&'a u8> for Assembler<R> {
-    fn extend<T>(&mut self, iter: T) where T: IntoIterator<Item=&'a u8> {
-        self.ops.extend(iter)
+    fn with_memory(memory: MemoryManager) -> Self {
+        Self {
+            ops: Vec::new(),
+            memory,
+            labels: LabelRegistry::new(),
+            relocs: RelocRegistry::new(),
+            managed: ManagedRelocs::new(),
+            unwind: UnwindRegistry::new(),
+            hooks: CommitHooks::default(),
+            verifier: CommitVerifier::default(),
+            data: DataBuilder::new(),
+            data_relocs: Vec::new(),
+            counter_relocs: Vec::new(),
+            data_refs: Vec::new(),
+            weak_relocs: Vec::new(),
+            placeholder: None,
+            placeholders: Vec::new(),
+            diff_relocs: Vec::new(),
+            dynamic_uses: DynamicUses::default(),
+            functions: Vec::new(),
+            applied: None,
+            breakpoints: BTreeMap::new(),
+            thunks: BTreeMap::new(),
+            default_relocation: None,
+            boundaries: BTreeSet::new(),
+            externals: Vec::new(),
+            declared: None,
+            page_align_sections: false,
+            commit_failed: false,
+            overlap_fields: Vec::new(),
+            error: None
+        }
     }
-}
 
-impl<R: Relocation> DynasmApi for Assembler<R> {
-    fn offset(&self) -> AssemblyOffset {
-        AssemblyOffset(self.memory.committed() + self.ops.len())
+    /// Create a new dynamic label ID
+    pub fn new_dynamic_label(&mut self) -> DynamicLabel {
+        self.labels.new_dynamic_label()
     }
 
-    fn push(&mut self, value: u8) {
-        self.ops.push(value);
+    /// Returns the dynamic label ID for `name`, creating it the first time `name` is used.
+    /// This allows labels to be named at runtime, without requiring `&'static str` names.
+    pub fn named_dynamic_label(&mut self, name: &str) -> DynamicLabel {
+        self.labels.named_dynamic_label(name)
     }
 
-    fn align(&mut self, alignment: usize, with: u8) {
-        let misalign = self.offset().0 % alignment;
-        if misalign != 0 {
-            for _ in misalign .. alignment {
-                self.push(with);
-            }
+    /// Declare the global labels in `names`, and only allow references to declared global labels from now on.
+    /// Referencing any other global label panics immediately, which points at the mistyped name instead of
+    /// failing when the code is committed. Declaring a label doesn't define it, so committing references to
+    /// declared labels that were never defined still fails. Can be called multiple times to declare more labels.
+    pub fn declare_globals(&mut self, names: &[&'static str]) {
+        self.declared.get_or_insert_with(BTreeSet::new).extend(names);
+    }
+
+    // panics if `name` may not be referenced, as it wasn't passed to `declare_globals`
+    fn check_declared(&self, name: &'static str) {
+        if let Some(declared) = &self.declared {
+            assert!(declared.contains(name), "Reference to undeclared global label ->{}", name);
         }
     }
-}
 
-impl<R: Relocation> DynasmLabelApi for Assembler<R> {
-    type Relocation = R;
+    /// Free the dynamic label `id`, so its id can be reused by `new_dynamic_label`.
+    /// This fails if relocations targeting the label are still pending, or if the label was already freed.
+    /// Weak references to the label that were pointed at the placeholder count as pending.
+    pub fn free_dynamic_label(&mut self, id: DynamicLabel) -> Result<(), DynasmError> {
+        if self.relocs.has_dynamic(id) || self.dynamic_uses.contains(id) {
+            return Err(DynasmError::LabelInUse(LabelKind::Dynamic(id)));
+        }
+        self.labels.free_dynamic_label(id)
+    }
 
-    fn local_label(&mut self, name: &'static str) {
+    /// Returns true if the dynamic label `id` has already been defined.
+    pub fn is_defined(&self, id: DynamicLabel) -> bool {
+        self.labels.is_dynamic_defined(id)
+    }
+
+    /// Returns true if the global label `name` has already been defined.
+    pub fn is_global_defined(&self, name: &'static str) -> bool {
+        self.labels.is_global_defined(name)
+    }
+
+    /// Returns where `label` is defined, telling apart labels pointing at committed code from those pointing at code
+    /// that hasn't been committed yet. Returns `None` if the label isn't defined. For local labels, this is the most
+    /// recent definition, which is what backward references resolve to.
+    ///
+    /// Offsets stay valid across commits, so labels keep their definition once the code they point at is committed.
+    /// Backward references to local labels and references to global and dynamic labels defined in an earlier commit
+    /// resolve to that definition, even when the buffer has moved since. Only `reset` removes label definitions,
+    /// together with all the code they could point at.
+    pub fn label_definition(&self, label: LabelKind) -> Option<LabelDefinition> {
+        let offset = match label {
+            LabelKind::Local(name) => self.labels.resolve_local(name),
+            LabelKind::Global(name) => self.labels.resolve_global(name),
+            LabelKind::Dynamic(id) => self.labels.resolve_dynamic(id),
+        }.ok()?;
+
+        if offset.0 < self.memory.committed() {
+            Some(LabelDefinition::Committed(offset))
+        } else {
+            Some(LabelDefinition::Uncommitted(offset))
+        }
+    }
+
+    /// Define the local label `name` at the current offset, resolving all forward references to it. Returns an error
+    /// if one of them cannot reach the label. Unlike `DynasmLabelApi::local_label`, the error is reported immediately
+    /// instead of being deferred, so it is reported at the label that is out of reach. The label is defined regardless.
+    pub fn try_local_label(&mut self, name: &'static str) -> Result<(), DynasmError> {
         let offset = self.offset();
+        let mut result = Ok(());
         for loc in self.relocs.take_locals_named(name) {
-            if let Err(_) = loc.patch(self.memory.committed(), self.memory.execbuffer_addr(), &mut self.ops, offset.0) {
-                self.error = Some(DynasmError::ImpossibleRelocation(TargetKind::Forward(name)))
-            } else if loc.needs_adjustment() {
+            if loc.patch(self.memory.committed(), self.memory.execbuffer_addr(), &mut self.ops, offset.0).is_err() {
+                result = Err(DynasmError::ImpossibleRelocation(TargetKind::Forward(name)));
+                continue;
+            }
+            record(&mut self.applied, &loc, TargetKind::Forward(name), Some(offset));
+            if loc.needs_adjustment() {
                 self.managed.add(loc)
             }
         }
         self.labels.define_local(name, offset);
+        result
     }
-    fn global_label( &mut self, name: &'static str) {
+
+    /// Define the global label `name` at the current offset, returning an error if it was already defined.
+    /// Unlike `DynasmLabelApi::global_label`, the error is reported immediately instead of being deferred.
+    pub fn try_global_label(&mut self, name: &'static str) -> Result<(), DynasmError> {
         let offset = self.offset();
-        if let Err(e) = self.labels.define_global(name, offset) {
-            self.error = Some(e)
-        }
+        self.labels.define_global(name, offset)
     }
-    fn dynamic_label(&mut self, id: DynamicLabel) {
+
+    /// Define the dynamic label `id` at the current offset, returning an error if it was already defined.
+    /// Unlike `DynasmLabelApi::dynamic_label`, the error is reported immediately instead of being deferred.
+    pub fn try_dynamic_label(&mut self, id: DynamicLabel) -> Result<(), DynasmError> {
         let offset = self.offset();
-        if let Err(e) = self.labels.define_dynamic(id, offset) {
-            self.error = Some(e)
-        }
-    }
-    fn global_relocation(&mut self, name: &'static str, offset: isize, kind: R) {
-        let location = self.offset();
-        self.relocs.add_global(name, PatchLoc::new(location, offset, kind));
+        self.labels.define_dynamic(id, offset)
     }
-    fn dynamic_relocation(&mut self, id: DynamicLabel, offset: isize, kind: R) {
-        let location = self.offset();
-        self.relocs.add_dynamic(id, PatchLoc::new(location, offset, kind));
+
+    /// Set what happens when a global label is defined that was already defined before.
+    pub fn set_duplicate_label_policy(&mut self, policy: DuplicateLabelPolicy) {
+        self.labels.set_duplicate_label_policy(policy);
     }
-    fn forward_relocation(&mut self, name: &'static str, offset: isize, kind: R) {
-        let location = self.offset();
-        self.relocs.add_local(name, PatchLoc::new(location, offset, kind));
+
+    /// Record a backward reference to the local label `name`, returning an error if the label is unknown
+    /// or out of range. Unlike `DynasmLabelApi::backward_reloc`, the error is reported immediately instead of being deferred.
+    pub fn try_backward_reloc(&mut self, name: &'static str, offset: isize, kind: R::Encoding) -> Result<(), DynasmError> {
+        self.try_backward_relocation(name, offset, R::from_encoding(kind))
     }
-    fn backward_relocation(&mut self, name: &'static str, offset: isize, kind: R) {
-        let target = match self.labels.resolve_local(name) {
-            Ok(target) => target.0,
-            Err(e) => {
-                self.error = Some(e);
-                return;
-            }
-        };
+
+    /// Equivalent of `try_backward_reloc` taking a non-encoded relocation.
+    pub fn try_backward_relocation(&mut self, name: &'static str, offset: isize, kind: R) -> Result<(), DynasmError> {
+        let target = self.labels.resolve_local(name)?.0;
         let location = self.offset();
         let loc = PatchLoc::new(location, offset, kind);
-        if let Err(_) = loc.patch(self.memory.committed(), self.memory.execbuffer_addr(), &mut self.ops, target) {
-            self.error = Some(DynasmError::ImpossibleRelocation(TargetKind::Backward(name)))
-        } else if loc.needs_adjustment() {
-            self.managed.add(loc)
+        if loc.patch(self.memory.committed(), self.memory.execbuffer_addr(), &mut self.ops, target).is_err() {
+            return Err(DynasmError::ImpossibleRelocation(TargetKind::Backward(name)));
         }
-    }
-    fn bare_relocation(&mut self, target: usize, kind: R) {
-        let location = self.offset();
-        let loc = PatchLoc::new(location, 0, kind);
-        if let Err(_) = loc.patch(self.memory.committed(), self.memory.execbuffer_addr(), &mut self.ops, target) {
-            self.error = Some(DynasmError::ImpossibleRelocation(TargetKind::Extern(target)))
-        } else if loc.needs_adjustment() {
+        record(&mut self.applied, &loc, TargetKind::Backward(name), Some(AssemblyOffset(target)));
+        if loc.needs_adjustment() {
             self.managed.add(loc)
         }
+        Ok(())
     }
-}
 
+    /// Copy the committed code into `dst`, adjusting any references that depend on the address of the code to the
+    /// address of `dst`. This allows placing the code in executable memory that is managed by someone else, which
+    /// is then responsible for making it executable and flushing the instruction cache where needed. The code should be
+    /// placed at the start of `dst`, as references to read-only data from `data` are resolved relative to it. Unwind
+    /// info is not registered for the copy. Returns `DynasmError::BufferFull` if `dst` is smaller than the committed
+    /// code, and `DynasmError::ImpossibleRelocation` if a reference cannot reach its target from the new address, in
+    /// which case the copy is incomplete.
+    pub fn copy_into(&self, dst: &mut [u8]) -> Result<(), DynasmError> {
+        let buffer = self.memory.read();
+        let dst = dst.get_mut(.. buffer.len()).ok_or(DynasmError::BufferFull)?;
+        dst.copy_from_slice(&buffer);
+        let dst_addr = dst.as_ptr() as usize;
+        fix_moved(&self.managed, &self.placeholders, &self.data_refs, dst, self.memory.execbuffer_addr(), dst_addr)
+    }
 
-/// Allows modification of already committed assembly code. Contains an internal cursor
-/// into the emitted assembly, initialized to the start, that can be moved around either with the
-/// `goto` function, or just by assembling new code into this `Modifier`.
-#[derive(Debug)]
-pub struct Modifier<'a, R: Relocation> {
-    asmoffset: usize,
-    previous_asmoffset: usize,
-    buffer: &'a mut [u8],
+    /// Record that an instruction starts at the current offset. Once any instruction boundary has been recorded,
+    /// `patch` and `set_breakpoint` check that they don't overwrite part of an instruction, and `Modifier::check_insn_boundary`
+    /// can be used to check the same while altering code. As these checks only know about the recorded boundaries, every
+    /// instruction that may be patched has to be recorded, along with the instruction following such a patch.
+    pub fn mark_insn_boundary(&mut self) {
+        let offset = self.offset();
+        self.boundaries.insert(offset.0);
+    }
 
-    labels: &'a mut LabelRegistry,
-    relocs: &'a mut RelocRegistry<R>,
-    old_managed: &'a mut ManagedRelocs<R>,
-    new_managed: ManagedRelocs<R>,
+    /// Returns true if `offset` is at an instruction boundary recorded through `mark_insn_boundary`, or at the end of
+    /// the code. If no boundaries have been recorded, every offset counts as a boundary.
+    pub fn is_insn_boundary(&self, offset: AssemblyOffset) -> bool {
+        is_insn_boundary(&self.boundaries, offset, self.offset())
+    }
 
-    error: Option<DynasmError>
-}
+    /// Set the code that weak references to labels that are still undefined when they are committed are pointed at,
+    /// such as a stub that compiles the missing function. This applies to all weak references committed afterwards.
+    pub fn set_placeholder(&mut self, target: AssemblyOffset) {
+        self.placeholder = Some(target);
+    }
 
-impl<'a, R: Relocation> Modifier<'a, R> {
-    /// Move the modifier cursor to the selected location.
-    pub fn goto(&mut self, offset: AssemblyOffset) {
-        self.old_managed.remove_between(self.previous_asmoffset, self.asmoffset);
-        self.asmoffset = offset.0;
-        self.previous_asmoffset = offset.0;
+    /// Record a weak reference to the global label `name`. Unlike `DynasmLabelApi::global_reloc`, committing it doesn't
+    /// fail if the label is still undefined at that point. Instead, it is pointed at the placeholder set by `set_placeholder`,
+    /// until `resolve_placeholders` is called after the label has been defined. If no placeholder was set, committing it
+    /// fails with `DynasmError::UnknownLabel` like a normal reference.
+    pub fn weak_global_reloc(&mut self, name: &'static str, offset: isize, kind: R::Encoding) {
+        self.weak_global_relocation(name, offset, R::from_encoding(kind))
     }
 
-    /// Check that the modifier cursor has not moved past the specified location.
-    pub fn check(&self, offset: AssemblyOffset) -> Result<(), DynasmError> {
-        if self.asmoffset > offset.0 {
-            Err(DynasmError::CheckFailed)
-        } else {
-            Ok(())
-        }
+    /// Equivalent of `weak_global_reloc` taking a non-encoded relocation.
+    pub fn weak_global_relocation(&mut self, name: &'static str, offset: isize, kind: R) {
+        self.check_declared(name);
+        let location = self.offset();
+        self.weak_relocs.push((PatchLoc::new(location, offset, kind), TargetKind::Global(name)));
     }
 
-    /// Check that the modifier cursor is exactly at the specified location.
-    pub fn check_exact(&self, offset: AssemblyOffset) -> Result<(), DynasmError> {
-        if self.asmoffset != offset.0 {
-            Err(DynasmError::CheckFailed)
-        } else {
-            Ok(())
-        }
+    /// Record a reference to the symbol `name`, which is not resolved by this assembler but left for an external
+    /// linker. It is listed by `external_relocations`, and committing doesn't touch the field holding it. As the
+    /// symbol could be anywhere, the code should then be copied out and linked instead of being executed in place.
+    pub fn extern_reloc_by_name(&mut self, name: &str, offset: isize, kind: R::Encoding) {
+        self.extern_relocation_by_name(name, offset, R::from_encoding(kind))
     }
 
-    // encode uncommited relocations
-    fn encode_relocs(&mut self) -> Result<(), DynasmError> {
-        let buf_addr = self.buffer.as_ptr() as usize;
+    /// Equivalent of `extern_reloc_by_name` taking a non-encoded relocation.
+    pub fn extern_relocation_by_name(&mut self, name: &str, offset: isize, kind: R) {
+        let loc = PatchLoc::new(self.offset(), offset, kind);
+        let field = loc.field();
+        self.externals.push(ExternalReloc {
+            field: AssemblyOffset(field.start) .. AssemblyOffset(field.end),
+            base: AssemblyOffset(loc.location.0 - loc.relocation.start_offset()),
+            symbol: String::from(name),
+            addend: loc.offset,
+            relocation: loc.relocation,
+        });
+    }
 
-        // If we accrued any errors while assembling before, emit them now.
-        if let Some(e) = self.error.take() {
-            return Err(e);
-        }
+    /// Returns the references to external symbols recorded through `extern_reloc_by_name`, in the order they were recorded.
+    /// This includes references in code that hasn't been committed yet.
+    pub fn external_relocations(&self) -> &[ExternalReloc<R>] {
+        &self.externals
+    }
 
-        // Resolve globals
-        for (loc, name) in self.relocs.take_globals() {
-            let target = self.labels.resolve_global(name)?;
-            if let Err(_) = loc.patch(0, buf_addr, self.buffer, target.0) {
-                return Err(DynasmError::ImpossibleRelocation(TargetKind::Global(name)));
-            }
-            if loc.needs_adjustment() {
-                self.new_managed.add(loc);
+    /// Record a weak reference to the dynamic label `id`. See `weak_global_reloc`.
+    pub fn weak_dynamic_reloc(&mut self, id: DynamicLabel, offset: isize, kind: R::Encoding) {
+        self.weak_dynamic_relocation(id, offset, R::from_encoding(kind))
+    }
+
+    /// Equivalent of `weak_dynamic_reloc` taking a non-encoded relocation.
+    pub fn weak_dynamic_relocation(&mut self, id: DynamicLabel, offset: isize, kind: R) {
+        let location = self.offset();
+        self.weak_relocs.push((PatchLoc::new(location, offset, kind), TargetKind::Dynamic(id)));
+        self.dynamic_uses.add(TargetKind::Dynamic(id));
+    }
+
+    /// Record a field of `size` bytes ending at the current offset, which is filled with the offset of label `a` minus
+    /// the offset of label `b` when the code is committed. This can be used for jump tables holding the distance
+    /// of each case to a base label, or for the size of a piece of code. Both labels have to be global or dynamic
+    /// labels, but they don't have to be defined yet. Other targets are reported as `DynasmError::ImpossibleRelocation`
+    /// on the next commit, like differences that don't fit in the field.
+    pub fn diff_reloc(&mut self, a: TargetKind, b: TargetKind, size: RelocationSize) {
+        for &target in &[a, b] {
+            if !matches!(target, TargetKind::Global(_) | TargetKind::Dynamic(_)) {
+                self.error = Some(DynasmError::ImpossibleRelocation(target));
+                return;
             }
         }
+        let location = self.offset();
+        self.diff_relocs.push((location, size, a, b));
+        self.dynamic_uses.add(a);
+        self.dynamic_uses.add(b);
+    }
 
-        // Resolve dynamics
-        for (loc, id) in self.relocs.take_dynamics() {
-            let target = self.labels.resolve_dynamic(id)?;
-            if let Err(_) = loc.patch(0, buf_addr, self.buffer, target.0) {
-                return Err(DynasmError::ImpossibleRelocation(TargetKind::Dynamic(id)));
-            }
-            if loc.needs_adjustment() {
-                self.new_managed.add(loc);
-            }
+    /// Point the weak references that were pointed at the placeholder to their labels, if these have been defined since.
+    /// Uncommitted code is committed first. While this is happening no code can be executed as the relevant pages are
+    /// remapped as writable. Returns the amount of references that still point at the placeholder.
+    pub fn resolve_placeholders(&mut self) -> Result<usize, DynasmError> {
+        self.commit()?;
+
+        let labels = &self.labels;
+        let resolved = self.placeholders.iter().filter(|&&(_, target)| resolve_label(labels, target).is_ok()).count();
+        if resolved == 0 {
+            return Ok(self.placeholders.len());
         }
 
-        // Check for unknown locals
-        for (_, name) in self.relocs.take_locals() {
-            return Err(DynasmError::UnknownLabel(LabelKind::Local(name)));
+        // swap out a buffer from base
+        let mut lock = self.memory.write();
+        let buffer = mem::take(&mut *lock);
+        let mut buffer = buffer.make_mut().expect("Could not swap buffer protection modes");
+
+        let buf_addr = self.memory.execbuffer_addr();
+        let mut result = Ok(());
+        for (loc, target) in mem::take(&mut self.placeholders) {
+            match resolve_label(&self.labels, target) {
+                Ok(offset) => {
+                    self.dynamic_uses.remove(target);
+                    if loc.patch(0, buf_addr, &mut buffer, offset.0).is_err() {
+                        result = Err(DynasmError::ImpossibleRelocation(target));
+                        continue;
+                    }
+                    record(&mut self.applied, &loc, target, Some(offset));
+                    if loc.needs_adjustment() {
+                        self.managed.add(loc);
+                    }
+                },
+                Err(_) => self.placeholders.push((loc, target)),
+            }
         }
 
-        self.old_managed.remove_between(self.previous_asmoffset, self.asmoffset);
-        self.previous_asmoffset = self.asmoffset;
+        // repack the buffer
+        let buffer = buffer.make_exec().expect("Could not swap buffer protection modes");
+        *lock = buffer;
+        result.map(|_| self.placeholders.len())
+    }
 
-        self.old_managed.append(&mut self.new_managed);
+    /// Use an `UncommittedModifier` to alter uncommitted code.
+    /// This does not allow the user to change labels/relocations.
+    pub fn alter_uncommitted(&mut self) -> UncommittedModifier {
+        let offset = self.memory.committed();
+        UncommittedModifier::new(&mut self.ops, AssemblyOffset(offset))
+    }
 
-        Ok(())
+    /// Use a `Modifier` to alter committed code directly. While this is happening
+    /// no code can be executed as the relevant pages are remapped as writable.
+    /// This API supports defining new labels/relocations, and overwriting previously defined relocations.
+    /// Code can also be appended by writing at the end of the committed code, which grows the executable buffer
+    /// like `commit` does when it is full. Code moved this way is only visible to `Executor`s once this returns.
+    pub fn alter<F, O>(&mut self, f: F) -> Result<O, DynasmError>
+    where F: FnOnce(&mut Modifier<R>) -> O {
+        self.commit()?;
+        let growable = !self.memory.is_fixed();
+        let max_size = self.memory.capacity();
+        let anchor = self.memory.anchor();
+
+        let result = {
+            // swap out a buffer from base
+            let mut lock = self.memory.write();
+            let buffer = mem::replace(&mut *lock, ExecutableBuffer::default());
+            let mut buffer = buffer.make_mut().expect("Could not swap buffer protection modes");
+
+            // construct the modifier
+            let mut modifier = Modifier {
+                asmoffset: 0,
+                previous_asmoffset: 0,
+                buffer: &mut buffer,
+
+                labels: &mut self.labels,
+                relocs: &mut self.relocs,
+                old_managed: &mut self.managed,
+                new_managed: ManagedRelocs::new(),
+                applied: &mut self.applied,
+                boundaries: &self.boundaries,
+                placeholders: &self.placeholders,
+                data_refs: &self.data_refs,
+
+                growable,
+                max_size,
+                anchor,
+
+                error: None
+            };
+
+            // execute the user code
+            let output = f(&mut modifier);
+
+            // flush any changes made by the user code to the buffer
+            let result = modifier.encode_relocs();
+
+            // repack the buffer, also when the changes failed so the code isn't lost
+            let buffer = buffer.make_exec().expect("Could not swap buffer protection modes");
+            *lock = buffer;
+            result.map(|_| output)
+        };
+
+        // the buffer may have grown and moved
+        self.memory.buffer_changed();
+        self.update_unwind()?;
+
+        // call it a day
+        result
     }
-}
 
-impl<'a, R: Relocation> Extend<u8> for Modifier<'a,R> {
-    fn extend<T>(&mut self, iter: T) where T: IntoIterator<Item=u8> {
-        for (src, dst) in iter.into_iter().zip(self.buffer[self.asmoffset ..].iter_mut()) {
-            *dst = src;
+    /// Overwrite committed code at offset `at` with `bytes`. This is a cheaper alternative to `alter` for
+    /// small patches like changing a jump target. While this is happening no code can be executed as the
+    /// relevant pages are remapped as writable. Any managed relocations in the overwritten range are discarded.
+    /// Returns an error if the range to overwrite is not within the committed code. If instruction boundaries are
+    /// tracked through `mark_insn_boundary`, `DynasmError::CheckFailed` is returned if the range doesn't both start
+    /// and end at an instruction boundary.
+    pub fn patch(&mut self, at: AssemblyOffset, bytes: &[u8]) -> Result<(), DynasmError> {
+        if let Some(end) = at.0.checked_add(bytes.len()) {
+            if end <= self.memory.committed() && !(self.is_insn_boundary(at) && self.is_insn_boundary(AssemblyOffset(end))) {
+                return Err(DynasmError::CheckFailed);
+            }
         }
+        let end = self.overwrite(at, bytes)?;
+        self.managed.remove_between(at.0, end);
+        forget_applied(&mut self.applied, at.0 .. end);
+        let dynamic_uses = &mut self.dynamic_uses;
+        self.placeholders.retain(|&(ref loc, target)| {
+            let start = loc.location.0 - loc.relocation.field_offset();
+            let keep = start < at.0 || start >= end;
+            if !keep {
+                dynamic_uses.remove(target);
+            }
+            keep
+        });
+        Ok(())
     }
-}
 
-impl<'a, 'b, R: Relocation> Extend<&'b u8> for Modifier<'a, R> {
-    fn extend<T>(&mut self, iter: T) where T: IntoIterator<Item=&'b u8> {
-        for (src, dst) in iter.into_iter().zip(self.buffer[self.asmoffset ..].iter_mut()) {
-            *dst = *src;
+    // Overwrite committed code at `at` with `bytes`, returning the end of the overwritten range.
+    fn overwrite(&mut self, at: AssemblyOffset, bytes: &[u8]) -> Result<usize, DynasmError> {
+        let end = at.0.checked_add(bytes.len()).ok_or(DynasmError::OutOfBounds(at))?;
+        if end > self.memory.committed() {
+            return Err(DynasmError::OutOfBounds(AssemblyOffset(end)));
         }
-    }
-}
 
-impl<'a, R: Relocation> DynasmApi for Modifier<'a, R> {
-    fn offset(&self) -> AssemblyOffset {
-        AssemblyOffset(self.asmoffset)
-    }
+        // swap out a buffer from base
+        let mut lock = self.memory.write();
+        let buffer = mem::take(&mut *lock);
+        let mut buffer = buffer.make_mut().expect("Could not swap buffer protection modes");
 
-    fn push(&mut self, value: u8) {
-        self.buffer[self.asmoffset] = value;
-        self.asmoffset += 1
+        buffer[at.0 .. end].copy_from_slice(bytes);
+
+        // repack the buffer
+        let buffer = buffer.make_exec().expect("Could not swap buffer protection modes");
+        *lock = buffer;
+        Ok(end)
     }
 
-    fn align(&mut self, alignment: usize, with: u8) {
-        let mismatch = self.asmoffset % alignment;
-        if mismatch != 0 {
-            for _ in mismatch .. alignment {
-                self.push(with)
-            }
+    // Overwrite the committed instruction at `at` with the trap instruction `trap`, saving the bytes it replaces.
+    pub(crate) fn set_breakpoint_with(&mut self, at: AssemblyOffset, trap: &[u8]) -> Result<(), DynasmError> {
+        if self.breakpoints.contains_key(&at.0) {
+            return Ok(());
         }
+        if !self.is_insn_boundary(at) {
+            return Err(DynasmError::CheckFailed);
+        }
+        let original = match self.memory.read().get(at.0 .. at.0.saturating_add(trap.len())) {
+            Some(original) => original.to_vec(),
+            None => return Err(DynasmError::OutOfBounds(at)),
+        };
+        self.overwrite(at, trap)?;
+        self.breakpoints.insert(at.0, original);
+        Ok(())
     }
-}
 
-impl<'a, R: Relocation> DynasmLabelApi for Modifier<'a, R> {
-    type Relocation = R;
+    /// Remove a breakpoint installed with `set_breakpoint` at the committed offset `at`, putting back the code it
+    /// replaced. Returns false if no breakpoint was installed there.
+    pub fn clear_breakpoint(&mut self, at: AssemblyOffset) -> Result<bool, DynasmError> {
+        let original = match self.breakpoints.remove(&at.0) {
+            Some(original) => original,
+            None => return Ok(false),
+        };
+        self.overwrite(at, &original)?;
+        Ok(true)
+    }
 
-    fn local_label(&mut self, name: &'static str) {
-        let offset = self.offset();
-        for loc in self.relocs.take_locals_named(name) {
-            if let Err(_) = loc.patch(0, self.buffer.as_ptr() as usize, self.buffer, offset.0)  {
-                self.error = Some(DynasmError::ImpossibleRelocation(TargetKind::Forward(name)));
-            } else if loc.needs_adjustment() {
-                self.new_managed.add(loc);
-            }
+    /// Copy all committed code, so it can later be put back using `restore`.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            code: self.reader().lock().to_vec(),
+            addr: self.memory.execbuffer_addr(),
         }
-        self.labels.define_local(name, offset);
     }
-    fn global_label( &mut self, name: &'static str) {
-        let offset = self.offset();
-        if let Err(e) = self.labels.define_global(name, offset) {
-            self.error = Some(e);
+
+    /// Retire the committed code in `range`, by remapping it as readable but not executable. Any later call into
+    /// the retired code faults, instead of running code that should no longer be used. The code stays retired when
+    /// the buffer is remapped or moved, and can still be read and altered. As protection can only be changed for whole
+    /// pages, both ends of `range` have to be multiples of the page size, otherwise `DynasmError::CheckFailed` is
+    /// returned. Returns `DynasmError::OutOfBounds` if `range` extends past the committed code.
+    pub fn retire(&mut self, range: ops::Range<AssemblyOffset>) -> Result<(), DynasmError> {
+        if range.end.0 > self.memory.committed() {
+            return Err(DynasmError::OutOfBounds(range.end));
         }
-    }
-    fn dynamic_label(&mut self, id: DynamicLabel) {
-        let offset = self.offset();
-        if let Err(e) = self.labels.define_dynamic(id, offset) {
-            self.error = Some(e);
+        let page_size = mmap::page_size();
+        let misalign = (range.start.0 | range.end.0) % page_size;
+        if misalign != 0 || range.start > range.end {
+            return Err(DynasmError::CheckFailed);
+        }
+        if range.start == range.end {
+            return Ok(());
         }
+
+        self.memory.write().retire(range.start.0 .. range.end.0).expect("Could not change buffer protection");
+        Ok(())
     }
-    fn global_relocation(&mut self, name: &'static str, offset: isize, kind: R) {
-        let location = self.offset();
-        self.relocs.add_global(name, PatchLoc::new(location, offset, kind));
+
+    /// Overwrite all committed code with a snapshot taken by `snapshot`. While this is happening no code
+    /// can be executed as the relevant pages are remapped as writable. Returns `DynasmError::CheckFailed`
+    /// if the length of the snapshot doesn't match the length of the committed code, i.e. when code has
+    /// been committed since the snapshot was taken. If the buffer has moved since then, for instance through `grow_to`,
+    /// the restored code is adjusted to its new address. Managed relocations that were discarded by `patch` or `alter`
+    /// after taking the snapshot are not restored.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), DynasmError> {
+        if snapshot.len() != self.memory.committed() {
+            return Err(DynasmError::CheckFailed);
+        }
+
+        // swap out a buffer from base
+        let mut lock = self.memory.write();
+        let buffer = mem::take(&mut *lock);
+        let mut buffer = buffer.make_mut().expect("Could not swap buffer protection modes");
+
+        // the buffer can have moved without committing more code, in which case everything that depends
+        // on its address has to be fixed up, like when it moved during a commit
+        buffer.copy_from_slice(&snapshot.code);
+        let new_addr = self.memory.execbuffer_addr();
+        let result = if snapshot.addr != new_addr {
+            fix_moved(&self.managed, &self.placeholders, &self.data_refs, &mut buffer, snapshot.addr, new_addr)
+        } else {
+            Ok(())
+        };
+
+        // repack the buffer
+        let buffer = buffer.make_exec().expect("Could not swap buffer protection modes");
+        *lock = buffer;
+        result
     }
-    fn dynamic_relocation(&mut self, id: DynamicLabel, offset: isize, kind: R) {
+
+    /// Commit code, flushing the temporary internal assembling buffer to the mapped executable memory.
+    /// This makes assembled code available for execution. If the executable buffer has to grow but
+    /// cannot, `DynasmError::BufferFull` is returned and the committed code is left untouched.
+    /// The uncommitted code is kept, so committing can be retried after memory has been freed.
+    /// If the unwind info of the code cannot be registered, `DynasmError::UnwindFailed` is returned
+    /// after the code has been committed. In debug builds, this panics if the fields of two references
+    /// to labels overlap, as that is a bug in the code generator that would silently corrupt the code.
+    /// If the assembler was configured with `AssemblerConfig::page_align_sections`, the code is padded to
+    /// the next page boundary first.
+    pub fn commit(&mut self) -> Result<(), DynasmError> {
+        if self.page_align_sections && !self.ops.is_empty() {
+            self.align(mmap::page_size(), 0);
+        }
+        let range = AssemblyOffset(self.memory.committed()) .. self.offset();
+        // the relocations that will be resolved by this commit, if it succeeds
+        let relocations = if self.hooks.0.is_empty() {
+            Vec::new()
+        } else {
+            self.pending_relocs()
+                .filter(|r| matches!(r.target, TargetKind::Global(_) | TargetKind::Dynamic(_)))
+                .collect()
+        };
+
+        if let Err(e) = self.encode_relocs() {
+            self.commit_failed = true;
+            return Err(e);
+        }
+        self.encode_data_relocs()?;
+
+        if let (Some(verifier), false) = (&mut self.verifier.0, self.ops.is_empty()) {
+            verifier(&self.ops).map_err(DynasmError::VerificationFailed)?;
+        }
+
+        let managed = &self.managed;
+        let placeholders = &self.placeholders;
+        let data_refs = &self.data_refs;
+        let error = &mut self.error;
+
+        self.memory.commit(&mut self.ops, |buffer, old_addr, new_addr| {
+            if let Err(e) = fix_moved(managed, placeholders, data_refs, buffer, old_addr, new_addr) {
+                *error = Some(e);
+            }
+        })?;
+
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.update_unwind()?;
+
+        if !self.hooks.0.is_empty() {
+            let info = CommitInfo { range, relocations };
+            for hook in &mut self.hooks.0 {
+                hook(&info);
+            }
+        }
+        Ok(())
+    }
+
+    /// Commit code like `commit`, after padding it with zeroes so that the next code emitted starts at an
+    /// address that is a multiple of `alignment`. As the executable buffer always starts on a page boundary,
+    /// this alignment is preserved when the buffer moves to grow. `alignment` must therefore be a power
+    /// of two no larger than the page size, otherwise `DynasmError::InvalidAlignment` is returned and nothing is committed.
+    pub fn commit_aligned(&mut self, alignment: usize) -> Result<(), DynasmError> {
+        if !alignment.is_power_of_two() || alignment > mmap::page_size() {
+            return Err(DynasmError::InvalidAlignment(alignment));
+        }
+        self.align(alignment, 0);
+        self.commit()
+    }
+
+    /// Returns the amount of bytes that can still be assembled before a commit has to grow the executable buffer.
+    /// This includes code that has been assembled but not committed yet.
+    pub fn remaining_capacity(&self) -> usize {
+        self.memory.size().saturating_sub(self.offset().0)
+    }
+
+    /// Eagerly grow the executable buffer to at least `size` bytes. This moves the committed code like a commit
+    /// that needs more space would, allowing that cost to be paid at a convenient moment instead.
+    /// Returns `DynasmError::BufferFull` if the buffer cannot grow, such as when it was created with `new_in`.
+    pub fn grow_to(&mut self, size: usize) -> Result<(), DynasmError> {
+        let managed = &self.managed;
+        let placeholders = &self.placeholders;
+        let data_refs = &self.data_refs;
+        let mut result = Ok(());
+
+        self.memory.grow_to(size, |buffer, old_addr, new_addr| {
+            result = fix_moved(managed, placeholders, data_refs, buffer, old_addr, new_addr);
+        })?;
+
+        result?;
+        self.update_unwind()
+    }
+
+    /// Zero the executable memory of this assembler before it is unmapped, so the generated code doesn't
+    /// linger in freed memory. This also applies to the memory left behind when the executable buffer
+    /// moves to grow, and to the buffer returned by `finalize`.
+    pub fn set_zero_on_drop(&mut self, enabled: bool) {
+        self.memory.set_zero_on_drop(enabled);
+    }
+
+    // (re)register unwind info if the buffer moved or new info was recorded
+    fn update_unwind(&mut self) -> Result<(), DynasmError> {
+        let buf_addr = self.memory.execbuffer_addr();
+        if self.unwind.needs_registration(buf_addr) {
+            let registration = self.unwind.register(buf_addr).map_err(|_| DynasmError::UnwindFailed)?;
+            self.memory.write().set_unwind(registration);
+        }
+        Ok(())
+    }
+
+    /// Access the builder for read-only data that is committed together with this assembler.
+    pub fn data(&mut self) -> &mut DataBuilder {
+        &mut self.data
+    }
+
+    /// Record a relocation spot for a reference to the data label `name`. It is resolved when the data is committed.
+    pub fn data_reloc(&mut self, name: &'static str, offset: isize, kind: R::Encoding) {
+        self.data_relocation(name, offset, R::from_encoding(kind))
+    }
+
+    /// Equivalent of `data_reloc` taking a non-encoded relocation.
+    pub fn data_relocation(&mut self, name: &'static str, offset: isize, kind: R) {
         let location = self.offset();
-        self.relocs.add_dynamic(id, PatchLoc::new(location, offset, kind));
+        self.data_relocs.push((PatchLoc::new(location, offset, kind), name));
     }
-    fn forward_relocation(&mut self, name: &'static str, offset: isize, kind: R) {
+
+    /// Reserve a 64-bit counter that the code can write to, for instance to count how often a piece of code runs.
+    /// It is placed in writable memory next to the code when it is committed, starting at zero, and can be read and reset
+    /// through `counter` afterwards. Code references it through `counter_reloc`, or `inc_counter` on x64.
+    pub fn reserve_counter(&mut self) -> Counter {
+        self.data.reserve_counter()
+    }
+
+    /// Record a relocation spot for a reference to `counter`. It is resolved when the counter is committed.
+    pub fn counter_reloc(&mut self, counter: Counter, offset: isize, kind: R::Encoding) {
+        self.counter_relocation(counter, offset, R::from_encoding(kind))
+    }
+
+    /// Equivalent of `counter_reloc` taking a non-encoded relocation.
+    pub fn counter_relocation(&mut self, counter: Counter, offset: isize, kind: R) {
         let location = self.offset();
-        self.relocs.add_local(name, PatchLoc::new(location, offset, kind));
+        self.counter_relocs.push((PatchLoc::new(location, offset, kind), counter));
     }
-    fn backward_relocation(&mut self, name: &'static str, offset: isize, kind: R) {
-        let target = match self.labels.resolve_local(name) {
-            Ok(target) => target.0,
-            Err(e) => {
-                self.error = Some(e);
-                return;
+
+    /// Returns `counter`, once it has been committed. Its value can be read, or reset with `swap`, while code
+    /// updating it is running. Code that doesn't update it atomically can lose updates made at the same time, though.
+    pub fn counter(&self, counter: Counter) -> Option<&AtomicU64> {
+        // the counter lives as long as the executable buffer, which can't be dropped while `self` is borrowed
+        self.data.counter_addr(counter).map(|addr| unsafe { &*(addr as *const AtomicU64) })
+    }
+
+    /// Write a description of the labels and pending relocations of this assembler to `w`, for debugging.
+    /// Every label is listed with its offset, or as undefined if it is referenced but hasn't been defined yet.
+    /// Every relocation that hasn't been resolved yet is listed with its offset, size and target.
+    pub fn dump_labels(&self, w: &mut impl io::Write) -> io::Result<()> {
+        writeln!(w, "labels:")?;
+        for (name, offset) in self.labels.globals() {
+            writeln!(w, "    {}: 0x{:x}", LabelKind::Global(name), offset.0)?;
+        }
+        for (name, offset) in self.labels.locals() {
+            writeln!(w, "    {}: 0x{:x}", LabelKind::Local(name), offset.0)?;
+        }
+        for (id, offset) in self.labels.dynamics() {
+            match offset {
+                Some(offset) => writeln!(w, "    {}: 0x{:x}", LabelKind::Dynamic(id), offset.0)?,
+                None => writeln!(w, "    {}: undefined", LabelKind::Dynamic(id))?,
             }
-        };
-        let location = self.offset();
-        let loc = PatchLoc::new(location, offset, kind);
-        if let Err(_) = loc.patch(0, self.buffer.as_ptr() as usize, self.buffer, target) {
-            self.error = Some(DynasmError::ImpossibleRelocation(TargetKind::Backward(name)));
-        } else if loc.needs_adjustment() {
-            self.new_managed.add(loc)
         }
+
+        // labels that are referenced, but don't have a (next) definition yet
+        let mut undefined = Vec::new();
+        for (_, target) in self.relocs.iter() {
+            let label = match target {
+                TargetKind::Global(name) if !self.labels.is_global_defined(name) => LabelKind::Global(name),
+                TargetKind::Forward(name) => LabelKind::Local(name),
+                _ => continue
+            };
+            if !undefined.contains(&label) {
+                undefined.push(label);
+            }
+        }
+        for label in undefined {
+            writeln!(w, "    {}: undefined", label)?;
+        }
+
+        writeln!(w, "relocations:")?;
+        for (loc, target) in self.relocs.iter() {
+            writeln!(w, "    0x{:x}: {} bytes, {}", loc.location.0, loc.relocation.size(), target)?;
+        }
+        Ok(())
     }
-    fn bare_relocation(&mut self, target: usize, kind: R) {
-        let location = self.offset();
-        let loc = PatchLoc::new(location, 0, kind);
-        if let Err(_) = loc.patch(0, self.buffer.as_ptr() as usize, self.buffer, target) {
-            self.error = Some(DynasmError::ImpossibleRelocation(TargetKind::Extern(target)));
-        } else if loc.needs_adjustment() {
-            self.new_managed.add(loc)
+
+    /// Insert the pre-assembled code `bytes` at the current offset, recording the relocations `relocs` in it
+    /// as if the code was assembled here. Relocations inside the blob that are relative to itself stay valid
+    /// without being listed. Returns `DynasmError::OutOfBounds` if a relocation lies outside of the blob,
+    /// or `DynasmError::CheckFailed` if it targets `TargetKind::Managed`. In these cases nothing is inserted.
+    pub fn splice(&mut self, bytes: &[u8], relocs: &[BlobReloc<R>]) -> Result<(), DynasmError> where R: Clone {
+        let start = self.offset();
+        for reloc in relocs {
+            if reloc.offset > bytes.len() || reloc.offset < reloc.relocation.field_offset() {
+                return Err(DynasmError::OutOfBounds(start + reloc.offset));
+            }
+            if reloc.target == TargetKind::Managed {
+                return Err(DynasmError::CheckFailed);
+            }
         }
+
+        // relocations are recorded at the current offset, so emit the blob up to each of them in turn
+        let mut order: Vec<&BlobReloc<R>> = relocs.iter().collect();
+        order.sort_by_key(|reloc| reloc.offset);
+
+        let mut emitted = 0;
+        for reloc in order {
+            self.extend(&bytes[emitted .. reloc.offset]);
+            emitted = reloc.offset;
+
+            let kind = reloc.relocation.clone();
+            match reloc.target {
+                TargetKind::Forward(name) => self.forward_relocation(name, reloc.addend, kind),
+                TargetKind::Backward(name) => self.backward_relocation(name, reloc.addend, kind),
+                TargetKind::Global(name) => self.global_relocation(name, reloc.addend, kind),
+                TargetKind::Dynamic(id) => self.dynamic_relocation(id, reloc.addend, kind),
+                TargetKind::Extern(target) => self.bare_relocation(target.wrapping_add(reloc.addend as usize), kind),
+                TargetKind::Managed => unreachable!(),
+            }
+        }
+        self.extend(&bytes[emitted ..]);
+        Ok(())
     }
-}
 
+    /// Returns the offsets of all defined global and dynamic labels with a symbol name for each, sorted by offset.
+    /// This can be used to annotate the code when it is inspected with an external disassembler.
+    /// Global labels and dynamic labels created by `named_dynamic_label` are named after themselves.
+    /// Other dynamic labels are named `dynamic_<id>`.
+    pub fn symbol_map(&self) -> Vec<(AssemblyOffset, String)> {
+        let mut symbols: Vec<_> = self.labels.globals()
+            .map(|(name, offset)| (offset, name.to_string()))
+            .collect();
+        let names: Vec<_> = self.labels.named_dynamics().collect();
+        symbols.extend(self.labels.dynamics().filter_map(|(id, offset)| {
+            let name = match names.iter().find(|&&(_, named)| named == id) {
+                Some((name, _)) => name.to_string(),
+                None => format!("dynamic_{}", id.get_id())
+            };
+            offset.map(|offset| (offset, name))
+        }));
+        symbols.sort_by_key(|&(offset, _)| offset);
+        symbols
+    }
 
-/// This struct is a wrapper around an `Assembler` normally created using the
-/// `Assembler.alter_uncommitted` method. It allows the user to edit parts
-/// of the assembling buffer that cannot be determined easily or efficiently
-/// in advance. Due to limitations of the label resolution algorithms, this
-/// assembler does not allow labels to be used.
-#[derive(Debug)]
-pub struct UncommittedModifier<'a> {
-    buffer: &'a mut Vec<u8>,
-    base_offset: usize,
-    offset: usize
-}
+    /// Returns the global labels in the committed code as symbols for external tools, sorted by offset. Labels defined
+    /// through `function` span the function, other labels span the code up to the next label or the end of the
+    /// committed code. Labels pointing at code that hasn't been committed yet are left out.
+    ///
+    /// As no object file is built for the code, these symbols aren't known to the dynamic linker, so `dlsym` can't find
+    /// them. `write_perf_map` exposes them to profilers instead.
+    pub fn exported_symbols(&self) -> Vec<ExportedSymbol> {
+        let committed = self.memory.committed();
+        let mut globals: Vec<_> = self.labels.globals().filter(|&(_, offset)| offset.0 < committed).collect();
+        globals.sort_by_key(|&(_, offset)| offset);
 
-impl<'a> UncommittedModifier<'a> {
-    /// create a new uncommittedmodifier
-    pub fn new(buffer: &mut Vec<u8>, base_offset: AssemblyOffset) -> UncommittedModifier {
-        UncommittedModifier {
-            buffer,
-            base_offset: base_offset.0,
-            offset: base_offset.0
+        let mut symbols = Vec::with_capacity(globals.len());
+        for (i, &(name, offset)) in globals.iter().enumerate() {
+            // a label can be redefined, so only a function that starts where the label now points is its function
+            let function = self.functions.iter().rev().find(|&&(function, ref range)| function == name && range.start == offset);
+            let end = match function {
+                Some((_, range)) => range.end.0,
+                None => globals[i + 1 ..].iter().map(|&(_, next)| next.0).find(|&next| next > offset.0).unwrap_or(committed),
+            };
+            symbols.push(ExportedSymbol { name, offset, size: end.saturating_sub(offset.0) });
         }
+        symbols
     }
 
-    /// Sets the current modification offset to the given value
-    pub fn goto(&mut self, offset: AssemblyOffset) {
-        self.offset = offset.0;
+    /// Write the symbols from `exported_symbols` to `w` in the format of the `/tmp/perf-<pid>.map` files that `perf`
+    /// and other profilers read to name JIT compiled code: a line with the address and size in hexadecimal and the
+    /// name of every symbol. As the addresses change when the buffer moves, the map should be written again after every
+    /// commit that grows it.
+    pub fn write_perf_map(&self, w: &mut impl io::Write) -> io::Result<()> {
+        let addr = self.memory.execbuffer_addr();
+        for symbol in self.exported_symbols() {
+            writeln!(w, "{:x} {:x} {}", addr + symbol.offset.0, symbol.size, symbol.name)?;
+        }
+        Ok(())
     }
 
-    /// Checks that the current modification offset is not larger than the specified offset.
-    pub fn check(&mut self, offset: AssemblyOffset) -> Result<(), DynasmError> {
-        if self.offset > offset.0 {
-            Err(DynasmError::CheckFailed)
-        } else {
-            Ok(())
+    /// Emit a function named `name` using `f`. The entry of the function is aligned to `alignment` by padding with
+    /// zeroes, after which the global label `name` is defined there. An alignment of 1 leaves the entry unaligned.
+    /// Returns the range of the emitted function, which is also recorded in the table returned by `functions`.
+    /// Like with `commit_aligned`, `alignment` has to be a power of two no larger than the page size, otherwise
+    /// `DynasmError::InvalidAlignment` is returned and nothing is emitted. If the label `name` cannot be defined,
+    /// its error is returned after the padding has been emitted.
+    pub fn function<F>(&mut self, name: &'static str, alignment: usize, f: F) -> Result<ops::Range<AssemblyOffset>, DynasmError>
+    where F: FnOnce(&mut Self) {
+        if !alignment.is_power_of_two() || alignment > mmap::page_size() {
+            return Err(DynasmError::InvalidAlignment(alignment));
         }
+        self.align(alignment, 0);
+        self.try_global_label(name)?;
+
+        let start = self.offset();
+        f(self);
+        let range = start .. self.offset();
+        self.functions.push((name, range.clone()));
+        Ok(range)
     }
 
-    /// Checks that the current modification offset is exactly the specified offset.
-    pub fn check_exact(&mut self, offset: AssemblyOffset) -> Result<(), DynasmError> {
-        if self.offset != offset.0 {
-            Err(DynasmError::CheckFailed)
-        } else {
-            Ok(())
+    /// Iterate through all references that have been resolved in the code, sorted by the offset of their field. Together
+    /// with the kind of each reference, this tells if a piece of code can be moved, and what has to be fixed up when
+    /// it is. References are only recorded if this was enabled with `AssemblerConfig::track_relocations`, otherwise
+    /// this is empty. References that were created through `alter`, and differences between labels, are not included.
+    /// When any part of the field of a reference is overwritten through `patch` or `alter`, it is removed.
+    pub fn relocations<'a>(&'a self) -> impl Iterator<Item=&'a AppliedReloc> + 'a {
+        self.applied.iter().flat_map(|applied| applied.values())
+    }
+
+    /// Returns the names and ranges of all functions emitted through `function`, in the order they were emitted.
+    pub fn functions(&self) -> &[(&'static str, ops::Range<AssemblyOffset>)] {
+        &self.functions
+    }
+
+    /// Append the raw bytes `bytes` to the code, returning the range of offsets they were placed at.
+    pub fn append_bytes(&mut self, bytes: &[u8]) -> ops::Range<AssemblyOffset> {
+        let start = self.offset();
+        self.ops.extend_from_slice(bytes);
+        start .. self.offset()
+    }
+
+    // commit pending data, and encode uncommitted references to it
+    fn encode_data_relocs(&mut self) -> Result<(), DynasmError> {
+        if let Some(map) = self.data.commit(self.memory.data_anchor())? {
+            self.memory.add_data(map);
+        }
+        if let Some(map) = self.data.commit_counters(self.memory.data_anchor())? {
+            self.memory.add_data(map);
+        }
+
+        let buf_offset = self.memory.committed();
+        let buf_addr = self.memory.execbuffer_addr();
+        for (loc, name) in self.data_relocs.drain(..) {
+            let target = self.data.addr(name).ok_or(DynasmError::UnknownLabel(LabelKind::Global(name)))?;
+            if patch_data(&loc, buf_offset, buf_addr, &mut self.ops, target).is_err() {
+                return Err(DynasmError::ImpossibleRelocation(TargetKind::Extern(target)));
+            }
+            record(&mut self.applied, &loc, TargetKind::Extern(target), None);
+            self.data_refs.push((loc, target));
+        }
+        for (loc, counter) in self.counter_relocs.drain(..) {
+            let target = self.data.counter_addr(counter).expect("Counters are committed before references to them");
+            if patch_data(&loc, buf_offset, buf_addr, &mut self.ops, target).is_err() {
+                return Err(DynasmError::ImpossibleRelocation(TargetKind::Extern(target)));
+            }
+            record(&mut self.applied, &loc, TargetKind::Extern(target), None);
+            self.data_refs.push((loc, target));
         }
+        Ok(())
     }
-}
 
-impl<'a> DynasmApi for UncommittedModifier<'a> {
-    fn offset(&self) -> AssemblyOffset {
-        AssemblyOffset(self.offset)
+    /// Register a hook that is called at the end of every successful commit, after the committed code has become executable.
+    /// Hooks are called in the order they were registered.
+    pub fn on_commit(&mut self, f: CommitHook) {
+        self.hooks.0.push(f);
     }
 
-    fn push(&mut self, value: u8) {
-        self.buffer[self.offset - self.base_offset] = value;
-        self.offset += 1;
+    /// Set a verifier that inspects the code of every commit after its relocations have been resolved, before it is
+    /// copied into executable memory. If it rejects the code, the commit fails with `DynasmError::VerificationFailed`,
+    /// and the code is kept uncommitted. Code changed through `alter` or `patch` is not verified.
+    pub fn set_verifier(&mut self, verifier: Verifier) {
+        self.verifier.0 = Some(verifier);
     }
 
-    fn align(&mut self, alignment: usize, with: u8) {
-        let mismatch = self.offset % alignment;
-        if mismatch != 0 {
-            for _ in mismatch .. alignment {
-                self.push(with)
+    /// Finalize this assembler, returning the internal executablebuffer if no Executor instances exist.
+    /// This panics if any uncommitted changes caused errors near the end. To handle these, call `commit()` explicitly beforehand.
+    pub fn finalize(mut self) -> Result<ExecutableBuffer, Self> {
+        self.commit().expect("Errors were encountered when committing before finalization");
+        let memory = mem::replace(&mut self.memory, MemoryManager::new_lazy());
+        match memory.finalize() {
+            Ok(execbuffer) => Ok(execbuffer),
+            Err(memory) => {
+                self.memory = memory;
+                Err(self)
             }
         }
     }
-}
 
-impl<'a> Extend<u8> for UncommittedModifier<'a> {
-    fn extend<T>(&mut self, iter: T) where T: IntoIterator<Item=u8> {
-        for i in iter {
-            self.push(i)
+    /// Like `finalize`, but also returns the relocations in the code that depend on the address of the buffer, such as
+    /// absolute references to labels. With `ManagedRelocs::rebase` they allow a copy of the code to be moved to
+    /// a different address, for instance when loading it from a cache. References to data from `data` are not
+    /// included, so code using them cannot be moved.
+    // the assembler is returned by value on failure to mirror `finalize`, so both can be handled the same way
+    #[allow(clippy::result_large_err)]
+    pub fn finalize_relocatable(mut self) -> Result<(ExecutableBuffer, ManagedRelocs<R>), Self> {
+        self.commit().expect("Errors were encountered when committing before finalization");
+        let memory = mem::replace(&mut self.memory, MemoryManager::new_lazy());
+        match memory.finalize() {
+            Ok(execbuffer) => Ok((execbuffer, mem::replace(&mut self.managed, ManagedRelocs::new()))),
+            Err(memory) => {
+                self.memory = memory;
+                Err(self)
+            }
         }
     }
-}
 
-impl<'a, 'b> Extend<&'b u8> for UncommittedModifier<'a> {
-    fn extend<T>(&mut self, iter: T) where T: IntoIterator<Item=&'b u8> {
-        self.extend(iter.into_iter().cloned())
+    /// Create an executor which can be used to execute code while still assembling code
+    pub fn reader(&self) -> Executor {
+        Executor {
+            execbuffer: self.memory.reader()
+        }
+    }
+
+    /// Returns the code that has been assembled since the last commit. It starts at the offset
+    /// of the end of the committed code.
+    pub fn uncommitted_bytes(&self) -> &[u8] {
+        &self.ops
+    }
+
+    /// Gain read-access to the committed code, like `Executor::lock`. Committing or altering code
+    /// blocks until the returned guard has been dropped.
+    pub fn committed_bytes(&self) -> RwLockReadGuard<'_, ExecutableBuffer> {
+        self.memory.read()
+    }
+
+    /// Resolve `offset` to the absolute address of the committed code at it. The offset is not checked
+    /// against the committed length. As with `Executor::ptr_at`, the pointer only stays valid until a
+    /// commit moves the buffer to grow it.
+    pub fn resolve_address(&self, offset: AssemblyOffset) -> *const u8 {
+        self.reader().lock().base().wrapping_add(offset.0)
+    }
+
+    /// Provides access to the assemblers internal labels registry
+    pub fn labels(&self) -> &LabelRegistry {
+        &self.labels
+    }
+
+    /// Provides mutable access to the assemblers internal labels registry
+    pub fn labels_mut(&mut self) -> &mut LabelRegistry {
+        &mut self.labels
+    }
+
+    /// Iterate through all relocations that still have to be resolved, i.e. references to global
+    /// and dynamic labels since the last commit, and forward references to local labels that haven't been defined yet.
+    /// Weak references that were pointed at the placeholder are not included.
+    pub fn pending_relocs<'a>(&'a self) -> impl Iterator<Item=RelocInfo> + 'a {
+        let weak = self.weak_relocs.iter().map(|&(ref loc, target)| (loc, target));
+        self.relocs.iter().chain(weak).map(|(loc, target)| RelocInfo { target, location: loc.location })
+    }
+
+    // encode uncommited relocations
+    fn encode_relocs(&mut self) -> Result<(), DynasmError> {
+        let buf_offset = self.memory.committed();
+        let buf_addr = self.memory.execbuffer_addr();
+        let buf = &mut self.ops;
+
+        // If we accrued any errors while assembling before, emit them now.
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+
+        // In debug builds, catch references that would overwrite each other
+        if cfg!(debug_assertions) {
+            let fields = &mut self.overlap_fields;
+            fields.clear();
+            fields.extend(self.relocs.iter().map(|(loc, target)| (loc.field(), target))
+                .chain(self.weak_relocs.iter().map(|(loc, target)| (loc.field(), *target)))
+                .chain(self.diff_relocs.iter().map(|&(location, size, a, _)| (location.0 - size.size() .. location.0, a))));
+            check_overlaps(fields);
+        }
+
+        // Resolve globals
+        for (loc, name) in self.relocs.take_globals() {
+            let target = self.labels.resolve_global(name)?;
+            if let Err(_) = loc.patch(buf_offset, buf_addr, buf, target.0) {
+                return Err(DynasmError::ImpossibleRelocation(TargetKind::Global(name)));
+            }
+            record(&mut self.applied, &loc, TargetKind::Global(name), Some(target));
+            if loc.needs_adjustment() {
+                self.managed.add(loc)
+            }
+        }
+
+        // Resolve dynamics
+        for (loc, id) in self.relocs.take_dynamics() {
+            let target = self.labels.resolve_dynamic(id)?;
+            if let Err(_) = loc.patch(buf_offset, buf_addr, buf, target.0) {
+                return Err(DynasmError::ImpossibleRelocation(TargetKind::Dynamic(id)));
+            }
+            record(&mut self.applied, &loc, TargetKind::Dynamic(id), Some(target));
+            if loc.needs_adjustment() {
+                self.managed.add(loc)
+            }
+        }
+
+        // Resolve weak references, pointing those to undefined labels at the placeholder
+        for &(_, target) in &self.weak_relocs {
+            self.dynamic_uses.remove(target);
+        }
+        for (loc, target) in self.weak_relocs.drain(..) {
+            let (offset, weak) = match (resolve_label(&self.labels, target), self.placeholder) {
+                (Ok(offset), _) => (offset, false),
+                (Err(_), Some(placeholder)) => (placeholder, true),
+                (Err(e), None) => return Err(e),
+            };
+            if loc.patch(buf_offset, buf_addr, buf, offset.0).is_err() {
+                return Err(DynasmError::ImpossibleRelocation(target));
+            }
+            record(&mut self.applied, &loc, target, Some(offset));
+            if weak {
+                self.dynamic_uses.add(target);
+                self.placeholders.push((loc, target));
+            } else if loc.needs_adjustment() {
+                self.managed.add(loc)
+            }
+        }
+
+        // Resolve differences between labels
+        for &(_, _, a, b) in &self.diff_relocs {
+            self.dynamic_uses.remove(a);
+            self.dynamic_uses.remove(b);
+        }
+        for (location, size, a, b) in self.diff_relocs.drain(..) {
+            let value = (resolve_label(&self.labels, a)?.0 as isize).wrapping_sub(resolve_label(&self.labels, b)?.0 as isize);
+            let end = location.0 - buf_offset;
+            let start = end.checked_sub(size.size()).ok_or(DynasmError::OutOfBounds(location))?;
+            if size.write_value(&mut buf[start .. end], value).is_err() {
+                return Err(DynasmError::ImpossibleRelocation(a));
+            }
+        }
+
+        // Check that there are no unknown local labels
+        for (_, name) in self.relocs.take_locals() {
+            return Err(DynasmError::UnknownLabel(LabelKind::Local(name)));
+        }
+
+        Ok(())
+    }
+}
+
+// Panic if the fields of any two references overlap, as resolving one of them would corrupt the other.
+#[cfg(feature = "std")]
+fn check_overlaps(fields: &mut [(ops::Range<usize>, TargetKind)]) {
+    fields.sort_unstable_by_key(|(field, _)| field.start);
+    for pair in fields.windows(2) {
+        let ((a, a_target), (b, b_target)) = (&pair[0], &pair[1]);
+        if b.start < a.end {
+            panic!("Overlapping relocations: the reference to {} at {:?} overlaps the reference to {} at {:?}", a_target, a, b_target, b);
+        }
+    }
+}
+
+// Record that the reference `loc` to `target` has been resolved.
+#[cfg(feature = "std")]
+fn record<R: Relocation>(applied: &mut Option<BTreeMap<usize, AppliedReloc>>, loc: &PatchLoc<R>, target: TargetKind, target_offset: Option<AssemblyOffset>) {
+    if let Some(applied) = applied {
+        let reloc = AppliedReloc::new(loc, target, target_offset);
+        applied.insert(reloc.field.start.0, reloc);
+    }
+}
+
+// Forget the recorded references whose field overlaps `range`, as that code was overwritten.
+#[cfg(feature = "std")]
+fn forget_applied(applied: &mut Option<BTreeMap<usize, AppliedReloc>>, range: ops::Range<usize>) {
+    let applied = match applied {
+        Some(applied) => applied,
+        None => return,
+    };
+    // no field is larger than the largest relocation, so fields overlapping the range start shortly before it
+    let first = range.start.saturating_sub(RelocationSize::OWord as usize);
+    let overwritten: Vec<usize> = applied.range(first .. range.end)
+        .filter(|(_, reloc)| reloc.field.end.0 > range.start)
+        .map(|(&start, _)| start)
+        .collect();
+    for start in overwritten {
+        applied.remove(&start);
+    }
+}
+
+// Resolve the target of a weak reference or label difference, which is always a global or dynamic label.
+#[cfg(feature = "std")]
+fn resolve_label(labels: &LabelRegistry, target: TargetKind) -> Result<AssemblyOffset, DynasmError> {
+    match target {
+        TargetKind::Global(name) => labels.resolve_global(name),
+        TargetKind::Dynamic(id) => labels.resolve_dynamic(id),
+        _ => unreachable!("reference to {} resolved at commit", target),
+    }
+}
+
+// Patch `loc` to reference the absolute address `target`, for a buffer located at `buf_addr`.
+#[cfg(feature = "std")]
+fn patch_data<R: Relocation>(loc: &PatchLoc<R>, buf_offset: usize, buf_addr: usize, buffer: &mut [u8], target: usize) -> Result<(), ImpossibleRelocation> {
+    // patches take targets relative to the buffer, except for those that encode a relative reference to an absolute address
+    let target = match loc.relocation.kind() {
+        RelocationKind::RelToAbs => target,
+        RelocationKind::Relative
+        | RelocationKind::AbsToRel => target.wrapping_sub(buf_addr),
+    };
+    loc.patch(buf_offset, buf_addr, buffer, target)
+}
+
+// Fix up all references in `buffer` that depend on its address after it moved from `old_addr` to `new_addr`.
+#[cfg(feature = "std")]
+fn fix_moved<R: Relocation>(managed: &ManagedRelocs<R>, placeholders: &[(PatchLoc<R>, TargetKind)], data_refs: &[(PatchLoc<R>, usize)], buffer: &mut [u8], old_addr: usize, new_addr: usize) -> Result<(), DynasmError> {
+    let mut result = managed.rebase(buffer, old_addr, new_addr)
+        .map_err(|_| DynasmError::ImpossibleRelocation(TargetKind::Managed));
+
+    // references to the placeholder aren't managed, as they are replaced once their label is defined
+    let change = new_addr.wrapping_sub(old_addr) as isize;
+    for (loc, _) in placeholders.iter().filter(|(loc, _)| loc.needs_adjustment()) {
+        if loc.adjust(0, buffer, change).is_err() {
+            result = Err(DynasmError::ImpossibleRelocation(TargetKind::Managed));
+        }
+    }
+
+    // the data doesn't move with the code, so all references to it have to be recalculated
+    for (loc, target) in data_refs {
+        if patch_data(loc, 0, new_addr, buffer, *target).is_err() {
+            result = Err(DynasmError::ImpossibleRelocation(TargetKind::Extern(*target)))
+        }
+    }
+    result
+}
+
+#[cfg(feature = "std")]
+impl<R: Relocation> Extend<u8> for Assembler<R> {
+    fn extend<T>(&mut self, iter: T) where T: IntoIterator<Item=u8> {
+        self.ops.extend(iter)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, R: Relocation> Extend<&'a u8> for Assembler<R> {
+    fn extend<T>(&mut self, iter: T) where T: IntoIterator<Item=&'a u8> {
+        self.ops.extend(iter)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<R: Relocation + Clone> Assembler<R> {
+    /// Set the relocation used by `global_reloc_default` and the other `*_reloc_default` methods, for code generators
+    /// that use the same kind of relocation almost everywhere.
+    pub fn set_default_reloc(&mut self, kind: R::Encoding) {
+        self.default_relocation = Some(R::from_encoding(kind));
+    }
+
+    // the default relocation. Panics if none was set.
+    fn default_relocation(&self) -> R {
+        self.default_relocation.clone().expect("No default relocation set through set_default_reloc")
+    }
+
+    /// Record a reference to the global label `name`, using the relocation set with `set_default_reloc`.
+    /// This panics if no default relocation was set.
+    pub fn global_reloc_default(&mut self, name: &'static str, offset: isize) {
+        let kind = self.default_relocation();
+        self.global_relocation(name, offset, kind);
+    }
+
+    /// Record a reference to the dynamic label `id`, using the relocation set with `set_default_reloc`.
+    /// This panics if no default relocation was set.
+    pub fn dynamic_reloc_default(&mut self, id: DynamicLabel, offset: isize) {
+        let kind = self.default_relocation();
+        self.dynamic_relocation(id, offset, kind);
+    }
+
+    /// Record a forward reference to the local label `name`, using the relocation set with `set_default_reloc`.
+    /// This panics if no default relocation was set.
+    pub fn forward_reloc_default(&mut self, name: &'static str, offset: isize) {
+        let kind = self.default_relocation();
+        self.forward_relocation(name, offset, kind);
+    }
+
+    /// Record a backward reference to the local label `name`, using the relocation set with `set_default_reloc`.
+    /// This panics if no default relocation was set.
+    pub fn backward_reloc_default(&mut self, name: &'static str, offset: isize) {
+        let kind = self.default_relocation();
+        self.backward_relocation(name, offset, kind);
+    }
+}
+
+/// In debug builds, dropping an assembler panics if its uncommitted code still references labels, as these
+/// references would have failed to resolve if it was committed. Call `reset` to discard such code deliberately.
+/// Nothing is checked after a commit failed, as its error already reported the problem, or while panicking.
+#[cfg(feature = "std")]
+impl<R: Relocation> Drop for Assembler<R> {
+    fn drop(&mut self) {
+        if !cfg!(debug_assertions) || self.commit_failed || std::thread::panicking() {
+            return;
+        }
+        if let Some(reloc) = self.pending_relocs().next() {
+            panic!("Assembler dropped with pending relocations: the reference to {} at {:?} was never resolved", reloc.target, reloc.location);
+        }
+    }
+}
+
+/// Writes append to the assembling buffer. Flushing commits the assembled code.
+#[cfg(feature = "std")]
+impl<R: Relocation> io::Write for Assembler<R> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.extend(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.commit().map_err(io::Error::other)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<R: Relocation> DynasmApi for Assembler<R> {
+    fn offset(&self) -> AssemblyOffset {
+        AssemblyOffset(self.memory.committed() + self.ops.len())
+    }
+
+    fn push(&mut self, value: u8) {
+        self.ops.push(value);
+    }
+
+    fn align(&mut self, alignment: usize, with: u8) {
+        let misalign = self.offset().0 % alignment;
+        if misalign != 0 {
+            for _ in misalign .. alignment {
+                self.push(with);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<R: Relocation> DynasmLabelApi for Assembler<R> {
+    type Relocation = R;
+
+    fn local_label(&mut self, name: &'static str) {
+        if let Err(e) = self.try_local_label(name) {
+            self.error = Some(e);
+        }
+    }
+    fn global_label( &mut self, name: &'static str) {
+        if let Err(e) = self.try_global_label(name) {
+            self.error = Some(e);
+        }
+    }
+    fn dynamic_label(&mut self, id: DynamicLabel) {
+        if let Err(e) = self.try_dynamic_label(id) {
+            self.error = Some(e);
+        }
+    }
+    fn global_relocation(&mut self, name: &'static str, offset: isize, kind: R) {
+        self.check_declared(name);
+        let location = self.offset();
+        self.relocs.add_global(name, PatchLoc::new(location, offset, kind));
+    }
+    fn dynamic_relocation(&mut self, id: DynamicLabel, offset: isize, kind: R) {
+        let location = self.offset();
+        self.relocs.add_dynamic(id, PatchLoc::new(location, offset, kind));
+    }
+    fn forward_relocation(&mut self, name: &'static str, offset: isize, kind: R) {
+        let location = self.offset();
+        self.relocs.add_local(name, PatchLoc::new(location, offset, kind));
+    }
+    fn backward_relocation(&mut self, name: &'static str, offset: isize, kind: R) {
+        if let Err(e) = self.try_backward_relocation(name, offset, kind) {
+            self.error = Some(e);
+        }
+    }
+    fn bare_relocation(&mut self, target: usize, kind: R) {
+        let location = self.offset();
+        let loc = PatchLoc::new(location, 0, kind);
+        if let Err(_) = loc.patch(self.memory.committed(), self.memory.execbuffer_addr(), &mut self.ops, target) {
+            self.error = Some(DynasmError::ImpossibleRelocation(TargetKind::Extern(target)));
+            return;
+        }
+        record(&mut self.applied, &loc, TargetKind::Extern(target), None);
+        if loc.needs_adjustment() {
+            self.managed.add(loc)
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<R: Relocation> CodeBuffer for Assembler<R> {
+    fn new_dynamic_label(&mut self) -> DynamicLabel {
+        Assembler::new_dynamic_label(self)
+    }
+
+    fn labels(&self) -> &LabelRegistry {
+        Assembler::labels(self)
+    }
+
+    fn commit(&mut self) -> Result<(), DynasmError> {
+        Assembler::commit(self)
+    }
+
+    fn read_code(&self, range: ops::Range<AssemblyOffset>) -> Option<Vec<u8>> {
+        if range.start > range.end || range.end > self.offset() {
+            return None;
+        }
+
+        // the code is split between the executable buffer and the code that hasn't been committed yet
+        let committed = self.memory.committed();
+        let buffer = self.memory.read();
+        let mut code = Vec::with_capacity(range.end.0 - range.start.0);
+        if range.start.0 < committed {
+            code.extend_from_slice(&buffer[range.start.0 .. cmp::min(range.end.0, committed)]);
+        }
+        if range.end.0 > committed {
+            code.extend_from_slice(&self.ops[range.start.0.saturating_sub(committed) .. range.end.0 - committed]);
+        }
+        Some(code)
+    }
+}
+
+
+/// An assembler that writes directly into a fixed, user-provided `&mut [u8]`, instead of managing
+/// its own executable memory. Supports labels and all types of relocations, resolved against the
+/// address of the provided slice. As the buffer is never moved, relocations never need adjustment afterwards.
+/// Emitting more data than fits in the slice is not a panic, instead `finalize` reports `DynasmError::BufferFull`.
+///
+/// As it never allocates, a `SliceAssembler` over an array on the stack can assemble small functions without any
+/// heap traffic. If the code only uses relative references, it can then be copied into executable memory, for
+/// instance by extending an `Assembler` with it.
+#[derive(Debug)]
+pub struct SliceAssembler<'a, R: Relocation> {
+    inner: LabelledBuffer<SliceSink<'a>, R>,
+}
+
+impl<'a, R: Relocation> SliceAssembler<'a, R> {
+    /// Create a new assembler, assembling into `buffer` starting at offset 0.
+    pub fn new(buffer: &'a mut [u8]) -> SliceAssembler<'a, R> {
+        SliceAssembler {
+            inner: LabelledBuffer::new(SliceSink { buffer, asmoffset: 0 }),
+        }
+    }
+
+    /// The amount of bytes that can still be emitted before the buffer is full.
+    pub fn remaining(&self) -> usize {
+        self.inner.sink.buffer.len().saturating_sub(self.inner.sink.asmoffset)
+    }
+
+    /// Finalize this assembler, resolving any outstanding relocations. Returns the part of the buffer
+    /// that was assembled into.
+    pub fn finalize(mut self) -> Result<&'a mut [u8], DynasmError> {
+        self.inner.encode_relocs()?;
+        let SliceSink { buffer, asmoffset } = self.inner.sink;
+        Ok(&mut buffer[.. asmoffset])
+    }
+}
+
+
+/// An assembler that appends to a `Vec<u8>` instead of managing its own executable memory, for when the assembled
+/// code ends up somewhere other than in executable memory of this process. Unlike `VecAssembler` it supports labels
+/// and all types of relocations. These are resolved as if the code is placed at the base address given on creation.
+/// `finalize` hands back the `Vec` once all relocations have been resolved.
+#[derive(Debug)]
+pub struct VecLabelAssembler<R: Relocation> {
+    inner: LabelledBuffer<VecSink, R>,
+}
+
+impl<R: Relocation> VecLabelAssembler<R> {
+    /// Create a new, empty assembler for code that will be placed at `baseaddr`.
+    pub fn new(baseaddr: usize) -> VecLabelAssembler<R> {
+        VecLabelAssembler::from_vec(Vec::new(), baseaddr)
+    }
+
+    /// Create an assembler that appends to `buffer`, for code that will be placed at `baseaddr`. Offsets start at
+    /// the start of `buffer`, so any bytes already in it are treated as code preceding the assembled code.
+    pub fn from_vec(buffer: Vec<u8>, baseaddr: usize) -> VecLabelAssembler<R> {
+        VecLabelAssembler {
+            inner: LabelledBuffer::new(VecSink { buffer, baseaddr }),
+        }
+    }
+
+    /// Finalize this assembler, resolving any outstanding relocations. Returns the buffer that was assembled into.
+    pub fn finalize(mut self) -> Result<Vec<u8>, DynasmError> {
+        self.inner.encode_relocs()?;
+        Ok(self.inner.sink.buffer)
+    }
+}
+
+
+// Where a `LabelledBuffer` puts the code emitted into it
+trait ByteSink {
+    // the amount of bytes emitted so far, including any that didn't fit
+    fn offset(&self) -> usize;
+
+    // append `value`, returning false if it didn't fit
+    fn push(&mut self, value: u8) -> bool;
+
+    fn extend<T: IntoIterator<Item=u8>>(&mut self, iter: T) -> bool {
+        let mut fits = true;
+        for byte in iter {
+            fits &= self.push(byte);
+        }
+        fits
+    }
+
+    // the emitted bytes that were kept
+    fn code(&self) -> &[u8];
+
+    // patch the reference `loc` to `target` in the emitted code
+    fn patch<R: Relocation>(&mut self, loc: &PatchLoc<R>, target: usize) -> Result<(), ImpossibleRelocation>;
+}
+
+// The code of a `SliceAssembler`. Once the slice is full, only the offset keeps growing.
+#[derive(Debug)]
+struct SliceSink<'a> {
+    buffer: &'a mut [u8],
+    asmoffset: usize,
+}
+
+impl<'a> ByteSink for SliceSink<'a> {
+    fn offset(&self) -> usize {
+        self.asmoffset
+    }
+
+    fn push(&mut self, value: u8) -> bool {
+        let dst = self.buffer.get_mut(self.asmoffset);
+        self.asmoffset += 1;
+        match dst {
+            Some(dst) => {
+                *dst = value;
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn code(&self) -> &[u8] {
+        &self.buffer[.. self.asmoffset.min(self.buffer.len())]
+    }
+
+    // Write errors are ignored if the buffer was already overrun.
+    fn patch<R: Relocation>(&mut self, loc: &PatchLoc<R>, target: usize) -> Result<(), ImpossibleRelocation> {
+        if loc.location.0 > self.buffer.len() {
+            return Ok(());
+        }
+        loc.patch(0, self.buffer.as_ptr() as usize, self.buffer, target)
+    }
+}
+
+// The code of a `VecLabelAssembler`, and the address it will be placed at
+#[derive(Debug)]
+struct VecSink {
+    buffer: Vec<u8>,
+    baseaddr: usize,
+}
+
+impl ByteSink for VecSink {
+    fn offset(&self) -> usize {
+        self.buffer.len()
+    }
+
+    fn push(&mut self, value: u8) -> bool {
+        self.buffer.push(value);
+        true
+    }
+
+    fn extend<T: IntoIterator<Item=u8>>(&mut self, iter: T) -> bool {
+        self.buffer.extend(iter);
+        true
+    }
+
+    fn code(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    fn patch<R: Relocation>(&mut self, loc: &PatchLoc<R>, target: usize) -> Result<(), ImpossibleRelocation> {
+        loc.patch(0, self.baseaddr, &mut self.buffer, target)
+    }
+}
+
+// The label and relocation handling of `SliceAssembler` and `VecLabelAssembler`, which only differ in where their
+// code goes. Relocations are resolved as soon as their target is known, others wait for `encode_relocs`.
+#[derive(Debug)]
+struct LabelledBuffer<S: ByteSink, R: Relocation> {
+    sink: S,
+    labels: LabelRegistry,
+    relocs: RelocRegistry<R>,
+    error: Option<DynasmError>,
+}
+
+impl<S: ByteSink, R: Relocation> LabelledBuffer<S, R> {
+    fn new(sink: S) -> LabelledBuffer<S, R> {
+        LabelledBuffer {
+            sink,
+            labels: LabelRegistry::new(),
+            relocs: RelocRegistry::new(),
+            error: None,
+        }
+    }
+
+    fn offset(&self) -> AssemblyOffset {
+        AssemblyOffset(self.sink.offset())
+    }
+
+    fn push(&mut self, value: u8) {
+        if !self.sink.push(value) {
+            self.error = Some(DynasmError::BufferFull);
+        }
+    }
+
+    fn extend<T: IntoIterator<Item=u8>>(&mut self, iter: T) {
+        if !self.sink.extend(iter) {
+            self.error = Some(DynasmError::BufferFull);
+        }
+    }
+
+    fn align(&mut self, alignment: usize, with: u8) {
+        let misalign = self.sink.offset() % alignment;
+        if misalign != 0 {
+            for _ in misalign .. alignment {
+                self.push(with);
+            }
+        }
+    }
+
+    fn free_dynamic_label(&mut self, id: DynamicLabel) -> Result<(), DynasmError> {
+        if self.relocs.has_dynamic(id) {
+            return Err(DynasmError::LabelInUse(LabelKind::Dynamic(id)));
+        }
+        self.labels.free_dynamic_label(id)
+    }
+
+    fn try_local_label(&mut self, name: &'static str) -> Result<(), DynasmError> {
+        let offset = self.offset();
+        let mut result = Ok(());
+        for loc in self.relocs.take_locals_named(name) {
+            if self.sink.patch(&loc, offset.0).is_err() {
+                result = Err(DynasmError::ImpossibleRelocation(TargetKind::Forward(name)));
+            }
+        }
+        self.labels.define_local(name, offset);
+        result
+    }
+
+    fn try_backward_relocation(&mut self, name: &'static str, offset: isize, kind: R) -> Result<(), DynasmError> {
+        let target = self.labels.resolve_local(name)?.0;
+        let loc = PatchLoc::new(self.offset(), offset, kind);
+        if self.sink.patch(&loc, target).is_err() {
+            return Err(DynasmError::ImpossibleRelocation(TargetKind::Backward(name)));
+        }
+        Ok(())
+    }
+
+    fn bare_relocation(&mut self, target: usize, kind: R) {
+        let loc = PatchLoc::new(self.offset(), 0, kind);
+        if self.sink.patch(&loc, target).is_err() {
+            self.error = Some(DynasmError::ImpossibleRelocation(TargetKind::Extern(target)));
+        }
+    }
+
+    // record a deferred error
+    fn defer(&mut self, result: Result<(), DynasmError>) {
+        if let Err(e) = result {
+            self.error = Some(e);
+        }
+    }
+
+    // encode uncommited relocations
+    fn encode_relocs(&mut self) -> Result<(), DynasmError> {
+        // If we accrued any errors while assembling before, emit them now.
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+
+        // Resolve globals
+        for (loc, name) in self.relocs.take_globals() {
+            let target = self.labels.resolve_global(name)?;
+            if self.sink.patch(&loc, target.0).is_err() {
+                return Err(DynasmError::ImpossibleRelocation(TargetKind::Global(name)));
+            }
+        }
+
+        // Resolve dynamics
+        for (loc, id) in self.relocs.take_dynamics() {
+            let target = self.labels.resolve_dynamic(id)?;
+            if self.sink.patch(&loc, target.0).is_err() {
+                return Err(DynasmError::ImpossibleRelocation(TargetKind::Dynamic(id)));
+            }
+        }
+
+        // Check for unknown locals
+        if let Some((_, name)) = self.relocs.take_locals().next() {
+            return Err(DynasmError::UnknownLabel(LabelKind::Local(name)));
+        }
+
+        Ok(())
+    }
+
+    fn read_code(&self, range: ops::Range<AssemblyOffset>) -> Option<Vec<u8>> {
+        // code emitted after a buffer was overrun is gone
+        if range.end.0 > self.sink.offset() {
+            return None;
+        }
+        self.sink.code().get(range.start.0 .. range.end.0).map(|code| code.to_vec())
+    }
+}
+
+// The API that `SliceAssembler` and `VecLabelAssembler` share, implemented on top of their `LabelledBuffer`
+macro_rules! labelled_buffer_api {
+    ([$($generics:tt)*] $ty:ty) => {
+        impl<$($generics)*> $ty {
+            /// Create a new dynamic label ID
+            pub fn new_dynamic_label(&mut self) -> DynamicLabel {
+                self.inner.labels.new_dynamic_label()
+            }
+
+            /// Returns the dynamic label ID for `name`, creating it the first time `name` is used.
+            /// This allows labels to be named at runtime, without requiring `&'static str` names.
+            pub fn named_dynamic_label(&mut self, name: &str) -> DynamicLabel {
+                self.inner.labels.named_dynamic_label(name)
+            }
+
+            /// Free the dynamic label `id`, so its id can be reused by `new_dynamic_label`.
+            /// This fails if relocations targeting the label are still pending, or if the label was already freed.
+            pub fn free_dynamic_label(&mut self, id: DynamicLabel) -> Result<(), DynasmError> {
+                self.inner.free_dynamic_label(id)
+            }
+
+            /// Provides access to the assemblers internal labels registry
+            pub fn labels(&self) -> &LabelRegistry {
+                &self.inner.labels
+            }
+
+            /// Provides mutable access to the assemblers internal labels registry
+            pub fn labels_mut(&mut self) -> &mut LabelRegistry {
+                &mut self.inner.labels
+            }
+
+            /// Iterate through all relocations that still have to be resolved, i.e. references to global
+            /// and dynamic labels, and forward references to local labels that haven't been defined yet.
+            pub fn pending_relocs<'b>(&'b self) -> impl Iterator<Item=RelocInfo> + 'b {
+                self.inner.relocs.iter().map(|(loc, target)| RelocInfo { target, location: loc.location })
+            }
+
+            /// Define the local label `name` at the current offset, resolving all forward references to it. Returns an error
+            /// if one of them cannot reach the label. Unlike `DynasmLabelApi::local_label`, the error is reported immediately
+            /// instead of being deferred, so it is reported at the label that is out of reach. The label is defined regardless.
+            pub fn try_local_label(&mut self, name: &'static str) -> Result<(), DynasmError> {
+                self.inner.try_local_label(name)
+            }
+
+            /// Define the global label `name` at the current offset, returning an error if it was already defined.
+            /// Unlike `DynasmLabelApi::global_label`, the error is reported immediately instead of being deferred.
+            pub fn try_global_label(&mut self, name: &'static str) -> Result<(), DynasmError> {
+                let offset = self.offset();
+                self.inner.labels.define_global(name, offset)
+            }
+
+            /// Define the dynamic label `id` at the current offset, returning an error if it was already defined.
+            /// Unlike `DynasmLabelApi::dynamic_label`, the error is reported immediately instead of being deferred.
+            pub fn try_dynamic_label(&mut self, id: DynamicLabel) -> Result<(), DynasmError> {
+                let offset = self.offset();
+                self.inner.labels.define_dynamic(id, offset)
+            }
+
+            /// Set what happens when a global label is defined that was already defined before.
+            pub fn set_duplicate_label_policy(&mut self, policy: DuplicateLabelPolicy) {
+                self.inner.labels.set_duplicate_label_policy(policy);
+            }
+
+            /// Record a backward reference to the local label `name`, returning an error if the label is unknown
+            /// or out of range. Unlike `DynasmLabelApi::backward_reloc`, the error is reported immediately instead of being deferred.
+            pub fn try_backward_reloc(&mut self, name: &'static str, offset: isize, kind: R::Encoding) -> Result<(), DynasmError> {
+                self.try_backward_relocation(name, offset, R::from_encoding(kind))
+            }
+
+            /// Equivalent of `try_backward_reloc` taking a non-encoded relocation.
+            pub fn try_backward_relocation(&mut self, name: &'static str, offset: isize, kind: R) -> Result<(), DynasmError> {
+                self.inner.try_backward_relocation(name, offset, kind)
+            }
+        }
+
+        impl<$($generics)*> CodeBuffer for $ty {
+            fn new_dynamic_label(&mut self) -> DynamicLabel {
+                self.inner.labels.new_dynamic_label()
+            }
+
+            fn labels(&self) -> &LabelRegistry {
+                &self.inner.labels
+            }
+
+            fn commit(&mut self) -> Result<(), DynasmError> {
+                self.inner.encode_relocs()
+            }
+
+            fn read_code(&self, range: ops::Range<AssemblyOffset>) -> Option<Vec<u8>> {
+                self.inner.read_code(range)
+            }
+        }
+
+        impl<$($generics)*> Extend<u8> for $ty {
+            fn extend<T>(&mut self, iter: T) where T: IntoIterator<Item=u8> {
+                self.inner.extend(iter)
+            }
+        }
+
+        impl<'c, $($generics)*> Extend<&'c u8> for $ty {
+            fn extend<T>(&mut self, iter: T) where T: IntoIterator<Item=&'c u8> {
+                self.inner.extend(iter.into_iter().cloned())
+            }
+        }
+
+        impl<$($generics)*> DynasmApi for $ty {
+            fn offset(&self) -> AssemblyOffset {
+                self.inner.offset()
+            }
+
+            fn push(&mut self, value: u8) {
+                self.inner.push(value)
+            }
+
+            fn align(&mut self, alignment: usize, with: u8) {
+                self.inner.align(alignment, with)
+            }
+        }
+
+        impl<$($generics)*> DynasmLabelApi for $ty {
+            type Relocation = R;
+
+            fn local_label(&mut self, name: &'static str) {
+                let result = self.inner.try_local_label(name);
+                self.inner.defer(result);
+            }
+            fn global_label( &mut self, name: &'static str) {
+                let result = self.try_global_label(name);
+                self.inner.defer(result);
+            }
+            fn dynamic_label(&mut self, id: DynamicLabel) {
+                let result = self.try_dynamic_label(id);
+                self.inner.defer(result);
+            }
+            fn global_relocation(&mut self, name: &'static str, offset: isize, kind: R) {
+                let location = self.offset();
+                self.inner.relocs.add_global(name, PatchLoc::new(location, offset, kind));
+            }
+            fn dynamic_relocation(&mut self, id: DynamicLabel, offset: isize, kind: R) {
+                let location = self.offset();
+                self.inner.relocs.add_dynamic(id, PatchLoc::new(location, offset, kind));
+            }
+            fn forward_relocation(&mut self, name: &'static str, offset: isize, kind: R) {
+                let location = self.offset();
+                self.inner.relocs.add_local(name, PatchLoc::new(location, offset, kind));
+            }
+            fn backward_relocation(&mut self, name: &'static str, offset: isize, kind: R) {
+                let result = self.inner.try_backward_relocation(name, offset, kind);
+                self.inner.defer(result);
+            }
+            fn bare_relocation(&mut self, target: usize, kind: R) {
+                self.inner.bare_relocation(target, kind)
+            }
+        }
+    };
+}
+
+labelled_buffer_api!(['a, R: Relocation] SliceAssembler<'a, R>);
+labelled_buffer_api!([R: Relocation] VecLabelAssembler<R>);
+
+
+/// An assembler that doesn't emit anything, but only keeps track of the offset and of where labels and
+/// relocations would be placed. Running the same code that emits into another assembler against it tells
+/// how large the result will be and where its labels are, before any memory is allocated for it.
+/// As the labels are tracked by a `LabelRegistry` like the other assemblers do, dynamic labels get the same ids
+/// when they are created in the same order.
+#[derive(Debug)]
+pub struct SizingAssembler<R: Relocation> {
+    asmoffset: usize,
+    labels: LabelRegistry,
+    relocs: RelocRegistry<R>,
+    error: Option<DynasmError>,
+}
+
+impl<R: Relocation> SizingAssembler<R> {
+    /// Create a new sizing assembler, starting at offset 0.
+    pub fn new() -> SizingAssembler<R> {
+        SizingAssembler {
+            asmoffset: 0,
+            labels: LabelRegistry::new(),
+            relocs: RelocRegistry::new(),
+            error: None,
+        }
+    }
+
+    /// Create a new dynamic label ID
+    pub fn new_dynamic_label(&mut self) -> DynamicLabel {
+        self.labels.new_dynamic_label()
+    }
+
+    /// Returns the dynamic label ID for `name`, creating it the first time `name` is used.
+    pub fn named_dynamic_label(&mut self, name: &str) -> DynamicLabel {
+        self.labels.named_dynamic_label(name)
+    }
+
+    /// Provides access to the assemblers internal labels registry
+    pub fn labels(&self) -> &LabelRegistry {
+        &self.labels
+    }
+
+    /// Provides mutable access to the assemblers internal labels registry
+    pub fn labels_mut(&mut self) -> &mut LabelRegistry {
+        &mut self.labels
+    }
+
+    /// Check that all relocations target labels that have been defined, and return the amount of bytes that
+    /// would have been emitted. As nothing is encoded, this doesn't check if relocations can reach their targets.
+    pub fn finalize(mut self) -> Result<usize, DynasmError> {
+        self.check_relocs()?;
+        Ok(self.asmoffset)
+    }
+
+    // take out the pending relocations, checking that their targets are defined
+    fn check_relocs(&mut self) -> Result<(), DynasmError> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+
+        for (_, name) in self.relocs.take_globals() {
+            self.labels.resolve_global(name)?;
+        }
+        for (_, id) in self.relocs.take_dynamics() {
+            self.labels.resolve_dynamic(id)?;
+        }
+        if let Some((_, name)) = self.relocs.take_locals().next() {
+            return Err(DynasmError::UnknownLabel(LabelKind::Local(name)));
+        }
+
+        Ok(())
+    }
+}
+
+impl<R: Relocation> CodeBuffer for SizingAssembler<R> {
+    fn new_dynamic_label(&mut self) -> DynamicLabel {
+        SizingAssembler::new_dynamic_label(self)
+    }
+
+    fn labels(&self) -> &LabelRegistry {
+        SizingAssembler::labels(self)
+    }
+
+    fn commit(&mut self) -> Result<(), DynasmError> {
+        self.check_relocs()
+    }
+
+    fn read_code(&self, _range: ops::Range<AssemblyOffset>) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+impl<R: Relocation> Default for SizingAssembler<R> {
+    fn default() -> SizingAssembler<R> {
+        SizingAssembler::new()
+    }
+}
+
+impl<R: Relocation> Extend<u8> for SizingAssembler<R> {
+    fn extend<T>(&mut self, iter: T) where T: IntoIterator<Item=u8> {
+        self.asmoffset += iter.into_iter().count();
+    }
+}
+
+impl<'a, R: Relocation> Extend<&'a u8> for SizingAssembler<R> {
+    fn extend<T>(&mut self, iter: T) where T: IntoIterator<Item=&'a u8> {
+        self.asmoffset += iter.into_iter().count();
+    }
+}
+
+impl<R: Relocation> DynasmApi for SizingAssembler<R> {
+    fn offset(&self) -> AssemblyOffset {
+        AssemblyOffset(self.asmoffset)
+    }
+
+    fn push(&mut self, _value: u8) {
+        self.asmoffset += 1;
+    }
+
+    fn align(&mut self, alignment: usize, _with: u8) {
+        let misalign = self.asmoffset % alignment;
+        if misalign != 0 {
+            self.asmoffset += alignment - misalign;
+        }
+    }
+}
+
+impl<R: Relocation> DynasmLabelApi for SizingAssembler<R> {
+    type Relocation = R;
+
+    fn local_label(&mut self, name: &'static str) {
+        let offset = self.offset();
+        self.relocs.take_locals_named(name).for_each(drop);
+        self.labels.define_local(name, offset);
+    }
+    fn global_label( &mut self, name: &'static str) {
+        let offset = self.offset();
+        if let Err(e) = self.labels.define_global(name, offset) {
+            self.error = Some(e);
+        }
+    }
+    fn dynamic_label(&mut self, id: DynamicLabel) {
+        let offset = self.offset();
+        if let Err(e) = self.labels.define_dynamic(id, offset) {
+            self.error = Some(e);
+        }
+    }
+    fn global_relocation(&mut self, name: &'static str, offset: isize, kind: R) {
+        let location = self.offset();
+        self.relocs.add_global(name, PatchLoc::new(location, offset, kind));
+    }
+    fn dynamic_relocation(&mut self, id: DynamicLabel, offset: isize, kind: R) {
+        let location = self.offset();
+        self.relocs.add_dynamic(id, PatchLoc::new(location, offset, kind));
+    }
+    fn forward_relocation(&mut self, name: &'static str, offset: isize, kind: R) {
+        let location = self.offset();
+        self.relocs.add_local(name, PatchLoc::new(location, offset, kind));
+    }
+    fn backward_relocation(&mut self, name: &'static str, _offset: isize, _kind: R) {
+        if let Err(e) = self.labels.resolve_local(name) {
+            self.error = Some(e);
+        }
+    }
+    fn bare_relocation(&mut self, _target: usize, _kind: R) { }
+}
+
+
+/// Allows modification of already committed assembly code. Contains an internal cursor
+/// into the emitted assembly, initialized to the start, that can be moved around either with the
+/// `goto` function, or just by assembling new code into this `Modifier`.
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub struct Modifier<'a, R: Relocation> {
+    asmoffset: usize,
+    previous_asmoffset: usize,
+    buffer: &'a mut mmap::MutableBuffer,
+
+    labels: &'a mut LabelRegistry,
+    relocs: &'a mut RelocRegistry<R>,
+    old_managed: &'a mut ManagedRelocs<R>,
+    new_managed: ManagedRelocs<R>,
+    applied: &'a mut Option<BTreeMap<usize, AppliedReloc>>,
+    boundaries: &'a BTreeSet<usize>,
+    placeholders: &'a [(PatchLoc<R>, TargetKind)],
+    data_refs: &'a [(PatchLoc<R>, usize)],
+
+    // if the buffer may move to grow, the size it may not grow beyond, and the address it has to stay close to
+    growable: bool,
+    max_size: Option<usize>,
+    anchor: Option<usize>,
+
+    error: Option<DynasmError>
+}
+
+#[cfg(feature = "std")]
+impl<'a, R: Relocation> Modifier<'a, R> {
+    /// Move the modifier cursor to the selected location.
+    pub fn goto(&mut self, offset: AssemblyOffset) {
+        self.forget_overwritten();
+        self.asmoffset = offset.0;
+        self.previous_asmoffset = offset.0;
+    }
+
+    // forget the references in the code that was overwritten since the cursor was last moved
+    fn forget_overwritten(&mut self) {
+        self.old_managed.remove_between(self.previous_asmoffset, self.asmoffset);
+        forget_applied(self.applied, self.previous_asmoffset .. self.asmoffset);
+        self.previous_asmoffset = self.asmoffset;
+    }
+
+    /// Write `value` at the modifier cursor and advance it. Writing right at the end of the code appends to it,
+    /// growing the executable buffer like a commit would if it is full. Returns `DynasmError::OutOfBounds` if the cursor
+    /// is past the end of the code, or if the buffer is full and cannot grow, in which case nothing is written.
+    /// Unlike `push`, which defers this error until the end of the alteration, the error is reported immediately.
+    pub fn try_push(&mut self, value: u8) -> Result<(), DynasmError> {
+        if self.asmoffset == self.buffer.len() {
+            self.grow(self.asmoffset + 1).map_err(|_| DynasmError::OutOfBounds(AssemblyOffset(self.asmoffset)))?;
+        }
+        let dst = self.buffer.get_mut(self.asmoffset).ok_or(DynasmError::OutOfBounds(AssemblyOffset(self.asmoffset)))?;
+        *dst = value;
+        self.asmoffset += 1;
+        Ok(())
+    }
+
+    // extend the code to `len` bytes, moving it into a larger buffer if it doesn't fit
+    fn grow(&mut self, len: usize) -> Result<(), DynasmError> {
+        if len > self.buffer.size() {
+            if !self.growable || self.max_size.is_some_and(|max_size| len > max_size) {
+                return Err(DynasmError::BufferFull);
+            }
+
+            // the same growth strategy as `MemoryManager::commit`
+            let mut new_size = cmp::max(self.buffer.size(), mmap::page_size());
+            while new_size <= len {
+                new_size = new_size.checked_mul(2).ok_or(DynasmError::BufferFull)?;
+            }
+            if let Some(max_size) = self.max_size {
+                new_size = cmp::min(new_size, max_size);
+            }
+
+            // the managed relocations in the code overwritten so far are gone, so they shouldn't be moved along
+            self.forget_overwritten();
+
+            let old_addr = self.buffer.as_ptr() as usize;
+            self.buffer.grow(new_size, self.anchor).map_err(|_| DynasmError::BufferFull)?;
+            let new_addr = self.buffer.as_ptr() as usize;
+            fix_moved(self.old_managed, self.placeholders, self.data_refs, self.buffer, old_addr, new_addr)?;
+            self.new_managed.rebase(self.buffer, old_addr, new_addr)
+                .map_err(|_| DynasmError::ImpossibleRelocation(TargetKind::Managed))?;
+        }
+        self.buffer.set_len(len);
+        Ok(())
+    }
+
+    /// Move the modifier cursor to the location of the dynamic label `id`. This fails if the label hasn't been defined.
+    pub fn goto_label(&mut self, id: DynamicLabel) -> Result<(), DynasmError> {
+        let offset = self.labels.resolve_dynamic(id)?;
+        self.goto(offset);
+        Ok(())
+    }
+
+    /// Move the modifier cursor to the location of the global label `name`. This fails if the label hasn't been defined.
+    pub fn goto_global(&mut self, name: &'static str) -> Result<(), DynasmError> {
+        let offset = self.labels.resolve_global(name)?;
+        self.goto(offset);
+        Ok(())
+    }
+
+    /// Check that the modifier cursor has not moved past the specified location.
+    pub fn check(&self, offset: AssemblyOffset) -> Result<(), DynasmError> {
+        Ok(self.try_check(offset)?)
+    }
+
+    /// Check that the modifier cursor is exactly at the specified location.
+    pub fn check_exact(&self, offset: AssemblyOffset) -> Result<(), DynasmError> {
+        Ok(self.try_check_exact(offset)?)
+    }
+
+    /// Like `check`, but the error tells where the cursor was instead, for reporting layouts that didn't work out.
+    pub fn try_check(&self, offset: AssemblyOffset) -> Result<(), LayoutError> {
+        check_layout(self.asmoffset, offset, false)
+    }
+
+    /// Like `check_exact`, but the error tells where the cursor was instead.
+    pub fn try_check_exact(&self, offset: AssemblyOffset) -> Result<(), LayoutError> {
+        check_layout(self.asmoffset, offset, true)
+    }
+
+    /// Check that the modifier cursor is at a multiple of `alignment`. Returns `DynasmError::InvalidAlignment`
+    /// if `alignment` is zero.
+    pub fn check_aligned(&self, alignment: usize) -> Result<(), DynasmError> {
+        check_aligned(self.asmoffset, alignment)
+    }
+
+    /// Check that the modifier cursor is at an instruction boundary recorded through `Assembler::mark_insn_boundary`,
+    /// or at the end of the code. This always succeeds if no instruction boundaries have been recorded.
+    pub fn check_insn_boundary(&self) -> Result<(), DynasmError> {
+        if is_insn_boundary(self.boundaries, AssemblyOffset(self.asmoffset), AssemblyOffset(self.buffer.len())) {
+            Ok(())
+        } else {
+            Err(DynasmError::CheckFailed)
+        }
+    }
+
+    /// Define the local label `name` at the current offset, resolving all forward references to it. Returns an error
+    /// if one of them cannot reach the label. Unlike `DynasmLabelApi::local_label`, the error is reported immediately
+    /// instead of being deferred, so it is reported at the label that is out of reach. The label is defined regardless.
+    pub fn try_local_label(&mut self, name: &'static str) -> Result<(), DynasmError> {
+        let offset = self.offset();
+        let mut result = Ok(());
+        for loc in self.relocs.take_locals_named(name) {
+            if loc.patch(0, self.buffer.as_ptr() as usize, self.buffer, offset.0).is_err() {
+                result = Err(DynasmError::ImpossibleRelocation(TargetKind::Forward(name)));
+            } else if loc.needs_adjustment() {
+                self.new_managed.add(loc);
+            }
+        }
+        self.labels.define_local(name, offset);
+        result
+    }
+
+    /// Define the global label `name` at the current offset, returning an error if it was already defined.
+    /// Unlike `DynasmLabelApi::global_label`, the error is reported immediately instead of being deferred.
+    pub fn try_global_label(&mut self, name: &'static str) -> Result<(), DynasmError> {
+        let offset = self.offset();
+        self.labels.define_global(name, offset)
+    }
+
+    /// Define the dynamic label `id` at the current offset, returning an error if it was already defined.
+    /// Unlike `DynasmLabelApi::dynamic_label`, the error is reported immediately instead of being deferred.
+    pub fn try_dynamic_label(&mut self, id: DynamicLabel) -> Result<(), DynasmError> {
+        let offset = self.offset();
+        self.labels.define_dynamic(id, offset)
+    }
+
+    /// Record a backward reference to the local label `name`, returning an error if the label is unknown
+    /// or out of range. Unlike `DynasmLabelApi::backward_reloc`, the error is reported immediately instead of being deferred.
+    pub fn try_backward_reloc(&mut self, name: &'static str, offset: isize, kind: R::Encoding) -> Result<(), DynasmError> {
+        self.try_backward_relocation(name, offset, R::from_encoding(kind))
+    }
+
+    /// Equivalent of `try_backward_reloc` taking a non-encoded relocation.
+    pub fn try_backward_relocation(&mut self, name: &'static str, offset: isize, kind: R) -> Result<(), DynasmError> {
+        let target = self.labels.resolve_local(name)?.0;
+        let location = self.offset();
+        let loc = PatchLoc::new(location, offset, kind);
+        if loc.patch(0, self.buffer.as_ptr() as usize, self.buffer, target).is_err() {
+            return Err(DynasmError::ImpossibleRelocation(TargetKind::Backward(name)));
+        }
+        if loc.needs_adjustment() {
+            self.new_managed.add(loc)
+        }
+        Ok(())
+    }
+
+    // encode uncommited relocations
+    fn encode_relocs(&mut self) -> Result<(), DynasmError> {
+        let buf_addr = self.buffer.as_ptr() as usize;
+
+        // If we accrued any errors while assembling before, emit them now.
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+
+        // Resolve globals
+        for (loc, name) in self.relocs.take_globals() {
+            let target = self.labels.resolve_global(name)?;
+            if let Err(_) = loc.patch(0, buf_addr, self.buffer, target.0) {
+                return Err(DynasmError::ImpossibleRelocation(TargetKind::Global(name)));
+            }
+            if loc.needs_adjustment() {
+                self.new_managed.add(loc);
+            }
+        }
+
+        // Resolve dynamics
+        for (loc, id) in self.relocs.take_dynamics() {
+            let target = self.labels.resolve_dynamic(id)?;
+            if let Err(_) = loc.patch(0, buf_addr, self.buffer, target.0) {
+                return Err(DynasmError::ImpossibleRelocation(TargetKind::Dynamic(id)));
+            }
+            if loc.needs_adjustment() {
+                self.new_managed.add(loc);
+            }
+        }
+
+        // Check for unknown locals
+        for (_, name) in self.relocs.take_locals() {
+            return Err(DynasmError::UnknownLabel(LabelKind::Local(name)));
+        }
+
+        self.forget_overwritten();
+
+        self.old_managed.append(&mut self.new_managed);
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, R: Relocation> Extend<u8> for Modifier<'a,R> {
+    fn extend<T>(&mut self, iter: T) where T: IntoIterator<Item=u8> {
+        for byte in iter {
+            self.push(byte);
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, 'b, R: Relocation> Extend<&'b u8> for Modifier<'a, R> {
+    fn extend<T>(&mut self, iter: T) where T: IntoIterator<Item=&'b u8> {
+        self.extend(iter.into_iter().cloned())
+    }
+}
+
+/// Writes overwrite code at the modifier cursor, and append to it at the end of the code like `try_push`.
+/// Nothing is written if the cursor has been moved past the end of the code.
+#[cfg(feature = "std")]
+impl<'a, R: Relocation> io::Write for Modifier<'a, R> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut written = 0;
+        for &byte in buf {
+            if self.try_push(byte).is_err() {
+                break;
+            }
+            written += 1;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, R: Relocation> DynasmApi for Modifier<'a, R> {
+    fn offset(&self) -> AssemblyOffset {
+        AssemblyOffset(self.asmoffset)
+    }
+
+    fn push(&mut self, value: u8) {
+        if let Err(e) = self.try_push(value) {
+            // keep the first out of bounds write, which is where things went wrong
+            if self.error.is_none() {
+                self.error = Some(e);
+            }
+            self.asmoffset += 1;
+        }
+    }
+
+    fn align(&mut self, alignment: usize, with: u8) {
+        let mismatch = self.asmoffset % alignment;
+        if mismatch != 0 {
+            for _ in mismatch .. alignment {
+                self.push(with)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, R: Relocation> DynasmLabelApi for Modifier<'a, R> {
+    type Relocation = R;
+
+    fn local_label(&mut self, name: &'static str) {
+        if let Err(e) = self.try_local_label(name) {
+            self.error = Some(e);
+        }
+    }
+    fn global_label( &mut self, name: &'static str) {
+        if let Err(e) = self.try_global_label(name) {
+            self.error = Some(e);
+        }
+    }
+    fn dynamic_label(&mut self, id: DynamicLabel) {
+        if let Err(e) = self.try_dynamic_label(id) {
+            self.error = Some(e);
+        }
+    }
+    fn global_relocation(&mut self, name: &'static str, offset: isize, kind: R) {
+        let location = self.offset();
+        self.relocs.add_global(name, PatchLoc::new(location, offset, kind));
+    }
+    fn dynamic_relocation(&mut self, id: DynamicLabel, offset: isize, kind: R) {
+        let location = self.offset();
+        self.relocs.add_dynamic(id, PatchLoc::new(location, offset, kind));
+    }
+    fn forward_relocation(&mut self, name: &'static str, offset: isize, kind: R) {
+        let location = self.offset();
+        self.relocs.add_local(name, PatchLoc::new(location, offset, kind));
+    }
+    fn backward_relocation(&mut self, name: &'static str, offset: isize, kind: R) {
+        if let Err(e) = self.try_backward_relocation(name, offset, kind) {
+            self.error = Some(e);
+        }
+    }
+    fn bare_relocation(&mut self, target: usize, kind: R) {
+        let location = self.offset();
+        let loc = PatchLoc::new(location, 0, kind);
+        if let Err(_) = loc.patch(0, self.buffer.as_ptr() as usize, self.buffer, target) {
+            self.error = Some(DynasmError::ImpossibleRelocation(TargetKind::Extern(target)));
+        } else if loc.needs_adjustment() {
+            self.new_managed.add(loc)
+        }
+    }
+}
+
+
+/// This struct is a wrapper around an `Assembler` normally created using the
+/// `Assembler.alter_uncommitted` method. It allows the user to edit parts
+/// of the assembling buffer that cannot be determined easily or efficiently
+/// in advance. Due to limitations of the label resolution algorithms, this
+/// assembler does not allow labels to be used.
+#[derive(Debug)]
+pub struct UncommittedModifier<'a> {
+    buffer: &'a mut Vec<u8>,
+    base_offset: usize,
+    offset: usize
+}
+
+impl<'a> UncommittedModifier<'a> {
+    /// create a new uncommittedmodifier
+    pub fn new(buffer: &mut Vec<u8>, base_offset: AssemblyOffset) -> UncommittedModifier {
+        UncommittedModifier {
+            buffer,
+            base_offset: base_offset.0,
+            offset: base_offset.0
+        }
+    }
+
+    /// Sets the current modification offset to the given value
+    pub fn goto(&mut self, offset: AssemblyOffset) {
+        self.offset = offset.0;
+    }
+
+    /// Write `value` at the current modification offset and advance it. Returns `DynasmError::OutOfBounds` if the
+    /// offset is not within the uncommitted code, in which case nothing is written. `push` panics then instead.
+    pub fn try_push(&mut self, value: u8) -> Result<(), DynasmError> {
+        let offset = self.offset;
+        let dst = offset.checked_sub(self.base_offset)
+            .and_then(|index| self.buffer.get_mut(index))
+            .ok_or(DynasmError::OutOfBounds(AssemblyOffset(offset)))?;
+        *dst = value;
+        self.offset += 1;
+        Ok(())
+    }
+
+    /// Checks that the current modification offset is not larger than the specified offset.
+    pub fn check(&mut self, offset: AssemblyOffset) -> Result<(), DynasmError> {
+        Ok(self.try_check(offset)?)
+    }
+
+    /// Checks that the current modification offset is exactly the specified offset.
+    pub fn check_exact(&mut self, offset: AssemblyOffset) -> Result<(), DynasmError> {
+        Ok(self.try_check_exact(offset)?)
+    }
+
+    /// Like `check`, but the error tells where the modification offset was instead.
+    pub fn try_check(&self, offset: AssemblyOffset) -> Result<(), LayoutError> {
+        check_layout(self.offset, offset, false)
+    }
+
+    /// Like `check_exact`, but the error tells where the modification offset was instead.
+    pub fn try_check_exact(&self, offset: AssemblyOffset) -> Result<(), LayoutError> {
+        check_layout(self.offset, offset, true)
+    }
+
+    /// Checks that the current modification offset is a multiple of `alignment`. Returns
+    /// `DynasmError::InvalidAlignment` if `alignment` is zero.
+    pub fn check_aligned(&mut self, alignment: usize) -> Result<(), DynasmError> {
+        check_aligned(self.offset, alignment)
+    }
+}
+
+// Check if `offset` is at one of the recorded instruction `boundaries` in code ending at `end`.
+#[cfg(feature = "std")]
+fn is_insn_boundary(boundaries: &BTreeSet<usize>, offset: AssemblyOffset, end: AssemblyOffset) -> bool {
+    boundaries.is_empty() || offset == end || boundaries.contains(&offset.0)
+}
+
+// Check that a modifier cursor at `offset` is aligned to `alignment`.
+fn check_aligned(offset: usize, alignment: usize) -> Result<(), DynasmError> {
+    if alignment == 0 {
+        return Err(DynasmError::InvalidAlignment(alignment));
+    }
+    let misalign = offset % alignment;
+    if misalign != 0 {
+        Err(DynasmError::CheckFailed)
+    } else {
+        Ok(())
+    }
+}
+
+/// Writes overwrite code at the modifier cursor, up to the end of the uncommitted code.
+/// Nothing is written if the cursor has been moved outside of the uncommitted code.
+#[cfg(feature = "std")]
+impl<'a> io::Write for UncommittedModifier<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let remaining = self.offset.checked_sub(self.base_offset)
+            .map_or(0, |offset| self.buffer.len().saturating_sub(offset));
+        let len = cmp::min(buf.len(), remaining);
+        for &byte in &buf[.. len] {
+            self.push(byte);
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> DynasmApi for UncommittedModifier<'a> {
+    fn offset(&self) -> AssemblyOffset {
+        AssemblyOffset(self.offset)
+    }
+
+    fn push(&mut self, value: u8) {
+        if let Err(e) = self.try_push(value) {
+            panic!("{}", e);
+        }
+    }
+
+    fn align(&mut self, alignment: usize, with: u8) {
+        let mismatch = self.offset % alignment;
+        if mismatch != 0 {
+            for _ in mismatch .. alignment {
+                self.push(with)
+            }
+        }
+    }
+}
+
+impl<'a> Extend<u8> for UncommittedModifier<'a> {
+    fn extend<T>(&mut self, iter: T) where T: IntoIterator<Item=u8> {
+        for i in iter {
+            self.push(i)
+        }
+    }
+}
+
+impl<'a, 'b> Extend<&'b u8> for UncommittedModifier<'a> {
+    fn extend<T>(&mut self, iter: T) where T: IntoIterator<Item=&'b u8> {
+        self.extend(iter.into_iter().cloned())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relocations::RelocationSize;
+
+    #[test]
+    fn test_slice_assembler() {
+        let mut buffer = [0u8; 8];
+        let mut ops = SliceAssembler::<RelocationSize>::new(&mut buffer);
+        ops.push(0xAA);
+        ops.push(0);
+        ops.forward_relocation("forward", 0, RelocationSize::Byte);
+        ops.push(0);
+        ops.global_relocation("global", 0, RelocationSize::Byte);
+        assert_eq!(ops.pending_relocs().collect::<Vec<_>>(), vec![
+            RelocInfo { target: TargetKind::Global("global"), location: AssemblyOffset(3) },
+            RelocInfo { target: TargetKind::Forward("forward"), location: AssemblyOffset(2) },
+        ]);
+        ops.local_label("forward");
+        ops.global_label("global");
+        ops.push(0xBB);
+        assert_eq!(ops.pending_relocs().collect::<Vec<_>>(), vec![
+            RelocInfo { target: TargetKind::Global("global"), location: AssemblyOffset(3) },
+        ]);
+        assert_eq!(ops.remaining(), 4);
+        assert_eq!(ops.finalize().unwrap(), &[0xAA, 0x02, 0x01, 0xBB]);
+
+        let mut buffer = [0u8; 2];
+        let mut ops = SliceAssembler::<RelocationSize>::new(&mut buffer);
+        ops.push_u32(0x1234_5678);
+        ops.push_u16(0);
+        ops.global_relocation("global", 0, RelocationSize::Word);
+        ops.global_label("global");
+        assert_eq!(ops.offset().0, 6);
+        assert_eq!(ops.finalize(), Err(DynasmError::BufferFull));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_patch() {
+        let mut ops = x64::Assembler::new().unwrap();
+        ops.extend(&[0x90, 0x90, 0x90, 0xC3]);
+        assert_eq!(ops.patch(AssemblyOffset(0), &[0xCC]), Err(DynasmError::OutOfBounds(AssemblyOffset(1))));
+        ops.commit().unwrap();
+        ops.patch(AssemblyOffset(1), &[0xCC, 0xCC]).unwrap();
+        assert_eq!(ops.patch(AssemblyOffset(3), &[0xCC, 0xCC]), Err(DynasmError::OutOfBounds(AssemblyOffset(5))));
+        assert_eq!(&ops.reader().lock()[..], &[0x90, 0xCC, 0xCC, 0xC3]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_reloc_offset() {
+        use crate::x64::X64Relocation;
+
+        let mut ops = x64::Assembler::new().unwrap();
+        ops.push(0);
+        ops.forward_relocation("target", 0, X64Relocation::from_size(RelocationSize::Byte));
+        ops.push(0);
+        ops.forward_relocation("target", 3, X64Relocation::from_size(RelocationSize::Byte));
+        ops.local_label("target");
+        ops.global_label("target");
+        ops.commit().unwrap();
+        assert_eq!(&ops.reader().lock()[..], &[2, 4]);
+
+        ops.alter(|modifier| {
+            modifier.push(0);
+            modifier.global_relocation("target", -1, X64Relocation::from_size(RelocationSize::Byte));
+        }).unwrap();
+        assert_eq!(&ops.reader().lock()[..], &[1, 4]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_thread_safety() {
+        fn assert_send<T: Send>() {}
+        fn assert_sync<T: Sync>() {}
+
+        assert_send::<Executor>();
+        assert_sync::<Executor>();
+        assert_send::<ExecutableBuffer>();
+        assert_sync::<ExecutableBuffer>();
+        assert_send::<x64::Assembler>();
+        assert_send::<aarch64::Assembler>();
+
+        let mut ops = x64::Assembler::new().unwrap();
+        ops.extend(&[0xC3]);
+        ops.commit().unwrap();
+        let executor = ops.reader();
+        let workers: Vec<_> = (0 .. 2).map(|_| {
+            let executor = executor.clone();
+            std::thread::spawn(move || {
+                assert_eq!(&executor.lock()[..], &[0xC3]);
+            })
+        }).collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        assert_eq!(executor.ptr_at(AssemblyOffset(0)), Ok(executor.lock().ptr(AssemblyOffset(0))));
+        assert_eq!(executor.ptr_at(AssemblyOffset(1)), Err(DynasmError::OutOfBounds(AssemblyOffset(1))));
+    }
+
+    #[test]
+    fn test_free_dynamic_label() {
+        let mut buffer = [0u8; 4];
+        let mut ops = SliceAssembler::<RelocationSize>::new(&mut buffer);
+        let first = ops.new_dynamic_label();
+        let second = ops.new_dynamic_label();
+        ops.dynamic_label(first);
+        ops.push(0);
+        ops.dynamic_relocation(second, 0, RelocationSize::Byte);
+        assert_eq!(ops.free_dynamic_label(second), Err(DynasmError::LabelInUse(LabelKind::Dynamic(second))));
+        ops.free_dynamic_label(first).unwrap();
+        assert_eq!(ops.labels().resolve_dynamic(first), Err(DynasmError::UnknownLabel(LabelKind::Dynamic(first))));
+
+        // double frees and ids from other registries are rejected
+        assert_eq!(ops.free_dynamic_label(first), Err(DynasmError::UnknownLabel(LabelKind::Dynamic(first))));
+        let foreign = components::LabelRegistry::new().named_dynamic_label("a");
+        let mut other = components::LabelRegistry::new();
+        assert_eq!(other.free_dynamic_label(foreign), Err(DynasmError::UnknownLabel(LabelKind::Dynamic(foreign))));
+
+        // the freed id is handed out again, and can be redefined
+        let third = ops.new_dynamic_label();
+        assert_eq!(third, first);
+        ops.dynamic_label(third);
+        ops.dynamic_label(second);
+        assert_eq!(ops.new_dynamic_label().get_id(), 2);
+        assert_eq!(ops.finalize().unwrap(), &[1]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_verifier() {
+        let mut ops = x64::Assembler::new().unwrap();
+        // reject code containing a syscall
+        ops.set_verifier(Box::new(|code| {
+            if code.windows(2).any(|w| *w == [0x0F, 0x05]) {
+                Err(String::from("syscall"))
+            } else {
+                Ok(())
+            }
+        }));
+
+        ops.push(0x90);
+        ops.commit().unwrap();
+        ops.extend(&[0x0F, 0x05]);
+        assert_eq!(ops.commit(), Err(DynasmError::VerificationFailed(String::from("syscall"))));
+        assert_eq!(ops.committed_bytes()[..], [0x90]);
+        assert_eq!(ops.uncommitted_bytes(), [0x0F, 0x05]);
+
+        // the rejected code can be fixed up and committed again
+        ops.alter_uncommitted().extend(&[0x90, 0xC3]);
+        ops.commit().unwrap();
+        assert_eq!(ops.committed_bytes()[..], [0x90, 0x90, 0xC3]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_on_commit() {
+        use std::sync::Mutex;
+        use crate::x64::X64Relocation;
+
+        let commits = Arc::new(Mutex::new(Vec::new()));
+        let mut ops = x64::Assembler::new().unwrap();
+        let log = commits.clone();
+        ops.on_commit(Box::new(move |info| log.lock().unwrap().push(info.clone())));
+
+        ops.local_label("start");
+        ops.push(0);
+        ops.global_relocation("target", 0, X64Relocation::from_size(RelocationSize::Byte));
+        ops.push(0);
+        ops.backward_relocation("start", 0, X64Relocation::from_size(RelocationSize::Byte));
+        ops.global_label("target");
+        ops.commit().unwrap();
+        ops.push(0xC3);
+        ops.commit().unwrap();
+
+        assert_eq!(*commits.lock().unwrap(), vec![
+            CommitInfo {
+                range: AssemblyOffset(0) .. AssemblyOffset(2),
+                relocations: vec![RelocInfo { target: TargetKind::Global("target"), location: AssemblyOffset(1) }],
+            },
+            CommitInfo {
+                range: AssemblyOffset(2) .. AssemblyOffset(3),
+                relocations: vec![],
+            },
+        ]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_forward_references() {
+        let mut ops = x64::Assembler::new().unwrap();
+        let dynamic = ops.new_dynamic_label();
+        ops.push(0xE9);
+        ops.push_u32(0);
+        ops.global_reloc("global", 0, (0, 4));
+        ops.push(0xE9);
+        ops.push_u32(0);
+        ops.dynamic_reloc(dynamic, 0, (0, 4));
+        // mov rax, [rip + value]
+        ops.extend(&[0x48, 0x8B, 0x05, 0, 0, 0, 0]);
+        ops.data_reloc("value", 0, (0, 4));
+
+        // all labels are defined after the references to them, but before the commit
+        ops.global_label("global");
+        ops.push(0xC3);
+        ops.dynamic_label(dynamic);
+        ops.push(0xC3);
+        ops.data().label("value");
+        ops.data().push_u64(1);
+        ops.commit().unwrap();
+
+        let lock = ops.reader();
+        let lock = lock.lock();
+        let field = |at: usize| i32::from_le_bytes([lock[at], lock[at + 1], lock[at + 2], lock[at + 3]]);
+        assert_eq!(field(1), 12);
+        assert_eq!(field(6), 8);
+        let data = ops.data().addr("value").unwrap() as isize;
+        assert_eq!(field(13) as isize, data - (lock.as_ptr() as isize + 17));
+
+        // a label defined in a later commit is too late
+        drop(lock);
+        ops.push(0xE9);
+        ops.push_u32(0);
+        ops.global_reloc("later", 0, (0, 4));
+        assert_eq!(ops.commit(), Err(DynasmError::UnknownLabel(LabelKind::Global("later"))));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_put() {
+        let mut ops = x64::Assembler::new().unwrap();
+        ops.push(0xE8);
+        let field = ops.put_u32(0);
+        assert_eq!(field, AssemblyOffset(1));
+        assert_eq!(ops.put(&[0xC3]), AssemblyOffset(5));
+        ops.commit().unwrap();
+
+        ops.alter(|modifier| {
+            modifier.goto(field);
+            modifier.push_u32(0x12345678);
+        }).unwrap();
+        assert_eq!(&ops.reader().lock()[..], &[0xE8, 0x78, 0x56, 0x34, 0x12, 0xC3]);
+    }
+
+    #[test]
+    fn test_short_branches() {
+        fn emit<D: DynasmLabelApi<Relocation = x64::X64Relocation>>(ops: &mut D, branches: &mut x64::ShortBranches, labels: &[DynamicLabel]) {
+            ops.dynamic_label(labels[0]);
+            // je =>1; jne =>2; 200 bytes of padding; =>1: jl =>0; =>2:
+            branches.jcc(ops, 0x4, labels[1]);
+            branches.jcc(ops, 0x5, labels[2]);
+            ops.extend(&[0x90; 200]);
+            ops.dynamic_label(labels[1]);
+            branches.jcc(ops, 0xC, labels[0]);
+            ops.dynamic_label(labels[2]);
+        }
+
+        let mut branches = x64::ShortBranches::new();
+        let mut sizing = x64::SizingAssembler::new();
+        let labels: Vec<_> = (0 .. 3).map(|_| sizing.new_dynamic_label()).collect();
+        emit(&mut sizing, &mut branches, &labels);
+        branches.resolve(sizing.labels()).unwrap();
+        // the jumps over the padding are too far, and the backward jump as well
+        assert_eq!(branches.shortened(), 0);
+
+        // with less code in between, the first and last jumps become short
+        fn emit_small<D: DynasmLabelApi<Relocation = x64::X64Relocation>>(ops: &mut D, branches: &mut x64::ShortBranches, labels: &[DynamicLabel]) {
+            ops.dynamic_label(labels[0]);
+            branches.jcc(ops, 0x4, labels[1]);
+            branches.jcc(ops, 0x5, labels[2]);
+            ops.extend(&[0x90; 100]);
+            ops.dynamic_label(labels[1]);
+            branches.jcc(ops, 0xC, labels[0]);
+            ops.extend(&[0x90; 30]);
+            ops.dynamic_label(labels[2]);
+        }
+        let mut branches = x64::ShortBranches::new();
+        let mut sizing = x64::SizingAssembler::new();
+        let labels: Vec<_> = (0 .. 3).map(|_| sizing.new_dynamic_label()).collect();
+        emit_small(&mut sizing, &mut branches, &labels);
+        branches.resolve(sizing.labels()).unwrap();
+        assert_eq!(branches.shortened(), 2);
+
+        let mut ops = x64::VecLabelAssembler::new(0);
+        let labels: Vec<_> = (0 .. 3).map(|_| ops.new_dynamic_label()).collect();
+        emit_small(&mut ops, &mut branches, &labels);
+        let code = ops.finalize().unwrap();
+        assert_eq!(code.len(), 2 + 6 + 100 + 2 + 30);
+        assert_eq!(&code[.. 8], &[0x74, 106, 0x0F, 0x85, 132, 0, 0, 0]);
+        assert_eq!(&code[108 .. 110], &[0x7C, (-110i8) as u8]);
+    }
+
+    #[test]
+    fn test_short_branches_align() {
+        fn emit<D: DynasmLabelApi<Relocation = x64::X64Relocation>>(ops: &mut D, branches: &mut x64::ShortBranches, labels: &[DynamicLabel]) {
+            // je =>0; =>0: 5 bytes; je =>1; 127 bytes; align 16; =>1:
+            branches.jcc(ops, 0x4, labels[0]);
+            ops.dynamic_label(labels[0]);
+            ops.extend(&[0x90; 5]);
+            branches.jcc(ops, 0x4, labels[1]);
+            ops.extend(&[0x90; 127]);
+            branches.align(ops, 16, 0x90);
+            ops.dynamic_label(labels[1]);
+        }
+
+        let mut branches = x64::ShortBranches::new();
+        let mut sizing = x64::SizingAssembler::new();
+        let labels: Vec<_> = (0 .. 2).map(|_| sizing.new_dynamic_label()).collect();
+        emit(&mut sizing, &mut branches, &labels);
+        branches.resolve(sizing.labels()).unwrap();
+        // the second jump only reaches its target if the padding doesn't grow when the first jump is shortened
+        assert_eq!(branches.shortened(), 1);
+
+        let mut ops = x64::VecLabelAssembler::new(0);
+        let labels: Vec<_> = (0 .. 2).map(|_| ops.new_dynamic_label()).collect();
+        emit(&mut ops, &mut branches, &labels);
+        let code = ops.finalize().unwrap();
+        assert_eq!(code.len(), 144);
+        assert_eq!(&code[.. 2], &[0x74, 0]);
+        assert_eq!(&code[7 .. 13], &[0x0F, 0x84, 131, 0, 0, 0]);
+    }
+
+    #[cfg(all(feature = "std", target_arch = "x86_64"))]
+    #[test]
+    fn test_branch_islands() {
+        // mov eax, 1; ret, padded with nops. Not executed if the jumps work.
+        const FILL: [u8; 10] = [0xB8, 1, 0, 0, 0, 0xC3, 0x90, 0x90, 0x90, 0x90];
+
+        let mut ops = x64::Assembler::new().unwrap();
+        let mut islands = x64::BranchIslands::new(10);
+        let near = ops.new_dynamic_label();
+        let far = ops.new_dynamic_label();
+        let start = ops.offset();
+        // xor eax, eax; cmp eax, eax; je =>far; jmp =>near
+        ops.extend(&[0x31, 0xC0, 0x39, 0xC0]);
+        islands.jcc(&mut ops, 0x4, far);
+        islands.jmp(&mut ops, near);
+        ops.extend(&FILL);
+        islands.label(&mut ops, near);
+        // the jump to near is resolved directly
+        assert_eq!(islands.redirected(), 0);
+        for _ in 0 .. 30 {
+            ops.extend(&FILL);
+            islands.check(&mut ops);
+        }
+        islands.label(&mut ops, far);
+        // mov eax, 2; ret
+        ops.extend(&[0xB8, 2, 0, 0, 0, 0xC3]);
+        ops.commit().unwrap();
+        assert_eq!(islands.redirected(), 1);
+
+        let reader = ops.reader();
+        let code = reader.lock();
+        // jmp =>near skips the fill directly
+        assert_eq!(&code[6 .. 8], &[0xEB, 10]);
+        let f: extern "sysv64" fn() -> u32 = unsafe { mem::transmute(code.ptr(start)) };
+        assert_eq!(f(), 2);
+    }
+
+    #[test]
+    fn test_buffer_len() {
+        let buffer = ExecutableBuffer::default();
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.capacity(), 0);
+
+        let mut ops = x64::Assembler::new().unwrap();
+        ops.extend(&[0x90, 0x90, 0xC3]);
+        let buffer = ops.finalize().unwrap();
+        assert_eq!(buffer.len(), 3);
+        assert!(!buffer.is_empty());
+        assert!(buffer.capacity() >= mmap::page_size());
+        assert_eq!(buffer.as_slice(), &[0x90, 0x90, 0xC3]);
+    }
+
+    #[test]
+    fn test_declare_globals() {
+        let mut ops = x64::Assembler::new().unwrap();
+        // without declarations, any label can be referenced
+        ops.push_u32(0);
+        ops.global_reloc("anything", 0, (0, 4));
+        ops.global_label("anything");
+
+        ops.declare_globals(&["start"]);
+        ops.declare_globals(&["end"]);
+        ops.global_label("start");
+        ops.push_u32(0);
+        ops.global_reloc("end", 0, (0, 4));
+        ops.push_u32(0);
+        ops.weak_global_reloc("start", 0, (0, 4));
+        // declared but undefined labels still fail to commit
+        assert_eq!(ops.commit(), Err(DynasmError::UnknownLabel(LabelKind::Global("end"))));
+    }
+
+    #[test]
+    #[should_panic(expected = "Reference to undeclared global label ->strat")]
+    fn test_declare_globals_typo() {
+        let mut ops = x64::Assembler::new().unwrap();
+        ops.declare_globals(&["start"]);
+        ops.global_label("start");
+        ops.push_u32(0);
+        ops.global_reloc("strat", 0, (0, 4));
+    }
+
+    #[test]
+    fn test_max_size() {
+        let page_size = mmap::page_size();
+        let config = AssemblerConfig { max_size: Some(2 * page_size), ..AssemblerConfig::default() };
+        let mut ops = x64::Assembler::with_config(config).unwrap();
+        assert_eq!(ops.remaining_capacity(), page_size);
+
+        // growing stops at the maximum size
+        ops.extend(vec![0x90; page_size + 1]);
+        ops.commit().unwrap();
+        assert_eq!(ops.offset().0 + ops.remaining_capacity(), 2 * page_size);
+        ops.extend(vec![0x90; page_size]);
+        assert_eq!(ops.commit(), Err(DynasmError::BufferFull));
+        assert_eq!(ops.grow_to(3 * page_size), Err(DynasmError::BufferFull));
+        // the code stays uncommitted
+        assert_eq!(ops.reader().lock().len(), page_size + 1);
+
+        let config = AssemblerConfig::default().min_size(3 * page_size).max_size(Some(2 * page_size));
+        assert!(x64::Assembler::with_config(config).is_err());
+    }
+
+    #[test]
+    fn test_page_align_sections() {
+        let page_size = mmap::page_size();
+        let mut ops = x64::Assembler::with_config(AssemblerConfig::default().page_align_sections(true)).unwrap();
+        ops.extend(&[0x90, 0xC3]);
+        ops.commit().unwrap();
+        assert_eq!(ops.offset().0, page_size);
+        // nothing new to commit, so no padding
+        ops.commit().unwrap();
+        assert_eq!(ops.offset().0, page_size);
+
+        ops.extend(vec![0x90; page_size + 1]);
+        ops.commit().unwrap();
+        assert_eq!(ops.offset().0, 3 * page_size);
+        assert_eq!(&ops.reader().lock()[.. 3], &[0x90, 0xC3, 0]);
+
+        // each commit can be retired on its own
+        ops.retire(AssemblyOffset(0) .. AssemblyOffset(page_size)).unwrap();
+        assert!(!ops.reader().is_executable(AssemblyOffset(0)));
+        assert!(ops.reader().is_executable(AssemblyOffset(page_size)));
+
+        // the configuration survives resetting
+        ops.reset().unwrap();
+        ops.push(0xC3);
+        ops.commit().unwrap();
+        assert_eq!(ops.offset().0, page_size);
+    }
+
+    #[test]
+    fn test_external_relocations() {
+        use crate::x64::X64Relocation;
+
+        let mut ops = x64::Assembler::new().unwrap();
+        // call puts; jmp [rip + exit]
+        ops.push(0xE8);
+        ops.push_u32(0);
+        ops.extern_reloc_by_name("puts", 0, (0, 4));
+        ops.extend(&[0xFF, 0x25, 0, 0, 0, 0]);
+        ops.extern_relocation_by_name("exit", 8, X64Relocation::from_size(RelocationSize::DWord));
+        // the references are left alone when committing
+        ops.commit().unwrap();
+        let code = ops.reader().lock().to_vec();
+        assert_eq!(code, [0xE8, 0, 0, 0, 0, 0xFF, 0x25, 0, 0, 0, 0]);
+
+        let externals: Vec<_> = ops.external_relocations().iter()
+            .map(|r| (r.field.clone(), r.base, r.symbol.as_str(), r.addend, r.relocation.kind()))
+            .collect();
+        assert_eq!(externals, [
+            (AssemblyOffset(1) .. AssemblyOffset(5), AssemblyOffset(5), "puts", 0, RelocationKind::Relative),
+            // relocations created from their size are relative to the start of their field
+            (AssemblyOffset(7) .. AssemblyOffset(11), AssemblyOffset(7), "exit", 8, RelocationKind::Relative),
+        ]);
+    }
+
+    #[test]
+    fn test_prologue() {
+        use crate::unwind::{UnwindInfo, Reg};
+
+        let mut ops = x64::Assembler::new().unwrap();
+        let info = ops.emit_prologue(20);
+        assert_eq!(info, UnwindInfo::new().push_reg(1, Reg::Rbp).set_frame(4, Reg::Rbp, 0).alloc_stack(8, 32));
+        // mov eax, 7
+        ops.extend(&[0xB8, 7, 0, 0, 0]);
+        ops.emit_epilogue();
+        ops.register_unwind(AssemblyOffset(0) .. ops.offset(), info);
+
+        let large = ops.offset();
+        let info = ops.emit_prologue(0x200);
+        assert_eq!(info.prologue_size(), 11);
+        assert_eq!(info.ops().last().unwrap().1, crate::unwind::UnwindOp::AllocStack(0x200));
+        assert_eq!(ops.emit_prologue(0).prologue_size(), 4);
+        ops.commit().unwrap();
+
+        let reader = ops.reader();
+        let code = reader.lock();
+        assert_eq!(&code[.. 8], &[0x55, 0x48, 0x89, 0xE5, 0x48, 0x83, 0xEC, 32]);
+        assert_eq!(&code[13 .. 19], &[0x48, 0x8D, 0x65, 0x00, 0x5D, 0xC3]);
+        assert_eq!(&code[large.0 + 4 .. large.0 + 11], &[0x48, 0x81, 0xEC, 0, 2, 0, 0]);
+        #[cfg(target_arch = "x86_64")]
+        {
+            let f: extern "sysv64" fn() -> u32 = unsafe { mem::transmute(code.ptr(AssemblyOffset(0))) };
+            assert_eq!(f(), 7);
+        }
+    }
+
+    #[cfg(all(feature = "std", target_arch = "x86_64"))]
+    #[test]
+    fn test_call_extern() {
+        extern "C" fn add(a: u64, b: u64) -> u64 {
+            a * 10 + b
+        }
+
+        let mut ops = x64::Assembler::new().unwrap();
+        let info = ops.emit_prologue(0);
+        // mov edi, 4; mov esi, 2
+        ops.extend(&[0xBF, 4, 0, 0, 0, 0xBE, 2, 0, 0, 0]);
+        let call = ops.offset();
+        ops.call_extern(add as extern "C" fn(u64, u64) -> u64);
+        ops.emit_epilogue();
+        ops.register_unwind(AssemblyOffset(0) .. ops.offset(), info);
+        ops.commit().unwrap();
+
+        let reader = ops.reader();
+        let code = reader.lock();
+        // the buffer can move, so the address is always loaded into a register
+        assert_eq!(&code[call.0 .. call.0 + 2], &[0x49, 0xBB]);
+        assert_eq!(&code[call.0 + 2 .. call.0 + 10], &(add as usize as u64).to_le_bytes());
+        let f: extern "sysv64" fn() -> u64 = unsafe { mem::transmute(code.ptr(AssemblyOffset(0))) };
+        assert_eq!(f(), 42);
+    }
+
+    #[test]
+    fn test_label_definition() {
+        let mut ops = x64::Assembler::new().unwrap();
+        let id = ops.new_dynamic_label();
+        ops.local_label("loop");
+        ops.global_label("start");
+        ops.extend(&[0x90, 0x90]);
+        ops.dynamic_label(id);
+        assert_eq!(ops.label_definition(LabelKind::Local("loop")), Some(LabelDefinition::Uncommitted(AssemblyOffset(0))));
+        ops.commit().unwrap();
+        assert_eq!(ops.label_definition(LabelKind::Local("loop")), Some(LabelDefinition::Committed(AssemblyOffset(0))));
+        assert_eq!(ops.label_definition(LabelKind::Global("start")), Some(LabelDefinition::Committed(AssemblyOffset(0))));
+        // the label at the end of the committed code points at the code that follows it
+        assert_eq!(ops.label_definition(LabelKind::Dynamic(id)), Some(LabelDefinition::Uncommitted(AssemblyOffset(2))));
+        assert_eq!(ops.label_definition(LabelKind::Global("end")), None);
+
+        // backward references into earlier commits resolve, even after the buffer moved
+        ops.extend(vec![0x90; 0x2000]);
+        ops.commit().unwrap();
+        ops.push(0xE9);
+        ops.push_u32(0);
+        ops.backward_reloc("loop", 0, (0, 4));
+        ops.commit().unwrap();
+        let code = ops.reader().lock().to_vec();
+        assert_eq!(&code[0x2003 .. 0x2007], &(-0x2007i32).to_le_bytes());
+
+        // resetting removes all definitions
+        ops.reset().unwrap();
+        assert_eq!(ops.label_definition(LabelKind::Local("loop")), None);
+    }
+
+    #[test]
+    fn test_exported_symbols() {
+        let mut ops = x64::Assembler::new().unwrap();
+        ops.global_label("header");
+        ops.extend(&[0xCC; 3]);
+        ops.function("add", 16, |ops| ops.extend(&[0x48, 0x8D, 0x04, 0x37, 0xC3])).unwrap();
+        ops.global_label("tail");
+        ops.push(0xC3);
+        ops.commit().unwrap();
+        ops.global_label("uncommitted");
+        ops.push(0xC3);
+
+        assert_eq!(ops.exported_symbols(), [
+            ExportedSymbol { name: "header", offset: AssemblyOffset(0), size: 16 },
+            ExportedSymbol { name: "add", offset: AssemblyOffset(16), size: 5 },
+            ExportedSymbol { name: "tail", offset: AssemblyOffset(21), size: 1 },
+        ]);
+
+        let mut out = Vec::new();
+        ops.write_perf_map(&mut out).unwrap();
+        let addr = ops.reader().lock().as_ptr() as usize;
+        assert_eq!(String::from_utf8(out).unwrap(), format!("{:x} 10 header\n{:x} 5 add\n{:x} 1 tail\n", addr, addr + 16, addr + 21));
+    }
+
+    #[test]
+    fn test_exported_symbols_redefined() {
+        let mut ops = x64::Assembler::new().unwrap();
+        ops.set_duplicate_label_policy(DuplicateLabelPolicy::Replace);
+        ops.function("f", 1, |ops| ops.extend(&[0x90; 4])).unwrap();
+        ops.function("f", 16, |ops| ops.extend(&[0x90, 0xC3])).unwrap();
+        ops.commit().unwrap();
+
+        // the symbol spans the function the label was last defined for
+        assert_eq!(ops.exported_symbols(), [ExportedSymbol { name: "f", offset: AssemblyOffset(16), size: 2 }]);
+    }
+
+    #[cfg(all(feature = "std", target_arch = "x86_64"))]
+    #[test]
+    fn test_counters() {
+        use std::sync::atomic::Ordering;
+
+        let mut ops = x64::Assembler::new().unwrap();
+        let first = ops.reserve_counter();
+        let start = ops.offset();
+        ops.inc_counter(first);
+        ops.push(0xC3);
+        assert!(ops.counter(first).is_none());
+        ops.commit().unwrap();
+
+        // counters reserved later end up in their own mapping
+        let second = ops.reserve_counter();
+        let other = ops.offset();
+        ops.inc_counter(second);
+        ops.inc_counter(first);
+        ops.push(0xC3);
+        ops.commit().unwrap();
+        assert_eq!(ops.counter(second).unwrap().load(Ordering::Relaxed), 0);
+
+        let reader = ops.reader();
+        let code = reader.lock();
+        let f: extern "sysv64" fn() = unsafe { mem::transmute(code.ptr(start)) };
+        let g: extern "sysv64" fn() = unsafe { mem::transmute(code.ptr(other)) };
+        f();
+        f();
+        g();
+        assert_eq!(ops.counter(first).unwrap().swap(0, Ordering::Relaxed), 3);
+        assert_eq!(ops.counter(second).unwrap().load(Ordering::Relaxed), 1);
+        f();
+        assert_eq!(ops.counter(first).unwrap().load(Ordering::Relaxed), 1);
+    }
+
+    #[cfg(all(feature = "std", target_arch = "x86_64"))]
+    #[test]
+    fn test_alter_grow() {
+        use byteorder::{ByteOrder, LittleEndian};
+
+        let mut ops = x86::Assembler::new().unwrap();
+        let target = ops.memory.execbuffer_addr() + 0x10000;
+        // call target
+        ops.push(0xE8);
+        ops.push_u32(0);
+        ops.bare_reloc(target, (0, 4, 2));
+        ops.commit().unwrap();
+        let size = ops.memory.size();
+        let old_addr = ops.memory.execbuffer_addr();
+
+        // append past the end of the buffer, which has to move to grow
+        let start = ops.alter(|modifier| {
+            modifier.goto(AssemblyOffset(5));
+            for _ in 0 .. size {
+                modifier.push(0xCC);
+            }
+            // mov eax, 7; ret
+            modifier.global_label("appended");
+            modifier.extend(&[0xB8, 7, 0, 0, 0, 0xC3]);
+            AssemblyOffset(5 + size)
+        }).unwrap();
+        assert!(ops.memory.size() > size);
+        assert_ne!(ops.memory.execbuffer_addr(), old_addr);
+        assert_eq!(ops.memory.committed(), start.0 + 6);
+        assert_eq!(ops.labels().resolve_global("appended"), Ok(start));
+
+        // the call still reaches its target from the new address
+        let reader = ops.reader();
+        let lock = reader.lock();
+        let displacement = LittleEndian::read_i32(&lock[1 .. 5]) as isize;
+        assert_eq!((lock.base() as usize + 5).wrapping_add(displacement as usize), target);
+        let f: extern "sysv64" fn() -> u32 = unsafe { mem::transmute(lock.ptr(start)) };
+        assert_eq!(f(), 7);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_code_buffer() {
+        // jmp ->end; int3; ->end: ret
+        fn generate<B: CodeBuffer<Relocation = x64::X64Relocation>>(ops: &mut B) -> Result<Option<Vec<u8>>, DynasmError> {
+            let end = ops.new_dynamic_label();
+            ops.extend(&[0xEB, 0]);
+            ops.dynamic_reloc(end, 0, (0, 1));
+            ops.push(0xCC);
+            ops.dynamic_label(end);
+            ops.push(0xC3);
+            ops.commit()?;
+            assert_eq!(ops.labels().resolve_dynamic(end), Ok(AssemblyOffset(3)));
+            assert_eq!(ops.read_code(AssemblyOffset(2) .. AssemblyOffset(5)), None);
+            Ok(ops.read_code(AssemblyOffset(0) .. ops.offset()))
+        }
+        let code = vec![0xEB, 1, 0xCC, 0xC3];
+
+        let mut ops = x64::Assembler::new().unwrap();
+        assert_eq!(generate(&mut ops), Ok(Some(code.clone())));
+        // the code can be read back across the end of the committed code
+        ops.push(0x90);
+        assert_eq!(CodeBuffer::read_code(&ops, AssemblyOffset(3) .. AssemblyOffset(5)), Some(vec![0xC3, 0x90]));
+
+        let mut ops = VecLabelAssembler::new(0);
+        assert_eq!(generate(&mut ops), Ok(Some(code.clone())));
+
+        let mut buffer = [0; 4];
+        let mut ops = SliceAssembler::new(&mut buffer);
+        assert_eq!(generate(&mut ops), Ok(Some(code.clone())));
+
+        let mut ops = SizingAssembler::new();
+        assert_eq!(generate(&mut ops), Ok(None));
+        assert_eq!(ops.finalize(), Ok(4));
+
+        // references that can't be resolved at the commit are reported by all of them
+        let mut ops = SizingAssembler::<x64::X64Relocation>::new();
+        ops.forward_reloc("missing", 0, (0, 1));
+        assert_eq!(CodeBuffer::commit(&mut ops), Err(DynasmError::UnknownLabel(LabelKind::Local("missing"))));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_try_check() {
+        let mut ops = x64::Assembler::new().unwrap();
+        ops.extend(&[0x90, 0x90, 0xC3]);
+        ops.commit().unwrap();
+        ops.alter(|modifier| {
+            modifier.goto(AssemblyOffset(2));
+            assert_eq!(modifier.try_check(AssemblyOffset(2)), Ok(()));
+            assert_eq!(modifier.try_check(AssemblyOffset(1)), Err(LayoutError { expected: AssemblyOffset(1), actual: AssemblyOffset(2) }));
+            assert_eq!(modifier.try_check_exact(AssemblyOffset(3)), Err(LayoutError { expected: AssemblyOffset(3), actual: AssemblyOffset(2) }));
+            assert_eq!(modifier.check_exact(AssemblyOffset(3)), Err(DynasmError::CheckFailed));
+        }).unwrap();
+
+        ops.extend(&[0x90, 0x90]);
+        let mut modifier = ops.alter_uncommitted();
+        modifier.goto(AssemblyOffset(4));
+        assert_eq!(modifier.try_check_exact(AssemblyOffset(4)), Ok(()));
+        let error = modifier.try_check(AssemblyOffset(3)).unwrap_err();
+        assert_eq!(error.to_string(), "Modification cursor at offset 4 instead of offset 3");
+    }
+
+    #[test]
+    fn test_push_endian() {
+        let mut ops = VecAssembler(Vec::new());
+        ops.push_u16_be(0x0102);
+        ops.push_u16_le(0x0102);
+        ops.push_u32_be(0x0304_0506);
+        ops.push_i32_le(-2);
+        ops.push_u64_be(0x0708);
+        ops.push_i16_be(-2);
+        assert_eq!(ops.0, [
+            1, 2, 2, 1,
+            3, 4, 5, 6, 0xFE, 0xFF, 0xFF, 0xFF,
+            0, 0, 0, 0, 0, 0, 7, 8,
+            0xFF, 0xFE,
+        ]);
+    }
+
+    #[cfg(all(feature = "std", target_arch = "x86_64"))]
+    #[test]
+    fn test_push_rip_data() {
+        let mut ops = x64::Assembler::new().unwrap();
+        ops.data().label("value");
+        ops.data().push_u32(7);
+        // imul eax, [rip + value], 3; add eax, [rip + value]; ret
+        ops.extend(&[0x6B, 0x05]);
+        ops.push_rip_data("value", &[3]);
+        ops.extend(&[0x03, 0x05]);
+        ops.push_rip_data("value", &[]);
+        ops.push(0xC3);
+        ops.commit().unwrap();
+
+        let reader = ops.reader();
+        let lock = reader.lock();
+        let f: extern "sysv64" fn() -> u32 = unsafe { mem::transmute(lock.ptr(AssemblyOffset(0))) };
+        assert_eq!(f(), 28);
+    }
+
+    #[cfg(all(feature = "std", target_arch = "x86_64"))]
+    #[test]
+    fn test_data_reach() {
+        fn check(ops: &x64::Assembler, start: AssemblyOffset) {
+            let data = ops.data.addr("value").unwrap() as isize;
+            let reader = ops.reader();
+            let lock = reader.lock();
+            let code = lock.ptr(start) as isize;
+            assert!((data - code).abs() < 1 << 31, "data at {:x} is out of reach of code at {:x}", data, code);
+            let f: extern "sysv64" fn() -> u32 = unsafe { mem::transmute(code) };
+            assert_eq!(f(), 7);
+        }
+
+        // code far away from where memory is mapped by default still reaches its data
+        let addr = 0x10_0000_0000;
+        let mut ops = x64::Assembler::new_in(addr .. addr + 0x10000).unwrap();
+        ops.data().label("value");
+        ops.data().push_u32(7);
+        // mov eax, [rip + value]; ret
+        ops.extend(&[0x8B, 0x05]);
+        ops.push_rip_data("value", &[]);
+        ops.push(0xC3);
+        ops.commit().unwrap();
+        check(&ops, AssemblyOffset(0));
+
+        // code that moves to grow stays within reach of the data it references
+        let mut ops = x64::Assembler::new().unwrap();
+        ops.data().label("value");
+        ops.data().push_u32(7);
+        ops.extend(&[0x8B, 0x05]);
+        ops.push_rip_data("value", &[]);
+        ops.push(0xC3);
+        ops.commit().unwrap();
+        ops.grow_to(1 << 24).unwrap();
+        check(&ops, AssemblyOffset(0));
+        let end = ops.offset();
+        ops.alter(|modifier| {
+            modifier.goto(end);
+            modifier.extend(vec![0x90; 1 << 25]);
+        }).unwrap();
+        check(&ops, AssemblyOffset(0));
+        let start = ops.offset();
+        ops.extend(&[0x8B, 0x05]);
+        ops.push_rip_data("value", &[]);
+        ops.push(0xC3);
+        ops.extend(vec![0x90; 1 << 26]);
+        ops.commit().unwrap();
+        check(&ops, start);
+    }
+
+    #[test]
+    fn test_push_aligned() {
+        let mut ops = VecAssembler(Vec::new());
+        ops.push(0xC3);
+        assert_eq!(ops.push_aligned(&[1; 16], 16, 0xCC), AssemblyOffset(16));
+        assert_eq!(ops.push_aligned(&[2, 2], 16, 0xCC), AssemblyOffset(32));
+        let code = ops.0;
+        assert_eq!(code.len(), 34);
+        assert!(code[1 .. 16].iter().all(|&b| b == 0xCC));
+        assert_eq!(&code[16 .. 32], &[1; 16]);
+    }
+
+    #[test]
+    fn test_vec_label_assembler() {
+        let mut ops = x86::VecLabelAssembler::from_vec(vec![0x90], 0x1000_0000);
+        // jmp >skip
+        ops.extend(&[0xEB, 0]);
+        ops.forward_reloc("skip", 0, (0, 1, 0));
+        ops.push(0xCC);
+        ops.local_label("skip");
+        // mov rax, QWORD ->target; ret
+        ops.extend(&[0x48, 0xB8, 0, 0, 0, 0, 0, 0, 0, 0]);
+        ops.global_reloc("target", 0, (0, 8, 1));
+        ops.global_label("target");
+        ops.push(0xC3);
+
+        let code = ops.finalize().unwrap();
+        assert_eq!(code[.. 6], [0x90, 0xEB, 1, 0xCC, 0x48, 0xB8]);
+        // absolute references are resolved against the base address
+        assert_eq!(code[6 .. 14], 0x1000_000Eu64.to_le_bytes());
+        assert_eq!(code[14], 0xC3);
+
+        let mut ops = x86::VecLabelAssembler::new(0);
+        ops.global_reloc("missing", 0, (0, 1, 0));
+        assert_eq!(ops.finalize(), Err(DynasmError::UnknownLabel(LabelKind::Global("missing"))));
+    }
+
+    #[test]
+    fn test_sizing_assembler() {
+        fn emit<D: DynasmLabelApi<Relocation=RelocationSize>>(ops: &mut D, id: DynamicLabel) {
+            ops.push(0x11);
+            ops.forward_reloc("skip", 0, 1);
+            ops.extend(&[0x22, 0x33]);
+            ops.local_label("skip");
+            ops.align(8, 0);
+            ops.dynamic_label(id);
+            ops.push_u32(0);
+            ops.dynamic_reloc(id, 0, 4);
+            ops.global_label("end");
+        }
+
+        let mut sizing = SizingAssembler::new();
+        let id = sizing.new_dynamic_label();
+        emit(&mut sizing, id);
+        let offset = sizing.labels().resolve_dynamic(id).unwrap();
+        let end = sizing.labels().resolve_global("end").unwrap();
+        let size = sizing.finalize().unwrap();
+        assert_eq!(size, 12);
+
+        let mut buffer = vec![0; size];
+        let mut ops = SliceAssembler::<RelocationSize>::new(&mut buffer);
+        assert_eq!(ops.new_dynamic_label(), id);
+        emit(&mut ops, id);
+        assert_eq!(ops.labels().resolve_dynamic(id), Ok(offset));
+        assert_eq!(ops.labels().resolve_global("end"), Ok(end));
+        assert_eq!(ops.finalize().unwrap().len(), size);
+
+        // unresolved relocations are reported
+        let mut sizing = SizingAssembler::<RelocationSize>::new();
+        sizing.forward_reloc("missing", 0, 1);
+        assert_eq!(sizing.finalize(), Err(DynasmError::UnknownLabel(LabelKind::Local("missing"))));
+    }
+
+    #[cfg(all(feature = "std", target_arch = "x86_64"))]
+    #[test]
+    fn test_slice_assembler_on_stack() {
+        let mut ops = x64::Assembler::new().unwrap();
+        let start = ops.offset();
+        {
+            let mut buffer = [0u8; 256];
+            let mut small = x64::SliceAssembler::new(&mut buffer);
+            // xor eax, eax; jmp >done; mov eax, 1; done: ret
+            small.extend(&[0x31, 0xC0, 0xEB, 0]);
+            small.forward_reloc("done", 0, (0, 1));
+            small.extend(&[0xB8, 1, 0, 0, 0]);
+            small.local_label("done");
+            small.push(0xC3);
+            ops.extend(&*small.finalize().unwrap());
+        }
+        ops.commit().unwrap();
+
+        let reader = ops.reader();
+        let lock = reader.lock();
+        let f: extern "sysv64" fn() -> u32 = unsafe { mem::transmute(lock.ptr(start)) };
+        assert_eq!(f(), 0);
+    }
+
+    #[test]
+    fn test_try_labels() {
+        let mut buffer = [0u8; 4];
+        let mut ops = SliceAssembler::<RelocationSize>::new(&mut buffer);
+        let id = ops.new_dynamic_label();
+        ops.try_global_label("global").unwrap();
+        assert_eq!(ops.try_global_label("global"), Err(DynasmError::DuplicateLabel(LabelKind::Global("global"))));
+        ops.try_dynamic_label(id).unwrap();
+        assert_eq!(ops.try_dynamic_label(id), Err(DynasmError::DuplicateLabel(LabelKind::Dynamic(id))));
+
+        ops.push(0);
+        assert_eq!(ops.try_backward_relocation("local", 0, RelocationSize::Byte), Err(DynasmError::UnknownLabel(LabelKind::Local("local"))));
+        ops.local_label("local");
+        ops.push(0);
+        ops.try_backward_relocation("local", 0, RelocationSize::Byte).unwrap();
+
+        // errors reported by the checked variants are not deferred
+        assert_eq!(ops.finalize().unwrap(), &[0, 0]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_weak_relocs() {
+        fn displacement(ops: &x64::Assembler, end: usize) -> i32 {
+            let lock = ops.committed_bytes();
+            i32::from_le_bytes([lock[end - 4], lock[end - 3], lock[end - 2], lock[end - 1]])
+        }
+
+        let mut ops = x64::Assembler::new().unwrap();
+        // the stub calls are pointed at while their function isn't compiled yet
+        let stub = ops.offset();
+        ops.push(0xCC);
+        ops.set_placeholder(stub);
+        let func = ops.new_dynamic_label();
+
+        // call ->missing; call =>func
+        ops.push(0xE8);
+        ops.push_u32(0);
+        ops.weak_global_reloc("missing", 0, (0, 4));
+        let first = ops.offset().0;
+        ops.push(0xE8);
+        ops.push_u32(0);
+        ops.weak_dynamic_reloc(func, 0, (0, 4));
+        let second = ops.offset().0;
+        assert_eq!(ops.pending_relocs().count(), 2);
+        ops.commit().unwrap();
+        assert_eq!(displacement(&ops, first), -(first as i32));
+        assert_eq!(displacement(&ops, second), -(second as i32));
+        assert_eq!(ops.free_dynamic_label(func), Err(DynasmError::LabelInUse(LabelKind::Dynamic(func))));
+
+        // once a target exists, the references to it can be resolved
+        let target = ops.offset().0;
+        ops.dynamic_label(func);
+        ops.push(0xC3);
+        assert_eq!(ops.resolve_placeholders(), Ok(1));
+        assert_eq!(displacement(&ops, first), -(first as i32));
+        assert_eq!(displacement(&ops, second), (target - second) as i32);
+        ops.free_dynamic_label(func).unwrap();
+
+        // weak references to defined labels are resolved like normal ones
+        ops.global_label("missing");
+        ops.push(0xE8);
+        ops.push_u32(0);
+        ops.weak_global_reloc("missing", 0, (0, 4));
+        let third = ops.offset().0;
+        assert_eq!(ops.resolve_placeholders(), Ok(0));
+        assert_eq!(displacement(&ops, first), (third - 5 - first) as i32);
+        assert_eq!(displacement(&ops, third), -5);
+
+        // without a placeholder, undefined labels are still an error
+        let mut ops = x64::Assembler::new().unwrap();
+        ops.push_u32(0);
+        ops.weak_global_reloc("missing", 0, (0, 4));
+        assert_eq!(ops.commit(), Err(DynasmError::UnknownLabel(LabelKind::Global("missing"))));
+
+        // overwriting a reference pointed at the placeholder stops it from keeping its label in use
+        let mut ops = x64::Assembler::new().unwrap();
+        ops.push(0xCC);
+        ops.set_placeholder(AssemblyOffset(0));
+        let func = ops.new_dynamic_label();
+        ops.push_u32(0);
+        ops.weak_dynamic_reloc(func, 0, (0, 4));
+        ops.commit().unwrap();
+        assert_eq!(ops.free_dynamic_label(func), Err(DynasmError::LabelInUse(LabelKind::Dynamic(func))));
+        ops.patch(AssemblyOffset(1), &[0; 4]).unwrap();
+        ops.free_dynamic_label(func).unwrap();
+    }
+
+    #[cfg(all(feature = "std", target_arch = "x86_64"))]
+    #[test]
+    fn test_function() {
+        let mut ops = x64::Assembler::new().unwrap();
+        // mov eax, 1; ret
+        let one = ops.function("one", 1, |ops| ops.extend(&[0xB8, 1, 0, 0, 0, 0xC3])).unwrap();
+        // sub rsp, 8; call ->one; add rsp, 8; add eax, eax; ret
+        let two = ops.function("two", 16, |ops| {
+            ops.extend(&[0x48, 0x83, 0xEC, 0x08, 0xE8, 0, 0, 0, 0]);
+            ops.global_reloc("one", 0, (0, 4));
+            ops.extend(&[0x48, 0x83, 0xC4, 0x08, 0x01, 0xC0, 0xC3]);
+        }).unwrap();
+        assert_eq!(one, AssemblyOffset(0) .. AssemblyOffset(6));
+        assert_eq!(two, AssemblyOffset(16) .. AssemblyOffset(32));
+        assert_eq!(ops.functions(), &[("one", one), ("two", two.clone())]);
+
+        assert_eq!(ops.function("three", 3, |_| ()), Err(DynasmError::InvalidAlignment(3)));
+        assert_eq!(ops.function("one", 1, |_| ()), Err(DynasmError::DuplicateLabel(LabelKind::Global("one"))));
+        assert_eq!(ops.functions().len(), 2);
+
+        ops.commit().unwrap();
+        let reader = ops.reader();
+        let lock = reader.lock();
+        let f: extern "sysv64" fn() -> u32 = unsafe { mem::transmute(lock.ptr(two.start)) };
+        assert_eq!(f(), 2);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_copy_into() {
+        let mut ops = x64::Assembler::new().unwrap();
+        ops.data().label("value");
+        ops.data().push_u64(5);
+        // mov rax, [rip + value]; jmp ->end; ->end: ret
+        ops.extend(&[0x48, 0x8B, 0x05, 0, 0, 0, 0]);
+        ops.data_reloc("value", 0, (0, 4));
+        ops.push(0xE9);
+        ops.push_u32(0);
+        ops.global_reloc("end", 0, (0, 4));
+        ops.global_label("end");
+        ops.push(0xC3);
+        ops.commit().unwrap();
+
+        let mut small = [0; 12];
+        assert_eq!(ops.copy_into(&mut small), Err(DynasmError::BufferFull));
+
+        // the destination is mapped like the data, so the data stays within reach
+        let mut dst = mmap::MutableBuffer::new(16).unwrap();
+        dst.set_len(16);
+        dst.fill(0 .. 16, 0xCC);
+        ops.copy_into(&mut dst).unwrap();
+        let field = i32::from_le_bytes([dst[3], dst[4], dst[5], dst[6]]) as isize;
+        let data = ops.data().addr("value").unwrap() as isize;
+        assert_eq!(field, data - (dst.as_ptr() as isize + 7));
+        // references within the code don't change, and the rest of dst is left alone
+        assert_eq!(&dst[7 ..], &[0xE9, 0, 0, 0, 0, 0xC3, 0xCC, 0xCC, 0xCC]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_new_lazy() {
+        let mut ops = x64::Assembler::new_lazy();
+        ops.commit().unwrap();
+        assert_eq!(ops.memory.size(), 0);
+        assert!(ops.reader().lock().base().is_null());
+
+        ops.extend(&[0x90, 0xC3]);
+        ops.commit().unwrap();
+        assert_eq!(ops.memory.size(), mmap::page_size());
+        assert_eq!(&ops.reader().lock()[..], &[0x90, 0xC3]);
+
+        // an assembler that never emits anything is finalized into an empty buffer
+        let buf = x64::Assembler::new_lazy().finalize().unwrap();
+        assert_eq!(buf.size(), 0);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_insn_boundaries() {
+        let mut ops = x64::Assembler::new().unwrap();
+        // without recorded boundaries, anything can be patched
+        ops.extend(&[0x90, 0x90]);
+        ops.commit().unwrap();
+        assert!(ops.is_insn_boundary(AssemblyOffset(1)));
+
+        // mov eax, 1; nop; ret
+        ops.mark_insn_boundary();
+        ops.extend(&[0xB8, 1, 0, 0, 0]);
+        ops.mark_insn_boundary();
+        ops.push(0x90);
+        ops.mark_insn_boundary();
+        ops.push(0xC3);
+        ops.commit().unwrap();
+
+        assert!(ops.is_insn_boundary(AssemblyOffset(2)));
+        assert!(!ops.is_insn_boundary(AssemblyOffset(3)));
+        assert!(ops.is_insn_boundary(AssemblyOffset(9)));
+        assert_eq!(ops.patch(AssemblyOffset(3), &[2]), Err(DynasmError::CheckFailed));
+        assert_eq!(ops.patch(AssemblyOffset(2), &[0x90, 0x90]), Err(DynasmError::CheckFailed));
+        ops.patch(AssemblyOffset(2), &[0xB8, 2, 0, 0, 0]).unwrap();
+        ops.patch(AssemblyOffset(8), &[0xC3]).unwrap();
+        assert_eq!(ops.set_breakpoint(AssemblyOffset(4)), Err(DynasmError::CheckFailed));
+        ops.set_breakpoint(AssemblyOffset(7)).unwrap();
+
+        ops.alter(|modifier| {
+            modifier.goto(AssemblyOffset(3));
+            assert_eq!(modifier.check_insn_boundary(), Err(DynasmError::CheckFailed));
+            modifier.goto(AssemblyOffset(7));
+            assert_eq!(modifier.check_insn_boundary(), Ok(()));
+        }).unwrap();
+        assert_eq!(&ops.reader().lock()[2 ..], &[0xB8, 2, 0, 0, 0, 0xCC, 0xC3]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_default_reloc() {
+        let mut ops = x64::Assembler::new().unwrap();
+        ops.set_default_reloc((0, 4));
+        let id = ops.new_dynamic_label();
+        // jmp back; jmp fwd; jmp ->end; jmp =>id
+        ops.local_label("back");
+        ops.push(0xE9);
+        ops.push_u32(0);
+        ops.backward_reloc_default("back", 0);
+        ops.push(0xE9);
+        ops.push_u32(0);
+        ops.forward_reloc_default("fwd", 0);
+        ops.push(0xE9);
+        ops.push_u32(0);
+        ops.global_reloc_default("end", 0);
+        ops.push(0xE9);
+        ops.push_u32(0);
+        ops.dynamic_reloc_default(id, 0);
+        ops.local_label("fwd");
+        ops.global_label("end");
+        ops.dynamic_label(id);
+        ops.commit().unwrap();
+
+        let lock = ops.reader();
+        let lock = lock.lock();
+        let fields: Vec<i32> = (0 .. 4).map(|i| i32::from_le_bytes([lock[i * 5 + 1], lock[i * 5 + 2], lock[i * 5 + 3], lock[i * 5 + 4]])).collect();
+        assert_eq!(fields, [-5, 10, 5, 0]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    #[should_panic(expected = "No default relocation set")]
+    fn test_default_reloc_unset() {
+        let mut ops = x64::Assembler::new().unwrap();
+        ops.global_reloc_default("end", 0);
+    }
+
+    #[cfg(all(feature = "std", debug_assertions))]
+    #[test]
+    #[should_panic(expected = "the reference to target ->a at 0..4 overlaps the reference to target ->b at 2..4")]
+    fn test_overlapping_relocs() {
+        let mut ops = x64::Assembler::new().unwrap();
+        ops.global_label("a");
+        ops.global_label("b");
+        ops.push_u32(0);
+        ops.global_reloc("a", 0, (0, 4));
+        ops.global_reloc("b", 0, (0, 2));
+        let _ = ops.commit();
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_buffer_from_vec() {
+        use std::convert::TryFrom;
+
+        let buffer = ExecutableBuffer::from_slice(&[0x90, 0xC3]).unwrap();
+        assert_eq!(&buffer[..], &[0x90, 0xC3]);
+        assert_eq!(ExecutableBuffer::from_slice(&[]).unwrap().len(), 0);
+
+        // mov eax, 7; ret
+        let buffer = ExecutableBuffer::try_from(vec![0xB8, 7, 0, 0, 0, 0xC3]).unwrap();
+        assert_eq!(buffer.len(), 6);
+        #[cfg(target_arch = "x86_64")]
+        {
+            let f: extern "sysv64" fn() -> u32 = unsafe { mem::transmute(buffer.ptr(AssemblyOffset(0))) };
+            assert_eq!(f(), 7);
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_executor_with_bytes() {
+        let mut ops = x64::Assembler::new().unwrap();
+        let reader = ops.reader();
+        assert_eq!(reader.with_bytes(|code| code.len()), 0);
+
+        ops.extend(&[0x90, 0xC3]);
+        assert_eq!(reader.with_bytes(|code| code.len()), 0);
+        ops.commit().unwrap();
+        assert_eq!(reader.with_bytes(|code| code.to_vec()), vec![0x90, 0xC3]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_append_bytes() {
+        let mut ops = x64::Assembler::new().unwrap();
+        assert_eq!(ops.append_bytes(&[0x90, 0x90]), AssemblyOffset(0) .. AssemblyOffset(2));
+        ops.commit().unwrap();
+        assert_eq!(ops.append_bytes(&[1, 2, 3]), AssemblyOffset(2) .. AssemblyOffset(5));
+        assert_eq!(ops.append_bytes(&[]), AssemblyOffset(5) .. AssemblyOffset(5));
+        ops.commit().unwrap();
+        assert_eq!(&ops.reader().lock()[..], &[0x90, 0x90, 1, 2, 3]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_alloc_error() {
+        use std::error::Error;
+
+        assert!(x64::Assembler::try_new().is_ok());
+        let err = ExecutableBuffer::try_new(usize::MAX).unwrap_err();
+        assert!(matches!(err, AllocError::InvalidSize(usize::MAX)));
+        assert!(err.source().is_none());
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::InvalidInput);
+
+        let err = AllocError::ProtectFailed(io::Error::from(io::ErrorKind::PermissionDenied));
+        assert!(err.to_string().starts_with("Could not make memory executable"));
+        assert!(err.source().is_some());
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[cfg(all(feature = "std", target_arch = "x86_64"))]
+    #[test]
+    fn test_retpoline_thunks() {
+        let mut ops = x64::Assembler::new().unwrap();
+        let thunk = ops.retpoline_thunk(11);
+        assert_eq!(ops.retpoline_thunk(11), thunk);
+        assert_ne!(ops.retpoline_thunk(0), thunk);
+
+        // mov eax, 42; ret
+        let target = ops.new_dynamic_label();
+        ops.dynamic_label(target);
+        ops.extend(&[0xB8, 42, 0, 0, 0, 0xC3]);
+
+        // sub rsp, 8; lea r11, [=>target]; call =>thunk; add rsp, 8; ret
+        let start = ops.offset();
+        ops.extend(&[0x48, 0x83, 0xEC, 0x08, 0x4C, 0x8D, 0x1D, 0, 0, 0, 0]);
+        ops.dynamic_reloc(target, 0, (0, 4));
+        ops.extend(&[0xE8, 0, 0, 0, 0]);
+        ops.dynamic_reloc(thunk, 0, (0, 4));
+        ops.extend(&[0x48, 0x83, 0xC4, 0x08, 0xC3]);
+
+        // both thunks are placed once
+        ops.emit_retpoline_thunks();
+        let end = ops.offset();
+        ops.emit_retpoline_thunks();
+        assert_eq!(ops.offset(), end);
+        assert_eq!(ops.labels().resolve_dynamic(thunk), Ok(AssemblyOffset(end.0 - 17)));
+
+        ops.commit().unwrap();
+        let reader = ops.reader();
+        let lock = reader.lock();
+        let f: extern "sysv64" fn() -> u32 = unsafe { mem::transmute(lock.ptr(start)) };
+        assert_eq!(f(), 42);
+    }
+
+    #[cfg(all(feature = "std", target_arch = "x86_64"))]
+    #[test]
+    fn test_dev_mode() {
+        let mut ops = x64::Assembler::new_dev_mode().unwrap();
+        // mov eax, 1; ret
+        ops.extend(&[0xB8, 1, 0, 0, 0, 0xC3]);
+        ops.commit().unwrap();
+        assert!(ops.reader().lock().is_writable());
+
+        ops.patch(AssemblyOffset(1), &[2]).unwrap();
+        {
+            let reader = ops.reader();
+            let lock = reader.lock();
+            let f: extern "sysv64" fn() -> u32 = unsafe { mem::transmute(lock.ptr(AssemblyOffset(0))) };
+            assert_eq!(f(), 2);
+        }
+
+        // the buffer stays writable when it grows, and when the code is taken out of the assembler
+        let size = ops.memory.size();
+        ops.extend(&vec![0xCC; size]);
+        ops.commit().unwrap();
+        assert!(ops.memory.size() > size);
+        let buf = ops.finalize().unwrap();
+        assert!(buf.is_writable());
+        let f: extern "sysv64" fn() -> u32 = unsafe { mem::transmute(buf.ptr(AssemblyOffset(0))) };
+        assert_eq!(f(), 2);
+
+        assert!(!x64::Assembler::new().unwrap().reader().lock().is_writable());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_modifier_bounds() {
+        let mut ops = x64::Assembler::new().unwrap();
+        ops.extend(&[0x90, 0x90, 0xC3]);
+        ops.commit().unwrap();
+
+        // writes past the end of the committed code report the first offset that was out of bounds
+        let result = ops.alter(|modifier| {
+            modifier.extend(&[0xCC, 0xCC]);
+            assert_eq!(modifier.offset(), AssemblyOffset(2));
+            modifier.goto(AssemblyOffset(2));
+            assert_eq!(modifier.try_push(0xC3), Ok(()));
+            modifier.goto(AssemblyOffset(5));
+            assert_eq!(modifier.try_push(0xCC), Err(DynasmError::OutOfBounds(AssemblyOffset(5))));
+            modifier.extend(&[0xCC, 0xCC]);
+        });
+        assert_eq!(result, Err(DynasmError::OutOfBounds(AssemblyOffset(5))));
+        // the code stays available
+        assert_eq!(ops.committed_bytes()[..], [0xCC, 0xCC, 0xC3]);
+
+        ops.extend(&[0x90, 0x90]);
+        let mut modifier = ops.alter_uncommitted();
+        modifier.goto(AssemblyOffset(2));
+        assert_eq!(modifier.try_push(0xCC), Err(DynasmError::OutOfBounds(AssemblyOffset(2))));
+        modifier.goto(AssemblyOffset(4));
+        assert_eq!(modifier.try_push(0xCC), Ok(()));
+        assert_eq!(modifier.try_push(0xCC), Err(DynasmError::OutOfBounds(AssemblyOffset(5))));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_try_local_label() {
+        let mut ops = x64::Assembler::new().unwrap();
+        // jmp >near; jmp >far
+        ops.extend(&[0xEB, 0]);
+        ops.forward_reloc("near", 0, (0, 1));
+        ops.extend(&[0xEB, 0]);
+        ops.forward_reloc("far", 0, (0, 1));
+        ops.try_local_label("near").unwrap();
+        ops.extend(vec![0x90; 0x100]);
+
+        // the short jump can't reach, which is reported right away
+        assert_eq!(ops.try_local_label("far"), Err(DynasmError::ImpossibleRelocation(TargetKind::Forward("far"))));
+        assert_eq!(ops.labels().resolve_local("far"), Ok(AssemblyOffset(0x104)));
+        ops.commit().unwrap();
+        assert_eq!(ops.committed_bytes()[.. 2], [0xEB, 2]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_applied_relocs() {
+        // nothing is recorded by default
+        let mut ops = x86::Assembler::new().unwrap();
+        ops.extend(&[0xEB, 0]);
+        ops.global_reloc("top", 0, (0, 1, 0));
+        ops.global_label("top");
+        ops.commit().unwrap();
+        assert_eq!(ops.relocations().count(), 0);
+
+        let mut ops = x86::Assembler::with_config(AssemblerConfig::default().track_relocations(true)).unwrap();
+        ops.data().label("value");
+        ops.data().push_u64(0);
+
+        // top: jmp >next; next: jmp <top; mov rax, QWORD ->top; mov rax, [rip + value]
+        ops.local_label("top");
+        ops.extend(&[0xEB, 0]);
+        ops.forward_reloc("next", 0, (0, 1, 0));
+        ops.local_label("next");
+        ops.extend(&[0xEB, 0]);
+        ops.backward_reloc("top", 0, (0, 1, 0));
+        ops.extend(&[0x48, 0xB8, 0, 0, 0, 0, 0, 0, 0, 0]);
+        ops.global_reloc("top", 2, (0, 8, 1));
+        ops.extend(&[0x48, 0x8B, 0x05, 0, 0, 0, 0]);
+        ops.data_reloc("value", 0, (0, 4, 0));
+        ops.global_label("top");
+        ops.commit().unwrap();
+
+        let value = ops.data().addr("value").unwrap();
+        let relocs: Vec<_> = ops.relocations().cloned().collect();
+        assert_eq!(relocs, vec![
+            AppliedReloc {
+                field: AssemblyOffset(1) .. AssemblyOffset(2),
+                kind: RelocationKind::Relative,
+                target: TargetKind::Forward("next"),
+                target_offset: Some(AssemblyOffset(2)),
+                addend: 0,
+            },
+            AppliedReloc {
+                field: AssemblyOffset(3) .. AssemblyOffset(4),
+                kind: RelocationKind::Relative,
+                target: TargetKind::Backward("top"),
+                target_offset: Some(AssemblyOffset(0)),
+                addend: 0,
+            },
+            AppliedReloc {
+                field: AssemblyOffset(6) .. AssemblyOffset(14),
+                kind: RelocationKind::AbsToRel,
+                target: TargetKind::Global("top"),
+                target_offset: Some(AssemblyOffset(21)),
+                addend: 2,
+            },
+            AppliedReloc {
+                field: AssemblyOffset(17) .. AssemblyOffset(21),
+                kind: RelocationKind::Relative,
+                target: TargetKind::Extern(value),
+                target_offset: None,
+                addend: 0,
+            },
+        ]);
+
+        // overwritten references are forgotten, also when only part of their field is overwritten
+        ops.patch(AssemblyOffset(0), &[0x90, 0x90]).unwrap();
+        assert_eq!(ops.relocations().count(), 3);
+        ops.patch(AssemblyOffset(13), &[0x90, 0x90]).unwrap();
+        assert_eq!(ops.relocations().count(), 2);
+        ops.alter(|modifier| {
+            modifier.goto(AssemblyOffset(20));
+            modifier.push(0);
+        }).unwrap();
+        assert_eq!(ops.relocations().count(), 1);
+
+        // tracking survives a reset
+        ops.reset().unwrap();
+        ops.extend(&[0xEB, 0]);
+        ops.global_reloc("top", 0, (0, 1, 0));
+        ops.global_label("top");
+        ops.commit().unwrap();
+        assert_eq!(ops.relocations().count(), 1);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_diff_reloc() {
+        let mut ops = x64::Assembler::new().unwrap();
+        let base = ops.new_dynamic_label();
+        let cases = [ops.new_dynamic_label(), ops.new_dynamic_label()];
+
+        // a jump table of the distances from the base to each case, before any of them is defined
+        for &case in &cases {
+            ops.push_u32(0);
+            ops.diff_reloc(TargetKind::Dynamic(case), TargetKind::Dynamic(base), RelocationSize::DWord);
+        }
+        ops.push(0);
+        ops.diff_reloc(TargetKind::Global("end"), TargetKind::Global("start"), RelocationSize::Byte);
+
+        ops.global_label("start");
+        ops.dynamic_label(base);
+        ops.push(0x90);
+        ops.dynamic_label(cases[1]);
+        ops.push(0x90);
+        ops.dynamic_label(cases[0]);
+        ops.push(0xC3);
+        ops.global_label("end");
+        assert_eq!(ops.free_dynamic_label(base), Err(DynasmError::LabelInUse(LabelKind::Dynamic(base))));
+        ops.commit().unwrap();
+
+        assert_eq!(ops.committed_bytes()[.. 9], [2, 0, 0, 0, 1, 0, 0, 0, 3]);
+        ops.free_dynamic_label(base).unwrap();
+
+        // negative differences
+        ops.push(0);
+        ops.diff_reloc(TargetKind::Global("start"), TargetKind::Global("end"), RelocationSize::Byte);
+        ops.commit().unwrap();
+        assert_eq!(ops.committed_bytes()[12], 0xFD);
+
+        // only global and dynamic labels can be used, and the difference has to fit
+        ops.push(0);
+        ops.diff_reloc(TargetKind::Global("start"), TargetKind::Forward("end"), RelocationSize::Byte);
+        assert_eq!(ops.commit(), Err(DynasmError::ImpossibleRelocation(TargetKind::Forward("end"))));
+        ops.extend(vec![0x90; 0x100]);
+        ops.global_label("far");
+        ops.push(0);
+        ops.diff_reloc(TargetKind::Global("far"), TargetKind::Global("start"), RelocationSize::Byte);
+        assert_eq!(ops.commit(), Err(DynasmError::ImpossibleRelocation(TargetKind::Global("far"))));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_breakpoints() {
+        let mut ops = x64::Assembler::new().unwrap();
+        ops.breakpoint();
+        // mov eax, 1; ret
+        let start = ops.offset();
+        ops.extend(&[0xB8, 1, 0, 0, 0, 0xC3]);
+        assert_eq!(ops.set_breakpoint(start), Err(DynasmError::OutOfBounds(start)));
+        ops.commit().unwrap();
+
+        ops.set_breakpoint(start).unwrap();
+        // setting it twice keeps the original code around
+        ops.set_breakpoint(start).unwrap();
+        assert_eq!(ops.committed_bytes()[.. 3], [0xCC, 0xCC, 1]);
+
+        assert_eq!(ops.clear_breakpoint(start), Ok(true));
+        assert_eq!(ops.clear_breakpoint(start), Ok(false));
+        assert_eq!(ops.committed_bytes()[.. 3], [0xCC, 0xB8, 1]);
+        assert_eq!(ops.set_breakpoint(AssemblyOffset(7)), Err(DynasmError::OutOfBounds(AssemblyOffset(7))));
+
+        let mut ops = aarch64::Assembler::new().unwrap();
+        // ret
+        ops.extend(&[0xC0, 0x03, 0x5F, 0xD6]);
+        ops.commit().unwrap();
+        ops.set_breakpoint(AssemblyOffset(0)).unwrap();
+        assert_eq!(ops.committed_bytes()[..], [0x00, 0x00, 0x20, 0xD4]);
+        ops.clear_breakpoint(AssemblyOffset(0)).unwrap();
+        assert_eq!(ops.committed_bytes()[..], [0xC0, 0x03, 0x5F, 0xD6]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_offset_stability() {
+        let mut ops = x64::Assembler::new().unwrap();
+        let first = ops.put(&[0x11, 0x22]);
+        ops.commit().unwrap();
+        let second = ops.put(&[0x33]);
+        assert_eq!(second, AssemblyOffset(2));
+
+        // offsets keep addressing the same bytes after a commit that moves the buffer
+        ops.extend(vec![0x90; 0x2000]);
+        let third = ops.put(&[0x44]);
+        ops.commit().unwrap();
+        {
+            let reader = ops.reader();
+            let lock = reader.lock();
+            assert_eq!(lock[first.0 .. first.0 + 2], [0x11, 0x22]);
+            assert_eq!(lock[second.0], 0x33);
+            assert_eq!(lock[third.0], 0x44);
+        }
+
+        // the modifier reports the same offsets, and leaves the assembler offset alone
+        let end = ops.offset();
+        ops.alter(|modifier| {
+            modifier.goto(second);
+            assert_eq!(modifier.offset(), second);
+            modifier.push(0x55);
+            assert_eq!(modifier.offset(), second + 1);
+        }).unwrap();
+        assert_eq!(ops.offset(), end);
+        assert_eq!(ops.reader().lock()[second.0], 0x55);
+
+        // restoring a snapshot only changes the bytes at the offsets
+        let snapshot = ops.snapshot();
+        ops.patch(second, &[0x66]).unwrap();
+        ops.restore(&snapshot).unwrap();
+        assert_eq!(ops.offset(), end);
+        assert_eq!(ops.reader().lock()[second.0], 0x55);
+    }
+
+    #[cfg(all(feature = "std", target_arch = "x86_64"))]
+    #[test]
+    fn test_trampolines() {
+        extern "sysv64" fn answer() -> u64 {
+            42
+        }
+        extern "sysv64" fn double(value: u64) -> u64 {
+            value * 2
+        }
+
+        let mut ops = x64::Assembler::new().unwrap();
+        let mut trampolines = x64::Trampolines::new(11);
+        let start = ops.offset();
+        // sub rsp, 8
+        ops.extend(&[0x48, 0x83, 0xEC, 0x08]);
+        trampolines.call(&mut ops, answer as usize);
+        // add rsp, 8; mov rdi, rax
+        ops.extend(&[0x48, 0x83, 0xC4, 0x08, 0x48, 0x89, 0xC7]);
+        trampolines.jump(&mut ops, double as usize);
+        // both targets get their own trampoline
+        let island = ops.offset();
+        trampolines.emit(&mut ops);
+        assert_eq!(ops.offset().0 - island.0, 2 * 13);
+        ops.commit().unwrap();
+
+        {
+            let reader = ops.reader();
+            let lock = reader.lock();
+            // mov r11, imm64; jmp r11
+            assert_eq!(lock[island.0 .. island.0 + 2], [0x49, 0xBB]);
+            assert_eq!(lock[island.0 + 10 .. island.0 + 13], [0x41, 0xFF, 0xE3]);
+        }
+
+        // the trampolines keep working after the code has moved
+        ops.grow_to(0x10000).unwrap();
+        let buf = ops.finalize().unwrap();
+        let f: extern "sysv64" fn() -> u64 = unsafe { mem::transmute(buf.ptr(start)) };
+        assert_eq!(f(), 84);
+    }
+
+    #[cfg(all(feature = "std", target_arch = "x86_64"))]
+    #[test]
+    fn test_rip_relative_data() {
+        let mut ops = x64::Assembler::new().unwrap();
+        let start = ops.offset();
+        // lea rcx, [rip + data]
+        ops.extend(&[0x48, 0x8D, 0x0D, 0, 0, 0, 0]);
+        ops.forward_reloc("data", 0, (0, 4));
+        // mov rax, [rip + data]
+        ops.extend(&[0x48, 0x8B, 0x05, 0, 0, 0, 0]);
+        ops.forward_reloc("data", 0, (0, 4));
+        // ret
+        ops.push(0xC3);
+        ops.align(8, 0xCC);
+        ops.local_label("data");
+        ops.push_u64(0x0123_4567_89AB_CDEF);
+        ops.commit().unwrap();
+
+        let buf = ops.finalize().unwrap();
+        // displacements are relative to the end of each instruction
+        assert_eq!(&buf[3 .. 7], &9i32.to_le_bytes());
+        assert_eq!(&buf[10 .. 14], &2i32.to_le_bytes());
+
+        let f: extern "sysv64" fn() -> u64 = unsafe { mem::transmute(buf.ptr(start)) };
+        assert_eq!(f(), 0x0123_4567_89AB_CDEF);
+
+        // data in its own mapping is reached the same way
+        let mut ops = x64::Assembler::new().unwrap();
+        ops.data().label("data");
+        ops.data().push_u64(0x0123_4567_89AB_CDEF);
+        let start = ops.offset();
+        // lea rcx, [rip + data]; mov rax, [rip + data]; ret
+        ops.extend(&[0x48, 0x8D, 0x0D, 0, 0, 0, 0]);
+        ops.data_reloc("data", 0, (0, 4));
+        ops.extend(&[0x48, 0x8B, 0x05, 0, 0, 0, 0]);
+        ops.data_reloc("data", 0, (0, 4));
+        ops.push(0xC3);
+        ops.commit().unwrap();
+
+        let data = ops.data().addr("data").unwrap();
+        let buf = ops.finalize().unwrap();
+        let code = buf.ptr(start) as usize;
+        assert_eq!(&buf[3 .. 7], &(data.wrapping_sub(code + 7) as i32).to_le_bytes());
+        assert_eq!(&buf[10 .. 14], &(data.wrapping_sub(code + 14) as i32).to_le_bytes());
+        let f: extern "sysv64" fn() -> u64 = unsafe { mem::transmute(buf.ptr(start)) };
+        assert_eq!(f(), 0x0123_4567_89AB_CDEF);
+
+        // a displacement that doesn't fit in 32 bits fails the commit
+        let mut ops = x64::Assembler::new().unwrap();
+        ops.push(0x90);
+        ops.commit().unwrap();
+        let target = ops.reader().lock().ptr(AssemblyOffset(0)) as usize ^ (1 << 40);
+        // lea rcx, [rip + target]
+        ops.extend(&[0x48, 0x8D, 0x0D, 0, 0, 0, 0]);
+        ops.bare_reloc(target, (0, 4));
+        match ops.commit() {
+            Err(DynasmError::ImpossibleRelocation(TargetKind::Extern(t))) => assert_eq!(t, target),
+            r => panic!("unexpected commit result {:?}", r),
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_buffer_fill() {
+        let mut buffer = mmap::MutableBuffer::new(0x1000).unwrap();
+        buffer.set_len(0x1000);
+        assert!(buffer.iter().all(|&b| b == 0));
+
+        buffer.fill(0x10 .. 0x20, 0xCC);
+        assert!(buffer[.. 0x10].iter().all(|&b| b == 0));
+        assert!(buffer[0x10 .. 0x20].iter().all(|&b| b == 0xCC));
+        assert!(buffer[0x20 ..].iter().all(|&b| b == 0));
+
+        // growing the assembler's buffer leaves the unused tail zeroed
+        let mut ops = x64::Assembler::new().unwrap();
+        ops.extend(vec![0xCC; 0x1001]);
+        ops.commit().unwrap();
+        let mut buffer = ops.finalize().unwrap().make_mut().unwrap();
+        let size = buffer.size();
+        buffer.set_len(size);
+        assert!(buffer[0x1001 ..].iter().all(|&b| b == 0));
+    }
+
+    #[cfg(all(feature = "std", target_arch = "x86_64"))]
+    #[test]
+    fn test_data_builder() {
+        let mut ops = x64::Assembler::new().unwrap();
+        ops.data().bytes(b"padding");
+        ops.data().align(8, 0);
+        ops.data().label("value");
+        ops.data().push_u64(0x0123_4567_89AB_CDEF);
+
+        let start = ops.offset();
+        // mov rax, [rip + value]
+        ops.extend(&[0x48, 0x8B, 0x05, 0, 0, 0, 0]);
+        ops.data_reloc("value", 0, (0, 4));
+        // ret
+        ops.push(0xC3);
+        ops.commit().unwrap();
+
+        let addr = ops.data().addr("value").unwrap();
+        assert!(addr & 7 == 0);
+        let call = |ops: &x64::Assembler| {
+            let lock = ops.reader();
+            let lock = lock.lock();
+            let f: extern "sysv64" fn() -> u64 = unsafe { mem::transmute(lock.ptr(start)) };
+            f()
+        };
+        assert_eq!(call(&ops), 0x0123_4567_89AB_CDEF);
+
+        // the reference is updated when the code moves
+        ops.extend(vec![0xCC; 0x2000]);
+        ops.commit().unwrap();
+        assert_eq!(call(&ops), 0x0123_4567_89AB_CDEF);
+        assert_eq!(ops.data().addr("value"), Some(addr));
+
+        ops.data().push(0);
+        ops.data().label("value");
+        assert_eq!(ops.commit(), Err(DynasmError::DuplicateLabel(LabelKind::Global("value"))));
+        ops.data_reloc("missing", 0, (0, 4));
+        assert_eq!(ops.commit(), Err(DynasmError::UnknownLabel(LabelKind::Global("missing"))));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_with_initial_size() {
+        let page_size = mmap::page_size();
+        assert!(page_size.is_power_of_two());
+
+        let mut ops = x64::Assembler::with_initial_size(page_size + 1).unwrap();
+        ops.extend(vec![0xCC; page_size * 2]);
+        let addr = ops.reader().lock().as_ptr();
+        ops.commit().unwrap();
+        // no reallocation was necessary
+        assert_eq!(ops.reader().lock().as_ptr(), addr);
+        assert_eq!(ops.finalize().unwrap().size(), page_size * 2);
+
+        assert_eq!(x64::Assembler::with_initial_size(0).unwrap().finalize().unwrap().size(), page_size);
+    }
+
+    #[test]
+    fn test_wide_relocations() {
+        let mut buf = [0xAAu8; 16];
+        RelocationSize::OWord.write_value(&mut buf, 0x1234).unwrap();
+        assert_eq!(&buf, &[0x34, 0x12, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(RelocationSize::OWord.read_value(&buf), 0x1234);
+        assert!(RelocationSize::TByte.write_value(&mut buf[.. 10], -1).is_err());
+
+        let mut buffer = [0u8; 32];
+        let mut ops = SliceAssembler::<RelocationSize>::new(&mut buffer);
+        ops.extend(&[0xFF; 10]);
+        ops.forward_relocation("target", 0, RelocationSize::TByte);
+        ops.push(0);
+        ops.local_label("target");
+        let buffer = ops.finalize().unwrap();
+        assert_eq!(&buffer[.. 10], &[11, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Assembler dropped with pending relocations: the reference to target ->missing at AssemblyOffset(5) was never resolved")]
+    fn test_drop_pending() {
+        let mut ops = x64::Assembler::new().unwrap();
+        ops.global_label("start");
+        ops.push(0xE9);
+        ops.push_u32(0);
+        ops.global_reloc("missing", 0, (0, 4));
+        drop(ops);
+    }
+
+    #[test]
+    fn test_drop_discarded() {
+        // references that were resolved, reported by a failed commit, or discarded don't cause a panic
+        let mut ops = x64::Assembler::new().unwrap();
+        ops.push_u32(0);
+        ops.global_reloc("later", 0, (0, 4));
+        ops.global_label("later");
+        ops.commit().unwrap();
+        drop(ops);
+
+        let mut ops = x64::Assembler::new().unwrap();
+        ops.push_u32(0);
+        let undefined = ops.new_dynamic_label();
+        ops.dynamic_reloc(undefined, 0, (0, 4));
+        ops.push_u32(0);
+        ops.global_reloc("missing", 0, (0, 4));
+        assert!(ops.commit().is_err());
+        drop(ops);
+
+        let mut ops = x64::Assembler::new().unwrap();
+        ops.push_u32(0);
+        ops.forward_reloc("missing", 0, (0, 4));
+        ops.reset().unwrap();
+        drop(ops);
+    }
+
+    #[test]
+    fn test_dump_labels() {
+        use crate::x64::X64Relocation;
+
+        let mut ops = x64::Assembler::new().unwrap();
+        let defined = ops.new_dynamic_label();
+        let undefined = ops.new_dynamic_label();
+        ops.global_label("start");
+        ops.push(0);
+        ops.dynamic_label(defined);
+        ops.push(0);
+        ops.forward_relocation("next", 0, X64Relocation::from_size(RelocationSize::Byte));
+        ops.push_u32(0);
+        ops.dynamic_relocation(undefined, 0, X64Relocation::from_size(RelocationSize::DWord));
+        ops.push_u32(0);
+        ops.global_relocation("end", 0, X64Relocation::from_size(RelocationSize::DWord));
+
+        let mut out = Vec::new();
+        ops.dump_labels(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "\
+labels:
+    label ->start: 0x0
+    label =>0: 0x1
+    label =>1: undefined
+    label ->end: undefined
+    label next: undefined
+relocations:
+    0xa: 4 bytes, target ->end
+    0x6: 4 bytes, target =>1
+    0x2: 1 bytes, target >next
+");
+        // the labels are never defined, which would fail when dropping the assembler
+        ops.reset().unwrap();
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_goto_label() {
+        let mut ops = x64::Assembler::new().unwrap();
+        let site = ops.new_dynamic_label();
+        let undefined = ops.new_dynamic_label();
+        ops.push(0x90);
+        ops.dynamic_label(site);
+        ops.push(0x90);
+        ops.global_label("end");
+        ops.push(0xC3);
+
+        ops.alter(|modifier| {
+            modifier.goto_label(site).unwrap();
+            modifier.push(0xCC);
+            modifier.goto_global("end").unwrap();
+            modifier.check_exact(AssemblyOffset(2)).unwrap();
+            modifier.check_aligned(2).unwrap();
+            assert_eq!(modifier.check_aligned(4), Err(DynasmError::CheckFailed));
+            assert_eq!(modifier.check_aligned(0), Err(DynasmError::InvalidAlignment(0)));
+            assert_eq!(modifier.goto_label(undefined), Err(DynasmError::UnknownLabel(LabelKind::Dynamic(undefined))));
+        }).unwrap();
+        assert_eq!(&ops.reader().lock()[..], &[0x90, 0xCC, 0xC3]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_snapshot() {
+        let mut ops = x64::Assembler::new().unwrap();
+        ops.extend(&[0x90, 0x90, 0xC3]);
+        ops.commit().unwrap();
+        let snapshot = ops.snapshot();
+        assert_eq!(&snapshot[..], &[0x90, 0x90, 0xC3]);
+
+        ops.patch(AssemblyOffset(0), &[0xCC, 0xCC]).unwrap();
+        ops.restore(&snapshot).unwrap();
+        assert_eq!(&ops.reader().lock()[..], &[0x90, 0x90, 0xC3]);
+
+        ops.push(0xC3);
+        ops.commit().unwrap();
+        assert_eq!(ops.restore(&snapshot), Err(DynasmError::CheckFailed));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_restore_after_move() {
+        use byteorder::{ByteOrder, LittleEndian};
+
+        // mov rax, QWORD ->target; ret
+        let mut ops = x86::Assembler::new().unwrap();
+        ops.extend(&[0x48, 0xB8, 0, 0, 0, 0, 0, 0, 0, 0]);
+        ops.global_reloc("target", 0, (0, 8, 1));
+        ops.global_label("target");
+        ops.push(0xC3);
+        ops.commit().unwrap();
+        let snapshot = ops.snapshot();
+
+        ops.grow_to(0x10000).unwrap();
+        ops.restore(&snapshot).unwrap();
+        let reader = ops.reader();
+        let lock = reader.lock();
+        assert_eq!(LittleEndian::read_u64(&lock[2 .. 10]), lock.base() as u64 + 10);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_io_write() {
+        use std::io::Write;
+
+        let mut ops = x64::Assembler::new().unwrap();
+        ops.write_all(&[0x90, 0x90, 0x90]).unwrap();
+        {
+            let mut modifier = ops.alter_uncommitted();
+            modifier.goto(AssemblyOffset(1));
+            assert_eq!(modifier.write(&[0xCC, 0xCC, 0xCC]).unwrap(), 2);
+            assert_eq!(modifier.check_aligned(2), Err(DynasmError::CheckFailed));
+            modifier.check_aligned(3).unwrap();
+            modifier.goto(AssemblyOffset(5));
+            assert_eq!(modifier.write(&[0xCC]).unwrap(), 0);
+        }
+        ops.flush().unwrap();
+        assert_eq!(&ops.reader().lock()[..], &[0x90, 0xCC, 0xCC]);
+
+        ops.alter(|modifier| {
+            modifier.write_all(&[0xC3]).unwrap();
+            // the code grows when writing past its end
+            modifier.write_all(&[0; 3]).unwrap();
+            modifier.goto(AssemblyOffset(5));
+            assert_eq!(modifier.write(&[0xCC]).unwrap(), 0);
+        }).unwrap();
+        assert_eq!(&ops.reader().lock()[..], &[0xC3, 0, 0, 0]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_resolve_address() {
+        let mut ops = x64::Assembler::new().unwrap();
+        ops.extend(&[0x90, 0xC3]);
+        ops.commit().unwrap();
+
+        let reader = ops.reader();
+        let base = reader.lock().base();
+        assert!(!base.is_null());
+        assert_eq!(base, reader.lock().ptr(AssemblyOffset(0)));
+        assert_eq!(ops.resolve_address(AssemblyOffset(1)), reader.ptr_at(AssemblyOffset(1)).unwrap());
+        assert!(mmap::ExecutableBuffer::new(0).unwrap().base().is_null());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_assembled_bytes() {
+        let mut ops = x64::Assembler::new().unwrap();
+        ops.extend(&[0x90, 0x90]);
+        assert_eq!(ops.uncommitted_bytes(), &[0x90, 0x90]);
+        assert!(ops.committed_bytes().is_empty());
+        ops.commit().unwrap();
+        ops.push(0xC3);
+        assert_eq!(ops.uncommitted_bytes(), &[0xC3]);
+        assert_eq!(&ops.committed_bytes()[..], &[0x90, 0x90]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_is_executable() {
+        let mut ops = x64::Assembler::new().unwrap();
+        let reader = ops.reader();
+        ops.push(0xC3);
+        assert!(!reader.is_executable(AssemblyOffset(0)));
+        ops.commit().unwrap();
+        assert!(reader.is_executable(AssemblyOffset(0)));
+        assert!(!reader.is_executable(AssemblyOffset(1)));
+        assert_eq!(reader.ptr_at(AssemblyOffset(1)), Err(DynasmError::OutOfBounds(AssemblyOffset(1))));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_commit_aligned() {
+        let mut ops = x64::Assembler::new().unwrap();
+        ops.extend(&[0x90, 0xC3]);
+        ops.commit_aligned(64).unwrap();
+        assert_eq!(ops.offset(), AssemblyOffset(64));
+        assert_eq!(&ops.reader().lock()[..3], &[0x90, 0xC3, 0]);
+
+        // growing the buffer keeps the next function aligned
+        let old_base = ops.reader().lock().base() as usize;
+        ops.extend(vec![0xCC; 0x1001]);
+        ops.commit_aligned(16).unwrap();
+        let start = ops.offset();
+        assert!(start.is_aligned_to(16));
+        ops.push(0xC3);
+        ops.commit().unwrap();
+        assert_eq!(ops.resolve_address(start) as usize % 16, 0);
+
+        // as both the old and new buffer are page aligned, earlier aligned code stays aligned as well
+        let new_base = ops.reader().lock().base() as usize;
+        assert_ne!(old_base, new_base);
+        assert_eq!(old_base % mmap::page_size(), 0);
+        assert_eq!(new_base % mmap::page_size(), 0);
+        assert_eq!(ops.resolve_address(AssemblyOffset(64)) as usize % 64, 0);
+
+        assert_eq!(ops.commit_aligned(24), Err(DynasmError::InvalidAlignment(24)));
+        assert_eq!(ops.commit_aligned(mmap::page_size() * 2), Err(DynasmError::InvalidAlignment(mmap::page_size() * 2)));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_grow_to() {
+        let mut ops = x64::Assembler::with_initial_size(0x1000).unwrap();
+        ops.extend(vec![0x90; 0x100]);
+        assert_eq!(ops.remaining_capacity(), 0xF00);
+        ops.commit().unwrap();
+        assert_eq!(ops.remaining_capacity(), 0xF00);
+
+        let before = ops.reader().lock().base();
+        ops.grow_to(0x4000).unwrap();
+        assert_eq!(ops.remaining_capacity(), 0x3F00);
+        assert_ne!(ops.reader().lock().base(), before);
+        assert_eq!(&ops.reader().lock()[..], &[0x90; 0x100][..]);
+
+        // growing to a smaller size does nothing, and the next commit doesn't have to move the buffer
+        let before = ops.reader().lock().base();
+        ops.grow_to(0x1000).unwrap();
+        ops.extend(vec![0xCC; 0x3000]);
+        ops.commit().unwrap();
+        assert_eq!(ops.reader().lock().base(), before);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_symbol_map() {
+        let mut ops = x64::Assembler::new().unwrap();
+        // undefined dynamic labels have no symbol
+        let _ = ops.new_dynamic_label();
+        let label = ops.new_dynamic_label();
+        ops.global_label("start");
+        ops.push(0x90);
+        ops.local_label("loop");
+        ops.dynamic_label(label);
+        ops.push(0x90);
+        ops.global_label("end");
+        ops.push(0xC3);
+        let named = ops.named_dynamic_label(&format!("fn_{}", 1));
+        ops.dynamic_label(named);
+
+        assert_eq!(ops.symbol_map(), vec![
+            (AssemblyOffset(0), "start".to_string()),
+            (AssemblyOffset(1), format!("dynamic_{}", label.get_id())),
+            (AssemblyOffset(2), "end".to_string()),
+            (AssemblyOffset(3), "fn_1".to_string()),
+        ]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_splice() {
+        use crate::x64::X64Relocation;
+
+        // call rel32; jmp rel32 back to the start of the blob; ret
+        let blob = [0xE8, 0, 0, 0, 0, 0xE9, 0xF6, 0xFF, 0xFF, 0xFF, 0xC3];
+        let relocs = [
+            BlobReloc { offset: 5, target: TargetKind::Global("target"), addend: 0, relocation: X64Relocation::from_encoding((0, 4)) },
+            BlobReloc { offset: 12, target: TargetKind::Global("target"), addend: 0, relocation: X64Relocation::from_encoding((0, 4)) },
+        ];
+        let mut ops = x64::Assembler::new().unwrap();
+        ops.extend(&[0x90, 0x90]);
+        ops.splice(&blob, &relocs[.. 1]).unwrap();
+        ops.global_label("target");
+        ops.push(0xC3);
+        ops.commit().unwrap();
+
+        let buf = ops.reader().lock().to_vec();
+        // the call now reaches the label, while the internal jump still targets the start of the blob
+        assert_eq!(&buf[2 .. 7], &[0xE8, 6, 0, 0, 0]);
+        assert_eq!(&buf[7 .. 13], &blob[5 ..]);
+
+        assert_eq!(ops.splice(&blob, &relocs[1 ..]), Err(DynasmError::OutOfBounds(AssemblyOffset(26))));
+        let managed = BlobReloc { offset: 5, target: TargetKind::Managed, addend: 0, relocation: X64Relocation::from_encoding((0, 4)) };
+        assert_eq!(ops.splice(&blob, &[managed]), Err(DynasmError::CheckFailed));
+        assert_eq!(ops.offset(), AssemblyOffset(14));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_zero_on_drop() {
+        // freed memory can't be inspected, so this exercises the paths that zero buffers on drop
+        let mut ops = x64::Assembler::with_initial_size(0x1000).unwrap();
+        ops.set_zero_on_drop(true);
+        ops.extend(vec![0xCC; 0x10]);
+        ops.commit().unwrap();
+        ops.grow_to(0x2000).unwrap();
+        ops.patch(AssemblyOffset(0), &[0xC3]).unwrap();
+
+        let buffer = ops.finalize().unwrap();
+        assert_eq!(buffer[.. 2], [0xC3, 0xCC]);
+        let mut buffer = buffer.make_mut().unwrap();
+        buffer[1] = 0xC3;
+        drop(buffer.make_exec().unwrap());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_from_buffer() {
+        let mut ops = x64::Assembler::new().unwrap();
+        // mov eax, 7; ret
+        ops.extend(&[0xB8, 7, 0, 0, 0, 0xC3]);
+        let buffer = ops.finalize().unwrap();
+
+        let mut ops = x64::Assembler::from_buffer(buffer);
+        assert_eq!(ops.offset(), AssemblyOffset(6));
+        ops.labels_mut().define_global("seven", AssemblyOffset(0)).unwrap();
+
+        // jmp ->seven, past the end of the initial page so the adopted code moves
+        ops.extend(vec![0xCC; 0x1000]);
+        let start = ops.offset();
+        ops.push(0xE9);
+        ops.extend(&[0, 0, 0, 0]);
+        ops.global_reloc("seven", 0, (0, 4));
+        ops.commit().unwrap();
+
+        let reader = ops.reader();
+        let lock = reader.lock();
+        let f: extern "C" fn() -> u32 = unsafe { mem::transmute(lock.ptr(start)) };
+        assert_eq!(f(), 7);
+
+        let mut ops = x64::Assembler::from_buffer(ExecutableBuffer::new(0).unwrap());
+        ops.extend(&[0xC3]);
+        ops.commit().unwrap();
+        assert_eq!(ops.reader().lock()[..], [0xC3]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_failed_allocation() {
+        let mut ops = x64::Assembler::with_initial_size(0x1000).unwrap();
+        ops.extend(&[0x90, 0xC3]);
+        ops.commit().unwrap();
+
+        // the allocation fails, but the assembler stays usable
+        let base = ops.reader().lock().base();
+        assert_eq!(ops.grow_to(isize::MAX as usize), Err(DynasmError::BufferFull));
+        assert_eq!(ops.reader().lock().base(), base);
+        assert_eq!(ops.remaining_capacity(), 0x1000 - 2);
+
+        ops.extend(vec![0xCC; 0x1000]);
+        ops.commit().unwrap();
+        assert_eq!(ops.reader().lock()[.. 3], [0x90, 0xC3, 0xCC]);
+    }
+
+    #[test]
+    fn test_align_code() {
+        let mut buffer = [0u8; 32];
+        let mut ops = x64::SliceAssembler::new(&mut buffer);
+        ops.push(0xC3);
+        x64::align_code(&mut ops, 16);
+        assert_eq!(ops.offset(), AssemblyOffset(16));
+        x64::align_code(&mut ops, 16);
+        assert_eq!(ops.offset(), AssemblyOffset(16));
+        x86::nop_sled(&mut ops, 3);
+        assert_eq!(ops.finalize().unwrap(), &[
+            0xC3,
+            0x66, 0x0F, 0x1F, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x66, 0x0F, 0x1F, 0x44, 0x00, 0x00,
+            0x0F, 0x1F, 0x00,
+        ][..]);
+    }
+
+    #[test]
+    fn test_try_align() {
+        let mut ops = VecAssembler(Vec::new());
+        ops.push(0xAA);
+        assert_eq!(ops.try_align(0, 0), Err(DynasmError::InvalidAlignment(0)));
+        assert_eq!(ops.try_align(6, 0), Err(DynasmError::InvalidAlignment(6)));
+        assert_eq!(ops.offset(), AssemblyOffset(1));
+        ops.try_align(4, 0).unwrap();
+        assert_eq!(ops.offset(), AssemblyOffset(4));
+        ops.align_to_power_of_two(8, 0);
+        assert_eq!(ops.offset(), AssemblyOffset(8));
+
+        // align itself supports any non-zero alignment
+        ops.align(6, 0);
+        assert_eq!(ops.offset(), AssemblyOffset(12));
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic]
+    fn test_align_to_power_of_two_checked() {
+        let mut ops = VecAssembler(Vec::new());
+        ops.align_to_power_of_two(6, 0);
+    }
+
+    // a relocation that encodes the distance to its target in halfwords, in a 16-bit field
+    // preceded by a single opcode byte, relative to the start of the instruction.
+    #[derive(Debug, Clone)]
+    struct HalfwordRelocation;
+
+    impl Relocation for HalfwordRelocation {
+        type Encoding = ();
+        fn from_encoding(_: ()) -> Self {
+            HalfwordRelocation
+        }
+        fn from_size(_: RelocationSize) -> Self {
+            HalfwordRelocation
+        }
+        fn start_offset(&self) -> usize {
+            3
+        }
+        fn size(&self) -> usize {
+            2
+        }
+        fn write_value(&self, buf: &mut [u8], value: isize) -> Result<(), ImpossibleRelocation> {
+            if value % 2 != 0 {
+                return Err(ImpossibleRelocation {});
+            }
+            RelocationSize::Word.write_value(buf, value / 2)
+        }
+        fn read_value(&self, buf: &[u8]) -> isize {
+            RelocationSize::Word.read_value(buf) * 2
+        }
+        fn kind(&self) -> RelocationKind {
+            RelocationKind::Relative
+        }
+        fn page_size() -> usize {
+            4096
+        }
+    }
+
+    #[test]
+    fn test_custom_relocation() {
+        let mut buffer = [0u8; 12];
+        let mut ops = SliceAssembler::<HalfwordRelocation>::new(&mut buffer);
+        ops.local_label("back");
+        ops.extend(&[0xB0, 0, 0]);
+        ops.backward_reloc("back", 0, ());
+        ops.extend(&[0xB1, 0, 0]);
+        ops.forward_reloc("fwd", 0, ());
+        ops.push(0xAA);
+        ops.local_label("fwd");
+        ops.extend(&[0xB2, 0, 0]);
+        ops.forward_reloc("odd", 0, ());
+        ops.extend(&[0xAA, 0xAA]);
+        ops.local_label("odd");
+
+        assert_eq!(ops.finalize(), Err(DynasmError::ImpossibleRelocation(TargetKind::Forward("odd"))));
+        assert_eq!(buffer[.. 10], [0xB0, 0, 0, 0xB1, 2, 0, 0xAA, 0xB2, 0, 0]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_rebase() {
+        use byteorder::{ByteOrder, LittleEndian};
+
+        // mov rax, QWORD ->target; ret
+        let mut ops = x86::Assembler::new().unwrap();
+        ops.extend(&[0x48, 0xB8, 0, 0, 0, 0, 0, 0, 0, 0]);
+        ops.global_reloc("target", 0, (0, 8, 1));
+        ops.global_label("target");
+        ops.push(0xC3);
+        let (buffer, relocs) = ops.finalize_relocatable().unwrap();
+
+        let old_addr = buffer.base() as usize;
+        assert_eq!(LittleEndian::read_u64(&buffer[2 .. 10]), old_addr as u64 + 10);
+
+        let mut copy = mmap::MutableBuffer::new(buffer.len()).unwrap();
+        copy.set_len(buffer.len());
+        copy.copy_from_slice(&buffer);
+        let new_addr = copy.as_ptr() as usize;
+        relocs.rebase(&mut copy, old_addr, new_addr).unwrap();
+        assert_eq!(LittleEndian::read_u64(&copy[2 .. 10]), new_addr as u64 + 10);
+        assert_eq!(copy[10], 0xC3);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_try_clone() {
+        use byteorder::{ByteOrder, LittleEndian};
+
+        // mov rax, QWORD ->target; ret
+        let mut ops = x86::Assembler::new().unwrap();
+        ops.extend(&[0x48, 0xB8, 0, 0, 0, 0, 0, 0, 0, 0]);
+        ops.global_reloc("target", 0, (0, 8, 1));
+        ops.global_label("target");
+        ops.push(0xC3);
+        let (buffer, relocs) = ops.finalize_relocatable().unwrap();
+        let old_addr = buffer.base() as usize;
+
+        // a plain copy keeps referencing the original
+        let copy = buffer.try_clone().unwrap();
+        assert_eq!(copy.size(), buffer.size());
+        assert_ne!(copy.base(), buffer.base());
+        assert_eq!(&copy[..], &buffer[..]);
+        assert_eq!(LittleEndian::read_u64(&copy[2 .. 10]), old_addr as u64 + 10);
+
+        let copy = buffer.try_clone_relocatable(&relocs).unwrap();
+        assert_eq!(LittleEndian::read_u64(&copy[2 .. 10]), copy.base() as u64 + 10);
+        assert_eq!(LittleEndian::read_u64(&buffer[2 .. 10]), old_addr as u64 + 10);
+        assert_eq!(copy[10], 0xC3);
+
+        // code referencing data can't be copied
+        let mut ops = x64::Assembler::new().unwrap();
+        ops.data().push_u64(0);
+        ops.push(0xC3);
+        let buffer = ops.finalize().unwrap();
+        assert_eq!(buffer.try_clone().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    // the read, write and execute permissions of the mapping containing `addr`, as listed in /proc/self/maps
+    #[cfg(all(feature = "std", target_os = "linux"))]
+    fn permissions(addr: usize) -> String {
+        let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+        for line in maps.lines() {
+            let mut fields = line.split(' ');
+            let mut range = fields.next().unwrap().split('-').map(|a| usize::from_str_radix(a, 16).unwrap());
+            let (start, end) = (range.next().unwrap(), range.next().unwrap());
+            if start <= addr && addr < end {
+                return fields.next().unwrap()[.. 3].to_string();
+            }
+        }
+        panic!("Address {:x} is not mapped", addr);
+    }
+
+    #[cfg(all(feature = "std", target_os = "linux"))]
+    #[test]
+    fn test_retire() {
+        let page_size = mmap::page_size();
+        let mut ops = x64::Assembler::new().unwrap();
+        ops.extend(&vec![0xC3; page_size * 2]);
+        ops.commit().unwrap();
+
+        assert_eq!(ops.retire(AssemblyOffset(1) .. AssemblyOffset(page_size)), Err(DynasmError::CheckFailed));
+        assert_eq!(ops.retire(AssemblyOffset(0) .. AssemblyOffset(page_size * 3)), Err(DynasmError::OutOfBounds(AssemblyOffset(page_size * 3))));
+        ops.retire(AssemblyOffset(page_size) .. AssemblyOffset(page_size * 2)).unwrap();
+
+        let reader = ops.reader();
+        assert!(reader.is_executable(AssemblyOffset(0)));
+        assert!(!reader.is_executable(AssemblyOffset(page_size)));
+        let addr = ops.memory.execbuffer_addr();
+        assert_eq!(permissions(addr), "r-x");
+        assert_eq!(permissions(addr + page_size), "r--");
+
+        // retired code can still be altered, and stays retired afterwards or when the buffer moves
+        ops.patch(AssemblyOffset(page_size), &[0x90]).unwrap();
+        assert_eq!(permissions(addr + page_size), "r--");
+        drop(reader);
+        ops.extend(&vec![0xC3; page_size * 4]);
+        ops.commit().unwrap();
+        let addr = ops.memory.execbuffer_addr();
+        assert_eq!(permissions(addr), "r-x");
+        assert_eq!(permissions(addr + page_size), "r--");
+        assert_eq!(permissions(addr + page_size * 2), "r-x");
+        assert_eq!(ops.reader().lock()[page_size], 0x90);
+        assert!(!ops.reader().is_executable(AssemblyOffset(page_size)));
+
+        // in development mode, retired code is made writable again while it is altered
+        let mut ops = x64::Assembler::new_dev_mode().unwrap();
+        ops.extend(&vec![0xC3; page_size]);
+        ops.commit().unwrap();
+        ops.retire(AssemblyOffset(0) .. AssemblyOffset(page_size)).unwrap();
+        let addr = ops.memory.execbuffer_addr();
+        assert_eq!(permissions(addr), "r--");
+        ops.patch(AssemblyOffset(0), &[0x90]).unwrap();
+        assert_eq!(permissions(addr), "r--");
+        assert_eq!(ops.reader().lock()[0], 0x90);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_reset() {
+        let mut ops = x64::Assembler::new().unwrap();
+        ops.set_zero_on_drop(true);
+        ops.global_label("start");
+        ops.extend(&vec![0xCC; 0x1800]);
+        ops.commit().unwrap();
+        let size = ops.memory.size();
+        let addr = ops.memory.execbuffer_addr();
+
+        // the memory is kept, but the code and labels are gone
+        ops.reset().unwrap();
+        assert_eq!(ops.offset(), AssemblyOffset(0));
+        assert_eq!(ops.memory.size(), size);
+        assert_eq!(ops.memory.execbuffer_addr(), addr);
+        assert!(!ops.labels().is_global_defined("start"));
+        assert!(ops.memory.read().zero_on_drop());
+        ops.extend(&[0x90, 0xC3]);
+        ops.commit().unwrap();
+        assert_eq!(&ops.reader().lock()[..], &[0x90, 0xC3]);
+
+        // code still referenced by an executor is left alone
+        let reader = ops.reader();
+        ops.reset().unwrap();
+        assert_eq!(&reader.lock()[..], &[0x90, 0xC3]);
+        assert_eq!(ops.reader().lock().len(), 0);
+    }
+
+    #[test]
+    fn test_offset_arithmetic() {
+        let start = AssemblyOffset(8);
+        let end = start + 0x18;
+        assert_eq!(end, AssemblyOffset(0x20));
+        assert_eq!(end - 4, AssemblyOffset(0x1C));
+        assert_eq!(end - start, 0x18);
+        assert_eq!(AssemblyOffset::from(3), AssemblyOffset(3));
+        assert_eq!(usize::from(end), 0x20);
+        assert!(end.is_aligned_to(16));
+        assert!(!(end - 4).is_aligned_to(8));
+    }
+
+    #[test]
+    fn test_pad_to() {
+        let mut ops = VecAssembler(Vec::new());
+        ops.push(0xAA);
+        ops.pad_to(AssemblyOffset(4), 0x90);
+        ops.pad_to(AssemblyOffset(4), 0x90);
+        assert_eq!(ops.0, vec![0xAA, 0x90, 0x90, 0x90]);
     }
 }
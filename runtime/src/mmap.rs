@@ -1,21 +1,39 @@
-use std::ops::{Deref, DerefMut};
+use std::ops::{Deref, DerefMut, Range};
 use std::io;
+use std::mem;
+use std::ptr;
+use std::fmt;
+use std::error;
+use std::convert::TryFrom;
 
 use memmap::{Mmap, MmapMut};
 
 use crate::AssemblyOffset;
+use crate::components::ManagedRelocs;
+use crate::relocations::Relocation;
+use crate::unwind::Registration;
+use self::fixed::FixedMap;
 
 /// This module implements some wrappers around Mmap/MmapMut to also support a cheap "empty" variant.
 /// Unfortunately Memmap itself doesn't support a cheap zero-length variant
 
 /// A structure holding a buffer of executable memory. It also derefs to a `&[u8]`.
 /// This structure does not allocate when its size is 0.
+/// It owns its mapping and only hands out shared access to it, so it is both `Send` and `Sync`.
 #[derive(Debug)]
 pub struct ExecutableBuffer {
     // length of the buffer that has actually been written to
     length: usize,
     // backing buffer
-    buffer: Option<Mmap>
+    buffer: Option<ExecMap>,
+    // unwind info registered for the code in this buffer
+    unwind: Option<Registration>,
+    // data referenced by the code in this buffer
+    data: Vec<DataMap>,
+    // page-aligned ranges of code that have been retired, and are mapped without execute permissions
+    retired: Vec<Range<usize>>,
+    // if the backing memory should be zeroed before it is unmapped
+    zero_on_drop: bool,
 }
 
 /// ExecutableBuffer equivalent that holds a buffer of mutable memory instead of executable memory. It also derefs to a `&mut [u8]`.
@@ -25,7 +43,128 @@ pub struct MutableBuffer {
     // length of the buffer that has actually been written to
     length: usize,
     // backing buffer
-    buffer: Option<MmapMut>
+    buffer: Option<MutMap>,
+    // unwind info registered for the code in this buffer
+    unwind: Option<Registration>,
+    // data referenced by the code in this buffer
+    data: Vec<DataMap>,
+    // page-aligned ranges of code that have been retired, and are mapped without execute permissions
+    retired: Vec<Range<usize>>,
+    // if the backing memory should be zeroed before it is unmapped
+    zero_on_drop: bool,
+}
+
+/// A mapping of data referenced by the code in a buffer, which is kept alive as long as the code is.
+// the mappings are only held to keep them alive, they are accessed through the addresses of their contents
+#[derive(Debug)]
+#[allow(dead_code)]
+pub(crate) enum DataMap {
+    /// Read-only data emitted through `Assembler::data`
+    ReadOnly(Mmap),
+    /// Counters reserved through `Assembler::reserve_counter`, which the code writes to
+    Writable(MmapMut),
+    /// Data of either kind that was mapped close to the code. Its protection is set once when it is mapped.
+    Placed(FixedMap),
+}
+
+impl DataMap {
+    /// Map a read-only copy of `data`, close to `near` if there is free memory there.
+    pub(crate) fn read_only(data: &[u8], near: Option<usize>) -> io::Result<DataMap> {
+        if let Some(mut map) = near.and_then(|near| map_near(near, data.len())) {
+            map.copy_from_slice(data);
+            fixed::protect_read(map.as_ptr(), map.len())?;
+            return Ok(DataMap::Placed(map));
+        }
+        let mut map = MmapMut::map_anon(data.len())?;
+        map.copy_from_slice(data);
+        Ok(DataMap::ReadOnly(map.make_read_only()?))
+    }
+
+    /// Map `len` zeroed bytes of writable memory, close to `near` if there is free memory there.
+    pub(crate) fn writable(len: usize, near: Option<usize>) -> io::Result<DataMap> {
+        if let Some(map) = near.and_then(|near| map_near(near, len)) {
+            return Ok(DataMap::Placed(map));
+        }
+        Ok(DataMap::Writable(MmapMut::map_anon(len)?))
+    }
+
+    /// Returns the address of the start of this mapping.
+    pub(crate) fn as_ptr(&self) -> *const u8 {
+        match self {
+            DataMap::ReadOnly(map) => map.as_ptr(),
+            DataMap::Writable(map) => map.as_ptr(),
+            DataMap::Placed(map) => map.as_ptr(),
+        }
+    }
+}
+
+/// How far mappings are placed from the address they have to be close to. As code and the data it references through
+/// 32-bit displacements are placed this close to the same address, they never end up more than 2GiB apart.
+const REACH: usize = 1 << 30;
+
+// Map `len` bytes of readable and writable memory that lies entirely within `REACH` of `near`, trying addresses
+// spread over that range. Returns `None` if none of them was free.
+fn map_near(near: usize, len: usize) -> Option<FixedMap> {
+    let step = REACH / 16;
+    for i in 1 .. 16 {
+        let above = near.checked_add(i * step)
+            .filter(|&addr| addr.checked_add(len).is_some_and(|end| end - near <= REACH));
+        let below = near.checked_sub(i * step);
+        for addr in above.into_iter().chain(below) {
+            if let Ok(map) = FixedMap::new(addr, len) {
+                return Some(map);
+            }
+        }
+    }
+    None
+}
+
+/// The reason why executable memory could not be obtained.
+#[derive(Debug)]
+pub enum AllocError {
+    /// The requested size is too large to be mapped
+    InvalidSize(usize),
+    /// The memory could not be mapped, usually because the process ran out of memory
+    MapFailed(io::Error),
+    /// The memory was mapped, but could not be made executable. This happens on systems that
+    /// restrict executable memory, for instance through a W^X policy.
+    ProtectFailed(io::Error),
+}
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AllocError::InvalidSize(size) => write!(f, "Invalid size for executable memory: {}", size),
+            AllocError::MapFailed(e) => write!(f, "Could not map memory: {}", e),
+            AllocError::ProtectFailed(e) => write!(f, "Could not make memory executable: {}", e),
+        }
+    }
+}
+
+impl error::Error for AllocError {
+    fn description(&self) -> &str {
+        match self {
+            AllocError::InvalidSize(_) => "Invalid size for executable memory",
+            AllocError::MapFailed(_) => "Could not map memory",
+            AllocError::ProtectFailed(_) => "Could not make memory executable",
+        }
+    }
+
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            AllocError::InvalidSize(_) => None,
+            AllocError::MapFailed(e) | AllocError::ProtectFailed(e) => Some(e),
+        }
+    }
+}
+
+impl From<AllocError> for io::Error {
+    fn from(e: AllocError) -> io::Error {
+        match e {
+            AllocError::InvalidSize(_) => io::Error::new(io::ErrorKind::InvalidInput, e.to_string()),
+            AllocError::MapFailed(e) | AllocError::ProtectFailed(e) => e,
+        }
+    }
 }
 
 impl ExecutableBuffer {
@@ -41,18 +180,70 @@ impl ExecutableBuffer {
         &self[offset.0] as *const u8
     }
 
+    /// Obtain a pointer to the start of the executable memory. Adding an `AssemblyOffset` to it gives
+    /// the absolute address of the code at that offset. This is a null pointer if the buffer has no backing memory.
+    /// As the backing memory is always mapped directly from the OS, the base is aligned to the page size. Code aligned
+    /// to at most the page size therefore stays aligned when the assembler moves it into a larger buffer.
+    pub fn base(&self) -> *const u8 {
+        self.buffer.as_ref().map(|b| b.as_ptr()).unwrap_or(ptr::null())
+    }
+
     /// Create a new executable buffer, backed by a buffer of size `size`.
-    /// It will start with an initialized length of 0.
+    /// It will start with an initialized length of 0. The backing memory is zero-initialized.
     pub fn new(size: usize) -> io::Result<ExecutableBuffer> {
+        Ok(Self::try_new(size)?)
+    }
+
+    /// Like `new`, but tells why the memory could not be obtained.
+    pub fn try_new(size: usize) -> Result<ExecutableBuffer, AllocError> {
+        // mappings larger than isize::MAX bytes cannot be indexed
+        if size > isize::MAX as usize {
+            return Err(AllocError::InvalidSize(size));
+        }
+
+        let buffer = if size == 0 {
+            None
+        } else {
+            let map = MmapMut::map_anon(size).map_err(AllocError::MapFailed)?;
+            Some(ExecMap::Mapped(map.make_exec().map_err(AllocError::ProtectFailed)?))
+        };
+
+        Ok(ExecutableBuffer {
+            length: 0,
+            buffer,
+            unwind: None,
+            data: Vec::new(),
+            retired: Vec::new(),
+            zero_on_drop: false,
+        })
+    }
+
+    /// Create a new executable buffer holding a copy of `code`. Its length is the length of `code`.
+    pub fn from_slice(code: &[u8]) -> io::Result<ExecutableBuffer> {
+        let mut buffer = MutableBuffer::new(code.len())?;
+        buffer.set_len(code.len());
+        buffer.copy_from_slice(code);
+        buffer.make_exec()
+    }
+
+    /// Create a new executable buffer of size `size`, backed by memory mapped at exactly `addr`.
+    /// Both `addr` and `size` should be multiples of the page size. If the memory cannot be
+    /// mapped at this address, an error is returned.
+    /// It will start with an initialized length of 0.
+    pub fn new_at(addr: usize, size: usize) -> io::Result<ExecutableBuffer> {
         let buffer = if size == 0 {
             None
         } else {
-            Some(MmapMut::map_anon(size)?.make_exec()?)
+            Some(ExecMap::Fixed(FixedMap::new(addr, size)?.make_exec()?))
         };
 
         Ok(ExecutableBuffer {
             length: 0,
-            buffer
+            buffer,
+            unwind: None,
+            data: Vec::new(),
+            retired: Vec::new(),
+            zero_on_drop: false,
         })
     }
 
@@ -61,34 +252,182 @@ impl ExecutableBuffer {
         self.buffer.as_ref().map(|b| b.len()).unwrap_or(0) as usize
     }
 
+    /// Returns the amount of bytes of code in this buffer. This excludes the unused part of the mapping.
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    /// Returns true if this buffer holds no code.
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// Returns the amount of bytes mapped for this buffer, which is at least its `len`. Equal to `size`.
+    pub fn capacity(&self) -> usize {
+        self.size()
+    }
+
+    /// Returns the code in this buffer, without the unused part of the mapping. Equal to dereferencing it.
+    pub fn as_slice(&self) -> &[u8] {
+        self
+    }
+
+    /// Returns true if this buffer was mapped at a fixed address, and therefore shouldn't be moved.
+    pub(crate) fn is_fixed(&self) -> bool {
+        matches!(self.buffer, Some(ExecMap::Fixed(_)))
+    }
+
+    /// Returns true if this buffer is mapped readable, writable and executable at the same time.
+    pub(crate) fn is_writable(&self) -> bool {
+        matches!(self.buffer, Some(ExecMap::Writable(_)))
+    }
+
+    /// Returns true if this buffer will be zeroed when it is dropped.
+    pub(crate) fn zero_on_drop(&self) -> bool {
+        self.zero_on_drop
+    }
+
+    /// Attach an unwind info registration to this buffer. It is deregistered when the buffer is dropped
+    /// or another registration is attached.
+    pub(crate) fn set_unwind(&mut self, registration: Registration) {
+        self.unwind = Some(registration);
+    }
+
+    /// Attach a mapping of data to this buffer, so it lives as long as the code referencing it.
+    pub(crate) fn add_data(&mut self, data: DataMap) {
+        self.data.push(data);
+    }
+
+    /// Attach data mappings that were taken from a previous buffer.
+    pub(crate) fn set_data(&mut self, data: Vec<DataMap>) {
+        self.data = data;
+    }
+
+    /// Detach all data mappings from this buffer, so they can be moved to a new buffer.
+    pub(crate) fn take_data(&mut self) -> Vec<DataMap> {
+        mem::take(&mut self.data)
+    }
+
+    /// Returns true if data mappings are attached to this buffer.
+    pub(crate) fn has_data(&self) -> bool {
+        !self.data.is_empty()
+    }
+
+    /// Returns the page-aligned ranges of code in this buffer that have been retired through `retire`.
+    pub(crate) fn retired(&self) -> &[Range<usize>] {
+        &self.retired
+    }
+
+    /// Returns true if `offset` lies in code that has been retired.
+    pub(crate) fn is_retired(&self, offset: usize) -> bool {
+        self.retired.iter().any(|range| range.contains(&offset))
+    }
+
+    /// Remap the page-aligned range `range` of this buffer as only readable, so executing code in it faults.
+    /// The range stays like this when the buffer is made mutable and executable again.
+    pub(crate) fn retire(&mut self, range: Range<usize>) -> io::Result<()> {
+        if let Some(map) = &self.buffer {
+            fixed::protect_read(map[range.clone()].as_ptr(), range.len())?;
+        }
+        self.retired.push(range);
+        Ok(())
+    }
+
+    /// Zero the backing memory of this buffer before it is unmapped when it is dropped.
+    /// This keeps the generated code from lingering in freed memory.
+    pub fn set_zero_on_drop(&mut self, enabled: bool) {
+        self.zero_on_drop = enabled;
+    }
+
+    /// Create a copy of this buffer in a new mapping of the same size. As the code is copied as is, the copy only works
+    /// if the code doesn't depend on its own address. Code containing absolute references to its labels can be copied
+    /// with `try_clone_relocatable` instead. Unwind info registered for this buffer is not registered for the copy.
+    /// Returns an error if this buffer holds read-only data committed through `Assembler::data` or counters reserved
+    /// through `Assembler::reserve_counter`, as code referencing them would keep referencing those of this buffer.
+    pub fn try_clone(&self) -> io::Result<ExecutableBuffer> {
+        self.copy()?.make_exec()
+    }
+
+    /// Like `try_clone`, but also adjusts the relocations `relocs` to the address of the copy. These are the
+    /// relocations returned together with this buffer by `Assembler::finalize_relocatable`.
+    pub fn try_clone_relocatable<R: Relocation>(&self, relocs: &ManagedRelocs<R>) -> io::Result<ExecutableBuffer> {
+        let mut copy = self.copy()?;
+        let new_addr = copy.as_ptr() as usize;
+        relocs.rebase(&mut copy, self.base() as usize, new_addr)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Relocation out of range in copied code"))?;
+        copy.make_exec()
+    }
+
+    // Copy the code of this buffer into a new mutable buffer of the same size.
+    fn copy(&self) -> io::Result<MutableBuffer> {
+        if !self.data.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Cannot copy a buffer holding data referenced by its code"));
+        }
+
+        let mut copy = MutableBuffer::new(self.size())?;
+        copy.set_len(self.length);
+        copy.copy_from_slice(self);
+        copy.set_zero_on_drop(self.zero_on_drop);
+        Ok(copy)
+    }
+
     /// Change this executable buffer into a mutable buffer.
-    pub fn make_mut(self) -> io::Result<MutableBuffer> {
-        let buffer = if let Some(map) = self.buffer {
-            Some(map.make_mut()?)
+    pub fn make_mut(mut self) -> io::Result<MutableBuffer> {
+        let buffer = if let Some(map) = self.buffer.take() {
+            let map = map.make_mut()?;
+            // retired code in writable buffers isn't remapped along with the rest of the buffer
+            if let MutMap::Writable(map) = &map {
+                for range in &self.retired {
+                    fixed::protect_writable(map[range.clone()].as_ptr() as *mut u8, range.len())?;
+                }
+            }
+            Some(map)
         } else {
             None
         };
 
         Ok(MutableBuffer {
             length: self.length,
-            buffer
+            buffer,
+            unwind: self.unwind.take(),
+            data: mem::take(&mut self.data),
+            retired: mem::take(&mut self.retired),
+            zero_on_drop: self.zero_on_drop,
         })
     }
 }
 
 impl MutableBuffer {
     /// Create a new mutable buffer, backed by a buffer of size `size`.
-    /// It will start with an initialized length of 0.
+    /// It will start with an initialized length of 0. The backing memory is zero-initialized.
     pub fn new(size: usize) -> io::Result<MutableBuffer> {
+        Self::new_near(size, None, false)
+    }
+
+    /// Create a new mutable buffer, backed by a buffer of size `size` that is mapped readable, writable and
+    /// executable at the same time. Changing the buffer between its mutable and executable forms does not
+    /// change the protection of the memory, so it never stops being writable.
+    /// It will start with an initialized length of 0. The backing memory is zero-initialized.
+    pub(crate) fn new_writable(size: usize) -> io::Result<MutableBuffer> {
+        Self::new_near(size, None, true)
+    }
+
+    /// Like `new`, or `new_writable` if `writable` is set, but maps the memory close to `near` if there is free memory
+    /// there, so the code stays within reach of the data it references.
+    pub(crate) fn new_near(size: usize, near: Option<usize>, writable: bool) -> io::Result<MutableBuffer> {
         let buffer = if size == 0 {
             None
         } else {
-            Some(MmapMut::map_anon(size)?)
+            Some(MutMap::new(size, near, writable)?)
         };
 
         Ok(MutableBuffer {
             length: 0,
-            buffer
+            buffer,
+            unwind: None,
+            data: Vec::new(),
+            retired: Vec::new(),
+            zero_on_drop: false,
         })
     }
 
@@ -103,26 +442,236 @@ impl MutableBuffer {
         self.length = length
     }
 
+    /// Overwrite all bytes in `range` with `byte`. This panics if `range` extends past the usable part of this buffer.
+    pub fn fill(&mut self, range: Range<usize>, byte: u8) {
+        for b in &mut self[range] {
+            *b = byte;
+        }
+    }
+
+    /// Zero the backing memory of this buffer before it is unmapped when it is dropped.
+    /// This setting is kept when the buffer is changed into an executable buffer.
+    pub fn set_zero_on_drop(&mut self, enabled: bool) {
+        self.zero_on_drop = enabled;
+    }
+
+    /// Discard the contents of this buffer, zeroing the part that was written to and setting its length to 0.
+    /// Unwind info and read-only data attached to it are dropped as well, and retired code is no longer retired.
+    pub(crate) fn clear(&mut self) {
+        self.unwind = None;
+        self.data.clear();
+        self.retired.clear();
+        zero(&mut self[..]);
+        self.length = 0;
+    }
+
+    /// Set the retired ranges of this buffer, which are taken from the buffer it replaces. They are remapped
+    /// as only readable when this buffer is made executable.
+    pub(crate) fn set_retired(&mut self, retired: Vec<Range<usize>>) {
+        self.retired = retired;
+    }
+
+    /// Move the contents of this buffer into new memory of `size` bytes, mapped the same way as the current memory,
+    /// close to `near` if possible. The read-only data and retired ranges of the buffer are kept, but as the code moves,
+    /// its unwind info is dropped and has to be registered again. Memory mapped at a fixed address cannot move, so this
+    /// fails for it. Nothing changes if this fails.
+    pub(crate) fn grow(&mut self, size: usize, near: Option<usize>) -> io::Result<()> {
+        let buffer = match self.buffer {
+            Some(MutMap::Fixed(_)) => return Err(io::Error::new(io::ErrorKind::AddrInUse, "Memory mapped at a fixed address cannot move")),
+            Some(MutMap::Writable(_)) => MutMap::new(size, near, true)?,
+            Some(MutMap::Mapped(_)) | Some(MutMap::Placed(_)) | None => MutMap::new(size, near, false)?,
+        };
+        let mut buffer = Some(buffer);
+        if let Some(new) = &mut buffer {
+            new[.. self.length].copy_from_slice(&self[..]);
+        }
+
+        // the unwind info describes the code at its old address
+        self.unwind = None;
+        mem::swap(&mut self.buffer, &mut buffer);
+        if let (true, Some(map)) = (self.zero_on_drop, &mut buffer) {
+            zero(map);
+        }
+        Ok(())
+    }
+
     /// Change this mutable buffer into an executable buffer.
-    pub fn make_exec(self) -> io::Result<ExecutableBuffer> {
-        let buffer = if let Some(map) = self.buffer {
-            Some(map.make_exec()?)
+    /// On architectures that need it, this also flushes the instruction cache for the written part of the buffer.
+    /// Code that has been retired is mapped as only readable.
+    pub fn make_exec(mut self) -> io::Result<ExecutableBuffer> {
+        let buffer = if let Some(map) = self.buffer.take() {
+            flush_icache(map.as_ptr(), self.length);
+            let map = map.make_exec()?;
+            for range in &self.retired {
+                fixed::protect_read(map[range.clone()].as_ptr(), range.len())?;
+            }
+            Some(map)
         } else {
             None
         };
 
         Ok(ExecutableBuffer {
             length: self.length,
-            buffer
+            buffer,
+            unwind: self.unwind.take(),
+            data: mem::take(&mut self.data),
+            retired: mem::take(&mut self.retired),
+            zero_on_drop: self.zero_on_drop,
+        })
+    }
+}
+
+/// Returns the page size of the host, which is the granularity at which memory is allocated and protected.
+pub fn page_size() -> usize {
+    fixed::page_size()
+}
+
+/// Make sure that instruction fetches from the memory range `start .. start + len` observe any prior writes to it.
+/// This is a no-op on x86, which has coherent instruction caches.
+#[inline]
+#[allow(unused_variables)]
+fn flush_icache(start: *const u8, len: usize) {
+    #[cfg(all(any(target_arch = "aarch64", target_arch = "arm"), target_vendor = "apple"))]
+    {
+        extern "C" {
+            fn sys_icache_invalidate(start: *mut u8, len: usize);
+        }
+        unsafe {
+            sys_icache_invalidate(start as *mut u8, len);
+        }
+    }
+
+    #[cfg(all(any(target_arch = "aarch64", target_arch = "arm"), windows))]
+    {
+        #[link(name = "kernel32")]
+        extern "system" {
+            fn GetCurrentProcess() -> *mut u8;
+            fn FlushInstructionCache(process: *mut u8, start: *const u8, len: usize) -> i32;
+        }
+        unsafe {
+            FlushInstructionCache(GetCurrentProcess(), start, len);
+        }
+    }
+
+    #[cfg(all(any(target_arch = "aarch64", target_arch = "arm"), not(target_vendor = "apple"), not(windows)))]
+    {
+        extern "C" {
+            fn __clear_cache(start: *mut u8, end: *mut u8);
+        }
+        unsafe {
+            __clear_cache(start as *mut u8, start.add(len) as *mut u8);
+        }
+    }
+}
+
+// Backing storage of an ExecutableBuffer
+#[derive(Debug)]
+enum ExecMap {
+    Mapped(Mmap),
+    Fixed(FixedMap),
+    // mapped readable, writable and executable, so it doesn't need to be remapped
+    Writable(FixedMap),
+    // mapped close to the data referenced by the code, but free to move like `Mapped`
+    Placed(FixedMap),
+}
+
+// Backing storage of a MutableBuffer
+#[derive(Debug)]
+enum MutMap {
+    Mapped(MmapMut),
+    Fixed(FixedMap),
+    Writable(FixedMap),
+    Placed(FixedMap),
+}
+
+impl ExecMap {
+    fn make_mut(self) -> io::Result<MutMap> {
+        Ok(match self {
+            ExecMap::Mapped(map) => MutMap::Mapped(map.make_mut()?),
+            ExecMap::Fixed(map) => MutMap::Fixed(map.make_mut()?),
+            ExecMap::Writable(map) => MutMap::Writable(map),
+            ExecMap::Placed(map) => MutMap::Placed(map.make_mut()?),
+        })
+    }
+}
+
+impl MutMap {
+    // map `size` bytes, close to `near` if possible
+    fn new(size: usize, near: Option<usize>, writable: bool) -> io::Result<MutMap> {
+        match (near.and_then(|near| map_near(near, size)), writable) {
+            (Some(map), true) => {
+                fixed::protect_writable(map.as_ptr() as *mut u8, map.len())?;
+                Ok(MutMap::Writable(map))
+            },
+            (Some(map), false) => Ok(MutMap::Placed(map)),
+            (None, true) => Ok(MutMap::Writable(FixedMap::new_writable(size)?)),
+            (None, false) => Ok(MutMap::Mapped(MmapMut::map_anon(size)?)),
+        }
+    }
+
+    fn make_exec(self) -> io::Result<ExecMap> {
+        Ok(match self {
+            MutMap::Mapped(map) => ExecMap::Mapped(map.make_exec()?),
+            MutMap::Fixed(map) => ExecMap::Fixed(map.make_exec()?),
+            MutMap::Writable(map) => ExecMap::Writable(map),
+            MutMap::Placed(map) => ExecMap::Placed(map.make_exec()?),
         })
     }
 }
 
+impl Deref for ExecMap {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        match self {
+            ExecMap::Mapped(map) => map,
+            ExecMap::Fixed(map) => map,
+            ExecMap::Writable(map) => map,
+            ExecMap::Placed(map) => map,
+        }
+    }
+}
+
+impl Deref for MutMap {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        match self {
+            MutMap::Mapped(map) => map,
+            MutMap::Fixed(map) => map,
+            MutMap::Writable(map) => map,
+            MutMap::Placed(map) => map,
+        }
+    }
+}
+
+impl DerefMut for MutMap {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self {
+            MutMap::Mapped(map) => map,
+            MutMap::Fixed(map) => map,
+            MutMap::Writable(map) => map,
+            MutMap::Placed(map) => map,
+        }
+    }
+}
+
+/// Makes the code in a vector executable. As executable memory has to be mapped separately, the code is copied like
+/// `ExecutableBuffer::from_slice` does.
+impl TryFrom<Vec<u8>> for ExecutableBuffer {
+    type Error = io::Error;
+    fn try_from(code: Vec<u8>) -> io::Result<ExecutableBuffer> {
+        ExecutableBuffer::from_slice(&code)
+    }
+}
+
 impl Default for ExecutableBuffer {
     fn default() -> ExecutableBuffer {
         ExecutableBuffer {
             length: 0,
-            buffer: None
+            buffer: None,
+            unwind: None,
+            data: Vec::new(),
+            retired: Vec::new(),
+            zero_on_drop: false,
         }
     }
 }
@@ -131,7 +680,11 @@ impl Default for MutableBuffer {
     fn default() -> MutableBuffer {
         MutableBuffer {
             length: 0,
-            buffer: None
+            buffer: None,
+            unwind: None,
+            data: Vec::new(),
+            retired: Vec::new(),
+            zero_on_drop: false,
         }
     }
 }
@@ -167,3 +720,286 @@ impl DerefMut for MutableBuffer {
         }
     }
 }
+
+impl Drop for ExecutableBuffer {
+    fn drop(&mut self) {
+        if !self.zero_on_drop {
+            return;
+        }
+        // the code can't be executed anymore while it's being zeroed, so its unwind info goes first
+        self.unwind = None;
+        if let Some(map) = self.buffer.take() {
+            if let Ok(mut map) = map.make_mut() {
+                zero(&mut map);
+            }
+        }
+    }
+}
+
+impl Drop for MutableBuffer {
+    fn drop(&mut self) {
+        if self.zero_on_drop {
+            if let Some(map) = &mut self.buffer {
+                zero(map);
+            }
+        }
+    }
+}
+
+// volatile writes, so the zeroing isn't optimized out because the memory is unmapped right after
+fn zero(buf: &mut [u8]) {
+    for byte in buf {
+        unsafe {
+            ptr::write_volatile(byte, 0);
+        }
+    }
+}
+
+
+/// Anonymous memory mappings placed at a fixed address, or mapped readable, writable and executable at the same time,
+/// which memmap doesn't support.
+mod fixed {
+    use std::io;
+    use std::ops::{Deref, DerefMut};
+    use std::slice;
+
+    /// An anonymous memory mapping at a fixed address. It is unmapped when dropped.
+    /// The protection of the mapping is tracked by the users of this type.
+    #[derive(Debug)]
+    pub struct FixedMap {
+        ptr: *mut u8,
+        len: usize,
+    }
+
+    // FixedMap uniquely owns its mapping, and only hands out access to it through references to itself.
+    unsafe impl Send for FixedMap {}
+    unsafe impl Sync for FixedMap {}
+
+    impl FixedMap {
+        /// Map `len` bytes of readable and writable memory at `addr`.
+        pub fn new(addr: usize, len: usize) -> io::Result<FixedMap> {
+            let ptr = sys::map(addr, len)?;
+            let map = FixedMap { ptr, len };
+            if ptr as usize != addr {
+                return Err(io::Error::new(io::ErrorKind::AddrInUse, "Could not map memory at the requested address"));
+            }
+            Ok(map)
+        }
+
+        /// Map `len` bytes of readable, writable and executable memory wherever the OS places it.
+        pub fn new_writable(len: usize) -> io::Result<FixedMap> {
+            let ptr = sys::map(0, len)?;
+            let map = FixedMap { ptr, len };
+            sys::protect_writable(map.ptr, map.len)?;
+            Ok(map)
+        }
+
+        /// Make this mapping readable and executable
+        pub fn make_exec(self) -> io::Result<FixedMap> {
+            sys::protect(self.ptr, self.len, true)?;
+            Ok(self)
+        }
+
+        /// Make this mapping readable and writable
+        pub fn make_mut(self) -> io::Result<FixedMap> {
+            sys::protect(self.ptr, self.len, false)?;
+            Ok(self)
+        }
+    }
+
+    impl Deref for FixedMap {
+        type Target = [u8];
+        fn deref(&self) -> &[u8] {
+            unsafe { slice::from_raw_parts(self.ptr, self.len) }
+        }
+    }
+
+    impl DerefMut for FixedMap {
+        fn deref_mut(&mut self) -> &mut [u8] {
+            unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
+        }
+    }
+
+    pub use self::sys::{page_size, protect_writable};
+
+    /// Make the memory range `ptr .. ptr + len`, which has to be part of a mapping, only readable.
+    pub fn protect_read(ptr: *const u8, len: usize) -> io::Result<()> {
+        sys::protect_read(ptr as *mut u8, len)
+    }
+
+    impl Drop for FixedMap {
+        fn drop(&mut self) {
+            sys::unmap(self.ptr, self.len);
+        }
+    }
+
+    #[cfg(unix)]
+    mod sys {
+        use std::io;
+        use libc;
+
+        // the address is only passed as a hint, so existing mappings are never replaced.
+        // Callers have to check if the mapping ended up at the requested address.
+        pub fn map(addr: usize, len: usize) -> io::Result<*mut u8> {
+            let ptr = unsafe {
+                libc::mmap(addr as *mut libc::c_void, len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_PRIVATE | libc::MAP_ANON, -1, 0)
+            };
+            if ptr == libc::MAP_FAILED {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(ptr as *mut u8)
+            }
+        }
+
+        pub fn protect(ptr: *mut u8, len: usize, exec: bool) -> io::Result<()> {
+            let prot = if exec {
+                libc::PROT_READ | libc::PROT_EXEC
+            } else {
+                libc::PROT_READ | libc::PROT_WRITE
+            };
+            if unsafe { libc::mprotect(ptr as *mut libc::c_void, len, prot) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        pub fn protect_writable(ptr: *mut u8, len: usize) -> io::Result<()> {
+            let prot = libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC;
+            if unsafe { libc::mprotect(ptr as *mut libc::c_void, len, prot) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        pub fn protect_read(ptr: *mut u8, len: usize) -> io::Result<()> {
+            if unsafe { libc::mprotect(ptr as *mut libc::c_void, len, libc::PROT_READ) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        pub fn unmap(ptr: *mut u8, len: usize) {
+            unsafe {
+                libc::munmap(ptr as *mut libc::c_void, len);
+            }
+        }
+
+        pub fn page_size() -> usize {
+            unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+        }
+    }
+
+    #[cfg(windows)]
+    mod sys {
+        use std::io;
+        use std::mem;
+
+        const MEM_COMMIT: u32 = 0x1000;
+        const MEM_RESERVE: u32 = 0x2000;
+        const MEM_RELEASE: u32 = 0x8000;
+        const PAGE_READONLY: u32 = 0x02;
+        const PAGE_READWRITE: u32 = 0x04;
+        const PAGE_EXECUTE_READ: u32 = 0x20;
+        const PAGE_EXECUTE_READWRITE: u32 = 0x40;
+
+        #[repr(C)]
+        struct SystemInfo {
+            processor_architecture: u16,
+            reserved: u16,
+            page_size: u32,
+            minimum_application_address: *mut u8,
+            maximum_application_address: *mut u8,
+            active_processor_mask: usize,
+            number_of_processors: u32,
+            processor_type: u32,
+            allocation_granularity: u32,
+            processor_level: u16,
+            processor_revision: u16,
+        }
+
+        #[link(name = "kernel32")]
+        extern "system" {
+            fn GetSystemInfo(info: *mut SystemInfo);
+            fn VirtualAlloc(addr: *mut u8, size: usize, allocation_type: u32, protect: u32) -> *mut u8;
+            fn VirtualProtect(addr: *mut u8, size: usize, protect: u32, old_protect: *mut u32) -> i32;
+            fn VirtualFree(addr: *mut u8, size: usize, free_type: u32) -> i32;
+        }
+
+        // VirtualAlloc fails if the requested address is already in use, and rounds it down to the
+        // allocation granularity otherwise. Callers have to check if the mapping ended up at the requested address.
+        pub fn map(addr: usize, len: usize) -> io::Result<*mut u8> {
+            let ptr = unsafe { VirtualAlloc(addr as *mut u8, len, MEM_COMMIT | MEM_RESERVE, PAGE_READWRITE) };
+            if ptr.is_null() {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(ptr)
+            }
+        }
+
+        pub fn protect(ptr: *mut u8, len: usize, exec: bool) -> io::Result<()> {
+            let prot = if exec { PAGE_EXECUTE_READ } else { PAGE_READWRITE };
+            let mut old = 0;
+            if unsafe { VirtualProtect(ptr, len, prot, &mut old) } == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        pub fn protect_writable(ptr: *mut u8, len: usize) -> io::Result<()> {
+            let mut old = 0;
+            if unsafe { VirtualProtect(ptr, len, PAGE_EXECUTE_READWRITE, &mut old) } == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        pub fn protect_read(ptr: *mut u8, len: usize) -> io::Result<()> {
+            let mut old = 0;
+            if unsafe { VirtualProtect(ptr, len, PAGE_READONLY, &mut old) } == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        pub fn unmap(ptr: *mut u8, _len: usize) {
+            unsafe {
+                VirtualFree(ptr, 0, MEM_RELEASE);
+            }
+        }
+
+        pub fn page_size() -> usize {
+            unsafe {
+                let mut info: SystemInfo = mem::zeroed();
+                GetSystemInfo(&mut info);
+                info.page_size as usize
+            }
+        }
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    mod sys {
+        use std::io;
+
+        pub fn map(_addr: usize, _len: usize) -> io::Result<*mut u8> {
+            Err(io::Error::new(io::ErrorKind::Other, "Fixed address mappings are not supported on this platform"))
+        }
+
+        pub fn protect(_ptr: *mut u8, _len: usize, _exec: bool) -> io::Result<()> {
+            Ok(())
+        }
+
+        pub fn protect_writable(_ptr: *mut u8, _len: usize) -> io::Result<()> {
+            Ok(())
+        }
+
+        pub fn protect_read(_ptr: *mut u8, _len: usize) -> io::Result<()> {
+            Ok(())
+        }
+
+        pub fn unmap(_ptr: *mut u8, _len: usize) {}
+
+        pub fn page_size() -> usize {
+            4096
+        }
+    }
+}
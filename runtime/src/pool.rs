@@ -0,0 +1,195 @@
+//! This module implements a pool of assemblers, for JITs that assemble many short-lived pieces of code
+//! and want to avoid mapping new memory for each of them.
+//!
+//! An assembler is taken out of the pool with `AssemblerPool::get`, which hands out a `PooledAssembler`
+//! guard. When the guard is dropped, the assembler is reset and put back into the pool, keeping the memory
+//! it allocated. The pool can be shared between threads, each of which takes its own assemblers out of it.
+
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+
+use crate::relocations::Relocation;
+use crate::Assembler;
+
+
+/// A pool of assemblers that are reused once they are no longer in use. At most a fixed number of idle
+/// assemblers, and optionally a fixed amount of their executable memory, is kept around. The memory of any further
+/// assemblers is released when they are returned.
+#[derive(Debug)]
+pub struct AssemblerPool<R: Relocation> {
+    // assemblers that have been reset and are ready to be handed out again
+    idle: Mutex<Vec<Assembler<R>>>,
+    max_idle: usize,
+    max_idle_size: usize,
+}
+
+impl<R: Relocation> AssemblerPool<R> {
+    /// Create an empty pool, which keeps at most `max_idle` assemblers around while they are not in use.
+    pub fn new(max_idle: usize) -> Self {
+        Self::with_max_idle_size(max_idle, usize::MAX)
+    }
+
+    /// Create an empty pool, which keeps at most `max_idle` assemblers around while they are not in use, whose
+    /// executable memory takes up at most `max_idle_size` bytes in total. Assemblers whose memory grew large are
+    /// released instead of being kept, as the memory of an assembler doesn't shrink when it is reset.
+    pub fn with_max_idle_size(max_idle: usize, max_idle_size: usize) -> Self {
+        AssemblerPool {
+            idle: Mutex::new(Vec::new()),
+            max_idle,
+            max_idle_size,
+        }
+    }
+
+    /// Take an assembler out of the pool, or create a new one if the pool is empty. The assembler is returned
+    /// to the pool when the returned guard is dropped.
+    pub fn get(&self) -> io::Result<PooledAssembler<'_, R>> {
+        let assembler = self.idle.lock().unwrap().pop();
+        let assembler = match assembler {
+            Some(assembler) => assembler,
+            None => Assembler::new()?,
+        };
+
+        Ok(PooledAssembler {
+            pool: self,
+            assembler: Some(assembler),
+        })
+    }
+
+    /// Returns the amount of assemblers that are currently waiting in the pool to be used again.
+    pub fn idle(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+
+    // whether `idle` has room for an assembler with `size` bytes of executable memory
+    fn has_room(&self, idle: &[Assembler<R>], size: usize) -> bool {
+        let idle_size = idle.iter().fold(0usize, |total, assembler| total.saturating_add(assembler.memory.size()));
+        idle.len() < self.max_idle && idle_size.saturating_add(size) <= self.max_idle_size
+    }
+
+    // reset `assembler` and keep it for later use, if there is room for it
+    fn put(&self, mut assembler: Assembler<R>) {
+        // resetting doesn't change the size of the memory, so assemblers that won't be kept aren't reset
+        let size = assembler.memory.size();
+        match self.idle.lock() {
+            Ok(idle) if self.has_room(&idle, size) => (),
+            _ => return,
+        }
+        // resetting can take a while, so it happens without holding the lock
+        if assembler.reset().is_err() {
+            return;
+        }
+        if let Ok(mut idle) = self.idle.lock() {
+            if self.has_room(&idle, size) {
+                idle.push(assembler);
+            }
+        }
+    }
+}
+
+
+/// An assembler taken out of an `AssemblerPool`. It derefs to the `Assembler` itself. When it is dropped, the
+/// assembler is reset and returned to the pool. As resetting discards all code, it should be finalized or copied
+/// out first if it is needed afterwards. If existing `Executor`s still reference the code, the code is kept
+/// alive for them, and the pooled assembler continues with new memory.
+#[derive(Debug)]
+pub struct PooledAssembler<'a, R: Relocation> {
+    pool: &'a AssemblerPool<R>,
+    // only empty while the guard is being consumed
+    assembler: Option<Assembler<R>>,
+}
+
+impl<'a, R: Relocation> PooledAssembler<'a, R> {
+    /// Take the assembler out of the pool for good, so it isn't reset when it is no longer used.
+    pub fn into_inner(mut self) -> Assembler<R> {
+        self.assembler.take().unwrap()
+    }
+}
+
+impl<'a, R: Relocation> Deref for PooledAssembler<'a, R> {
+    type Target = Assembler<R>;
+    fn deref(&self) -> &Assembler<R> {
+        self.assembler.as_ref().unwrap()
+    }
+}
+
+impl<'a, R: Relocation> DerefMut for PooledAssembler<'a, R> {
+    fn deref_mut(&mut self) -> &mut Assembler<R> {
+        self.assembler.as_mut().unwrap()
+    }
+}
+
+impl<'a, R: Relocation> Drop for PooledAssembler<'a, R> {
+    fn drop(&mut self) {
+        if let Some(assembler) = self.assembler.take() {
+            self.pool.put(assembler);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    #[test]
+    fn test_assembler_pool() {
+        let pool = x64::AssemblerPool::new(2);
+        {
+            let mut ops = pool.get().unwrap();
+            ops.extend(&[0x90, 0xC3]);
+            ops.commit().unwrap();
+        }
+        assert_eq!(pool.idle(), 1);
+
+        // returned assemblers are handed out again, after being reset
+        {
+            let ops = pool.get().unwrap();
+            assert_eq!(pool.idle(), 0);
+            assert_eq!(ops.offset(), AssemblyOffset(0));
+            assert_eq!(ops.reader().lock().len(), 0);
+        }
+
+        // threads share the pool, which only keeps up to two idle assemblers
+        std::thread::scope(|scope| {
+            for _ in 0 .. 4 {
+                scope.spawn(|| {
+                    let mut ops = pool.get().unwrap();
+                    ops.extend(&[0xC3]);
+                    ops.commit().unwrap();
+                });
+            }
+        });
+        assert!(pool.idle() <= 2);
+
+        let ops = pool.get().unwrap().into_inner();
+        assert_eq!(ops.offset(), AssemblyOffset(0));
+        assert!(pool.idle() <= 1);
+    }
+
+    #[test]
+    fn test_assembler_pool_size() {
+        let page_size = mmap::page_size();
+        let pool = x64::AssemblerPool::with_max_idle_size(4, page_size);
+        {
+            let mut ops = pool.get().unwrap();
+            ops.push(0xC3);
+            ops.commit().unwrap();
+        }
+        assert_eq!(pool.idle(), 1);
+
+        // the memory of an assembler that grew doesn't fit in the pool
+        {
+            let mut ops = pool.get().unwrap();
+            ops.extend(core::iter::repeat(0x90).take(page_size * 2));
+            ops.commit().unwrap();
+        }
+        assert_eq!(pool.idle(), 0);
+
+        // and neither does a second assembler that didn't
+        let first = pool.get().unwrap();
+        let second = pool.get().unwrap();
+        drop(first);
+        drop(second);
+        assert_eq!(pool.idle(), 1);
+    }
+}
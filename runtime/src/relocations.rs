@@ -1,6 +1,6 @@
 use byteorder::{ByteOrder, LittleEndian};
 
-use std::convert::TryFrom;
+use core::convert::TryFrom;
 
 /// Error returned when encoding a relocation failed
 #[derive(Debug)]
@@ -75,6 +75,9 @@ impl RelocationKind {
 /// A descriptor for the size of a relocation. This also doubles as a relocation itself
 /// for relocations in data directives. Can be converted to relocations of any kind of architecture
 /// using `Relocation::from_size`.
+///
+/// Only the sizes listed here are supported. Fields of any other size cannot be relocated, and
+/// `Relocation::from_encoding` panics when it is given such a size.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum RelocationSize {
     /// A byte-sized relocation
@@ -85,6 +88,12 @@ pub enum RelocationSize {
     DWord = 4,
     /// An 8-byte sized relocation
     QWord = 8,
+    /// A 10-byte sized relocation, i.e. for fields the size of an x87 extended precision value.
+    /// The value is written zero-extended, so this is only suitable for data relocations holding addresses.
+    TByte = 10,
+    /// A 16-byte sized relocation, i.e. for fields the size of an SSE vector.
+    /// The value is written zero-extended, so this is only suitable for data relocations holding addresses.
+    OWord = 16,
 }
 
 impl Relocation for RelocationSize {
@@ -95,6 +104,8 @@ impl Relocation for RelocationSize {
             2 => RelocationSize::Word,
             4 => RelocationSize::DWord,
             8 => RelocationSize::QWord,
+            10 => RelocationSize::TByte,
+            16 => RelocationSize::OWord,
             x => panic!("Unsupported relocation size {}", x)
         }
     }
@@ -118,6 +129,14 @@ impl Relocation for RelocationSize {
             RelocationSize::QWord => LittleEndian::write_i64(buf,
                 i64::try_from(value).map_err(|_| ImpossibleRelocation { } )?
             ),
+            RelocationSize::TByte
+            | RelocationSize::OWord => {
+                let value = u64::try_from(value).map_err(|_| ImpossibleRelocation { } )?;
+                LittleEndian::write_u64(&mut buf[.. 8], value);
+                for byte in &mut buf[8 ..] {
+                    *byte = 0;
+                }
+            },
         }
         Ok(())
     }
@@ -127,6 +146,8 @@ impl Relocation for RelocationSize {
             RelocationSize::Word => LittleEndian::read_i16(buf) as isize,
             RelocationSize::DWord => LittleEndian::read_i32(buf) as isize,
             RelocationSize::QWord => LittleEndian::read_i64(buf) as isize,
+            RelocationSize::TByte
+            | RelocationSize::OWord => LittleEndian::read_u64(&buf[.. 8]) as isize,
         }
     }
     fn kind(&self) -> RelocationKind {
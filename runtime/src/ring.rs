@@ -0,0 +1,181 @@
+//! This module implements assembling into a fixed-size ring of executable memory, for JITs that keep
+//! generating code and need to bound the memory it occupies by evicting old code.
+//!
+//! Code is emitted in chunks through `RingAssembler::emit`. Each chunk is assembled in place by a
+//! `SliceAssembler`, so its relocations are resolved against its final address, and is represented by a
+//! `RingRegion` handle. Chunks are placed after each other, wrapping around to the start of the ring when
+//! the end is reached. A region can only be overwritten once its handle and all clones of it have been
+//! dropped. Until then, `emit` refuses to place new code over it.
+
+use std::marker::PhantomData;
+use std::ops::Range;
+use std::sync::{Arc, RwLock, RwLockReadGuard, Weak};
+use std::io;
+use std::mem;
+
+use crate::mmap::{ExecutableBuffer, MutableBuffer};
+use crate::relocations::Relocation;
+use crate::{AssemblyOffset, DynasmError, SliceAssembler};
+
+
+/// An assembler that places code in a fixed-size ring of executable memory, reusing the memory of old code
+/// once it is no longer in use.
+#[derive(Debug)]
+pub struct RingAssembler<R: Relocation> {
+    // the ring. Its length covers the entire backing memory.
+    execbuffer: Arc<RwLock<ExecutableBuffer>>,
+    // address and size of the ring. It never moves.
+    base: usize,
+    size: usize,
+    // offset at which the next chunk is placed, if it fits before the end of the ring
+    head: usize,
+    // regions that have been emitted, with a reference to find out if their handles are still alive
+    regions: Vec<(Range<usize>, Weak<()>)>,
+    phantom: PhantomData<R>,
+}
+
+impl<R: Relocation> RingAssembler<R> {
+    /// Create a new ring assembler, backed by a ring of `size` bytes of executable memory.
+    pub fn new(size: usize) -> io::Result<Self> {
+        let mut buffer = MutableBuffer::new(size)?;
+        buffer.set_len(size);
+        let buffer = buffer.make_exec()?;
+        let base = buffer.base() as usize;
+
+        Ok(RingAssembler {
+            execbuffer: Arc::new(RwLock::new(buffer)),
+            base,
+            size,
+            head: 0,
+            regions: Vec::new(),
+            phantom: PhantomData,
+        })
+    }
+
+    /// Returns the size of the ring.
+    pub fn capacity(&self) -> usize {
+        self.size
+    }
+
+    /// Assemble a chunk of at most `size` bytes of code using `f`, and place it in the ring. The chunk is placed
+    /// right after the previous one, or at the start of the ring if it doesn't fit before the end.
+    /// Returns `DynasmError::BufferFull` if this would overwrite a region that is still in use, or if `f` emits more
+    /// than `size` bytes. Any other error encountered while assembling the chunk is returned as well.
+    /// While the chunk is written, no code in the ring can be executed, so `f` must not call `RingRegion::lock`.
+    pub fn emit<F>(&mut self, size: usize, f: F) -> Result<RingRegion, DynasmError> where F: FnOnce(&mut SliceAssembler<R>) {
+        let capacity = self.capacity();
+        if size > capacity {
+            return Err(DynasmError::BufferFull);
+        }
+        let start = if size > capacity - self.head { 0 } else { self.head };
+        let end = start + size;
+
+        // forget regions that are no longer in use, and make sure the others aren't overwritten
+        self.regions.retain(|(_, live)| live.strong_count() != 0);
+        if self.regions.iter().any(|(region, _)| region.start < end && start < region.end) {
+            return Err(DynasmError::BufferFull);
+        }
+
+        // swap out the ring
+        let mut lock = self.execbuffer.write().unwrap();
+        let buffer = mem::take(&mut *lock);
+        let mut buffer = buffer.make_mut().expect("Could not swap buffer protection modes");
+
+        let result = {
+            let mut ops = SliceAssembler::new(&mut buffer[start .. end]);
+            f(&mut ops);
+            ops.finalize().map(|code| code.len())
+        };
+
+        // repack the ring
+        *lock = buffer.make_exec().expect("Could not swap buffer protection modes");
+        drop(lock);
+
+        let range = start .. start + result?;
+        let live = Arc::new(());
+        self.head = range.end;
+        self.regions.push((range.clone(), Arc::downgrade(&live)));
+
+        Ok(RingRegion {
+            execbuffer: self.execbuffer.clone(),
+            addr: self.base + range.start,
+            range,
+            _live: live,
+        })
+    }
+}
+
+
+/// A handle to a region of code emitted by a `RingAssembler`. The region is not overwritten as long as
+/// this handle or a clone of it exists. The memory of the ring also stays mapped for as long as that.
+#[derive(Debug, Clone)]
+pub struct RingRegion {
+    execbuffer: Arc<RwLock<ExecutableBuffer>>,
+    addr: usize,
+    range: Range<usize>,
+    _live: Arc<()>,
+}
+
+impl RingRegion {
+    /// Returns the range of offsets in the ring that this region occupies.
+    pub fn range(&self) -> Range<AssemblyOffset> {
+        AssemblyOffset(self.range.start) .. AssemblyOffset(self.range.end)
+    }
+
+    /// Gain read-access to the ring. The code of this region can only be executed while the returned guard is
+    /// alive, as the ring is made writable when more code is emitted into it.
+    pub fn lock(&self) -> RwLockReadGuard<'_, ExecutableBuffer> {
+        self.execbuffer.read().unwrap()
+    }
+
+    /// Obtain a pointer to the start of this region. As the ring never moves, this pointer stays valid for as long
+    /// as this handle exists, but the code behind it should only be executed while a guard returned by `lock` is alive.
+    pub fn ptr(&self) -> *const u8 {
+        self.addr as *const u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    #[test]
+    fn test_ring_assembler() {
+        let mut ring = x64::RingAssembler::new(0x1000).unwrap();
+        assert_eq!(ring.capacity(), 0x1000);
+
+        // mov eax, 1; ret
+        let first = ring.emit(0x800, |ops| ops.extend(&[0xB8, 1, 0, 0, 0, 0xC3])).unwrap();
+        assert_eq!(first.range(), AssemblyOffset(0) .. AssemblyOffset(6));
+        // mov eax, 2; jmp >end; int3; end: ret
+        let second = ring.emit(0x800, |ops| {
+            ops.extend(&[0xB8, 2, 0, 0, 0, 0xEB, 0]);
+            ops.forward_reloc("end", 0, (0, 1));
+            ops.push(0xCC);
+            ops.local_label("end");
+            ops.push(0xC3);
+        }).unwrap();
+        assert_eq!(second.range(), AssemblyOffset(6) .. AssemblyOffset(15));
+        assert_eq!(second.ptr() as usize, first.ptr() as usize + 6);
+
+        {
+            let _guard = second.lock();
+            let f: extern "C" fn() -> u32 = unsafe { mem::transmute(first.ptr()) };
+            assert_eq!(f(), 1);
+            let f: extern "C" fn() -> u32 = unsafe { mem::transmute(second.ptr()) };
+            assert_eq!(f(), 2);
+        }
+
+        // doesn't fit before the end, and the start of the ring is still in use
+        assert_eq!(ring.emit(0x1000 - 14, |ops| ops.push(0xC3)).unwrap_err(), DynasmError::BufferFull);
+        drop(first);
+        assert_eq!(ring.emit(0x1000 - 14, |ops| ops.push(0xC3)).unwrap_err(), DynasmError::BufferFull);
+        drop(second);
+        let third = ring.emit(0x1000 - 14, |ops| ops.extend(&[0xB8, 3, 0, 0, 0, 0xC3])).unwrap();
+        assert_eq!(third.range(), AssemblyOffset(0) .. AssemblyOffset(6));
+
+        // chunks can't be larger than they were declared to be
+        assert_eq!(ring.emit(2, |ops| ops.extend(&[0x90, 0x90, 0xC3])).unwrap_err(), DynasmError::BufferFull);
+        assert_eq!(ring.emit(0x1001, |ops| ops.push(0xC3)).unwrap_err(), DynasmError::BufferFull);
+    }
+}
@@ -0,0 +1,305 @@
+//! A randomized round trip test of label and relocation resolution. It generates programs out of label definitions,
+//! references to them of every kind and size, alignment and commits, assembles them with every assembler that
+//! implements `CodeBuffer`, and checks that each reference ends up holding the displacement predicted by a simple model
+//! of how labels resolve. References that can't reach their target have to make the commit that resolves them fail.
+//!
+//! New operations are added by extending `Op`, generating them in `generate`, emitting them in `emit` and adding
+//! them to the model in `predict`. A failing program is reported with its seed, so it can be reproduced by passing
+//! that seed to `generate`.
+
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::format;
+
+use crate::{AssemblyOffset, CodeBuffer, DynamicLabel, DynasmError, Assembler, VecLabelAssembler, SliceAssembler};
+use crate::x64::X64Relocation;
+
+// the amount of labels of each kind that programs use
+const LABELS: usize = 3;
+const LOCALS: [&str; LABELS] = ["a", "b", "c"];
+const GLOBALS: [&str; LABELS] = ["x", "y", "z"];
+const SIZES: [u8; 4] = [1, 2, 4, 8];
+
+/// A label that can be defined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Label {
+    Local(usize),
+    Global(usize),
+    Dynamic(usize),
+}
+
+/// The target of a reference.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Target {
+    Forward(usize),
+    Backward(usize),
+    Global(usize),
+    Dynamic(usize),
+}
+
+/// A single step of a generated program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    /// Emit this many filler bytes
+    Filler(usize),
+    /// Align to this power of two
+    Align(usize),
+    Define(Label),
+    /// A reference in a field of the given size, relative to the end of the field
+    Reference(Target, u8),
+    Commit,
+}
+
+// xorshift64, as the tests shouldn't depend on a random number crate
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % n as u64) as usize
+    }
+}
+
+/// Generate a program of about `len` operations. Programs only commit when nothing is pending that a commit
+/// can't resolve, and define every label they reference.
+fn generate(seed: u64, len: usize) -> Vec<Op> {
+    let mut rng = Rng::new(seed);
+    let mut program = Vec::new();
+    let mut locals_defined = [false; LABELS];
+    let mut forward_pending = [false; LABELS];
+    let mut globals = [(false, false); LABELS];
+    let mut dynamics = [(false, false); LABELS];
+
+    for _ in 0 .. len {
+        let i = rng.below(LABELS);
+        let op = match rng.below(7) {
+            0 => Op::Filler(rng.below(32)),
+            1 => Op::Align(1 << rng.below(5)),
+            2 => {
+                locals_defined[i] = true;
+                forward_pending[i] = false;
+                Op::Define(Label::Local(i))
+            },
+            3 => {
+                let (labels, label) = if rng.below(2) == 0 { (&mut globals, Label::Global(i)) } else { (&mut dynamics, Label::Dynamic(i)) };
+                if labels[i].0 {
+                    Op::Filler(1)
+                } else {
+                    labels[i].0 = true;
+                    Op::Define(label)
+                }
+            },
+            4 | 5 => {
+                let target = match rng.below(4) {
+                    0 if locals_defined[i] => Target::Backward(i),
+                    0 | 1 => {
+                        forward_pending[i] = true;
+                        Target::Forward(i)
+                    },
+                    2 => {
+                        globals[i].1 = true;
+                        Target::Global(i)
+                    },
+                    _ => {
+                        dynamics[i].1 = true;
+                        Target::Dynamic(i)
+                    },
+                };
+                Op::Reference(target, SIZES[rng.below(SIZES.len())])
+            },
+            _ => {
+                let resolvable = |&(defined, used): &(bool, bool)| defined || !used;
+                if forward_pending.iter().any(|&p| p) || !globals.iter().all(resolvable) || !dynamics.iter().all(resolvable) {
+                    Op::Filler(1)
+                } else {
+                    Op::Commit
+                }
+            },
+        };
+        program.push(op);
+    }
+
+    // define everything that is still needed
+    for i in 0 .. LABELS {
+        if forward_pending[i] {
+            program.push(Op::Define(Label::Local(i)));
+        }
+        if globals[i] == (false, true) {
+            program.push(Op::Define(Label::Global(i)));
+        }
+        if dynamics[i] == (false, true) {
+            program.push(Op::Define(Label::Dynamic(i)));
+        }
+    }
+    program.push(Op::Commit);
+    program
+}
+
+// A reference as predicted by the model
+struct Expected {
+    // the range of the field containing the reference
+    start: usize,
+    size: usize,
+    displacement: i64,
+    // the index of the commit that resolves it
+    commit: usize,
+}
+
+impl Expected {
+    fn fits(&self) -> bool {
+        let bits = self.size * 8;
+        bits == 64 || (self.displacement >= -(1 << (bits - 1)) && self.displacement < 1 << (bits - 1))
+    }
+}
+
+/// Predict where every reference in `program` points, by laying out the program and resolving the labels by hand.
+fn predict(program: &[Op]) -> Vec<Expected> {
+    // the offset of every op
+    let mut offsets = Vec::with_capacity(program.len());
+    let mut offset = 0;
+    for op in program {
+        offsets.push(offset);
+        offset += match *op {
+            Op::Filler(n) => n,
+            Op::Align(alignment) => (alignment - offset % alignment) % alignment,
+            Op::Reference(_, size) => size as usize,
+            Op::Define(_) | Op::Commit => 0,
+        };
+    }
+    let definition = |label: Label, mut range: core::ops::Range<usize>, last: bool| {
+        let index = if last {
+            range.rev().find(|&j| program[j] == Op::Define(label))
+        } else {
+            range.find(|&j| program[j] == Op::Define(label))
+        };
+        offsets[index.expect("generated programs define all labels they reference")]
+    };
+
+    let mut expected = Vec::new();
+    let mut commit = 0;
+    for (i, op) in program.iter().enumerate() {
+        match *op {
+            Op::Reference(target, size) => {
+                let target = match target {
+                    Target::Forward(l) => definition(Label::Local(l), i .. program.len(), false),
+                    Target::Backward(l) => definition(Label::Local(l), 0 .. i, true),
+                    Target::Global(l) => definition(Label::Global(l), 0 .. program.len(), false),
+                    Target::Dynamic(l) => definition(Label::Dynamic(l), 0 .. program.len(), false),
+                };
+                let start = offsets[i];
+                let end = start + size as usize;
+                expected.push(Expected { start, size: size as usize, displacement: target as i64 - end as i64, commit });
+            },
+            Op::Commit => commit += 1,
+            _ => (),
+        }
+    }
+    expected
+}
+
+/// Emit `program` into `ops`, returning the results of its commits. Emission stops at the first commit that fails.
+fn emit<B: CodeBuffer<Relocation = X64Relocation>>(ops: &mut B, program: &[Op]) -> Vec<Result<(), DynasmError>> {
+    let dynamics: Vec<DynamicLabel> = (0 .. LABELS).map(|_| ops.new_dynamic_label()).collect();
+    let mut results = Vec::new();
+    for op in program {
+        match *op {
+            Op::Filler(n) => ops.extend(core::iter::repeat(0x90).take(n)),
+            Op::Align(alignment) => ops.align(alignment, 0x90),
+            Op::Define(Label::Local(l)) => ops.local_label(LOCALS[l]),
+            Op::Define(Label::Global(l)) => ops.global_label(GLOBALS[l]),
+            Op::Define(Label::Dynamic(l)) => ops.dynamic_label(dynamics[l]),
+            Op::Reference(target, size) => {
+                ops.extend(core::iter::repeat(0).take(size as usize));
+                match target {
+                    Target::Forward(l) => ops.forward_reloc(LOCALS[l], 0, (0, size)),
+                    Target::Backward(l) => ops.backward_reloc(LOCALS[l], 0, (0, size)),
+                    Target::Global(l) => ops.global_reloc(GLOBALS[l], 0, (0, size)),
+                    Target::Dynamic(l) => ops.dynamic_reloc(dynamics[l], 0, (0, size)),
+                }
+            },
+            Op::Commit => {
+                let result = ops.commit();
+                let failed = result.is_err();
+                results.push(result);
+                if failed {
+                    break;
+                }
+            },
+        }
+    }
+    results
+}
+
+/// Assemble `program` with `ops` and compare the result against the model.
+fn check<B: CodeBuffer<Relocation = X64Relocation>>(ops: &mut B, program: &[Op]) -> Result<(), String> {
+    let expected = predict(program);
+    let results = emit(ops, program);
+
+    // a commit has to fail exactly if one of the references it resolves can't reach its target
+    for (commit, result) in results.iter().enumerate() {
+        let fits = expected.iter().filter(|e| e.commit == commit).all(Expected::fits);
+        if result.is_ok() != fits {
+            return Err(format!("commit {} returned {:?}", commit, result));
+        }
+    }
+    if results.last().map_or(true, |r| r.is_err()) {
+        return Ok(());
+    }
+
+    let code = ops.read_code(AssemblyOffset(0) .. ops.offset()).ok_or("the code can't be read back")?;
+    for e in &expected {
+        let field = &code[e.start .. e.start + e.size];
+        let mut value = 0i64;
+        for (i, &byte) in field.iter().enumerate() {
+            value |= (byte as i64) << (i * 8);
+        }
+        // sign extend the field
+        let shift = 64 - e.size * 8;
+        let value = (value << shift) >> shift;
+        if value != e.displacement {
+            return Err(format!("the reference at {} holds {} instead of {}", e.start, value, e.displacement));
+        }
+    }
+    Ok(())
+}
+
+// check `program` against all assemblers that keep their code
+fn check_all(program: &[Op]) -> Result<(), String> {
+    check(&mut Assembler::<X64Relocation>::new().unwrap(), program).map_err(|e| format!("Assembler: {}", e))?;
+    check(&mut VecLabelAssembler::<X64Relocation>::new(0), program).map_err(|e| format!("VecLabelAssembler: {}", e))?;
+    let mut buffer = [0; 0x1000];
+    check(&mut SliceAssembler::<X64Relocation>::new(&mut buffer), program).map_err(|e| format!("SliceAssembler: {}", e))?;
+    Ok(())
+}
+
+#[test]
+fn test_random_programs() {
+    for seed in 0 .. 500 {
+        let program = generate(seed, 40);
+        if let Err(e) = check_all(&program) {
+            panic!("seed {}: {}\n{:?}", seed, e, program);
+        }
+    }
+}
+
+#[test]
+fn test_model() {
+    // jmp >a; ->x: a: jmp <a; jmp ->x
+    let program = [
+        Op::Reference(Target::Forward(0), 1),
+        Op::Define(Label::Global(0)),
+        Op::Define(Label::Local(0)),
+        Op::Reference(Target::Backward(0), 4),
+        Op::Reference(Target::Global(0), 2),
+        Op::Commit,
+    ];
+    let displacements: Vec<i64> = predict(&program).iter().map(|e| e.displacement).collect();
+    assert_eq!(displacements, [0, -4, -6]);
+    check_all(&program).unwrap();
+}
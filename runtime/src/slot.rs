@@ -0,0 +1,76 @@
+//! This module implements a slot holding the current version of some code, for JITs that recompile code while
+//! it is running and want to switch its callers over to the new version without stopping them.
+//!
+//! The new version is assembled by an `Assembler` of its own, finalized, and swapped into the slot with
+//! `CodeSlot::publish`. Callers obtain the current version with `CodeSlot::load`, which hands out a reference
+//! counted handle to it. Callers that still hold the handle to an old version keep executing it, and its memory
+//! is released once the last of them drops the handle.
+
+use std::sync::{Arc, RwLock};
+
+use crate::ExecutableBuffer;
+
+
+/// A slot holding the current version of some code, which can be replaced atomically. It is `Send + Sync`,
+/// so it can be shared between threads, for instance through an `Arc`.
+#[derive(Debug)]
+pub struct CodeSlot {
+    current: RwLock<Arc<ExecutableBuffer>>,
+}
+
+impl CodeSlot {
+    /// Create a slot holding `buffer` as the current version.
+    pub fn new(buffer: ExecutableBuffer) -> CodeSlot {
+        CodeSlot {
+            current: RwLock::new(Arc::new(buffer)),
+        }
+    }
+
+    /// Returns the current version. The lock on the slot is only held while the handle is cloned, so
+    /// holding the handle doesn't prevent new versions from being published.
+    pub fn load(&self) -> Arc<ExecutableBuffer> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Replace the current version by `buffer`, returning the previous version. Callers that load the code
+    /// afterwards obtain the new version, and those that loaded it before keep the previous one.
+    pub fn publish(&self, buffer: ExecutableBuffer) -> Arc<ExecutableBuffer> {
+        let buffer = Arc::new(buffer);
+        core::mem::replace(&mut *self.current.write().unwrap(), buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use super::CodeSlot;
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_code_slot() {
+        fn version(value: u8) -> ExecutableBuffer {
+            let mut ops = x64::Assembler::new().unwrap();
+            // mov eax, value; ret
+            ops.extend(&[0xB8, value, 0, 0, 0, 0xC3]);
+            ops.finalize().unwrap()
+        }
+        fn call(buffer: &ExecutableBuffer) -> u32 {
+            let f: extern "sysv64" fn() -> u32 = unsafe { mem::transmute(buffer.ptr(AssemblyOffset(0))) };
+            f()
+        }
+
+        let slot = Arc::new(CodeSlot::new(version(1)));
+        let old = slot.load();
+        let publisher = Arc::clone(&slot);
+        std::thread::spawn(move || {
+            publisher.publish(version(2));
+        }).join().unwrap();
+
+        // the old version keeps working for those that still hold it
+        assert_eq!(call(&old), 1);
+        assert_eq!(call(&slot.load()), 2);
+        let previous = slot.publish(version(3));
+        assert_eq!(call(&previous), 2);
+        assert_eq!(call(&slot.load()), 3);
+    }
+}
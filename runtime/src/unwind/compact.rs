@@ -0,0 +1,154 @@
+//! Encoding of unwind descriptions into Apple's compact unwind format, as found in the `__unwind_info`
+//! section of Mach-O images.
+//!
+//! The macOS unwinder only reads compact unwind info from the images it has loaded, and offers no way to
+//! register it for generated code. `Assembler::register_unwind` therefore registers DWARF unwind info on
+//! macOS as well. The encodings produced here are meant for embedding generated code in Mach-O objects.
+
+use crate::unwind::{UnwindInfo, UnwindOp, Reg};
+
+/// Mode of a function with an `rbp` based frame.
+pub const UNWIND_X86_64_MODE_RBP_FRAME: u32 = 0x0100_0000;
+/// Mode of a function without a frame pointer, whose stack size is encoded directly.
+pub const UNWIND_X86_64_MODE_STACK_IMMD: u32 = 0x0200_0000;
+/// Mode of a function whose unwind info can only be described with DWARF.
+pub const UNWIND_X86_64_MODE_DWARF: u32 = 0x0400_0000;
+
+// the maximum amount of registers that can be saved in either mode
+const MAX_FRAME_REGS: usize = 5;
+const MAX_FRAMELESS_REGS: usize = 6;
+
+impl Reg {
+    // The number used for this register in compact unwind info, if it is one of the saved registers it supports.
+    fn compact(self) -> Option<u32> {
+        match self {
+            Reg::Rbx => Some(1),
+            Reg::R12 => Some(2),
+            Reg::R13 => Some(3),
+            Reg::R14 => Some(4),
+            Reg::R15 => Some(5),
+            Reg::Rbp => Some(6),
+            _ => None,
+        }
+    }
+}
+
+/// Encode the prologue described by `info` as a compact unwind encoding. Prologues that start with `push rbp`
+/// followed by `mov rbp, rsp` are encoded as an `rbp` based frame, which may save up to 5 more registers. Prologues
+/// without a frame pointer are encoded with their stack size, which may be at most 2040 bytes including the
+/// pushed registers, of which there may be at most 6. In both cases the registers have to be pushed before
+/// allocating more stack space. Returns `None` for prologues that don't fit either form, which have to be
+/// described as `UNWIND_X86_64_MODE_DWARF` with DWARF unwind info instead.
+pub fn encode_compact_unwind(info: &UnwindInfo) -> Option<u32> {
+    let ops: Vec<UnwindOp> = info.ops().iter().map(|&(_, op)| op).collect();
+
+    match ops.get(.. 2) {
+        Some(&[UnwindOp::PushReg(Reg::Rbp), UnwindOp::SetFramePointer(Reg::Rbp, 0)]) => encode_rbp_frame(&ops[2 ..]),
+        _ => encode_frameless(&ops),
+    }
+}
+
+// Splits ops into the registers pushed by them, and the stack allocated after that.
+fn pushes_then_alloc(ops: &[UnwindOp]) -> Option<(Vec<u32>, u32)> {
+    let mut regs = Vec::new();
+    let mut alloc = 0;
+    for op in ops {
+        match *op {
+            UnwindOp::PushReg(reg) if alloc == 0 => regs.push(reg.compact()?),
+            UnwindOp::AllocStack(size) => alloc += size,
+            _ => return None,
+        }
+    }
+    Some((regs, alloc))
+}
+
+fn encode_rbp_frame(ops: &[UnwindOp]) -> Option<u32> {
+    let (regs, _) = pushes_then_alloc(ops)?;
+    if regs.len() > MAX_FRAME_REGS {
+        return None;
+    }
+
+    // the registers are saved below rbp, and listed starting from the lowest address
+    let mut encoded = 0;
+    for (i, reg) in regs.iter().rev().enumerate() {
+        encoded |= reg << (i * 3);
+    }
+    Some(UNWIND_X86_64_MODE_RBP_FRAME | (regs.len() as u32) << 16 | encoded)
+}
+
+fn encode_frameless(ops: &[UnwindOp]) -> Option<u32> {
+    let (regs, alloc) = pushes_then_alloc(ops)?;
+    if regs.len() > MAX_FRAMELESS_REGS {
+        return None;
+    }
+
+    // the stack size includes the return address, in units of 8 bytes
+    let stack_size = (8 + 8 * regs.len() as u32).checked_add(alloc)? / 8;
+    if stack_size > 0xFF {
+        return None;
+    }
+
+    // The order of the registers, starting from the lowest address, is encoded as a permutation. Every register
+    // is numbered by its position among the registers that haven't been listed yet.
+    let count = regs.len();
+    let mut used = [false; 7];
+    let mut permutation = 0;
+    for (i, &reg) in regs.iter().rev().enumerate() {
+        let position = (1 .. reg).filter(|&r| !used[r as usize]).count() as u32;
+        used[reg as usize] = true;
+        let weight: u32 = (i + 1 .. count).map(|j| (MAX_FRAMELESS_REGS - j) as u32).product();
+        permutation += position * weight;
+    }
+
+    Some(UNWIND_X86_64_MODE_STACK_IMMD | stack_size << 16 | (count as u32) << 10 | permutation)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rbp_frame() {
+        // push rbp; mov rbp, rsp; push rbx; push r12; sub rsp, 16
+        let info = UnwindInfo::new()
+            .push_reg(1, Reg::Rbp)
+            .set_frame(4, Reg::Rbp, 0)
+            .push_reg(5, Reg::Rbx)
+            .push_reg(7, Reg::R12)
+            .alloc_stack(11, 16);
+        assert_eq!(encode_compact_unwind(&info), Some(0x0102_000A));
+
+        // frame pointers at an offset from rsp can't be described
+        let info = UnwindInfo::new()
+            .push_reg(1, Reg::Rbp)
+            .set_frame(5, Reg::Rbp, 16);
+        assert_eq!(encode_compact_unwind(&info), None);
+    }
+
+    #[test]
+    fn test_frameless() {
+        assert_eq!(encode_compact_unwind(&UnwindInfo::new()), Some(0x0201_0000));
+
+        // push rbx; push r14; sub rsp, 0x20
+        let info = UnwindInfo::new()
+            .push_reg(1, Reg::Rbx)
+            .push_reg(3, Reg::R14)
+            .alloc_stack(7, 0x20);
+        assert_eq!(encode_compact_unwind(&info), Some(0x0207_080F));
+
+        // all six registers, pushed in order. Every register is the first of the remaining ones.
+        let info = UnwindInfo::new()
+            .push_reg(1, Reg::Rbp)
+            .push_reg(3, Reg::R15)
+            .push_reg(5, Reg::R14)
+            .push_reg(7, Reg::R13)
+            .push_reg(9, Reg::R12)
+            .push_reg(10, Reg::Rbx);
+        assert_eq!(encode_compact_unwind(&info), Some(0x0207_1800));
+
+        // registers that aren't saved by compact unwind info, and stacks that are too large
+        assert_eq!(encode_compact_unwind(&UnwindInfo::new().push_reg(1, Reg::Rsi)), None);
+        assert_eq!(encode_compact_unwind(&UnwindInfo::new().alloc_stack(4, 0x800)), None);
+    }
+}
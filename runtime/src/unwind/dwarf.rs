@@ -0,0 +1,289 @@
+//! Encoding of unwind descriptions into a DWARF `.eh_frame` section, and its registration
+//! through `__register_frame`.
+
+use std::ops::Range;
+
+use crate::AssemblyOffset;
+use crate::unwind::{UnwindInfo, UnwindOp, Reg};
+
+const DW_CFA_NOP: u8 = 0x00;
+const DW_CFA_ADVANCE_LOC1: u8 = 0x02;
+const DW_CFA_DEF_CFA: u8 = 0x0C;
+const DW_CFA_DEF_CFA_OFFSET: u8 = 0x0E;
+const DW_CFA_ADVANCE_LOC: u8 = 0x40;
+const DW_CFA_OFFSET: u8 = 0x80;
+
+const DW_EH_PE_ABSPTR: u8 = 0x00;
+
+// The DWARF number of the pseudo-register holding the return address.
+const RETURN_ADDRESS: u8 = 16;
+const DATA_ALIGN: i64 = -8;
+
+impl Reg {
+    /// The number used for this register in DWARF call frame information.
+    pub fn dwarf(self) -> u8 {
+        match self {
+            Reg::Rax => 0,
+            Reg::Rdx => 1,
+            Reg::Rcx => 2,
+            Reg::Rbx => 3,
+            Reg::Rsi => 4,
+            Reg::Rdi => 5,
+            Reg::Rbp => 6,
+            Reg::Rsp => 7,
+            r => r.code(),
+        }
+    }
+}
+
+fn uleb128(buf: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+fn sleb128(buf: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+// Starts a length-prefixed record, returning the position of the length field
+fn start_record(buf: &mut Vec<u8>) -> usize {
+    let start = buf.len();
+    buf.extend(&[0; 4]);
+    start
+}
+
+// Pads a record to pointer alignment and fills in its length field
+fn end_record(buf: &mut Vec<u8>, start: usize) {
+    while (buf.len() - start) & 7 != 0 {
+        buf.push(DW_CFA_NOP);
+    }
+    let length = (buf.len() - start - 4) as u32;
+    buf[start .. start + 4].copy_from_slice(&length.to_le_bytes());
+}
+
+/// Translate the prologue described by `info` into DWARF call frame instructions.
+pub fn encode_cfa_instructions(info: &UnwindInfo) -> Vec<u8> {
+    let mut buf = Vec::new();
+    // amount of bytes between the current rsp and the CFA. Initially this is just the return address.
+    let mut depth = 8u32;
+    let mut frame_pointer = false;
+    let mut location = 0u8;
+
+    for &(offset, op) in info.ops() {
+        let delta = offset - location;
+        if delta < 0x40 {
+            buf.push(DW_CFA_ADVANCE_LOC | delta);
+        } else {
+            buf.push(DW_CFA_ADVANCE_LOC1);
+            buf.push(delta);
+        }
+        location = offset;
+
+        match op {
+            UnwindOp::PushReg(reg) => {
+                depth += 8;
+                if !frame_pointer {
+                    buf.push(DW_CFA_DEF_CFA_OFFSET);
+                    uleb128(&mut buf, u64::from(depth));
+                }
+                buf.push(DW_CFA_OFFSET | reg.dwarf());
+                uleb128(&mut buf, u64::from(depth / 8));
+            },
+            UnwindOp::AllocStack(size) => {
+                depth += size;
+                if !frame_pointer {
+                    buf.push(DW_CFA_DEF_CFA_OFFSET);
+                    uleb128(&mut buf, u64::from(depth));
+                }
+            },
+            UnwindOp::SetFramePointer(reg, offset) => {
+                frame_pointer = true;
+                buf.push(DW_CFA_DEF_CFA);
+                uleb128(&mut buf, u64::from(reg.dwarf()));
+                uleb128(&mut buf, u64::from(depth - u32::from(offset)));
+            },
+        }
+    }
+    buf
+}
+
+/// Encode an `.eh_frame` section describing the functions in `entries`, for a buffer residing at `buf_addr`.
+/// The section consists of a single CIE followed by an FDE per function, and is terminated by a zero-length entry.
+/// Returns the section and the offsets of the FDEs in it.
+pub fn encode_eh_frame(buf_addr: usize, entries: &[(Range<AssemblyOffset>, UnwindInfo)]) -> (Vec<u8>, Vec<usize>) {
+    let mut buf = Vec::new();
+
+    // CIE
+    let cie = start_record(&mut buf);
+    buf.extend(&0u32.to_le_bytes()); // CIE id
+    buf.push(1); // version
+    buf.extend(b"zR\0"); // augmentation string
+    uleb128(&mut buf, 1); // code alignment factor
+    sleb128(&mut buf, DATA_ALIGN); // data alignment factor
+    uleb128(&mut buf, u64::from(RETURN_ADDRESS)); // return address register
+    uleb128(&mut buf, 1); // augmentation data length
+    buf.push(DW_EH_PE_ABSPTR); // FDE pointer encoding
+    // on entry, CFA = rsp + 8 and the return address is stored at CFA - 8
+    buf.push(DW_CFA_DEF_CFA);
+    uleb128(&mut buf, u64::from(Reg::Rsp.dwarf()));
+    uleb128(&mut buf, 8);
+    buf.push(DW_CFA_OFFSET | RETURN_ADDRESS);
+    uleb128(&mut buf, 1);
+    end_record(&mut buf, cie);
+
+    // FDEs
+    let mut fdes = Vec::with_capacity(entries.len());
+    for (range, info) in entries {
+        let fde = start_record(&mut buf);
+        let cie_pointer = (buf.len() - cie) as u32;
+        buf.extend(&cie_pointer.to_le_bytes());
+        buf.extend(&((buf_addr + range.start.0) as u64).to_le_bytes());
+        buf.extend(&((range.end.0 - range.start.0) as u64).to_le_bytes());
+        uleb128(&mut buf, 0); // augmentation data length
+        buf.extend(encode_cfa_instructions(info));
+        end_record(&mut buf, fde);
+        fdes.push(fde);
+    }
+
+    // terminator
+    buf.extend(&[0; 4]);
+    (buf, fdes)
+}
+
+
+#[cfg(all(unix, target_arch = "x86_64"))]
+pub(crate) use self::registration::Registration;
+
+#[cfg(all(unix, target_arch = "x86_64"))]
+mod registration {
+    use std::io;
+    use std::ops::Range;
+
+    use memmap::MmapMut;
+
+    use crate::AssemblyOffset;
+    use crate::unwind::UnwindInfo;
+    use super::encode_eh_frame;
+
+    extern "C" {
+        fn __register_frame(begin: *const u8);
+        fn __deregister_frame(begin: *const u8);
+    }
+
+    /// An `.eh_frame` section registered with `__register_frame`. It is deregistered when dropped.
+    #[derive(Debug)]
+    pub(crate) struct Registration {
+        // the section has to stay at a fixed address while registered
+        data: MmapMut,
+        // the entries passed to __register_frame
+        registered: Vec<usize>,
+    }
+
+    impl Registration {
+        pub(crate) fn new(buf_addr: usize, entries: &[(Range<AssemblyOffset>, UnwindInfo)]) -> io::Result<Registration> {
+            let (section, fdes) = encode_eh_frame(buf_addr, entries);
+            let mut data = MmapMut::map_anon(section.len())?;
+            data.copy_from_slice(&section);
+
+            // libgcc's __register_frame takes a whole section, while libunwind's takes a single FDE
+            let registered = if cfg!(target_os = "macos") {
+                fdes
+            } else {
+                vec![0]
+            };
+
+            for &offset in &registered {
+                unsafe {
+                    __register_frame(data.as_ptr().add(offset));
+                }
+            }
+
+            Ok(Registration {
+                data,
+                registered,
+            })
+        }
+    }
+
+    impl Drop for Registration {
+        fn drop(&mut self) {
+            for &offset in &self.registered {
+                unsafe {
+                    __deregister_frame(self.data.as_ptr().add(offset));
+                }
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_cfa_instructions() {
+        // push rbp; mov rbp, rsp; push rbx; sub rsp, 0x28
+        let info = UnwindInfo::new()
+            .push_reg(1, Reg::Rbp)
+            .set_frame(4, Reg::Rbp, 0)
+            .push_reg(5, Reg::Rbx)
+            .alloc_stack(9, 0x28);
+
+        assert_eq!(encode_cfa_instructions(&info), vec![
+            DW_CFA_ADVANCE_LOC | 1, DW_CFA_DEF_CFA_OFFSET, 16, DW_CFA_OFFSET | 6, 2,
+            DW_CFA_ADVANCE_LOC | 3, DW_CFA_DEF_CFA, 6, 16,
+            DW_CFA_ADVANCE_LOC | 1, DW_CFA_OFFSET | 3, 3,
+            DW_CFA_ADVANCE_LOC | 4,
+        ]);
+    }
+
+    #[test]
+    fn test_encode_eh_frame() {
+        let entries = vec![
+            (AssemblyOffset(0x10) .. AssemblyOffset(0x30), UnwindInfo::new().push_reg(1, Reg::Rbp)),
+        ];
+        let (section, fdes) = encode_eh_frame(0x1000, &entries);
+
+        assert_eq!(fdes, vec![24]);
+        assert_eq!(section.len() % 8, 4);
+        assert_eq!(&section[fdes[0] + 4 .. fdes[0] + 8], &28u32.to_le_bytes());
+        assert_eq!(&section[fdes[0] + 8 .. fdes[0] + 16], &0x1010u64.to_le_bytes());
+        assert_eq!(&section[fdes[0] + 16 .. fdes[0] + 24], &0x20u64.to_le_bytes());
+        assert_eq!(&section[section.len() - 4 ..], &[0, 0, 0, 0]);
+    }
+
+    #[cfg(all(unix, target_arch = "x86_64"))]
+    #[test]
+    fn test_register_eh_frame() {
+        use crate::DynasmApi;
+
+        let mut ops = crate::x64::Assembler::new().unwrap();
+        // push rbp; mov rbp, rsp; pop rbp; ret
+        ops.extend(&[0x55, 0x48, 0x89, 0xE5, 0x5D, 0xC3]);
+        ops.register_unwind(AssemblyOffset(0) .. ops.offset(), UnwindInfo::new()
+            .push_reg(1, Reg::Rbp)
+            .set_frame(4, Reg::Rbp, 0));
+        ops.commit().unwrap();
+
+        // force the buffer to move, so the unwind info has to be registered again
+        ops.extend(vec![0xCC; 0x2000]);
+        ops.commit().unwrap();
+        drop(ops);
+    }
+}
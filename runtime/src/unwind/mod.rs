@@ -0,0 +1,184 @@
+//! This module contains the machinery needed to describe the prologues of generated x64 functions,
+//! so that the platform unwinder can walk through their stack frames.
+//!
+//! Unwind info is described in a platform-independent way using an `UnwindInfo`, and registered
+//! for a range of code using `Assembler::register_unwind`. On commit, the runtime encodes the
+//! recorded descriptions in the format the target platform expects and registers them with
+//! the OS. This registration is tied to the lifetime of the `ExecutableBuffer` it describes.
+//! Descriptions can also be encoded in Apple's compact unwind format, but as this cannot be registered
+//! for generated code, macOS uses DWARF unwind info for registration as well.
+
+use std::io;
+use std::ops::Range;
+
+use crate::AssemblyOffset;
+
+pub mod windows;
+pub mod dwarf;
+pub mod compact;
+
+
+/// An x64 general purpose register, numbered according to its instruction encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Reg {
+    Rax = 0,
+    Rcx = 1,
+    Rdx = 2,
+    Rbx = 3,
+    Rsp = 4,
+    Rbp = 5,
+    Rsi = 6,
+    Rdi = 7,
+    R8  = 8,
+    R9  = 9,
+    R10 = 10,
+    R11 = 11,
+    R12 = 12,
+    R13 = 13,
+    R14 = 14,
+    R15 = 15,
+}
+
+impl Reg {
+    /// The number used to encode this register in instructions.
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+}
+
+
+/// A single operation performed by a function prologue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UnwindOp {
+    /// `push reg`, saving a nonvolatile register to the stack.
+    PushReg(Reg),
+    /// `sub rsp, size`, allocating `size` bytes of stack space. `size` must be a multiple of 8.
+    AllocStack(u32),
+    /// `lea reg, [rsp + offset]` (or `mov reg, rsp` when `offset` is 0), establishing a frame pointer.
+    /// `offset` must be a multiple of 16 and at most 240.
+    SetFramePointer(Reg, u8),
+}
+
+
+/// A description of the prologue of a generated function. Every operation is recorded together
+/// with its prologue offset: the offset, relative to the start of the function, of the end of the
+/// instruction that performed it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UnwindInfo {
+    ops: Vec<(u8, UnwindOp)>,
+}
+
+impl UnwindInfo {
+    /// Create a new, empty unwind description. This describes a leaf function that doesn't touch the stack.
+    pub fn new() -> UnwindInfo {
+        UnwindInfo {
+            ops: Vec::new()
+        }
+    }
+
+    /// Record that the instruction ending at prologue offset `offset` pushed `reg`.
+    pub fn push_reg(self, offset: u8, reg: Reg) -> UnwindInfo {
+        self.op(offset, UnwindOp::PushReg(reg))
+    }
+
+    /// Record that the instruction ending at prologue offset `offset` allocated `size` bytes of stack.
+    pub fn alloc_stack(self, offset: u8, size: u32) -> UnwindInfo {
+        assert!(size != 0 && size & 7 == 0, "Stack allocations must be a nonzero multiple of 8 bytes");
+        self.op(offset, UnwindOp::AllocStack(size))
+    }
+
+    /// Record that the instruction ending at prologue offset `offset` set `reg` to `rsp + frame_offset`.
+    pub fn set_frame(self, offset: u8, reg: Reg, frame_offset: u8) -> UnwindInfo {
+        assert!(frame_offset & 15 == 0 && frame_offset <= 240, "Frame offsets must be a multiple of 16 no larger than 240");
+        self.op(offset, UnwindOp::SetFramePointer(reg, frame_offset))
+    }
+
+    fn op(mut self, offset: u8, op: UnwindOp) -> UnwindInfo {
+        if let Some(&(last, _)) = self.ops.last() {
+            assert!(offset > last, "Unwind operations must be recorded in prologue order");
+        }
+        self.ops.push((offset, op));
+        self
+    }
+
+    /// The recorded prologue operations, in the order they are executed.
+    pub fn ops(&self) -> &[(u8, UnwindOp)] {
+        &self.ops
+    }
+
+    /// The size of the described prologue in bytes.
+    pub fn prologue_size(&self) -> u8 {
+        self.ops.last().map(|&(offset, _)| offset).unwrap_or(0)
+    }
+
+    /// The frame pointer register and its offset from `rsp`, if the prologue establishes one.
+    pub fn frame_pointer(&self) -> Option<(Reg, u8)> {
+        self.ops.iter().rev().find_map(|&(_, op)| match op {
+            UnwindOp::SetFramePointer(reg, offset) => Some((reg, offset)),
+            _ => None
+        })
+    }
+}
+
+
+/// A registry of unwind descriptions for ranges of code in an assembler. This is useful when
+/// implementing your own assembler.
+#[derive(Debug, Default)]
+pub struct UnwindRegistry {
+    entries: Vec<(Range<AssemblyOffset>, UnwindInfo)>,
+    // the buffer address the current registration was made against, if any.
+    registered_addr: Option<usize>,
+}
+
+impl UnwindRegistry {
+    /// Create a new, empty unwind registry.
+    pub fn new() -> UnwindRegistry {
+        UnwindRegistry {
+            entries: Vec::new(),
+            registered_addr: None,
+        }
+    }
+
+    /// Record the unwind description `info` for the function occupying `range`.
+    pub fn add(&mut self, range: Range<AssemblyOffset>, info: UnwindInfo) {
+        self.entries.push((range, info));
+        self.registered_addr = None;
+    }
+
+    /// Iterate through all recorded unwind descriptions.
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item=&'a (Range<AssemblyOffset>, UnwindInfo)> + 'a {
+        self.entries.iter()
+    }
+
+    /// Returns true if the recorded descriptions have to be (re)registered for a buffer residing at `buf_addr`.
+    pub fn needs_registration(&self, buf_addr: usize) -> bool {
+        !self.entries.is_empty() && self.registered_addr != Some(buf_addr)
+    }
+
+    /// Register all recorded descriptions with the OS, for a buffer residing at `buf_addr`.
+    /// The registration lasts until the returned value is dropped.
+    pub(crate) fn register(&mut self, buf_addr: usize) -> io::Result<Registration> {
+        let registration = Registration::new(buf_addr, &self.entries)?;
+        self.registered_addr = Some(buf_addr);
+        Ok(registration)
+    }
+}
+
+
+#[cfg(all(windows, target_arch = "x86_64"))]
+pub(crate) use self::windows::Registration;
+
+#[cfg(all(unix, target_arch = "x86_64"))]
+pub(crate) use self::dwarf::Registration;
+
+/// Registration handle on platforms where the runtime doesn't know how to register unwind info.
+#[cfg(not(all(any(windows, unix), target_arch = "x86_64")))]
+#[derive(Debug)]
+pub(crate) struct Registration { }
+
+#[cfg(not(all(any(windows, unix), target_arch = "x86_64")))]
+impl Registration {
+    fn new(_buf_addr: usize, _entries: &[(Range<AssemblyOffset>, UnwindInfo)]) -> io::Result<Registration> {
+        Ok(Registration { })
+    }
+}
@@ -0,0 +1,175 @@
+//! Encoding of unwind descriptions into the Windows x64 `UNWIND_INFO` format, and their
+//! registration through `RtlAddFunctionTable`.
+
+use crate::unwind::{UnwindInfo, UnwindOp};
+
+const UWOP_PUSH_NONVOL: u8 = 0;
+const UWOP_ALLOC_LARGE: u8 = 1;
+const UWOP_ALLOC_SMALL: u8 = 2;
+const UWOP_SET_FPREG: u8 = 3;
+
+/// Encode `info` as a Windows x64 `UNWIND_INFO` structure. The result is padded to a multiple of 4 bytes,
+/// and has to be placed at a 4-byte aligned address.
+pub fn encode_unwind_info(info: &UnwindInfo) -> Vec<u8> {
+    // unwind codes are stored in reverse order of execution. Each code occupies one or more 2-byte slots.
+    let mut slots: Vec<[u8; 2]> = Vec::new();
+    for &(offset, op) in info.ops().iter().rev() {
+        match op {
+            UnwindOp::PushReg(reg) => slots.push([offset, UWOP_PUSH_NONVOL | (reg.code() << 4)]),
+            UnwindOp::AllocStack(size) if size <= 128 => {
+                slots.push([offset, UWOP_ALLOC_SMALL | (((size / 8 - 1) as u8) << 4)]);
+            },
+            UnwindOp::AllocStack(size) if size <= 0x7_FFF8 => {
+                slots.push([offset, UWOP_ALLOC_LARGE]);
+                slots.push(((size / 8) as u16).to_le_bytes());
+            },
+            UnwindOp::AllocStack(size) => {
+                slots.push([offset, UWOP_ALLOC_LARGE | (1 << 4)]);
+                slots.push((size as u16).to_le_bytes());
+                slots.push(((size >> 16) as u16).to_le_bytes());
+            },
+            UnwindOp::SetFramePointer(_, _) => slots.push([offset, UWOP_SET_FPREG]),
+        }
+    }
+
+    let (frame_reg, frame_offset) = info.frame_pointer().map(|(reg, offset)| (reg.code(), offset / 16)).unwrap_or((0, 0));
+    assert!(slots.len() <= 255, "Too many unwind operations for a single function");
+
+    let mut buf = vec![
+        1, // version 1, no flags
+        info.prologue_size(),
+        slots.len() as u8,
+        frame_reg | (frame_offset << 4),
+    ];
+    for slot in &slots {
+        buf.extend(slot);
+    }
+    // the unwind code array is always padded to an even number of slots
+    if slots.len() & 1 != 0 {
+        buf.extend(&[0, 0]);
+    }
+    buf
+}
+
+
+#[cfg(all(windows, target_arch = "x86_64"))]
+pub(crate) use self::registration::Registration;
+
+#[cfg(all(windows, target_arch = "x86_64"))]
+mod registration {
+    use std::io;
+    use std::ops::Range;
+
+    use memmap::MmapMut;
+
+    use crate::AssemblyOffset;
+    use crate::unwind::UnwindInfo;
+    use super::encode_unwind_info;
+
+    #[repr(C)]
+    #[derive(Debug, Clone, Copy)]
+    struct RuntimeFunction {
+        begin_address: u32,
+        end_address: u32,
+        unwind_data: u32,
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn RtlAddFunctionTable(function_table: *mut RuntimeFunction, entry_count: u32, base_address: u64) -> u8;
+        fn RtlDeleteFunctionTable(function_table: *mut RuntimeFunction) -> u8;
+    }
+
+    /// A function table registered with `RtlAddFunctionTable`. It is deregistered when dropped.
+    #[derive(Debug)]
+    pub(crate) struct Registration {
+        table: Vec<RuntimeFunction>,
+        // backing memory for the UNWIND_INFO structures. These are referenced by RVA,
+        // so they have to stay at a fixed address while registered.
+        _data: MmapMut,
+    }
+
+    impl Registration {
+        pub(crate) fn new(buf_addr: usize, entries: &[(Range<AssemblyOffset>, UnwindInfo)]) -> io::Result<Registration> {
+            let encoded: Vec<Vec<u8>> = entries.iter().map(|(_, info)| encode_unwind_info(info)).collect();
+            let size: usize = encoded.iter().map(|e| e.len()).sum();
+            let mut data = MmapMut::map_anon(size)?;
+
+            // RVAs are unsigned 32-bit offsets relative to the base address, so the base
+            // has to lie below both the code and the unwind data.
+            let data_addr = data.as_ptr() as usize;
+            let base = buf_addr.min(data_addr);
+            let rva = |addr: usize| {
+                let rva = addr - base;
+                if rva > u32::max_value() as usize {
+                    Err(io::Error::new(io::ErrorKind::Other, "Unwind data is not within 4GiB of the executable buffer"))
+                } else {
+                    Ok(rva as u32)
+                }
+            };
+
+            let mut table = Vec::with_capacity(entries.len());
+            let mut data_offset = 0;
+            for ((range, _), info) in entries.iter().zip(encoded.iter()) {
+                data[data_offset .. data_offset + info.len()].copy_from_slice(info);
+                table.push(RuntimeFunction {
+                    begin_address: rva(buf_addr + range.start.0)?,
+                    end_address: rva(buf_addr + range.end.0)?,
+                    unwind_data: rva(data_addr + data_offset)?,
+                });
+                data_offset += info.len();
+            }
+
+            if unsafe { RtlAddFunctionTable(table.as_mut_ptr(), table.len() as u32, base as u64) } == 0 {
+                return Err(io::Error::new(io::ErrorKind::Other, "RtlAddFunctionTable failed"));
+            }
+
+            Ok(Registration {
+                table,
+                _data: data,
+            })
+        }
+    }
+
+    impl Drop for Registration {
+        fn drop(&mut self) {
+            unsafe {
+                RtlDeleteFunctionTable(self.table.as_mut_ptr());
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unwind::Reg;
+
+    #[test]
+    fn test_encode_unwind_info() {
+        // push rbp; mov rbp, rsp; push rbx; sub rsp, 0x28
+        let info = UnwindInfo::new()
+            .push_reg(1, Reg::Rbp)
+            .set_frame(4, Reg::Rbp, 0)
+            .push_reg(5, Reg::Rbx)
+            .alloc_stack(9, 0x28);
+
+        assert_eq!(encode_unwind_info(&info), vec![
+            0x01, 9, 4, 0x05,
+            9, 0x42,
+            5, 0x30,
+            4, 0x03,
+            1, 0x50,
+        ]);
+
+        let info = UnwindInfo::new()
+            .alloc_stack(7, 0x1000);
+
+        assert_eq!(encode_unwind_info(&info), vec![
+            0x01, 7, 2, 0x00,
+            7, 0x01,
+            0x00, 0x02,
+        ]);
+    }
+}
@@ -1,4 +1,18 @@
 use crate::relocations::{Relocation, RelocationSize, RelocationKind, ImpossibleRelocation};
+#[cfg(feature = "std")]
+use crate::unwind::{UnwindInfo, Reg};
+#[cfg(feature = "std")]
+use crate::data::Counter;
+use crate::components::LabelRegistry;
+use crate::{AssemblyOffset, DynasmApi, DynasmError, DynasmLabelApi, DynamicLabel};
+
+use alloc::vec::Vec;
+
+#[cfg(feature = "std")]
+use std::collections::BTreeMap;
+
+#[cfg(feature = "std")]
+use std::ops::Range;
 
 
 /// Relocation implementation for the x64 architecture.
@@ -49,6 +63,475 @@ impl Relocation for X64Relocation {
 }
 
 
+#[cfg(feature = "std")]
+impl crate::Assembler<X64Relocation> {
+    /// Record the unwind description `info` for the function occupying `range`. The description is
+    /// registered with the platform unwinder during the next `commit`, and stays registered for as
+    /// long as the executable buffer containing the function exists.
+    /// On Windows this registers a function table through `RtlAddFunctionTable`, on other platforms
+    /// a DWARF `.eh_frame` section is registered through `__register_frame`.
+    ///
+    /// This panics if `range` extends past the current offset.
+    pub fn register_unwind(&mut self, range: Range<AssemblyOffset>, info: UnwindInfo) {
+        assert!(range.start <= range.end && range.end <= self.offset(), "Unwind info registered for code that hasn't been emitted");
+        self.unwind.add(range, info);
+    }
+
+    /// Emit the standard prologue of a function with an `rbp` based frame: `push rbp; mov rbp, rsp; sub rsp, size`.
+    /// `frame_size` is rounded up to a multiple of 16, so `rsp` stays aligned for calls under both the System V and
+    /// Win64 conventions. Win64 callers should include the shadow space of the functions they call in it. No stack is
+    /// allocated if it is 0. Returns the unwind description of the emitted prologue, to pass to `register_unwind`
+    /// for a range starting where the prologue was emitted.
+    pub fn emit_prologue(&mut self, frame_size: u32) -> UnwindInfo {
+        let start = self.offset().0;
+        // push rbp; mov rbp, rsp
+        self.extend(&[0x55, 0x48, 0x89, 0xE5]);
+        let info = UnwindInfo::new()
+            .push_reg(1, Reg::Rbp)
+            .set_frame(4, Reg::Rbp, 0);
+
+        let size = frame_size.checked_add(15).expect("Frame size too large") & !15;
+        if size == 0 {
+            return info;
+        }
+        // sub rsp, size
+        if size <= i8::MAX as u32 {
+            self.extend(&[0x48, 0x83, 0xEC, size as u8]);
+        } else {
+            self.extend(&[0x48, 0x81, 0xEC]);
+            self.push_u32(size);
+        }
+        info.alloc_stack((self.offset().0 - start) as u8, size)
+    }
+
+    /// Emit the epilogue matching `emit_prologue`: `lea rsp, [rbp]; pop rbp; ret`. Win64 unwinding recognizes
+    /// this form as an epilogue.
+    pub fn emit_epilogue(&mut self) {
+        self.extend(&[0x48, 0x8D, 0x65, 0x00, 0x5D, 0xC3]);
+    }
+
+    /// Emit a call to the host function `f`. If the code is placed at a fixed address through `new_in` and `f` is within
+    /// reach, this is a `call rel32`. Otherwise the code can move, so the address is loaded as `mov r11, imm64; call r11`,
+    /// which clobbers `r11`. That register isn't used for arguments and isn't preserved across calls under either the
+    /// System V or the Win64 convention. The arguments have to be set up beforehand, and the stack aligned for the call.
+    pub fn call_extern<F: ExternFn>(&mut self, f: F) {
+        let target = f.addr();
+        if self.memory.is_fixed() {
+            let end = self.memory.execbuffer_addr() + self.offset().0 + 5;
+            let displacement = target.wrapping_sub(end) as isize;
+            if displacement == displacement as i32 as isize {
+                self.push(0xE8);
+                self.push_i32(displacement as i32);
+                return;
+            }
+        }
+        // mov r11, imm64; call r11
+        self.extend(&[0x49, 0xBB]);
+        self.push_u64(target as u64);
+        self.extend(&[0x41, 0xFF, 0xD3]);
+    }
+
+    /// Emit an `inc qword [rip + counter]`, incrementing `counter`. This is not atomic, so increments from multiple threads
+    /// running the code at the same time can get lost.
+    pub fn inc_counter(&mut self, counter: Counter) {
+        self.extend(&[0x48, 0xFF, 0x05]);
+        self.push_u32(0);
+        self.counter_reloc(counter, 0, (0, 4));
+    }
+
+    /// Emit the 32-bit displacement of a RIP-relative memory operand referencing the data label `name`, followed by
+    /// `trailing`, the rest of the instruction such as an immediate operand. The displacement is relative to the end of
+    /// the instruction rather than to the end of the displacement, which this accounts for.
+    pub fn push_rip_data(&mut self, name: &'static str, trailing: &[u8]) {
+        self.push_u32(0);
+        self.extend(trailing);
+        self.data_reloc(name, 0, (trailing.len() as u8, 4));
+    }
+
+    /// Emit a breakpoint, an `int3` instruction.
+    pub fn breakpoint(&mut self) {
+        self.push(0xCC);
+    }
+
+    /// Install a breakpoint at the committed offset `at`, by overwriting the first byte of the instruction there
+    /// with an `int3`. The overwritten byte is saved, so `clear_breakpoint` can put it back. While this is happening no
+    /// code can be executed as the relevant pages are remapped as writable. Does nothing if a breakpoint was already
+    /// installed at `at`. Returns an error if `at` is not within the committed code.
+    pub fn set_breakpoint(&mut self, at: AssemblyOffset) -> Result<(), DynasmError> {
+        self.set_breakpoint_with(at, &[0xCC])
+    }
+
+    /// Returns the label of a retpoline thunk that branches to the address in the register numbered `reg`, like 0 for
+    /// `rax` or 11 for `r11`. Calling or jumping to the thunk performs a `call reg` or `jmp reg` without an indirect branch
+    /// that can be speculated on. Every register has a single thunk per assembler, so repeated calls return the same
+    /// label. The thunk is placed when `emit_retpoline_thunks` is called. Panics if `reg` is not the number of a general
+    /// purpose register.
+    pub fn retpoline_thunk(&mut self, reg: u8) -> DynamicLabel {
+        assert!(reg < 16, "Invalid retpoline register {}", reg);
+        if let Some(&label) = self.thunks.get(&reg) {
+            return label;
+        }
+        let label = self.new_dynamic_label();
+        self.thunks.insert(reg, label);
+        label
+    }
+
+    /// Place the retpoline thunks that have been requested through `retpoline_thunk` and haven't been placed yet. This
+    /// should be done at a location that execution doesn't fall through into, within 2GiB of the branches to the thunks.
+    /// Does nothing if no thunks have to be placed.
+    pub fn emit_retpoline_thunks(&mut self) {
+        let pending: Vec<(u8, DynamicLabel)> = self.thunks.iter()
+            .filter(|&(_, &label)| !self.labels.is_dynamic_defined(label))
+            .map(|(&reg, &label)| (reg, label))
+            .collect();
+
+        for (reg, label) in pending {
+            self.dynamic_label(label);
+            // call set_up_target
+            self.extend(&[0xE8, 0x07, 0x00, 0x00, 0x00]);
+            // capture_spec: pause; lfence; jmp capture_spec
+            self.extend(&[0xF3, 0x90, 0x0F, 0xAE, 0xE8, 0xEB, 0xF9]);
+            // set_up_target: mov [rsp], reg; ret
+            self.extend(&[0x48 | (reg >> 3) << 2, 0x89, 0x04 | (reg & 7) << 3, 0x24, 0xC3]);
+        }
+    }
+}
+
+
+/// Host functions that generated code can call through `Assembler::call_extern`. This is implemented for
+/// `extern "C"` function pointers taking up to six arguments, which is as many as System V passes in registers.
+pub trait ExternFn: Copy {
+    /// The address of the function
+    fn addr(self) -> usize;
+}
+
+macro_rules! extern_fn {
+    ($($arg:ident),*) => {
+        impl<Ret, $($arg),*> ExternFn for extern "C" fn($($arg),*) -> Ret {
+            fn addr(self) -> usize {
+                self as usize
+            }
+        }
+    };
+}
+
+extern_fn!();
+extern_fn!(A);
+extern_fn!(A, B);
+extern_fn!(A, B, C);
+extern_fn!(A, B, C, D);
+extern_fn!(A, B, C, D, E);
+extern_fn!(A, B, C, D, E, F);
+
+
+// The recommended multi-byte NOP encodings, indexed by their length minus one.
+const NOPS: [&[u8]; 9] = [
+    &[0x90],
+    &[0x66, 0x90],
+    &[0x0F, 0x1F, 0x00],
+    &[0x0F, 0x1F, 0x40, 0x00],
+    &[0x0F, 0x1F, 0x44, 0x00, 0x00],
+    &[0x66, 0x0F, 0x1F, 0x44, 0x00, 0x00],
+    &[0x0F, 0x1F, 0x80, 0x00, 0x00, 0x00, 0x00],
+    &[0x0F, 0x1F, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+    &[0x66, 0x0F, 0x1F, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+];
+
+/// Push `len` bytes of NOP instructions, using as few instructions as possible.
+/// These encodings are also valid in 32-bit mode.
+pub fn nop_sled<D: DynasmApi + ?Sized>(ops: &mut D, mut len: usize) {
+    while len != 0 {
+        let nop = NOPS[len.min(NOPS.len()) - 1];
+        ops.extend(nop);
+        len -= nop.len();
+    }
+}
+
+/// Push NOP instructions until the assembling target end is aligned to `alignment`. Unlike `DynasmApi::align`,
+/// which is meant for padding data, the padding emitted by this is cheap to execute, so it can be used to
+/// align code that execution falls through into.
+pub fn align_code<D: DynasmApi + ?Sized>(ops: &mut D, alignment: usize) {
+    let misalign = ops.offset().0 % alignment;
+    if misalign != 0 {
+        nop_sled(ops, alignment - misalign);
+    }
+}
+
+
+/// Conditional jumps that are encoded as the 2-byte `jcc rel8` when their target is close enough, and as the 6-byte
+/// `jcc rel32` otherwise. As the distance to a target is only known once the code in between has been emitted, the code
+/// containing the jumps is emitted twice through `jcc`. The first pass, typically into a `SizingAssembler`, encodes all
+/// jumps as `rel32` and records where they and their targets are. After `resolve` has picked the encodings, the second pass
+/// emits the same code with the chosen encodings. Both passes have to emit the same jumps in the same order.
+///
+/// A jump is shortened if its target is within reach while all other jumps are encoded as `rel32`. Shortening other
+/// jumps brings targets closer, but it can also make the padding of an alignment between a jump and its target grow,
+/// by up to the alignment minus one byte. Alignments in code containing the jumps therefore have to be emitted through
+/// `align` in both passes, so this growth is taken into account. Targets behind alignments emitted any other way can
+/// end up out of reach, which makes committing the second pass fail with `DynasmError::ImpossibleRelocation`.
+#[derive(Debug, Clone, Default)]
+pub struct ShortBranches {
+    // the start of every jump emitted in the first pass, and the label it targets
+    sized: Vec<(AssemblyOffset, DynamicLabel)>,
+    // the offset of every alignment emitted in the first pass before it was padded, and the alignment
+    aligns: Vec<(AssemblyOffset, usize)>,
+    // if each jump is shortened. Empty until `resolve` is called.
+    short: Vec<bool>,
+    // the index of the next jump emitted in the second pass
+    next: usize,
+    resolved: bool,
+}
+
+impl ShortBranches {
+    /// Create a new set of conditional jumps, for the first pass.
+    pub fn new() -> ShortBranches {
+        ShortBranches::default()
+    }
+
+    /// Emit a conditional jump to `label`, taken if condition code `cond` holds, like 0x4 for `je` or 0xC for `jl`.
+    /// Panics if `cond` is not a condition code, or if more jumps are emitted in the second pass than in the first.
+    pub fn jcc<D: DynasmLabelApi<Relocation = X64Relocation> + ?Sized>(&mut self, ops: &mut D, cond: u8, label: DynamicLabel) {
+        assert!(cond < 16, "Invalid condition code {}", cond);
+        let short = if self.resolved {
+            let short = *self.short.get(self.next).expect("More conditional jumps emitted than during sizing");
+            self.next += 1;
+            short
+        } else {
+            self.sized.push((ops.offset(), label));
+            false
+        };
+
+        if short {
+            ops.extend(&[0x70 | cond, 0]);
+            ops.dynamic_reloc(label, 0, (0, 1));
+        } else {
+            ops.extend(&[0x0F, 0x80 | cond, 0, 0, 0, 0]);
+            ops.dynamic_reloc(label, 0, (0, 4));
+        }
+    }
+
+    /// Pad with `with` until the offset is aligned to `alignment`, like `DynasmApi::align`. In the first pass, the
+    /// alignment is recorded, so `resolve` accounts for its padding growing in the second pass.
+    pub fn align<D: DynasmApi + ?Sized>(&mut self, ops: &mut D, alignment: usize, with: u8) {
+        if !self.resolved {
+            self.aligns.push((ops.offset(), alignment));
+        }
+        ops.align(alignment, with);
+    }
+
+    /// Pick the encoding of every jump emitted during the first pass, using the offsets of their targets in `labels`, the
+    /// label registry of the assembler used for the first pass. Afterwards, `jcc` emits the jumps with these encodings.
+    /// Returns an error if a target label was never defined.
+    pub fn resolve(&mut self, labels: &LabelRegistry) -> Result<(), DynasmError> {
+        let mut short = Vec::with_capacity(self.sized.len());
+        for &(start, label) in &self.sized {
+            let target = labels.resolve_dynamic(label)?.0 as isize;
+            let start = start.0 as isize;
+            // the padding of every alignment between the jump and its target can grow by up to the alignment minus one
+            let (low, high) = if target > start { (start + 6, target) } else { (target, start) };
+            let slack: isize = self.aligns.iter()
+                .filter(|&&(offset, _)| offset.0 as isize >= low && offset.0 as isize <= high)
+                .map(|&(_, alignment)| alignment as isize - 1)
+                .sum();
+            // the distance from the end of the short form, as all other code between it and the target can only shrink
+            let distance = if target > start { target - (start + 6) + slack } else { target - (start + 2) - slack };
+            short.push(distance >= i8::MIN as isize && distance <= i8::MAX as isize);
+        }
+        self.short = short;
+        self.next = 0;
+        self.resolved = true;
+        Ok(())
+    }
+
+    /// Returns how many jumps were shortened, once `resolve` has been called.
+    pub fn shortened(&self) -> usize {
+        self.short.iter().filter(|&&short| short).count()
+    }
+}
+
+
+/// An island of trampolines, for jumping to and calling absolute addresses that might be further than 2GiB
+/// away from the code, out of reach of a 32-bit displacement. Jumps and calls are emitted with a 32-bit
+/// displacement to a trampoline, which loads the target address into a scratch register and jumps to it.
+/// As the trampolines don't depend on the address of the code, they keep working if the code moves.
+///
+/// The trampolines are placed in the code when `emit` is called. This should be done at a location that execution
+/// doesn't fall through into, such as after the last return of a function, and within 2GiB of the jumps using it.
+/// Trampolines for the same target are shared between all jumps and calls emitted since the previous `emit`.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone)]
+pub struct Trampolines {
+    scratch: u8,
+    // the trampoline label for every target that has been jumped to since the last island was emitted
+    pending: BTreeMap<usize, DynamicLabel>,
+}
+
+#[cfg(feature = "std")]
+impl Trampolines {
+    /// Create an empty island of trampolines, which clobber the register numbered `scratch`, like 0 for `rax`
+    /// or 11 for `r11`. Panics if `scratch` is not the number of a general purpose register.
+    pub fn new(scratch: u8) -> Trampolines {
+        assert!(scratch < 16, "Invalid trampoline scratch register {}", scratch);
+        Trampolines {
+            scratch,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// Emit a `jmp` to the absolute address `target`, through a trampoline.
+    pub fn jump(&mut self, ops: &mut Assembler, target: usize) {
+        ops.push(0xE9);
+        self.push_displacement(ops, target);
+    }
+
+    /// Emit a `call` to the absolute address `target`, through a trampoline. As the trampoline leaves the
+    /// return address alone, the call returns to right after this instruction.
+    pub fn call(&mut self, ops: &mut Assembler, target: usize) {
+        ops.push(0xE8);
+        self.push_displacement(ops, target);
+    }
+
+    fn push_displacement(&mut self, ops: &mut Assembler, target: usize) {
+        let label = *self.pending.entry(target).or_insert_with(|| ops.new_dynamic_label());
+        ops.push_u32(0);
+        ops.dynamic_reloc(label, 0, (0, 4));
+    }
+
+    /// Place the trampolines for all jumps and calls emitted since the previous island. Each consists of a
+    /// `mov scratch, imm64` followed by a `jmp scratch`. Does nothing if no trampolines are needed.
+    pub fn emit(&mut self, ops: &mut Assembler) {
+        let rex_b = (self.scratch >> 3) & 1;
+        let reg = self.scratch & 7;
+        for (target, label) in core::mem::take(&mut self.pending) {
+            ops.dynamic_label(label);
+            // mov scratch, imm64
+            ops.push(0x48 | rex_b);
+            ops.push(0xB8 | reg);
+            ops.push_u64(target as u64);
+            // jmp scratch
+            if rex_b != 0 {
+                ops.push(0x41);
+            }
+            ops.push(0xFF);
+            ops.push(0xE0 | reg);
+        }
+    }
+}
+
+
+/// Forward jumps that are emitted as the 2-byte `rel8` encodings, which are redirected through a branch island when
+/// their target isn't emitted soon enough. Unlike `ShortBranches`, this works in a single pass: the distance to every
+/// pending jump is tracked as code is emitted, and `check` places an island once a jump is about to go out of reach.
+/// An island is skipped over by a `jmp rel32`, and contains a `jmp rel32` to the eventual target of every jump that is
+/// still pending. These then point their `rel8` displacement at the island.
+///
+/// `check` has to be called at least every `max_gap` bytes of emitted code, for instance after every instruction.
+/// Jumping and defining targets already check. Targets of the jumps have to be defined through `label`, so the jumps
+/// that reach them directly can be resolved.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone)]
+pub struct BranchIslands {
+    max_gap: usize,
+    // the end of the displacement of every jump that is still pending, its target, and the label it actually jumps to
+    pending: Vec<(usize, DynamicLabel, DynamicLabel)>,
+    // how many jumps were redirected through islands
+    redirected: usize,
+}
+
+#[cfg(feature = "std")]
+impl BranchIslands {
+    // the size of the jump over an island, and of every entry in it
+    const JUMP_SIZE: usize = 5;
+
+    /// Create a new set of jumps, for code that calls `check` at least every `max_gap` bytes.
+    /// Panics if `max_gap` is more than 100 bytes, as jumps could go out of reach between checks.
+    pub fn new(max_gap: usize) -> BranchIslands {
+        assert!(max_gap <= 100, "Gap between branch island checks of {} bytes is too large", max_gap);
+        BranchIslands {
+            max_gap,
+            pending: Vec::new(),
+            redirected: 0,
+        }
+    }
+
+    /// Emit a short `jmp` to `label`, which has to be defined later through `label`.
+    pub fn jmp(&mut self, ops: &mut Assembler, label: DynamicLabel) {
+        self.check(ops);
+        ops.extend(&[0xEB, 0]);
+        self.push_pending(ops, label);
+    }
+
+    /// Emit a short conditional jump to `label`, taken if condition code `cond` holds, like 0x4 for `je`.
+    /// `label` has to be defined later through `label`. Panics if `cond` is not a condition code.
+    pub fn jcc(&mut self, ops: &mut Assembler, cond: u8, label: DynamicLabel) {
+        assert!(cond < 16, "Invalid condition code {}", cond);
+        self.check(ops);
+        ops.extend(&[0x70 | cond, 0]);
+        self.push_pending(ops, label);
+    }
+
+    fn push_pending(&mut self, ops: &mut Assembler, label: DynamicLabel) {
+        let via = ops.new_dynamic_label();
+        ops.dynamic_reloc(via, 0, (0, 1));
+        self.pending.push((ops.offset().0, label, via));
+        self.check(ops);
+    }
+
+    /// Define `label` at the current offset, resolving the pending jumps to it.
+    pub fn label(&mut self, ops: &mut Assembler, label: DynamicLabel) {
+        self.check(ops);
+        ops.dynamic_label(label);
+        self.pending.retain(|&(_, target, via)| {
+            if target == label {
+                ops.dynamic_label(via);
+            }
+            target != label
+        });
+    }
+
+    /// Place an island if any pending jump would be out of reach of it after another `max_gap` bytes of code.
+    pub fn check(&mut self, ops: &mut Assembler) {
+        let latest = ops.offset().0 + self.max_gap + Self::JUMP_SIZE;
+        let due = self.pending.iter().enumerate()
+            .any(|(i, &(end, _, _))| latest + i * Self::JUMP_SIZE - end > i8::MAX as usize);
+        if due {
+            self.emit(ops);
+        }
+    }
+
+    /// Place an island for all pending jumps now. Does nothing if no jumps are pending.
+    pub fn emit(&mut self, ops: &mut Assembler) {
+        if self.pending.is_empty() {
+            return;
+        }
+        // jmp over the island
+        ops.push(0xE9);
+        ops.push_u32((self.pending.len() * Self::JUMP_SIZE) as u32);
+        self.redirected += self.pending.len();
+        for (_, target, via) in self.pending.drain(..) {
+            ops.dynamic_label(via);
+            ops.push(0xE9);
+            ops.push_u32(0);
+            ops.dynamic_reloc(target, 0, (0, 4));
+        }
+    }
+
+    /// Returns how many jumps were redirected through islands so far.
+    pub fn redirected(&self) -> usize {
+        self.redirected
+    }
+}
+
+
+#[cfg(feature = "std")]
 pub type Assembler = crate::Assembler<X64Relocation>;
+#[cfg(feature = "std")]
 pub type AssemblyModifier<'a> = crate::Modifier<'a, X64Relocation>;
 pub type UncommittedModifier<'a> = crate::UncommittedModifier<'a>;
+pub type SliceAssembler<'a> = crate::SliceAssembler<'a, X64Relocation>;
+pub type SizingAssembler = crate::SizingAssembler<X64Relocation>;
+pub type VecLabelAssembler = crate::VecLabelAssembler<X64Relocation>;
+#[cfg(feature = "std")]
+pub type RingAssembler = crate::ring::RingAssembler<X64Relocation>;
+#[cfg(feature = "std")]
+pub type AssemblerPool = crate::pool::AssemblerPool<X64Relocation>;
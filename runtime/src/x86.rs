@@ -1,5 +1,9 @@
 use crate::relocations::{Relocation, RelocationSize, RelocationKind, ImpossibleRelocation};
 
+pub use crate::x64::{nop_sled, align_code};
+#[cfg(feature = "std")]
+use crate::{AssemblyOffset, DynasmApi, DynasmError};
+
 
 /// Relocation implementation for the x86 architecture.
 #[derive(Debug, Clone)]
@@ -52,6 +56,29 @@ impl Relocation for X86Relocation {
 }
 
 
+#[cfg(feature = "std")]
+impl crate::Assembler<X86Relocation> {
+    /// Emit a breakpoint, an `int3` instruction.
+    pub fn breakpoint(&mut self) {
+        self.push(0xCC);
+    }
+
+    /// Install a breakpoint at the committed offset `at`. See `x64::Assembler::set_breakpoint`.
+    pub fn set_breakpoint(&mut self, at: AssemblyOffset) -> Result<(), DynasmError> {
+        self.set_breakpoint_with(at, &[0xCC])
+    }
+}
+
+
+#[cfg(feature = "std")]
 pub type Assembler = crate::Assembler<X86Relocation>;
+#[cfg(feature = "std")]
 pub type AssemblyModifier<'a> = crate::Modifier<'a, X86Relocation>;
 pub type UncommittedModifier<'a> = crate::UncommittedModifier<'a>;
+pub type SliceAssembler<'a> = crate::SliceAssembler<'a, X86Relocation>;
+pub type SizingAssembler = crate::SizingAssembler<X86Relocation>;
+pub type VecLabelAssembler = crate::VecLabelAssembler<X86Relocation>;
+#[cfg(feature = "std")]
+pub type RingAssembler = crate::ring::RingAssembler<X86Relocation>;
+#[cfg(feature = "std")]
+pub type AssemblerPool = crate::pool::AssemblerPool<X86Relocation>;
@@ -0,0 +1,70 @@
+#![cfg(all(feature = "std", target_arch = "x86_64"))]
+
+// This test replaces the global allocator, so it lives in its own binary. Only allocations made by the thread running
+// the test are counted, so the test harness doesn't get in the way.
+
+extern crate dynasmrt;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use dynasmrt::{x64, DynasmApi, DynasmLabelApi};
+
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+fn count() {
+    // the thread local may already be gone while the thread shuts down
+    let _ = ALLOCATIONS.try_with(|a| a.set(a.get() + 1));
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count();
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count();
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+#[test]
+fn test_commit_allocations() {
+    // like the commit_bench example, size the buffer up front so the commits don't have to move it
+    let mut ops = x64::Assembler::with_initial_size(1000 * 16).unwrap();
+    ops.global_label("exit");
+    ops.push(0xC3);
+    // the first commit sets up the scratch space that later commits reuse
+    ops.push(0xE9);
+    ops.push_u32(0);
+    ops.global_reloc("exit", 0, (0, 4));
+    ops.commit().unwrap();
+    let labels: Vec<_> = (0 .. 1000).map(|_| ops.new_dynamic_label()).collect();
+
+    let mut allocations = 0;
+    for &label in &labels {
+        // call =>label; jmp ->exit
+        ops.dynamic_label(label);
+        ops.push(0xE8);
+        ops.push_u32(0);
+        ops.dynamic_reloc(label, 0, (0, 4));
+        ops.push(0xE9);
+        ops.push_u32(0);
+        ops.global_reloc("exit", 0, (0, 4));
+
+        let before = ALLOCATIONS.with(Cell::get);
+        ops.commit().unwrap();
+        allocations += ALLOCATIONS.with(Cell::get) - before;
+    }
+    assert_eq!(allocations, 0);
+}
@@ -0,0 +1,53 @@
+#![cfg(feature = "std")]
+
+// This test lives in its own binary, as finding a free address range by mapping and unmapping a buffer
+// would race with other tests allocating executable memory in the same process.
+
+extern crate dynasmrt;
+
+use dynasmrt::{x64, AssemblyOffset, DynasmApi, DynasmError, ExecutableBuffer};
+
+#[test]
+fn test_new_in() {
+    // find a free, suitably aligned address range by temporarily mapping one
+    let size = 0x10000;
+    let addr = {
+        let scratch = ExecutableBuffer::new(size * 2).unwrap();
+        (scratch.as_ptr() as usize + size - 1) & !(size - 1)
+    };
+
+    let mut ops = x64::Assembler::new_in(addr .. addr + size).unwrap();
+    ops.extend(&[0x90, 0xC3]);
+    ops.commit().unwrap();
+    assert_eq!(ops.reader().lock().ptr(AssemblyOffset(0)) as usize, addr);
+
+    // the region is already in use
+    assert!(x64::Assembler::new_in(addr .. addr + size).is_err());
+
+    // the code cannot grow past the region
+    ops.extend(vec![0xCC; size]);
+    assert_eq!(ops.commit(), Err(DynasmError::BufferFull));
+    assert_eq!(ops.reader().lock().len(), 2);
+    assert_eq!(ops.grow_to(size * 2), Err(DynasmError::BufferFull));
+
+    // code that can't move calls functions within reach directly
+    ops.reset().unwrap();
+    // mov eax, 5; ret
+    ops.extend(&[0xB8, 5, 0, 0, 0, 0xC3]);
+    ops.commit().unwrap();
+    let callee: extern "C" fn() -> u32 = unsafe { std::mem::transmute(addr) };
+    let start = ops.offset();
+    // push rax to align the stack; call callee; pop rcx; ret
+    ops.push(0x50);
+    ops.call_extern(callee);
+    ops.extend(&[0x59, 0xC3]);
+    ops.commit().unwrap();
+
+    let reader = ops.reader();
+    let code = reader.lock();
+    assert_eq!(&code[7 .. 12], &[0xE8, 0xF4, 0xFF, 0xFF, 0xFF]);
+    if cfg!(target_arch = "x86_64") {
+        let f: extern "sysv64" fn() -> u32 = unsafe { std::mem::transmute(code.ptr(start)) };
+        assert_eq!(f(), 5);
+    }
+}
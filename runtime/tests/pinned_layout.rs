@@ -0,0 +1,36 @@
+#![cfg(all(feature = "std", target_os = "linux", target_pointer_width = "64"))]
+
+// Like new_in.rs, this test lives in its own binary so nothing else maps memory in the region it uses.
+
+extern crate dynasmrt;
+
+use dynasmrt::{x86, DynasmApi, DynasmLabelApi};
+
+// A region that 64-bit Linux doesn't place anything in by default, and a function outside of it.
+const REGION: usize = 0x10_0000_0000;
+const SIZE: usize = 0x10000;
+const FUNCTION: usize = 0x10_1234_5000;
+
+fn assemble() -> Vec<u8> {
+    let mut ops = x86::Assembler::new_in(REGION .. REGION + SIZE).unwrap();
+    ops.global_label("start");
+    // call FUNCTION
+    ops.push(0xE8);
+    ops.push_u32(0);
+    ops.bare_reloc(FUNCTION, (0, 4, 2));
+    // jmp ->start
+    ops.push(0xE9);
+    ops.push_u32(0);
+    ops.global_reloc("start", 0, (0, 4, 0));
+    ops.commit().unwrap();
+    let code = ops.reader().lock().to_vec();
+    code
+}
+
+#[test]
+fn test_pinned_layout() {
+    // the displacement to the absolute address only matches the snapshot if the code is placed at REGION
+    let snapshot = [0xE8, 0xFB, 0x4F, 0x34, 0x12, 0xE9, 0xF6, 0xFF, 0xFF, 0xFF];
+    assert_eq!(assemble(), snapshot);
+    assert_eq!(assemble(), snapshot);
+}